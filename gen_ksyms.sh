@@ -0,0 +1,36 @@
+#!/bin/sh
+# Generate the assembly for the kernel symbol table from the function
+# symbols of a linked kernel, sorted by address. Without a kernel an empty
+# table is generated, which is what the first of the two links uses.
+
+set -e
+
+if [ -n "$1" ]; then
+    nm -n --defined-only "$1" | awk '$2 ~ /^[tTwW]$/ { print $1, $3 }'
+fi | awk '
+BEGIN { n = 0 }
+{ addr[n] = $1; name[n] = $2; n++ }
+END {
+    print "/* Generated by gen_ksyms.sh */"
+    print ".section .ksyms, \"a\""
+    print ".align 8"
+    print ".global ksyms_count"
+    print "ksyms_count:"
+    printf "    .quad %d\n", n
+    print ".global ksyms_addresses"
+    print "ksyms_addresses:"
+    for (i = 0; i < n; i++)
+        printf "    .quad 0x%s\n", addr[i]
+    print ".global ksyms_offsets"
+    print "ksyms_offsets:"
+    offset = 0
+    for (i = 0; i < n; i++) {
+        printf "    .long %d\n", offset
+        offset += length(name[i])
+    }
+    printf "    .long %d\n", offset
+    print ".global ksyms_names"
+    print "ksyms_names:"
+    for (i = 0; i < n; i++)
+        printf "    .ascii \"%s\"\n", name[i]
+}'
@@ -10,3 +10,50 @@ pub mod x86_64;
 /// Permanently stop the system, attempting to target a low power state
 /// This is usually the last step in an unrecoverable error
 pub use self::x86_64::halt;
+
+/// Reset the processor in the most brutal way possible. This is only used
+/// by platforms as a fallback when all nicer methods of reset have failed
+pub use self::x86_64::triple_fault;
+
+/// Read the free running cycle counter of the calling processor
+pub use self::x86_64::cpu::rdtsc;
+
+/// Hardware identifier of the calling processor, as used in diagnostics
+pub use self::x86_64::cpu::current_cpu_id;
+
+/// Walk the stack of the caller, or of some other frame, yielding return
+/// addresses
+pub use self::x86_64::backtrace::{current_frames, frames};
+
+/// Disable interrupts on the calling processor until the returned value is
+/// dropped
+pub use self::x86_64::cpu::disable_interrupts;
+
+/// Stop every other processor, for when this one has panicked, and print
+/// the registers they were stopped with
+pub use self::x86_64::{stop_other_cpus, write_stopped_cpus};
+
+/// Print the details of the exception the current panic came from, if any
+pub use self::x86_64::write_exception;
+
+/// Registers saved on an exception, which the GDB stub reads and changes
+pub use self::x86_64::ExceptionFrame;
+
+/// Access to exception state and breakpoints for the GDB stub
+pub use self::x86_64::debug;
+
+/// Describe the page tables and processors for the debugger monitor
+pub use self::x86_64::{page_table_root, write_page_tables, write_cpu_state};
+
+/// Find user pages, and mark the ones that hold proxy capabilities
+pub use self::x86_64::{UserPage, lookup_user_page, mark_proxy_page};
+
+/// End of the part of the address space that user level can use
+pub use self::x86_64::USER_TOP;
+
+/// Processor support for running user level threads, see `thread`
+pub use self::x86_64::thread::X86_64 as Arch;
+
+/// Window onto the first 4GB of physical memory, through which devices and
+/// the debugger monitor access it
+pub use self::x86_64::DeviceWindow;
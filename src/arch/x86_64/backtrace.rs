@@ -0,0 +1,53 @@
+//! Stack walking by frame pointers
+//!
+//! The kernel is built keeping frame pointers, so every function starts by
+//! pushing the caller's rbp and pointing rbp at it, with the return address
+//! just above. Following the chain of saved rbp values from any frame
+//! yields the return addresses of all its callers. The boot code starts
+//! the chain with a null rbp.
+//!
+//! Nothing guarantees the chain is intact after memory corruption, so each
+//! step is checked to stay in the kernel half of the address space and to
+//! move up the stack, and walking stops at the first suspicious frame.
+
+/// Frames more than this many calls deep are not reported
+const MAX_DEPTH: usize = 64;
+/// Lowest address of the kernel half of the address space
+const KERNEL_BASE: usize = 0xffff800000000000;
+
+/// Iterator over the return addresses of a chain of frames
+pub struct Frames {
+    rbp: usize,
+    depth: usize,
+}
+
+/// Walk the frames starting at the frame pointer `rbp`
+pub fn frames(rbp: usize) -> Frames {
+    Frames { rbp: rbp, depth: 0 }
+}
+
+/// Walk the frames of the caller
+#[inline(always)]
+pub fn current_frames() -> Frames {
+    let rbp: usize;
+    unsafe{asm!("movq %rbp, $0" : "=r"(rbp) : : : "volatile")};
+    frames(rbp)
+}
+
+impl Iterator for Frames {
+    type Item = usize;
+    fn next(&mut self) -> Option<usize> {
+        if self.rbp < KERNEL_BASE || self.rbp % 8 != 0 || self.depth == MAX_DEPTH {
+            return None;
+        }
+        let (next, ret) = unsafe {
+            let frame = self.rbp as *const usize;
+            (*frame, *frame.offset(1))
+        };
+        /* the caller's frame must be further up the stack, anything else
+         * is a corrupt chain */
+        self.rbp = if next > self.rbp { next } else { 0 };
+        self.depth += 1;
+        if ret == 0 { None } else { Some(ret) }
+    }
+}
@@ -16,14 +16,31 @@ use panic::*;
 use steal_mem::*;
 use types::*;
 use ::config::BootConfig;
-use ::core::fmt::Write;
+use cluster::{Clusters, ClusterPolicy};
+use object::Tcb;
+use thread::ArchThread;
+use arch::Arch;
 use ::core::marker::PhantomData;
 use ::core::cmp;
 use super::halt::halt;
 use super::vspace::*;
 use super::cpu;
+use super::idt;
+use super::multiboot2;
+use super::multiboot2::Multiboot2;
 extern crate multiboot;
 
+/// Bit of the Multiboot1 information flags indicating that the framebuffer
+/// fields are valid. The multiboot crate does not understand these fields
+/// so we decode them ourselves
+const MB1_FLAG_FRAMEBUFFER: u32 = 1 << 12;
+/// Offset of the framebuffer fields in the Multiboot1 information
+const MB1_FRAMEBUFFER_OFFSET: usize = 88;
+/// Size of the framebuffer fields, up to the end of the colour information
+const MB1_FRAMEBUFFER_SIZE: usize = 28;
+/// Offset of the colour information within the framebuffer fields
+const MB1_FRAMEBUFFER_COLOR_OFFSET: usize = 22;
+
 extern {
     /// Represent the start of the kernel image region. The type is not
     /// correct as there is no way to properly represent the type we want
@@ -42,7 +59,7 @@ struct EarlyBootState<'a, 'h, 'l> where 'h: 'a, 'l: 'a {
     /// cannot be returned in `PostEarlyBootState`
     low_window : &'a BootLowWindow<'l>,
     /// The value of EAX passed from the assembly entry. This is checked
-    /// to ensure we were multiboot loaded
+    /// to ensure we were multiboot loaded, and which version
     mbi_magic: usize,
     /// Raw physical pointer that should point to the multiboot structure
     mbi: *const usize,
@@ -52,36 +69,144 @@ struct EarlyBootState<'a, 'h, 'l> where 'h: 'a, 'l: 'a {
 struct PostEarlyBootState<'a> {
     /// An initialized platform interface
     plat: PlatInterfaceType,
+    /// Tables of the final kernel window, not yet loaded
+    kernel_root: PAddr,
+    /// The first user level thread, ready to be started
+    first_thread: *mut Tcb,
     /// Currently the lifetime 'a is unused, so have some `PhantomData` to get
     /// around that
     phantom: PhantomData<&'a usize>,
 }
 
-/// Debug function to print out the contents of the multiboot information
-fn display_multiboot<'a, F: Fn(u64, usize) -> Option<&'a [u8]>>(plat: &mut PlatInterfaceType, mbi: &'a multiboot::Multiboot<'a, F>) {
-    write!(plat, "Multiboot information:\n").unwrap();
+/// Log, at debug level, the contents of the multiboot information
+fn display_multiboot<'a, F: Fn(u64, usize) -> Option<&'a [u8]>>(mbi: &multiboot::Multiboot<'a, F>) {
+    debug!("Multiboot information:");
     if let Some(low) = mbi.lower_memory_bound() {
-        write!(plat,"\t{}kb of low memory\n", low).unwrap();
+        debug!("\t{}kb of low memory", low);
     }
     if let Some(high) = mbi.upper_memory_bound() {
-        write!(plat,"\t{}mb of high memory\n", high / 1024).unwrap();
+        debug!("\t{}mb of high memory", high / 1024);
     }
     if let Some(boot) = mbi.boot_device() {
-        write!(plat,"\tBoot device {:?}\n", boot).unwrap();
+        debug!("\tBoot device {:?}", boot);
     }
     if let Some(line) = mbi.command_line() {
-        write!(plat,"\tCommand line \"{}\"\n", line).unwrap();
+        debug!("\tCommand line \"{}\"", line);
     }
     if let Some(modules) = mbi.modules() {
-        write!(plat,"Multiboot modules:\n").unwrap();
+        debug!("Multiboot modules:");
         for m in modules {
-            write!(plat,"\t{:?}\n", m).unwrap();
+            debug!("\t{:?}", m);
         }
     }
     if let Some(memory) = mbi.memory_regions() {
-        write!(plat,"Memory regions:\n").unwrap();
+        debug!("Memory regions:");
+        for m in memory {
+            debug!("\t{:?}", m);
+        }
+    }
+}
+
+/// Log, at debug level, the contents of the multiboot2 information
+fn display_multiboot2(mbi: &Multiboot2) {
+    debug!("Multiboot2 information:");
+    if let Some(line) = mbi.command_line() {
+        debug!("\tCommand line \"{}\"", line);
+    }
+    if let Some(rsdp) = mbi.acpi_rsdp() {
+        debug!("\tACPI RSDP copy at {:x}", rsdp.0);
+    }
+    if let Some(memory) = mbi.memory_regions() {
+        debug!("Memory regions:");
         for m in memory {
-            write!(plat,"\t{:?}\n", m).unwrap();
+            debug!("\t{:?}", m);
+        }
+    }
+}
+
+/// Decode the framebuffer fields of the Multiboot1 information at `mbi`
+fn multiboot1_framebuffer<'a, W: VSpaceWindow<'a>>(window: &'a W, mbi: PAddr) -> Option<Framebuffer> {
+    let flags = window.try_from_paddr(mbi)
+        .and_then(|addr| unsafe{window.make::<u32>(addr)});
+    match flags {
+        Some(flags) if *flags & MB1_FLAG_FRAMEBUFFER != 0 => (),
+        _ => return None,
+    }
+    window.try_from_paddr(PAddr(mbi.0 + MB1_FRAMEBUFFER_OFFSET))
+        .and_then(|addr| unsafe{window.make_slice::<u8>(addr, MB1_FRAMEBUFFER_SIZE)})
+        .and_then(|bytes| multiboot2::framebuffer_fields(bytes, MB1_FRAMEBUFFER_COLOR_OFFSET))
+}
+
+/// Log, at debug level, the framebuffer the boot loader gave us
+fn display_framebuffer(fb: &Framebuffer) {
+    debug!("\tFramebuffer {}x{} {}bpp at {:x}", fb.width, fb.height, fb.bpp, fb.base.0);
+}
+
+/// Boot information from whichever version of multiboot loaded us. The
+/// framebuffer of Multiboot1 is decoded up front, as it is not covered by
+/// the multiboot crate
+enum BootInfo<'a, F> where F: Fn(u64, usize) -> Option<&'a [u8]> + 'a {
+    V1(multiboot::Multiboot<'a, F>, Option<Framebuffer>),
+    V2(Multiboot2<'a>),
+}
+
+impl<'a, F: Fn(u64, usize) -> Option<&'a [u8]>> BootInfo<'a, F> {
+    fn command_line(&self) -> Option<&'a str> {
+        match *self {
+            BootInfo::V1(ref mbi, _) => mbi.command_line(),
+            BootInfo::V2(ref mbi) => mbi.command_line(),
+        }
+    }
+    fn memory_regions(&self) -> Option<BootMemIter<'a, F>> {
+        match *self {
+            BootInfo::V1(ref mbi, _) => mbi.memory_regions().map(BootMemIter::V1),
+            BootInfo::V2(ref mbi) => mbi.memory_regions().map(BootMemIter::V2),
+        }
+    }
+    /// Information the platform needs from the boot loader
+    fn loader_info(&self) -> LoaderInfo {
+        match *self {
+            BootInfo::V1(_, framebuffer) =>
+                LoaderInfo { acpi_rsdp: None, framebuffer: framebuffer },
+            BootInfo::V2(ref mbi) =>
+                LoaderInfo { acpi_rsdp: mbi.acpi_rsdp(), framebuffer: mbi.framebuffer() },
+        }
+    }
+    fn display(&self) {
+        match *self {
+            BootInfo::V1(ref mbi, _) => display_multiboot(mbi),
+            BootInfo::V2(ref mbi) => display_multiboot2(mbi),
+        }
+        if let Some(ref fb) = self.loader_info().framebuffer {
+            display_framebuffer(fb);
+        }
+    }
+}
+
+/// Iterator over the usable RAM regions of either multiboot memory map
+enum BootMemIter<'a, F> where F: Fn(u64, usize) -> Option<&'a [u8]> + 'a {
+    V1(multiboot::MemoryMapIter<'a, F>),
+    V2(multiboot2::MemoryMapIter<'a>),
+}
+
+impl<'a, F: Fn(u64, usize) -> Option<&'a [u8]>> Iterator for BootMemIter<'a, F> {
+    type Item=(PAddr, PAddr);
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (base, len) = match *self {
+                BootMemIter::V1(ref mut iter) => match iter.next() {
+                    None => return None,
+                    Some(m) if m.memory_type() == multiboot::MemoryType::RAM =>
+                        (m.base_address(), m.length()),
+                    Some(_) => continue,
+                },
+                BootMemIter::V2(ref mut iter) => match iter.next() {
+                    None => return None,
+                    Some(m) if m.is_ram() => (m.base, m.length),
+                    Some(_) => continue,
+                },
+            };
+            return Some((PAddr(base as usize), PAddr((base + len) as usize)));
         }
     }
 }
@@ -97,8 +222,8 @@ fn get_kernel_image_region<'a>(window: &BootHighWindow<'a>) -> (HighWindowAddr,
 /// Small wrapper around the boot info memory map iterator to only return
 /// usable RAM regions
 struct BIMemIterator<'a, F> where F: Fn(u64, usize) -> Option<&'a [u8]> + 'a {
-    /// Iterator over the raw multiboot memory regions
-    iter: multiboot::MemoryMapIter<'a, F>,
+    /// Iterator over the RAM regions of the boot info
+    iter: BootMemIter<'a, F>,
     /// Physical memory above which we actually consider usable. This allows
     /// for a coarse grained way of skipping the memory that is currently
     /// used by the kernel image
@@ -108,18 +233,12 @@ struct BIMemIterator<'a, F> where F: Fn(u64, usize) -> Option<&'a [u8]> + 'a {
 impl<'a, F: Fn(u64, usize) -> Option<&'a [u8]>> Iterator for BIMemIterator<'a, F> {
     type Item=(PAddr, PAddr);
     fn next(&mut self) -> Option<Self::Item> {
-        let mem = match self.iter.next() {
+        let (base, end) = match self.iter.next() {
             None => return None,
             Some(m) => m,
         };
-        if mem.memory_type() == multiboot::MemoryType::RAM {
-            let base = mem.base_address() as usize;
-            let len = mem.length() as usize;
-            let end = PAddr(base + len);
-            if end > self.start {
-                let ret = (cmp::max(PAddr(base), self.start), end);
-                return Some(ret);
-            }
+        if end > self.start {
+            return Some((cmp::max(base, self.start), end));
         }
         self.next()
     }
@@ -136,58 +255,98 @@ impl<'a, F: Fn(u64, usize) -> Option<&'a [u8]>> Iterator for BIMemIterator<'a, F
 /// Should only be called once during bootup with the correct
 /// initial state
 unsafe fn try_early_boot_system<'a, 'h, 'l>(init: EarlyBootState<'a, 'h, 'l>) -> Result<PostEarlyBootState<'h>, ()> {
-    /* check that we are multi-booted */
-    if init.mbi_magic as u32 != multiboot::SIGNATURE_EAX {
-        return Err(());
-    }
-    /* construct a reference to the mbi to get all of our boot information */
-    let mbi = match multiboot::Multiboot::new(init.mbi as multiboot::PAddr,
-            |p, s| init.low_window.make_slice(
-                init.low_window.from_paddr(PAddr(p as usize)),
-                s)) {
+    /* check that we are multi-booted, and construct a reference to the mbi
+     * to get all of our boot information */
+    let mbi = match init.mbi_magic as u32 {
+        multiboot::SIGNATURE_EAX =>
+            multiboot::Multiboot::new(init.mbi as multiboot::PAddr,
+                |p, s| init.low_window.make_slice(
+                    init.low_window.from_paddr(PAddr(p as usize)),
+                    s)).map(|mbi| BootInfo::V1(mbi,
+                        multiboot1_framebuffer(init.low_window, PAddr(init.mbi as usize)))),
+        multiboot2::SIGNATURE_EAX =>
+            Multiboot2::new(init.low_window, PAddr(init.mbi as usize)).map(BootInfo::V2),
+        _ => None,
+    };
+    let mbi = match mbi {
         Some(mbi) => mbi,
         None => {
                 return Err(());
             }
         };
     let bootconfig = BootConfig::new(mbi.command_line().unwrap_or(""));
+    /* Set up log filtering before anything is logged */
+    ::log::init(&bootconfig);
+    panic_init(&bootconfig);
+    ::monitor::init(&bootconfig);
     /* Initial the serial output of our platform first so that
      * we can get debugging output. */
-    let mut plat = get_platform(&bootconfig);
+    let mut plat = get_platform(&bootconfig, &mbi.loader_info());
     plat.init_serial();
     /* Initialize the panic function so we can see anything
      * really bad that happens */
     panic_set_plat(&mut plat);
-    write!(plat, "R4: In early setup\n").unwrap();
+    /* and report, rather than triple fault on, any exceptions */
+    idt::init();
+    info!("R4: In early setup");
     let (ki_start, ki_end) = get_kernel_image_region(init.high_window);
-    write!(plat, "Kernel image region {:x} {:x}\n", *ki_start, *ki_end).unwrap();
+    debug!("Kernel image region {:x} {:x}", *ki_start, *ki_end);
     /* Now we can continue with the rest of init */
-    display_multiboot(&mut plat, &mbi);
-    /* Construct early kernel allocator for memory stealing. For simplicity
-     * we just ignore any memory that occurs before the end of the kernel
-     * image. */
-    let regions = match mbi.memory_regions() {
+    mbi.display();
+    /* Remember the memory map for the debugger monitor */
+    if let Some(regions) = mbi.memory_regions() {
+        ::monitor::set_memory_map(regions);
+    }
+    /* Perform early platform specific system initialization */
+    try!(plat.early_init());
+    /* Do any platform device discovery */
+    try!(plat.early_device_discovery(init.low_window));
+    /* Now that we know the topology we can decide on clusters, and which
+     * cluster each region of memory belongs to */
+    let clusters = Clusters::new(plat.topology(), ClusterPolicy::from_config(&bootconfig));
+    info!("{} clusters", clusters.count());
+    let ram = match mbi.memory_regions() {
         None => {
-            write!(plat, "No memory regions found in multiboot\n").unwrap();
+            error!("No memory regions found in multiboot");
             return Err(());
             }
         Some(reg) => reg,
     };
-    let mut early_alloc = StealMem::new(
-        BIMemIterator{
-            iter: regions,
-            start: init.high_window.to_paddr(ki_end),
-        },
-        init.high_window);
-    /* Perform early platform specific system initialization */
-    try!(plat.early_init());
-    /* Do any platform device discovery */
-    try!(plat.early_device_discovery(init.low_window));
-    /* Do early CPU initialiation */
-    try!(cpu::early_init(&mut plat));
-    /* Construct kernel window */
-    let window = try!(make_kernel_window(&mut plat, &mut early_alloc));
-    Ok(PostEarlyBootState{ plat: plat, phantom: PhantomData })
+    for (start, end, cluster) in clusters.tag(BIMemIterator { iter: ram, start: PAddr(0) }) {
+        debug!("\t{:x}-{:x} cluster {:?}", start.0, end.0, cluster);
+    }
+    let ram_top = match mbi.memory_regions() {
+        Some(regions) => BIMemIterator { iter: regions, start: PAddr(0) }
+            .fold(PAddr(0), |top, (_, end)| cmp::max(top, end)),
+        None => PAddr(0),
+    };
+    /* Construct early kernel allocator for memory stealing. For simplicity
+     * we just ignore any memory that occurs before the end of the kernel
+     * image. Memory in the cluster of the boot processor is used first, so
+     * that is where the kernel window and first thread end up */
+    let (kernel_root, first_thread) = {
+        let boot_cluster = clusters.cluster_of_cpu(cpu::current_cpu_id());
+        let start = init.high_window.to_paddr(ki_end);
+        let (first, rest) = match (mbi.memory_regions(), mbi.memory_regions()) {
+            (Some(first), Some(rest)) => (BIMemIterator { iter: first, start: start },
+                BIMemIterator { iter: rest, start: start }),
+            _ => return Err(()),
+        };
+        let mut early_alloc = StealMem::new(clusters.local_first(boot_cluster, first, rest),
+            init.high_window);
+        /* Block stray DMA before anything is handed to user level */
+        try!(plat.init_iommu(init.low_window, &mut early_alloc));
+        /* Do early CPU initialiation */
+        try!(cpu::early_init());
+        /* Construct kernel window, which maps all of memory */
+        let kernel_root = try!(make_kernel_window(ram_top, &mut early_alloc));
+        /* Budgets are measured with the cycle counter calibrated above */
+        ::sched::init(plat.tsc_frequency());
+        /* The first thread is made whilst the boot allocator is still around */
+        (kernel_root, try!(::thread::create_initial(&mut early_alloc)))
+    };
+    Ok(PostEarlyBootState{ plat: plat, kernel_root: kernel_root, first_thread: first_thread,
+        phantom: PhantomData })
 }
 
 /// Perform the rest of the system boot in the final kernel Window.
@@ -195,14 +354,16 @@ unsafe fn try_early_boot_system<'a, 'h, 'l>(init: EarlyBootState<'a, 'h, 'l>) ->
 /// # Safety
 ///
 /// Should only be called oncce during bootup. Assumes that the kernel
-/// address space has been loaded and is currently active
-unsafe fn try_boot_system() {
+/// address space has been loaded and is currently active. Never returns,
+/// as it ends by starting `first_thread`
+unsafe fn try_boot_system(first_thread: *mut Tcb) -> ! {
     /* Initialize CPU */
+    Arch::init_cpu();
     /* Initialize other system state? */
     /* Perform any post cpu platform init */
-    /* Load initial user thread. Part of this will have to be
-     * moved earlier as the data for this is in the early boot
-     * window :(. Worry about this later */
+    /* Start the initial user thread, which was made during early boot
+     * whilst its data was still in the early boot window */
+    ::thread::start(first_thread)
 }
 
 /// Rust entry point for the kernel. This expects two parameters, one the
@@ -211,6 +372,8 @@ unsafe fn try_boot_system() {
 /// correctly in the active address space root
 #[no_mangle]
 pub extern fn boot_system(magic: usize, mbi: *const usize) -> ! {
+    /* Give every log message the right CPU without asking the processor */
+    unsafe{cpu::init_cpu_id(::sched::BOOT_CPU)};
     /* This *will* be our final kernel window, but it is not our window
      * yet. We create it here so that our temporary high kernel window,
      * which is a subset of the final kernel window, can be constructed
@@ -239,7 +402,7 @@ pub extern fn boot_system(magic: usize, mbi: *const usize) -> ! {
     /* The 'plat' definition got moved into boot. Reset the panic location */
     panic_set_plat(&mut boot.plat);
     /* Switch to kernel address space for this cluster */
+    unsafe{load_kernel_window(boot.kernel_root)};
     /* Now we can perform the rest of the system boot */
-    unsafe{try_boot_system();}
-    unimplemented!()
+    unsafe{try_boot_system(boot.first_thread)}
 }
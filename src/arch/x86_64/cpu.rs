@@ -3,9 +3,9 @@ extern crate raw_cpuid;
 extern crate x86;
 
 use self::raw_cpuid::*;
-use plat::*;
-use ::core::fmt::Write;
+use cluster::MAX_CPUS;
 use ::core::mem::transmute;
+use ::core::sync::atomic::{AtomicBool, Ordering};
 
 const IA32_PAT_MT_UNCACHEABLE: u8     = 0x00;
 const IA32_PAT_MT_WRITE_COMBINING: u8 = 0x01;
@@ -19,7 +19,7 @@ const PAT_INDEX_WRITE_BACK: usize = 0;
 const PAT_INDEX_WRITE_THROUGH: usize = 1;
 const PAT_INDEX_UNCACHED: usize = 2;
 const PAT_INDEX_UNCACHEABLE: usize = 3;
-const PAT_INDEX_WRITE_COMBINING: usize = 4;
+pub const PAT_INDEX_WRITE_COMBINING: usize = 4;
 
 #[derive(Copy, Clone)]
 pub struct Feature_Pat;
@@ -48,11 +48,125 @@ fn init_pat(pat: Feature_Pat) {
     unsafe{x86::msr::wrmsr(x86::msr::IA32_PAT, pat)};
 }
 
+/// Read the current value of the time stamp counter
+pub fn rdtsc() -> u64 {
+    let low: u32;
+    let high: u32;
+    unsafe {
+        asm!("rdtsc" : "={eax}"(low), "={edx}"(high) : : : "volatile");
+    }
+    ((high as u64) << 32) | (low as u64)
+}
+
+/// Flush the cache line containing `addr` back to memory. Needed when
+/// writing structures that are read by devices that do not snoop caches
+pub unsafe fn clflush(addr: usize) {
+    asm!("clflush ($0)" : : "r"(addr) : "memory" : "volatile");
+}
+
+/// Interrupt flag of RFLAGS
+const RFLAGS_IF: u64 = 1 << 9;
+
+/// Witness that interrupts were disabled by `disable_interrupts`. When
+/// dropped interrupts are enabled again, if they were enabled before
+pub struct InterruptsDisabled {
+    rflags: u64,
+}
+
+/// Disable interrupts on the calling processor until the returned value
+/// is dropped. Nests, so may be used by code that does not know whether
+/// interrupts are already disabled
+pub fn disable_interrupts() -> InterruptsDisabled {
+    let rflags: u64;
+    unsafe {
+        asm!("pushfq
+              popq $0
+              cli"
+             : "=r"(rflags) : : "memory" : "volatile");
+    }
+    InterruptsDisabled { rflags: rflags }
+}
+
+impl Drop for InterruptsDisabled {
+    fn drop(&mut self) {
+        if self.rflags & RFLAGS_IF != 0 {
+            unsafe{asm!("sti" : : : "memory" : "volatile")};
+        }
+    }
+}
+
+/// The kernel never runs `swapgs`, so the MSR that the GS base would be
+/// swapped with is free to hold the index of each processor
+const IA32_KERNEL_GS_BASE: u32 = 0xc0000102;
+
+/// APIC IDs of the processors that have called `init_cpu_id`, by index
+static mut CPU_IDS: [u32; MAX_CPUS] = [0; MAX_CPUS];
+/// Some processor has called `init_cpu_id`
+static CPU_IDS_SET: AtomicBool = AtomicBool::new(false);
+
+/// Execute `cpuid` for `leaf` and `subleaf`, returning eax, ebx, ecx and
+/// edx
+fn cpuid(leaf: u32, subleaf: u32) -> [u32; 4] {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    unsafe {
+        asm!("cpuid" : "={eax}"(eax), "={ebx}"(ebx), "={ecx}"(ecx), "={edx}"(edx)
+             : "{eax}"(leaf), "{ecx}"(subleaf) : : "volatile");
+    }
+    [eax, ebx, ecx, edx]
+}
+
+/// APIC ID of the calling processor, as assigned at reset. This is the full
+/// x2APIC ID if the processor has the extended topology leaf, as the 8 bit
+/// ID of leaf 1 is truncated on machines that need x2APIC
+fn read_apic_id() -> u32 {
+    if cpuid(0, 0)[0] >= 0xb && cpuid(0xb, 0)[1] != 0 {
+        cpuid(0xb, 0)[3]
+    } else {
+        cpuid(1, 0)[1] >> 24
+    }
+}
+
+/// Record the calling processor as being the one with `index`, which must
+/// be less than `MAX_CPUS`, so that `current_cpu_index` and
+/// `current_cpu_id` need not ask the processor
+///
+/// # Safety
+///
+/// Must be called once by each processor, before it is used for anything
+/// more than logging
+pub unsafe fn init_cpu_id(index: usize) {
+    assert!(index < MAX_CPUS);
+    CPU_IDS[index] = read_apic_id();
+    x86::msr::wrmsr(IA32_KERNEL_GS_BASE, index as u64);
+    CPU_IDS_SET.store(true, Ordering::Release);
+}
+
+/// Index of the calling processor, as given to `init_cpu_id`. The boot
+/// processor is the only one that can run before that, and is index 0
+pub fn current_cpu_index() -> usize {
+    if CPU_IDS_SET.load(Ordering::Acquire) {
+        unsafe{x86::msr::rdmsr(IA32_KERNEL_GS_BASE)} as usize
+    } else {
+        0
+    }
+}
+
+/// APIC ID of the calling processor, as assigned at reset. This is what
+/// identifies a CPU in diagnostics. It is only read from the processor
+/// until `init_cpu_id` has been called
+pub fn current_cpu_id() -> u32 {
+    if CPU_IDS_SET.load(Ordering::Acquire) {
+        unsafe{CPU_IDS[current_cpu_index()]}
+    } else {
+        read_apic_id()
+    }
+}
+
 /// Performs early CPU initialization and returns a witness to required
 /// CPU features
-pub fn early_init(plat: &mut PlatInterfaceType) -> Result<Features, ()> {
+pub fn early_init() -> Result<Features, ()> {
     let cpuid = CpuId::new();
-    cpuid.get_vendor_info().map(|info| write!(plat, "CPU vendor {}\n", info).unwrap());
+    cpuid.get_vendor_info().map(|info| info!("CPU vendor {}", info));
     let features = try!(cpuid.get_feature_info().ok_or(()));
     if !features.has_pat() {
         return Err(())
@@ -0,0 +1,118 @@
+//! Processor support for the GDB stub
+//!
+//! Registers are numbered as in GDB's x86-64 description: the sixteen
+//! general purpose registers in the order rax, rbx, rcx, rdx, rsi, rdi,
+//! rbp, rsp, r8 to r15, then rip, eflags and the segment selectors cs, ss,
+//! ds, es, fs and gs. The floating point and vector registers are not
+//! reported, as the kernel does not use them. The data segment selectors
+//! are not saved on an exception, and read as zero.
+use vspace::VSpaceWindow;
+use super::idt::ExceptionFrame;
+use super::vspace::{BootHighWindow, DeviceWindow};
+
+/// Length of the breakpoint instruction
+pub const BREAKPOINT_LEN: usize = 1;
+/// Instruction that software breakpoints are replaced with
+pub const BREAKPOINT: [u8; BREAKPOINT_LEN] = [0xcc];
+/// Number of registers in a `g` packet
+pub const NUM_REGISTERS: usize = 24;
+
+/// Trap flag in rflags, which raises a debug exception after the next
+/// instruction
+const RFLAGS_TF: u64 = 1 << 8;
+/// Vector of the debug exception, raised by single stepping
+const VECTOR_DEBUG: u64 = 1;
+/// Vector of the breakpoint exception, raised by `int3`
+const VECTOR_BREAKPOINT: u64 = 3;
+
+/// POSIX signal numbers that GDB expects stop reasons in
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGBUS: u8 = 7;
+const SIGFPE: u8 = 8;
+const SIGSEGV: u8 = 11;
+
+/// Value and size in bytes of register `n`, or `None` past the last one
+pub fn register(frame: &ExceptionFrame, n: usize) -> Option<(u64, usize)> {
+    let value = match n {
+        0 => frame.rax, 1 => frame.rbx, 2 => frame.rcx, 3 => frame.rdx,
+        4 => frame.rsi, 5 => frame.rdi, 6 => frame.rbp, 7 => frame.rsp,
+        8 => frame.r8, 9 => frame.r9, 10 => frame.r10, 11 => frame.r11,
+        12 => frame.r12, 13 => frame.r13, 14 => frame.r14, 15 => frame.r15,
+        16 => frame.rip,
+        17 => return Some((frame.rflags, 4)),
+        18 => return Some((frame.cs, 4)),
+        19 => return Some((frame.ss, 4)),
+        20 ... 23 => return Some((0, 4)),
+        _ => return None,
+    };
+    Some((value, 8))
+}
+
+/// Set register `n`. Returns false if there is no such register. Writes to
+/// the segment selectors are ignored, as changing them would most likely
+/// fault on the return from the exception
+pub fn set_register(frame: &mut ExceptionFrame, n: usize, value: u64) -> bool {
+    match n {
+        0 => frame.rax = value, 1 => frame.rbx = value, 2 => frame.rcx = value,
+        3 => frame.rdx = value, 4 => frame.rsi = value, 5 => frame.rdi = value,
+        6 => frame.rbp = value, 7 => frame.rsp = value, 8 => frame.r8 = value,
+        9 => frame.r9 = value, 10 => frame.r10 = value, 11 => frame.r11 = value,
+        12 => frame.r12 = value, 13 => frame.r13 = value, 14 => frame.r14 = value,
+        15 => frame.r15 = value, 16 => frame.rip = value,
+        17 => frame.rflags = (frame.rflags & !0xffffffff) | (value & 0xffffffff),
+        18 ... 23 => (),
+        _ => return false,
+    }
+    true
+}
+
+/// Address of the instruction the exception will return to
+pub fn pc(frame: &ExceptionFrame) -> usize {
+    frame.rip as usize
+}
+
+pub fn set_pc(frame: &mut ExceptionFrame, pc: usize) {
+    frame.rip = pc as u64;
+}
+
+/// Whether to trap again after a single instruction once the exception
+/// returns
+pub fn set_single_step(frame: &mut ExceptionFrame, step: bool) {
+    if step {
+        frame.rflags |= RFLAGS_TF;
+    } else {
+        frame.rflags &= !RFLAGS_TF;
+    }
+}
+
+/// True if the exception was a breakpoint, in which case the return
+/// address is just after the breakpoint instruction
+pub fn is_breakpoint(frame: &ExceptionFrame) -> bool {
+    frame.vector == VECTOR_BREAKPOINT
+}
+
+/// Signal to report an exception to GDB as
+pub fn signal(frame: &ExceptionFrame) -> u8 {
+    match frame.vector {
+        VECTOR_DEBUG | VECTOR_BREAKPOINT => SIGTRAP,
+        0 | 16 | 19 => SIGFPE,
+        6 => SIGILL,
+        13 | 14 => SIGSEGV,
+        _ => SIGBUS,
+    }
+}
+
+/// Stop in the debugger, as if a breakpoint had been hit here
+#[inline(always)]
+pub fn breakpoint() {
+    unsafe{asm!("int3" : : : "memory" : "volatile")};
+}
+
+/// True if `len` bytes at `addr` can be accessed without faulting. This is
+/// the kernel image and the physical memory seen through the device window
+pub fn memory_valid(addr: usize, len: usize) -> bool {
+    let high: BootHighWindow = unsafe{BootHighWindow::new(())};
+    let device: DeviceWindow = unsafe{DeviceWindow::new(())};
+    high.range_valid(addr, len) || device.range_valid(addr, len)
+}
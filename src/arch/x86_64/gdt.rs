@@ -0,0 +1,90 @@
+//! Global descriptor table and task state segment
+//!
+//! The table loaded by head.S only has kernel segments. Running user level
+//! also needs user code and data segments, and a TSS to give the stack that
+//! the processor switches to when an exception or interrupt arrives from
+//! user level. This table replaces it, keeping the kernel selectors the
+//! same. Only the boot processor is running, so there is a single table,
+//! TSS and stack.
+
+/// Kernel code segment selector, the same as in head.S
+pub const KERNEL_CS: u16 = 0x08;
+/// User data and code segment selectors, with a requested privilege level
+/// of 3. Data comes before code, which is the order `sysret` expects
+pub const USER_DS: u16 = 0x18 | 3;
+pub const USER_CS: u16 = 0x20 | 3;
+/// Selector of the TSS, which takes two entries
+const TSS_SELECTOR: u16 = 0x28;
+
+/// Number of 8 byte entries in the table
+const GDT_ENTRIES: usize = 7;
+/// Present, ring 0, 64-bit code
+const DESC_KERNEL_CODE: u64 = 0x00209a0000000000;
+/// Present, ring 0, writable data
+const DESC_KERNEL_DATA: u64 = 0x0000920000000000;
+/// Present, ring 3, writable data
+const DESC_USER_DATA: u64 = 0x0000f20000000000;
+/// Present, ring 3, 64-bit code
+const DESC_USER_CODE: u64 = 0x0020fa0000000000;
+/// Type of a present, available 64-bit TSS
+const DESC_TSS: u64 = 0x89;
+
+/// Size of the TSS, and hence its limit plus one
+const TSS_SIZE: usize = 104;
+/// Size of the stack used when entering the kernel from user level
+const TRAP_STACK_SIZE: usize = 16 * 1024;
+
+#[repr(C, packed)]
+struct Tss {
+    reserved0: u32,
+    /// Stacks for entries from each privilege level, only the first of
+    /// which is used
+    rsp: [u64; 3],
+    reserved1: u64,
+    ist: [u64; 7],
+    reserved2: u64,
+    reserved3: u16,
+    /// Offset of the IO permission map, which is past the end so there is
+    /// none
+    iomap_base: u16,
+}
+
+/// Operand of `lgdt`
+#[repr(C, packed)]
+struct GdtPointer {
+    limit: u16,
+    base: u64,
+}
+
+static mut GDT: [u64; GDT_ENTRIES] = [
+    0, DESC_KERNEL_CODE, DESC_KERNEL_DATA, DESC_USER_DATA, DESC_USER_CODE, 0, 0,
+];
+
+static mut TSS: Tss = Tss {
+    reserved0: 0, rsp: [0; 3], reserved1: 0, ist: [0; 7], reserved2: 0, reserved3: 0,
+    iomap_base: TSS_SIZE as u16,
+};
+
+static mut TRAP_STACK: [u8; TRAP_STACK_SIZE] = [0; TRAP_STACK_SIZE];
+
+/// Load the table and TSS on the calling processor
+///
+/// # Safety
+///
+/// Must only be called once, on the boot processor
+pub unsafe fn init() {
+    let stack = &TRAP_STACK as *const _ as u64 + TRAP_STACK_SIZE as u64;
+    /* the processor aligns the stack itself, but keep to the ABI anyway */
+    TSS.rsp[0] = stack & !0xf;
+    let tss = &TSS as *const Tss as u64;
+    GDT[(TSS_SELECTOR >> 3) as usize] = (TSS_SIZE as u64 - 1) | (tss & 0xffffff) << 16
+        | DESC_TSS << 40 | (tss >> 24 & 0xff) << 56;
+    GDT[(TSS_SELECTOR >> 3) as usize + 1] = tss >> 32;
+    let pointer = GdtPointer {
+        limit: (GDT_ENTRIES * 8 - 1) as u16,
+        base: &GDT as *const _ as u64,
+    };
+    asm!("lgdt ($0)
+          ltr $1"
+         : : "r"(&pointer), "r"(TSS_SELECTOR) : "memory" : "volatile");
+}
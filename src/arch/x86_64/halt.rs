@@ -8,3 +8,28 @@ pub fn halt() -> ! {
         }
     }
 }
+
+/// Descriptor table pointer as consumed by `lidt`
+#[repr(packed)]
+struct EmptyIdtPointer {
+    limit: u16,
+    base: u64,
+}
+
+/// Reset the processor by forcing a triple fault. This is done by loading
+/// an empty IDT and then raising an exception, which cannot be delivered.
+/// This is the last resort for resetting a machine when every other
+/// method has failed
+pub fn triple_fault() -> ! {
+    let idt = EmptyIdtPointer { limit: 0, base: 0 };
+    unsafe {
+        asm!("lidt ($0)
+              int3"
+             :
+             : "r"(&idt)
+             : "memory"
+             : "volatile");
+    }
+    /* Should never get here, but just in case */
+    halt()
+}
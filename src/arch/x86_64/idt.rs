@@ -0,0 +1,220 @@
+//! Interrupt descriptor table and processor exceptions
+//!
+//! Only the architecturally defined exceptions, the local APIC timer and
+//! the system call vector have handlers. Anything that arrives from user
+//! level is handed to the thread code. The timer only interrupts the kernel
+//! whilst it waits for something to run, and is just acknowledged so that
+//! the wait ends. None of the exceptions are expected to happen in the
+//! kernel, so each one is handed to the GDB stub, if there is one, or
+//! otherwise turned into a panic, which also reports the registers and a
+//! backtrace of the code that caused it. The remaining vectors are left
+//! not present, so an unexpected interrupt shows up as a segment not
+//! present exception.
+use symbols;
+use gdb;
+use ::core::fmt;
+use ::core::fmt::Write;
+use super::backtrace;
+use super::stop;
+use super::thread;
+use super::timer;
+use super::gdt::KERNEL_CS;
+
+/// Number of vectors reserved for exceptions
+const NUM_EXCEPTIONS: usize = 32;
+/// Number of entries in the table
+const NUM_VECTORS: usize = 256;
+/// Present, ring 0, 64-bit interrupt gate
+const GATE_INTERRUPT: u8 = 0x8e;
+/// As `GATE_INTERRUPT`, but user level may raise it with `int`
+const GATE_USER_INTERRUPT: u8 = 0xee;
+/// Vector user level makes system calls with
+pub const VECTOR_SYSCALL: u64 = 0x80;
+/// Vector of the local APIC timer
+pub const VECTOR_TIMER: u64 = 0x30;
+/// Vector of the NMI, which is also how other processors are stopped
+const VECTOR_NMI: u64 = 2;
+/// Vector of the page fault exception, which reports the address in CR2
+pub const VECTOR_PAGE_FAULT: u64 = 14;
+
+/// Names of the exceptions, by vector
+const EXCEPTION_NAMES: [&'static str; NUM_EXCEPTIONS] = [
+    "divide error", "debug", "NMI", "breakpoint", "overflow", "bound range exceeded",
+    "invalid opcode", "device not available", "double fault", "coprocessor segment overrun",
+    "invalid TSS", "segment not present", "stack segment fault", "general protection",
+    "page fault", "reserved", "x87 floating point", "alignment check", "machine check",
+    "SIMD floating point", "virtualization", "control protection", "reserved", "reserved",
+    "reserved", "reserved", "reserved", "reserved", "hypervisor injection",
+    "VMM communication", "security", "reserved",
+];
+
+extern {
+    /// Entry points from traps.S, by vector
+    static exception_stubs: [u64; NUM_EXCEPTIONS];
+    /// Entry point of system calls from traps.S
+    fn syscall_entry();
+    /// Entry point of the timer interrupt from traps.S
+    fn timer_entry();
+}
+
+/// A gate descriptor
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+struct IdtEntry {
+    offset_low: u16,
+    selector: u16,
+    ist: u8,
+    flags: u8,
+    offset_mid: u16,
+    offset_high: u32,
+    reserved: u32,
+}
+
+const EMPTY_ENTRY: IdtEntry = IdtEntry {
+    offset_low: 0, selector: 0, ist: 0, flags: 0, offset_mid: 0, offset_high: 0, reserved: 0,
+};
+
+impl IdtEntry {
+    fn interrupt_gate(handler: u64) -> IdtEntry {
+        IdtEntry {
+            offset_low: handler as u16,
+            selector: KERNEL_CS,
+            ist: 0,
+            flags: GATE_INTERRUPT,
+            offset_mid: (handler >> 16) as u16,
+            offset_high: (handler >> 32) as u32,
+            reserved: 0,
+        }
+    }
+}
+
+/// Operand of `lidt`
+#[repr(C, packed)]
+struct IdtPointer {
+    limit: u16,
+    base: u64,
+}
+
+/// An exception being turned into a panic, along with CR2 at the time
+static mut CURRENT_EXCEPTION: Option<(ExceptionFrame, u64)> = None;
+
+/// The table is shared by every processor
+static mut IDT: [IdtEntry; NUM_VECTORS] = [EMPTY_ENTRY; NUM_VECTORS];
+
+/// State pushed by traps.S on an exception, lowest address first
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct ExceptionFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    /// Zero for exceptions that do not push an error code
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+/// Fill in the exception gates and load the table on the calling
+/// processor
+///
+/// # Safety
+///
+/// The table is only written by the first call, which must happen before
+/// any other processor is started
+pub unsafe fn init() {
+    if IDT[0].flags == 0 {
+        for (entry, stub) in IDT.iter_mut().zip(exception_stubs.iter()) {
+            *entry = IdtEntry::interrupt_gate(*stub);
+        }
+        IDT[VECTOR_SYSCALL as usize] = IdtEntry::interrupt_gate(syscall_entry as u64);
+        IDT[VECTOR_SYSCALL as usize].flags = GATE_USER_INTERRUPT;
+        IDT[VECTOR_TIMER as usize] = IdtEntry::interrupt_gate(timer_entry as u64);
+    }
+    let pointer = IdtPointer {
+        limit: (NUM_VECTORS * 16 - 1) as u16,
+        base: &IDT as *const _ as u64,
+    };
+    asm!("lidt ($0)" : : "r"(&pointer) : "memory" : "volatile");
+}
+
+/// Print the registers of an exception frame
+pub fn write_registers(out: &mut Write, frame: &ExceptionFrame) -> fmt::Result {
+    try!(write!(out, "rax {:016x} rbx {:016x} rcx {:016x}\n", frame.rax, frame.rbx, frame.rcx));
+    try!(write!(out, "rdx {:016x} rsi {:016x} rdi {:016x}\n", frame.rdx, frame.rsi, frame.rdi));
+    try!(write!(out, "rbp {:016x} rsp {:016x} r8  {:016x}\n", frame.rbp, frame.rsp, frame.r8));
+    try!(write!(out, "r9  {:016x} r10 {:016x} r11 {:016x}\n", frame.r9, frame.r10, frame.r11));
+    try!(write!(out, "r12 {:016x} r13 {:016x} r14 {:016x}\n", frame.r12, frame.r13, frame.r14));
+    try!(write!(out, "r15 {:016x} rflags {:016x} cs {:x} ss {:x}\n",
+        frame.r15, frame.rflags, frame.cs, frame.ss));
+    Ok(())
+}
+
+/// Print the state at the time of the exception that caused the current
+/// panic, including a backtrace of the code that caused it. Returns false,
+/// having printed nothing, if the panic did not come from an exception
+pub fn write_exception(out: &mut Write) -> Result<bool, fmt::Error> {
+    let (frame, cr2) = match unsafe{CURRENT_EXCEPTION} {
+        Some(exception) => exception,
+        None => return Ok(false),
+    };
+    if frame.vector == VECTOR_PAGE_FAULT {
+        try!(write!(out, "Faulting address {:016x}\n", cr2));
+    }
+    try!(write!(out, "rip {:016x} ", frame.rip));
+    try!(symbols::write_symbol(out, frame.rip as usize));
+    try!(write!(out, "\n"));
+    try!(write_registers(out, &frame));
+    try!(symbols::write_backtrace(out, backtrace::frames(frame.rbp as usize)));
+    Ok(true)
+}
+
+/// Read the address of the last page fault
+pub fn cr2() -> u64 {
+    let cr2: u64;
+    unsafe{asm!("movq %cr2, $0" : "=r"(cr2) : : : "volatile")};
+    cr2
+}
+
+/// Called by traps.S for every exception. Returning resumes the code
+/// the exception happened in with the registers in `frame`
+#[no_mangle]
+pub extern fn handle_exception(frame: &mut ExceptionFrame) {
+    if frame.vector == VECTOR_NMI {
+        stop::nmi(frame);
+    }
+    if frame.vector == VECTOR_TIMER {
+        timer::acknowledge();
+    }
+    /* an NMI has nothing to do with the code it interrupted, so is never
+     * handed to the thread that happened to be running */
+    if frame.cs & 3 == 3 && frame.vector != VECTOR_NMI {
+        thread::user_trap(frame);
+    }
+    if frame.vector == VECTOR_TIMER {
+        return;
+    }
+    /* an attached debugger decides what happens, even to exceptions that
+     * would otherwise be fatal */
+    if gdb::enter(frame) {
+        return;
+    }
+    unsafe{CURRENT_EXCEPTION = Some((*frame, cr2()))};
+    panic!("unhandled {} exception ({}) error code {:x}",
+        EXCEPTION_NAMES[frame.vector as usize % NUM_EXCEPTIONS], frame.vector, frame.error_code);
+}
@@ -0,0 +1,235 @@
+//! Local APIC driver
+//!
+//! The local APIC is accessed either through its memory mapped registers
+//! (xAPIC mode) or through MSRs (x2APIC mode). x2APIC mode is needed to
+//! address CPUs with APIC IDs above 255, so is used whenever the processor
+//! supports it. Register offsets are always given as xAPIC MMIO offsets and
+//! translated for x2APIC mode.
+extern crate raw_cpuid;
+extern crate x86;
+
+use self::raw_cpuid::CpuId;
+use vspace::VSpaceWindow;
+use util::Volatile;
+use types::PAddr;
+use super::DeviceWindow;
+
+/// Register holding the APIC ID
+const REG_ID: usize = 0x20;
+/// Register holding the APIC version and number of LVT entries
+const REG_VERSION: usize = 0x30;
+/// End of interrupt register
+const REG_EOI: usize = 0xb0;
+/// Spurious interrupt vector register
+const REG_SVR: usize = 0xf0;
+/// Interrupt command register. In xAPIC mode the destination is in a
+/// second register at `REG_ICR_HIGH`, in x2APIC mode it is one 64-bit MSR
+const REG_ICR: usize = 0x300;
+const REG_ICR_HIGH: usize = 0x310;
+/// Local vector table entry for the timer
+const REG_LVT_TIMER: usize = 0x320;
+/// Local vector table entry for the LINT0 pin
+const REG_LVT_LINT0: usize = 0x350;
+/// Local vector table entry for the LINT1 pin
+const REG_LVT_LINT1: usize = 0x360;
+/// Timer count the timer starts from, and the count it has left
+const REG_TIMER_INITIAL: usize = 0x380;
+const REG_TIMER_CURRENT: usize = 0x390;
+/// Timer divide configuration
+const REG_TIMER_DIVIDE: usize = 0x3e0;
+/// Size of the memory mapped register block
+const MMIO_SIZE: usize = 0x400;
+
+/// Spurious vector register: APIC software enable
+const SVR_ENABLE: u32 = 1 << 8;
+/// Vector used for spurious interrupts
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+/// LVT delivery mode for NMI
+const LVT_DELIVERY_NMI: u32 = 0x4 << 8;
+/// LVT input pin polarity is active low
+const LVT_ACTIVE_LOW: u32 = 1 << 13;
+/// LVT entry is masked
+const LVT_MASKED: u32 = 1 << 16;
+/// Timer divide configuration that counts at the full rate of the timer
+/// clock
+const TIMER_DIVIDE_1: u32 = 0xb;
+
+/// ICR delivery mode for NMI
+const ICR_DELIVERY_NMI: u32 = 0x4 << 8;
+/// ICR: interrupt is still being sent. Only exists in xAPIC mode
+const ICR_PENDING: u32 = 1 << 12;
+/// ICR: assert level, which must be set for everything but INIT deassert
+const ICR_ASSERT: u32 = 1 << 14;
+/// ICR destination shorthand for every processor except ourselves
+const ICR_ALL_BUT_SELF: u32 = 0x3 << 18;
+/// Number of times to poll for a previous IPI to be sent
+const ICR_POLL_LIMIT: usize = 100_000;
+
+/// IA32_APIC_BASE: x2APIC mode enable
+const APIC_BASE_EXTD: u64 = 1 << 10;
+/// IA32_APIC_BASE: global APIC enable
+const APIC_BASE_EN: u64 = 1 << 11;
+/// IA32_APIC_BASE: bits of the physical address of the registers
+const APIC_BASE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+/// First MSR of the x2APIC register space
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+/// MPS INTI flags: mask of the polarity field
+const INTI_POLARITY_MASK: u16 = 0x3;
+/// MPS INTI flags: input is active low
+const INTI_POLARITY_LOW: u16 = 0x3;
+
+/// Determine if the processor supports x2APIC mode
+pub fn has_x2apic() -> bool {
+    CpuId::new().get_feature_info().map_or(false, |f| f.has_x2apic())
+}
+
+/// Access to the local APIC of the current processor
+pub struct LocalApic<'a> {
+    /// Memory mapped registers, or `None` in x2APIC mode
+    mmio: Option<&'a [Volatile<u32>]>,
+}
+
+impl<'a> LocalApic<'a> {
+    /// Access the local APIC in xAPIC mode through the registers at `base`
+    ///
+    /// # Safety
+    ///
+    /// `base` must be the local APIC address of this machine, and the
+    /// APIC must not be in x2APIC mode
+    pub unsafe fn new_xapic<W: VSpaceWindow<'a>>(window: &'a W, base: PAddr) -> Option<LocalApic<'a>> {
+        window.try_from_paddr(base)
+            .and_then(|addr| window.make_slice(addr, MMIO_SIZE / 4))
+            .map(|regs| LocalApic { mmio: Some(regs) })
+    }
+    /// Switch the local APIC into x2APIC mode and access it through MSRs.
+    /// Returns `None` if the processor does not support x2APIC
+    ///
+    /// # Safety
+    ///
+    /// Once in x2APIC mode the APIC can only leave it by being reset, so
+    /// nothing else may be using the APIC in xAPIC mode
+    pub unsafe fn new_x2apic() -> Option<LocalApic<'a>> {
+        if !has_x2apic() {
+            return None;
+        }
+        let base = x86::msr::rdmsr(x86::msr::IA32_APIC_BASE);
+        /* the APIC must be globally enabled before x2APIC mode is */
+        if base & APIC_BASE_EN == 0 {
+            x86::msr::wrmsr(x86::msr::IA32_APIC_BASE, base | APIC_BASE_EN);
+        }
+        x86::msr::wrmsr(x86::msr::IA32_APIC_BASE, base | APIC_BASE_EN | APIC_BASE_EXTD);
+        Some(LocalApic { mmio: None })
+    }
+    /// Access the local APIC in whatever mode it is already in. This is for
+    /// paths, such as panic, that cannot get at the platform's description
+    /// of the APIC. Returns `None` if the APIC is disabled or its registers
+    /// are not within the `DeviceWindow`
+    ///
+    /// # Safety
+    ///
+    /// The mode of the APIC must not change whilst this is in use
+    pub unsafe fn current() -> Option<LocalApic<'static>> {
+        let base = x86::msr::rdmsr(x86::msr::IA32_APIC_BASE);
+        if base & APIC_BASE_EN == 0 {
+            None
+        } else if base & APIC_BASE_EXTD != 0 {
+            Some(LocalApic { mmio: None })
+        } else {
+            let window: DeviceWindow<'static> = DeviceWindow::new(());
+            window.try_from_paddr(PAddr((base & APIC_BASE_ADDR_MASK) as usize))
+                .and_then(|addr| window.make_slice(addr, MMIO_SIZE / 4))
+                .map(|regs| LocalApic { mmio: Some(regs) })
+        }
+    }
+    /// True if this APIC is being accessed in x2APIC mode
+    pub fn is_x2apic(&self) -> bool {
+        self.mmio.is_none()
+    }
+    fn read(&self, reg: usize) -> u32 {
+        match self.mmio {
+            Some(regs) => regs[reg / 4].read(),
+            None => unsafe{x86::msr::rdmsr(X2APIC_MSR_BASE + (reg >> 4) as u32) as u32},
+        }
+    }
+    fn write(&self, reg: usize, val: u32) {
+        match self.mmio {
+            Some(regs) => regs[reg / 4].write(val),
+            None => unsafe{x86::msr::wrmsr(X2APIC_MSR_BASE + (reg >> 4) as u32, val as u64)},
+        }
+    }
+    /// APIC ID of the current processor
+    pub fn id(&self) -> u32 {
+        match self.mmio {
+            Some(_) => self.read(REG_ID) >> 24,
+            None => self.read(REG_ID),
+        }
+    }
+    /// Version of the APIC
+    pub fn version(&self) -> u8 {
+        self.read(REG_VERSION) as u8
+    }
+    /// Software enable the APIC. Until this is done all the LVT entries
+    /// are forced to be masked
+    pub fn enable(&self) {
+        let svr = self.read(REG_SVR);
+        self.write(REG_SVR, (svr & !0xff) | SVR_ENABLE | SPURIOUS_VECTOR as u32);
+    }
+    /// Signal the end of the current interrupt
+    pub fn eoi(&self) {
+        self.write(REG_EOI, 0);
+    }
+    /// Send an NMI to every other processor. Gives up, returning an error,
+    /// if a previous IPI never finishes being sent
+    pub fn send_nmi_all_but_self(&self) -> Result<(), ()> {
+        let command = ICR_DELIVERY_NMI | ICR_ASSERT | ICR_ALL_BUT_SELF;
+        match self.mmio {
+            Some(_) => {
+                if !(0..ICR_POLL_LIMIT).any(|_| self.read(REG_ICR) & ICR_PENDING == 0) {
+                    return Err(());
+                }
+                /* the destination is ignored with a shorthand */
+                self.write(REG_ICR_HIGH, 0);
+                self.write(REG_ICR, command);
+            },
+            None => unsafe {
+                x86::msr::wrmsr(X2APIC_MSR_BASE + (REG_ICR >> 4) as u32, command as u64);
+            },
+        }
+        Ok(())
+    }
+    /// Start the timer counting down from `count` at the full rate of the
+    /// timer clock, raising `vector` once it reaches zero. With no vector
+    /// the timer counts with its interrupt masked. A count of zero stops
+    /// the timer
+    pub fn timer_one_shot(&self, vector: Option<u8>, count: u32) {
+        self.write(REG_TIMER_DIVIDE, TIMER_DIVIDE_1);
+        self.write(REG_LVT_TIMER, vector.map_or(LVT_MASKED, |v| v as u32));
+        self.write(REG_TIMER_INITIAL, count);
+    }
+    /// Count left before the timer reaches zero
+    pub fn timer_count(&self) -> u32 {
+        self.read(REG_TIMER_CURRENT)
+    }
+    /// Stop the timer and mask its interrupt
+    pub fn timer_stop(&self) {
+        self.write(REG_LVT_TIMER, LVT_MASKED);
+        self.write(REG_TIMER_INITIAL, 0);
+    }
+    /// Configure one of the LINT pins to deliver an NMI. `flags` are the
+    /// MPS INTI flags from the firmware describing the pin polarity
+    pub fn set_lint_nmi(&self, lint: u8, flags: u16) -> Result<(), ()> {
+        let reg = match lint {
+            0 => REG_LVT_LINT0,
+            1 => REG_LVT_LINT1,
+            _ => return Err(()),
+        };
+        /* NMIs are always edge triggered so only the polarity matters */
+        let mut lvt = LVT_DELIVERY_NMI;
+        if flags & INTI_POLARITY_MASK == INTI_POLARITY_LOW {
+            lvt |= LVT_ACTIVE_LOW;
+        }
+        self.write(reg, lvt);
+        Ok(())
+    }
+}
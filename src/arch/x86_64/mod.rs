@@ -4,7 +4,20 @@ pub extern crate x86;
 pub mod boot;
 pub mod halt;
 mod vspace;
-mod cpu;
+pub mod cpu;
 mod paging;
+mod multiboot2;
+pub mod lapic;
+pub mod backtrace;
+mod idt;
+mod stop;
+pub mod debug;
+mod gdt;
+pub mod thread;
+pub mod timer;
 
-pub use self::halt::halt;
+pub use self::halt::{halt, triple_fault};
+pub use self::stop::{stop_other_cpus, write_stopped_cpus, write_cpu_state};
+pub use self::idt::{ExceptionFrame, write_exception, VECTOR_TIMER};
+pub use self::vspace::{DeviceWindow, map_write_combining, page_table_root};
+pub use self::paging::{write_page_tables, UserPage, USER_TOP, lookup_user_page, mark_proxy_page};
@@ -0,0 +1,231 @@
+//! Minimal Multiboot2 information parser
+//!
+//! Only the tags the kernel actually needs are understood, everything else
+//! is skipped. Unlike Multiboot1 the boot loader may hand over copies of
+//! firmware structures, such as the ACPI RSDP, which is the only way to
+//! find them on machines without a legacy BIOS area.
+//!
+//! The information structure is a sequence of tags, each aligned to 8
+//! bytes, preceded by its total size and terminated by an end tag. As it
+//! comes from the boot loader every length is checked before being used.
+use vspace::VSpaceWindow;
+use types::PAddr;
+use plat::Framebuffer;
+use ::core::str;
+
+/// Value of EAX when loaded by a Multiboot2 compliant boot loader
+pub const SIGNATURE_EAX: u32 = 0x36d76289;
+
+/// Tag type terminating the list
+const TAG_END: u32 = 0;
+/// Tag type of the kernel command line
+const TAG_CMDLINE: u32 = 1;
+/// Tag type of the memory map
+const TAG_MMAP: u32 = 6;
+/// Tag type describing the framebuffer the boot loader set up
+const TAG_FRAMEBUFFER: u32 = 8;
+/// Tag type holding a copy of an ACPI 1.0 RSDP
+const TAG_ACPI_OLD: u32 = 14;
+/// Tag type holding a copy of an ACPI 2.0+ RSDP
+const TAG_ACPI_NEW: u32 = 15;
+/// Size of the fixed header of the information structure and of each tag
+const HEADER_SIZE: usize = 8;
+/// Size of the fixed part of the memory map tag before the entries
+const MMAP_HEADER_SIZE: usize = 16;
+/// Size of the memory map entry fields that we understand
+const MMAP_ENTRY_SIZE: usize = 24;
+/// Memory map entry type of usable RAM
+const MMAP_AVAILABLE: u32 = 1;
+/// Framebuffer type with direct RGB colour, as opposed to indexed colour
+/// or EGA text
+const FRAMEBUFFER_TYPE_RGB: u8 = 1;
+/// Offset of the colour information in the framebuffer tag data
+const FRAMEBUFFER_COLOR_OFFSET: usize = 24;
+/// Refuse information structures larger than this, as they are surely
+/// corrupt and we do not want to construct an enormous slice
+const MAX_INFO_SIZE: usize = 64 * 1024;
+
+/// Read a little endian u32 out of a byte slice
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    bytes.get(offset..offset + 4)
+        .map(|b| b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24)
+}
+
+/// Read a little endian u64 out of a byte slice
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    match (read_u32(bytes, offset), read_u32(bytes, offset + 4)) {
+        (Some(low), Some(high)) => Some(low as u64 | (high as u64) << 32),
+        _ => None,
+    }
+}
+
+/// Decode the framebuffer fields shared by Multiboot1 and Multiboot2.
+/// `bytes` starts at the framebuffer address, and the two versions only
+/// differ in where the colour information that follows the type begins.
+/// Only direct RGB framebuffers are returned
+pub fn framebuffer_fields(bytes: &[u8], color_offset: usize) -> Option<Framebuffer> {
+    let color = match bytes.get(color_offset..color_offset + 6) {
+        Some(color) => color,
+        None => return None,
+    };
+    match (read_u64(bytes, 0), read_u32(bytes, 8), read_u32(bytes, 12), read_u32(bytes, 16),
+            bytes.get(20), bytes.get(21)) {
+        (Some(base), Some(pitch), Some(width), Some(height), Some(&bpp),
+                Some(&FRAMEBUFFER_TYPE_RGB)) =>
+            Some(Framebuffer {
+                base: PAddr(base as usize),
+                pitch: pitch as usize,
+                width: width as usize,
+                height: height as usize,
+                bpp: bpp,
+                red: (color[0], color[1]),
+                green: (color[2], color[3]),
+                blue: (color[4], color[5]),
+            }),
+        _ => None,
+    }
+}
+
+/// A single tag of the information structure
+#[derive(Debug, Copy, Clone)]
+pub struct Tag<'a> {
+    pub tag_type: u32,
+    /// Contents of the tag, excluding the type and size
+    pub data: &'a [u8],
+    /// Physical address of `data`
+    pub paddr: PAddr,
+}
+
+/// Iterator over the tags of an information structure
+pub struct TagIter<'a> {
+    bytes: &'a [u8],
+    paddr: PAddr,
+    offset: usize,
+}
+
+impl<'a> Iterator for TagIter<'a> {
+    type Item = Tag<'a>;
+    fn next(&mut self) -> Option<Tag<'a>> {
+        let (tag_type, size) = match (read_u32(self.bytes, self.offset),
+                read_u32(self.bytes, self.offset + 4)) {
+            (Some(t), Some(s)) => (t, s as usize),
+            _ => return None,
+        };
+        if tag_type == TAG_END || size < HEADER_SIZE
+                || self.offset + size > self.bytes.len() {
+            return None;
+        }
+        let tag = Tag {
+            tag_type: tag_type,
+            data: &self.bytes[self.offset + HEADER_SIZE..self.offset + size],
+            paddr: PAddr(self.paddr.0 + self.offset + HEADER_SIZE),
+        };
+        self.offset += (size + 7) & !7;
+        Some(tag)
+    }
+}
+
+/// A Multiboot2 memory map entry
+#[derive(Debug, Copy, Clone)]
+pub struct MemoryEntry {
+    pub base: u64,
+    pub length: u64,
+    pub entry_type: u32,
+}
+
+impl MemoryEntry {
+    /// True if the entry describes RAM available for general use
+    pub fn is_ram(&self) -> bool {
+        self.entry_type == MMAP_AVAILABLE
+    }
+}
+
+/// Iterator over the entries of the memory map tag
+pub struct MemoryMapIter<'a> {
+    entries: &'a [u8],
+    entry_size: usize,
+    offset: usize,
+}
+
+impl<'a> Iterator for MemoryMapIter<'a> {
+    type Item = MemoryEntry;
+    fn next(&mut self) -> Option<MemoryEntry> {
+        let entry = match (read_u64(self.entries, self.offset),
+                read_u64(self.entries, self.offset + 8),
+                read_u32(self.entries, self.offset + 16)) {
+            (Some(base), Some(length), Some(entry_type)) =>
+                MemoryEntry { base: base, length: length, entry_type: entry_type },
+            _ => return None,
+        };
+        self.offset += self.entry_size;
+        Some(entry)
+    }
+}
+
+/// Parsed Multiboot2 information structure
+pub struct Multiboot2<'a> {
+    bytes: &'a [u8],
+    paddr: PAddr,
+}
+
+impl<'a> Multiboot2<'a> {
+    /// Construct from the physical address the boot loader passed in EBX
+    ///
+    /// # Safety
+    ///
+    /// There must be a Multiboot2 information structure at `paddr`
+    pub unsafe fn new<W: VSpaceWindow<'a>>(window: &'a W, paddr: PAddr) -> Option<Multiboot2<'a>> {
+        let header: &'a [u8] = match window.try_from_paddr(paddr)
+                .and_then(|addr| window.make_slice(addr, HEADER_SIZE)) {
+            Some(h) => h,
+            None => return None,
+        };
+        let size = match read_u32(header, 0) {
+            Some(s) if s as usize >= HEADER_SIZE && s as usize <= MAX_INFO_SIZE => s as usize,
+            _ => return None,
+        };
+        window.try_from_paddr(paddr)
+            .and_then(|addr| window.make_slice(addr, size))
+            .map(|bytes| Multiboot2 { bytes: bytes, paddr: paddr })
+    }
+    /// Iterate over all the tags
+    pub fn tags(&self) -> TagIter<'a> {
+        TagIter { bytes: self.bytes, paddr: self.paddr, offset: HEADER_SIZE }
+    }
+    /// Find the first tag of a given type
+    fn find(&self, tag_type: u32) -> Option<Tag<'a>> {
+        self.tags().find(|t| t.tag_type == tag_type)
+    }
+    /// The kernel command line, if one was given and is valid UTF-8
+    pub fn command_line(&self) -> Option<&'a str> {
+        self.find(TAG_CMDLINE).and_then(|tag| {
+            let len = tag.data.iter().position(|b| *b == 0).unwrap_or(tag.data.len());
+            str::from_utf8(&tag.data[..len]).ok()
+        })
+    }
+    /// Iterate over the memory map
+    pub fn memory_regions(&self) -> Option<MemoryMapIter<'a>> {
+        self.find(TAG_MMAP).and_then(|tag| {
+            let entry_size = match read_u32(tag.data, 0) {
+                Some(s) if s as usize >= MMAP_ENTRY_SIZE => s as usize,
+                _ => return None,
+            };
+            /* the data includes the tag header fields we already skipped */
+            tag.data.get(MMAP_HEADER_SIZE - HEADER_SIZE..).map(|entries|
+                MemoryMapIter { entries: entries, entry_size: entry_size, offset: 0 })
+        })
+    }
+    /// Physical address of the boot loader's copy of the ACPI RSDP. The
+    /// ACPI 2.0+ copy is preferred as it can describe an XSDT
+    pub fn acpi_rsdp(&self) -> Option<PAddr> {
+        self.find(TAG_ACPI_NEW)
+            .or_else(|| self.find(TAG_ACPI_OLD))
+            .map(|tag| tag.paddr)
+    }
+    /// The framebuffer the boot loader left the display in, if it is a
+    /// direct RGB one
+    pub fn framebuffer(&self) -> Option<Framebuffer> {
+        self.find(TAG_FRAMEBUFFER)
+            .and_then(|tag| framebuffer_fields(tag.data, FRAMEBUFFER_COLOR_OFFSET))
+    }
+}
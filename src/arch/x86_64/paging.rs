@@ -1,7 +1,57 @@
 //! Definitions for paging structures
 
 use ::core::mem::size_of;
+use ::core::fmt;
+use ::core::fmt::Write;
 use arch::x86_64::x86::paging;
+use vspace::VSpaceWindow;
+use types::PAddr;
+use steal_mem::{FrameAllocator, FRAME_SIZE};
+use util;
+use util::Volatile;
+use super::vspace::{DeviceWindow, kernel_window_root};
+
+/// Entry is present
+pub const PAGE_PRESENT: u64 = 1 << 0;
+/// Mapping is writable
+pub const PAGE_WRITE: u64 = 1 << 1;
+/// Mapping is accessible from user level
+const PAGE_USER: u64 = 1 << 2;
+/// Low bit of the PAT index
+const PAGE_PWT: u64 = 1 << 3;
+/// Middle bit of the PAT index
+const PAGE_PCD: u64 = 1 << 4;
+/// Entry maps a large page instead of pointing to a table
+pub const PAGE_LARGE: u64 = 1 << 7;
+/// Mapping is not flushed from the TLB on a CR3 write
+const PAGE_GLOBAL: u64 = 1 << 8;
+/// Mapping cannot be executed from
+const PAGE_NO_EXECUTE: u64 = 1 << 63;
+/// Page holds proxy capabilities. One of the bits the processor ignores,
+/// and so only the kernel can set it
+const PAGE_AVL_PROXY: u64 = 1 << 9;
+/// Physical address of the page or table an entry refers to
+const PAGE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+/// Attributes of a mapping that are shown when dumping page tables
+const PAGE_DUMP_FLAGS: u64 = PAGE_WRITE | PAGE_USER | PAGE_PWT | PAGE_PCD | PAGE_GLOBAL
+    | PAGE_NO_EXECUTE;
+/// High bit of the PAT index in large page entries. In 4K entries this is
+/// bit 7 instead
+const PAGE_LARGE_PAT: u64 = 1 << 12;
+/// Size of a page mapped by a page directory entry
+pub const LARGE_PAGE_SIZE: usize = util::MB * 2;
+/// End of the lower half of the address space, which is all user level may
+/// have mapped
+pub const USER_TOP: usize = 0x0000_8000_0000_0000;
+
+/// Bits of a large page entry selecting entry `index` of the PAT
+pub fn large_page_pat_bits(index: usize) -> u64 {
+    let mut bits = 0;
+    if index & 1 != 0 { bits |= PAGE_PWT }
+    if index & 2 != 0 { bits |= PAGE_PCD }
+    if index & 4 != 0 { bits |= PAGE_LARGE_PAT }
+    bits
+}
 
 pub trait Level {
     type Table;
@@ -65,3 +115,376 @@ impl<L: Level> Default for Table<L> {
         Table { tables: L::new() }
     }
 }
+
+/// Mappings with the same attributes that are contiguous both virtually
+/// and physically, which are printed as one line
+struct Run {
+    virt: usize,
+    phys: usize,
+    size: usize,
+    flags: u64,
+}
+
+impl Run {
+    fn write(&self, out: &mut Write) -> fmt::Result {
+        let flag = |bit: u64, name: &'static str| if self.flags & bit != 0 { name } else { "" };
+        write!(out, "{:016x}-{:016x} -> {:012x} {}{}{}{}{}{}\n",
+            self.virt, self.virt.wrapping_add(self.size - 1), self.phys,
+            if self.flags & PAGE_WRITE != 0 { "rw" } else { "ro" },
+            flag(PAGE_USER, " user"), flag(PAGE_NO_EXECUTE, " nx"), flag(PAGE_GLOBAL, " global"),
+            flag(PAGE_PCD, " pcd"), flag(PAGE_PWT, " pwt"))
+    }
+}
+
+/// Add a mapping to `run`, printing the run first if the mapping does not
+/// continue it
+fn add_mapping(out: &mut Write, run: &mut Option<Run>, virt: usize, phys: usize, size: usize,
+        flags: u64) -> fmt::Result {
+    if let Some(ref mut run) = *run {
+        if run.virt.wrapping_add(run.size) == virt && run.phys + run.size == phys
+                && run.flags == flags {
+            run.size += size;
+            return Ok(());
+        }
+        try!(run.write(out));
+    }
+    *run = Some(Run { virt: virt, phys: phys, size: size, flags: flags });
+    Ok(())
+}
+
+/// Visit the present entries of the table at `paddr`, which is at `level`,
+/// from 3 for the PML4 down to 0 for a page table, and maps the virtual
+/// addresses from `base`
+fn walk(out: &mut Write, window: &DeviceWindow<'static>, paddr: usize, level: usize, base: usize,
+        run: &mut Option<Run>) -> fmt::Result {
+    let entries: &[Volatile<u64>] = match window.try_from_paddr(PAddr(paddr))
+            .and_then(|addr| unsafe{window.make_slice(addr, 512)}) {
+        Some(entries) => entries,
+        None => return write!(out, "{:016x}: table at {:x} is not in the device window\n",
+            base, paddr),
+    };
+    let shift = 12 + 9 * level;
+    for (i, entry) in entries.iter().enumerate() {
+        let entry = entry.read();
+        if entry & PAGE_PRESENT == 0 {
+            continue;
+        }
+        let mut virt = base | i << shift;
+        /* the upper half of the PML4 maps canonical high addresses */
+        if level == 3 && i >= 256 {
+            virt |= 0xffff000000000000;
+        }
+        let next = (entry & PAGE_ADDR_MASK) as usize;
+        if level == 0 || (level < 3 && entry & PAGE_LARGE != 0) {
+            /* the low bits of a large page address hold its PAT bit */
+            let phys = next & !((1 << shift) - 1);
+            try!(add_mapping(out, run, virt, phys, 1 << shift, entry & PAGE_DUMP_FLAGS));
+        } else {
+            try!(walk(out, window, next, level - 1, virt, run));
+        }
+    }
+    Ok(())
+}
+
+/// Print every mapping of the page tables rooted at `root`. Only the
+/// attributes of the final entry of each mapping are shown. Tables are
+/// read through the device window, so any outside it are skipped
+pub fn write_page_tables(out: &mut Write, root: PAddr) -> fmt::Result {
+    let window: DeviceWindow<'static> = unsafe{DeviceWindow::new(())};
+    let mut run = None;
+    try!(walk(out, &window, root.0 & PAGE_ADDR_MASK as usize, 3, 0, &mut run));
+    match run {
+        Some(run) => run.write(out),
+        None => write!(out, "Nothing mapped\n"),
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+/// A page that user level can access, as found by `lookup_user_page`
+pub struct UserPage {
+    /// Physical address that the looked up virtual address translates to
+    pub paddr: PAddr,
+    pub writable: bool,
+    /// Page has been marked with `mark_proxy_page`
+    pub proxy: bool,
+}
+
+/// Entry for `vaddr` in the table at `table`, which is at `level` as for
+/// `walk`
+fn table_entry<W>(window: &W, table: usize, vaddr: usize, level: usize)
+        -> Option<&'static Volatile<u64>> where W: VSpaceWindow<'static> {
+    let paddr = PAddr(table + ((vaddr >> (12 + 9 * level)) & 511) * size_of::<u64>());
+    window.try_from_paddr(paddr).and_then(|addr| unsafe{window.make(addr)})
+}
+
+/// Find the entry that finally maps `vaddr` in the tables at `root`, along
+/// with the size of the page it maps. Every level must allow user access
+fn find_user_entry<W>(window: &W, root: PAddr, vaddr: usize)
+        -> Option<(&'static Volatile<u64>, usize)> where W: VSpaceWindow<'static> {
+    if vaddr >= USER_TOP {
+        return None;
+    }
+    let mut table = root.0 & PAGE_ADDR_MASK as usize;
+    for level in (0..4).rev() {
+        let shift = 12 + 9 * level;
+        let entry = match table_entry(window, table, vaddr, level) {
+            Some(entry) => entry,
+            None => return None,
+        };
+        let value = entry.read();
+        if value & PAGE_PRESENT == 0 || value & PAGE_USER == 0 {
+            return None;
+        }
+        if level == 0 || (level < 3 && value & PAGE_LARGE != 0) {
+            return Some((entry, 1 << shift));
+        }
+        table = (value & PAGE_ADDR_MASK) as usize;
+    }
+    None
+}
+
+/// Translate `vaddr` through the tables at `root`, if user level can access
+/// it. Tables are read through the device window, so any outside it are
+/// treated as not mapped
+pub fn lookup_user_page(root: PAddr, vaddr: usize) -> Option<UserPage> {
+    let window: DeviceWindow<'static> = unsafe{DeviceWindow::new(())};
+    lookup_user_page_in(&window, root, vaddr)
+}
+
+/// As for `lookup_user_page`, reading tables through `window`
+fn lookup_user_page_in<W>(window: &W, root: PAddr, vaddr: usize) -> Option<UserPage>
+        where W: VSpaceWindow<'static> {
+    find_user_entry(window, root, vaddr).map(|(entry, size)| {
+        let value = entry.read();
+        /* the low bits of a large page address hold its PAT bit */
+        let base = (value & PAGE_ADDR_MASK) as usize & !(size - 1);
+        UserPage {
+            paddr: PAddr(base + (vaddr & (size - 1))),
+            writable: value & PAGE_WRITE != 0,
+            proxy: value & PAGE_AVL_PROXY != 0,
+        }
+    })
+}
+
+/// Mark or unmark the user page mapping `vaddr` in the tables at `root` as
+/// holding proxy capabilities. The mark is ignored by the processor, so no
+/// TLB flush is needed. Returns false if there is no user page there
+///
+/// # Safety
+///
+/// Nothing else may be changing the tables
+pub unsafe fn mark_proxy_page(root: PAddr, vaddr: usize, marked: bool) -> bool {
+    let window: DeviceWindow<'static> = DeviceWindow::new(());
+    mark_proxy_page_in(&window, root, vaddr, marked)
+}
+
+/// As for `mark_proxy_page`, reading tables through `window`
+unsafe fn mark_proxy_page_in<W>(window: &W, root: PAddr, vaddr: usize, marked: bool) -> bool
+        where W: VSpaceWindow<'static> {
+    match find_user_entry(window, root, vaddr) {
+        Some((entry, _)) => {
+            let value = entry.read() & !PAGE_AVL_PROXY;
+            entry.write(if marked { value | PAGE_AVL_PROXY } else { value });
+            true
+        },
+        None => false,
+    }
+}
+
+/// Construct a new address space that has the kernel mappings of the final
+/// kernel window, and nothing mapped for user level. Fails if the final
+/// kernel window has not been made, as the boot tables let user level in
+///
+/// # Safety
+///
+/// The allocator must return frames in the device window
+pub unsafe fn new_user_vspace<A: FrameAllocator>(alloc: &mut A) -> Option<PAddr> {
+    let window: DeviceWindow<'static> = DeviceWindow::new(());
+    let tables = |root: PAddr| window.try_from_paddr(root)
+        .and_then(|addr| window.make_slice::<Volatile<u64>>(addr, 512));
+    let kernel = match kernel_window_root().and_then(|root| tables(root)) {
+        Some(kernel) => kernel,
+        None => return None,
+    };
+    let root = match alloc.alloc_frame() {
+        Some(root) => root,
+        None => return None,
+    };
+    let new = match tables(root) {
+        Some(new) => new,
+        None => return None,
+    };
+    /* the kernel lives in the upper half, and shares its tables with every
+     * address space */
+    for (new, kernel) in new.iter().zip(kernel.iter()).skip(256) {
+        new.write(kernel.read());
+    }
+    Some(root)
+}
+
+/// Map the frame at `paddr` at the user address `vaddr` in the tables at
+/// `root`, allocating any tables that are missing. Returns false if
+/// something is already mapped there or a table could not be allocated.
+/// No execute is not enabled, so every user page is executable
+///
+/// # Safety
+///
+/// Nothing else may be changing the tables, and the allocator must return
+/// frames in the device window
+pub unsafe fn map_user_page<A: FrameAllocator>(root: PAddr, vaddr: usize, paddr: PAddr,
+        write: bool, alloc: &mut A) -> bool {
+    let window: DeviceWindow<'static> = DeviceWindow::new(());
+    map_user_page_in(&window, root, vaddr, paddr, write, alloc)
+}
+
+/// As for `map_user_page`, with tables in `window`
+unsafe fn map_user_page_in<W, A>(window: &W, root: PAddr, vaddr: usize, paddr: PAddr,
+        write: bool, alloc: &mut A) -> bool where W: VSpaceWindow<'static>, A: FrameAllocator {
+    if vaddr >= USER_TOP || vaddr % FRAME_SIZE != 0 || paddr.0 % FRAME_SIZE != 0 {
+        return false;
+    }
+    let mut table = root.0 & PAGE_ADDR_MASK as usize;
+    for level in (1..4).rev() {
+        let entry = match table_entry(window, table, vaddr, level) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        let mut value = entry.read();
+        if value & PAGE_PRESENT == 0 {
+            value = match alloc.alloc_frame() {
+                Some(frame) => frame.0 as u64 | PAGE_PRESENT | PAGE_WRITE | PAGE_USER,
+                None => return false,
+            };
+            entry.write(value);
+        } else if value & PAGE_LARGE != 0 || value & PAGE_USER == 0 {
+            return false;
+        }
+        table = (value & PAGE_ADDR_MASK) as usize;
+    }
+    match table_entry(window, table, vaddr, 0) {
+        Some(entry) if entry.read() & PAGE_PRESENT == 0 => {
+            entry.write(paddr.0 as u64 | PAGE_PRESENT | PAGE_USER
+                | if write { PAGE_WRITE } else { 0 });
+            true
+        },
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::core::ops::Deref;
+    use vspace::VSpaceWindow;
+    use types::PAddr;
+    use steal_mem::{FrameAllocator, FRAME_SIZE};
+    use util::Volatile;
+    use super::{lookup_user_page_in, mark_proxy_page_in, map_user_page_in, find_user_entry,
+        PAGE_AVL_PROXY, PAGE_USER, USER_TOP};
+
+    const FRAMES: usize = 8;
+
+    #[derive(Debug, Copy, Clone)]
+    struct TestAddr(usize);
+
+    impl Deref for TestAddr {
+        type Target = usize;
+        fn deref(&self) -> &usize {
+            &self.0
+        }
+    }
+
+    /// Window over host memory standing in for physical memory, which
+    /// starts at zero
+    struct TestWindow {
+        base: usize,
+    }
+
+    unsafe impl VSpaceWindow<'static> for TestWindow {
+        type Addr = TestAddr;
+        type InitData = usize;
+        fn base(&self) -> usize {
+            self.base
+        }
+        fn size(&self) -> usize {
+            FRAMES * FRAME_SIZE
+        }
+        unsafe fn new(base: usize) -> TestWindow {
+            TestWindow { base: base }
+        }
+        unsafe fn from_paddr(&self, paddr: PAddr) -> TestAddr {
+            TestAddr(self.base.wrapping_add(paddr.0))
+        }
+        unsafe fn to_paddr(&self, addr: TestAddr) -> PAddr {
+            PAddr(addr.0 - self.base)
+        }
+        unsafe fn to_addr(&self, addr: usize) -> TestAddr {
+            TestAddr(addr)
+        }
+    }
+
+    /// Hands out the frames of the window in order after the first, which
+    /// is the root table
+    struct TestAlloc {
+        next: usize,
+    }
+
+    impl FrameAllocator for TestAlloc {
+        fn alloc_frame(&mut self) -> Option<PAddr> {
+            if self.next == FRAMES {
+                return None;
+            }
+            self.next += 1;
+            Some(PAddr((self.next - 1) * FRAME_SIZE))
+        }
+    }
+
+    /// Zeroed memory for the window, an address space with nothing mapped
+    /// at physical zero, and an allocator for the rest
+    fn vspace() -> (Vec<u64>, TestWindow, TestAlloc) {
+        let memory = vec![0u64; FRAMES * FRAME_SIZE / 8];
+        let window = unsafe{TestWindow::new(memory.as_ptr() as usize)};
+        (memory, window, TestAlloc { next: 1 })
+    }
+
+    const PAGE: usize = 0x40_3000;
+    const FRAME: PAddr = PAddr(0x7000);
+
+    #[test]
+    fn marking_sets_and_clears_the_ignored_bit() {
+        let (_memory, window, mut alloc) = vspace();
+        unsafe {
+            assert!(map_user_page_in(&window, PAddr(0), PAGE, FRAME, true, &mut alloc));
+            let page = lookup_user_page_in(&window, PAddr(0), PAGE + 0x18).unwrap();
+            assert_eq!(page.paddr, PAddr(FRAME.0 + 0x18));
+            assert!(page.writable && !page.proxy);
+
+            let (entry, _) = find_user_entry(&window, PAddr(0), PAGE).unwrap();
+            let before = entry.read();
+            assert!(mark_proxy_page_in(&window, PAddr(0), PAGE + 0x18, true));
+            assert_eq!(entry.read(), before | PAGE_AVL_PROXY);
+            assert!(lookup_user_page_in(&window, PAddr(0), PAGE).unwrap().proxy);
+            /* the rest of the tables are left alone */
+            assert!(lookup_user_page_in(&window, PAddr(0), PAGE + FRAME_SIZE).is_none());
+
+            assert!(mark_proxy_page_in(&window, PAddr(0), PAGE, false));
+            assert_eq!(entry.read(), before);
+            assert!(!lookup_user_page_in(&window, PAddr(0), PAGE).unwrap().proxy);
+        }
+    }
+
+    #[test]
+    fn only_user_pages_can_be_marked() {
+        let (_memory, window, mut alloc) = vspace();
+        unsafe {
+            assert!(!mark_proxy_page_in(&window, PAddr(0), PAGE, true));
+            assert!(!mark_proxy_page_in(&window, PAddr(0), USER_TOP + PAGE, true));
+            assert!(map_user_page_in(&window, PAddr(0), PAGE, FRAME, false, &mut alloc));
+            /* take away user access at the top level */
+            let root = window.try_from_paddr(PAddr(0)).unwrap();
+            let top = &window.make_slice::<Volatile<u64>>(root, 512).unwrap()[PAGE >> 39];
+            top.write(top.read() & !PAGE_USER);
+            assert!(!mark_proxy_page_in(&window, PAddr(0), PAGE, true));
+            assert!(lookup_user_page_in(&window, PAddr(0), PAGE).is_none());
+        }
+    }
+}
@@ -0,0 +1,90 @@
+//! Stopping the other processors when one of them panics
+//!
+//! The panicking processor sends an NMI to every other processor, which
+//! cannot be masked so reaches them even with interrupts disabled. Each
+//! one records the registers it was interrupted with, so that they can be
+//! printed with the panic, and then halts for good.
+use ::core::fmt;
+use ::core::fmt::Write;
+use ::core::sync::atomic::{AtomicUsize, Ordering};
+use cluster::MAX_CPUS;
+use super::idt::ExceptionFrame;
+use super::lapic::LocalApic;
+use super::cpu::{current_cpu_id, rdtsc};
+use super::halt::halt;
+use symbols;
+
+/// Number of TSC cycles to wait for the other processors to stop. Their
+/// number is not known here, so this is always waited in full
+const STOP_WAIT_CYCLES: u64 = 100_000_000;
+
+/// Registers of a stopped processor
+#[derive(Copy, Clone)]
+struct Stopped {
+    cpu: u32,
+    frame: ExceptionFrame,
+}
+
+/// Number of processors that have stopped
+static STOPPED_COUNT: AtomicUsize = AtomicUsize::new(0);
+/// One more than the ID of the processor stopping the system, or zero.
+/// Once set any NMI stops every other processor
+static STOPPING: AtomicUsize = AtomicUsize::new(0);
+/// Register state of the stopped processors. Each slot is written once,
+/// by the processor that claimed it from `STOPPED_COUNT`
+static mut STOPPED: [Option<Stopped>; MAX_CPUS] = [None; MAX_CPUS];
+
+/// Send an NMI to every other processor and give them time to stop.
+/// Returns an error if the NMI could not be sent
+pub fn stop_other_cpus() -> Result<(), ()> {
+    STOPPING.store(current_cpu_id() as usize + 1, Ordering::SeqCst);
+    let apic = match unsafe{LocalApic::current()} {
+        Some(apic) => apic,
+        /* without an enabled APIC there cannot be other processors */
+        None => return Ok(()),
+    };
+    try!(apic.send_nmi_all_but_self());
+    let start = rdtsc();
+    while rdtsc().wrapping_sub(start) < STOP_WAIT_CYCLES {}
+    Ok(())
+}
+
+/// Called for every NMI. If another processor is stopping the system then
+/// record `frame` and halt, otherwise return to handle the NMI normally
+pub fn nmi(frame: &ExceptionFrame) {
+    match STOPPING.load(Ordering::SeqCst) {
+        0 => return,
+        stopper if stopper == current_cpu_id() as usize + 1 => return,
+        _ => (),
+    }
+    let slot = STOPPED_COUNT.fetch_add(1, Ordering::SeqCst);
+    if slot < MAX_CPUS {
+        unsafe{STOPPED[slot] = Some(Stopped { cpu: current_cpu_id(), frame: *frame })};
+    }
+    halt();
+}
+
+/// Print the registers of every processor that has stopped
+pub fn write_stopped_cpus(out: &mut Write) -> fmt::Result {
+    let count = STOPPED_COUNT.load(Ordering::SeqCst);
+    try!(write!(out, "{} other CPUs stopped\n", count));
+    for stopped in unsafe{&STOPPED}.iter().filter_map(|s| *s) {
+        try!(write!(out, "CPU {} was at {:016x}\n", stopped.cpu, stopped.frame.rip));
+        try!(super::idt::write_registers(out, &stopped.frame));
+    }
+    Ok(())
+}
+
+/// Print what is known of the state of the processor with ID `cpu`
+pub fn write_cpu_state(out: &mut Write, cpu: u32) -> fmt::Result {
+    if cpu == current_cpu_id() {
+        return write!(out, "this CPU");
+    }
+    match unsafe{&STOPPED}.iter().filter_map(|s| *s).find(|s| s.cpu == cpu) {
+        Some(stopped) => {
+            try!(write!(out, "stopped at {:016x} ", stopped.frame.rip));
+            symbols::write_symbol(out, stopped.frame.rip as usize)
+        },
+        None => write!(out, "unknown"),
+    }
+}
@@ -0,0 +1,219 @@
+//! Running user level threads on x86-64
+//!
+//! User level enters the kernel through the IDT, whether for an exception,
+//! an interrupt or a system call, arriving on the stack given by the TSS.
+//! The registers pushed by traps.S are copied into a `Context` and handed
+//! to the generic thread code, which never returns to traps.S. Leaving the
+//! kernel is always by `return_to_user`, which pops a `Context` and
+//! `iretq`s to it.
+//!
+//! System calls are `int $0x80`, with the number in rax, arguments in rdi,
+//! rsi, rdx, r10, r8 and r9, and the result returned in rax. A message received by IPC
+//! is returned in rdi, with the badge it was sent through in rsi.
+//!
+//! When the processor has PCIDs each address space is given one, so that
+//! switching back to an address space keeps its TLB entries. PCIDs are
+//! picked from the physical address of the root table, and only kept
+//! without a flush if the PCID was last used for the same root on the same
+//! processor, as TLBs are not shared between processors.
+extern crate raw_cpuid;
+
+use thread::{ArchThread, Trap};
+use steal_mem::FrameAllocator;
+use types::PAddr;
+use cluster::MAX_CPUS;
+use ::core::slice;
+use super::x86::controlregs::cr3_write;
+use super::x86::msr::{rdmsr, wrmsr};
+use self::raw_cpuid::CpuId;
+use super::idt::{self, ExceptionFrame, VECTOR_SYSCALL, VECTOR_PAGE_FAULT, VECTOR_TIMER};
+use super::gdt::{self, USER_CS, USER_DS};
+use super::timer;
+use super::cpu;
+use super::paging;
+
+const IA32_FS_BASE: u32 = 0xc0000100;
+const IA32_GS_BASE: u32 = 0xc0000101;
+
+/// Interrupt enable flag
+const RFLAGS_IF: u64 = 1 << 9;
+/// Bit 1 of rflags is always set
+const RFLAGS_RESERVED: u64 = 1 << 1;
+/// Flags user level may change: carry, parity, adjust, zero, sign, trap,
+/// direction and overflow
+const RFLAGS_USER: u64 = 0xdd5;
+
+/// Enables PCIDs
+const CR4_PCIDE: u64 = 1 << 17;
+/// Set in a CR3 write to keep the TLB entries of the new PCID
+const CR3_NO_FLUSH: u64 = 1 << 63;
+/// Length of the `int $0x80` instruction that system calls are made with
+const SYSCALL_INSN_SIZE: u64 = 2;
+
+/// Number of PCIDs in use. PCID 0 is left for the boot address space
+const NUM_PCIDS: usize = 256;
+
+extern {
+    /// Load the registers at `context` and return to user level
+    fn return_to_user(context: *const Context) -> !;
+    /// Code of the first thread, from user.S
+    static initial_thread_start: u8;
+    static initial_thread_end: u8;
+}
+
+/// The PCIDs have been enabled
+static mut PCID_ENABLED: bool = false;
+/// Root table each PCID was last used with, by processor
+static mut PCID_ROOTS: [[usize; NUM_PCIDS]; MAX_CPUS] = [[0; NUM_PCIDS]; MAX_CPUS];
+
+/// Saved user registers of a thread. The general purpose registers and
+/// the interrupt return frame are in the order `return_to_user` pops them
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct Context {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+    pub fs_base: u64,
+    pub gs_base: u64,
+}
+
+/// x86-64 implementation of `ArchThread`
+pub enum X86_64 {}
+
+/// Load the address space at `root`, with a PCID if there are any
+unsafe fn load_vspace(root: PAddr) {
+    if !PCID_ENABLED {
+        cr3_write(root.0 as u64);
+        return;
+    }
+    let pcid = (root.0 >> 12) % (NUM_PCIDS - 1) + 1;
+    let roots = &mut PCID_ROOTS[cpu::current_cpu_index()];
+    let keep = if roots[pcid] == root.0 { CR3_NO_FLUSH } else { 0 };
+    roots[pcid] = root.0;
+    cr3_write(root.0 as u64 | pcid as u64 | keep);
+}
+
+/// Turn on PCIDs if the processor has them. They can only be enabled with
+/// a PCID of zero in CR3, which the boot address space has
+unsafe fn enable_pcid() {
+    let has_pcid = CpuId::new().get_feature_info().map_or(false, |f| f.has_pcid());
+    if !has_pcid {
+        return;
+    }
+    let cr4: u64;
+    asm!("movq %cr4, $0" : "=r"(cr4) : : : "volatile");
+    asm!("movq $0, %cr4" : : "r"(cr4 | CR4_PCIDE) : "memory" : "volatile");
+    PCID_ENABLED = true;
+    info!("PCIDs enabled");
+}
+
+/// Called by `idt::handle_exception` for anything that arrives from user
+/// level
+pub fn user_trap(frame: &ExceptionFrame) -> ! {
+    let context = Context {
+        r15: frame.r15, r14: frame.r14, r13: frame.r13, r12: frame.r12,
+        r11: frame.r11, r10: frame.r10, r9: frame.r9, r8: frame.r8,
+        rbp: frame.rbp, rdi: frame.rdi, rsi: frame.rsi, rdx: frame.rdx,
+        rcx: frame.rcx, rbx: frame.rbx, rax: frame.rax,
+        rip: frame.rip, cs: frame.cs, rflags: frame.rflags, rsp: frame.rsp, ss: frame.ss,
+        fs_base: unsafe{rdmsr(IA32_FS_BASE)},
+        gs_base: unsafe{rdmsr(IA32_GS_BASE)},
+    };
+    let trap = match frame.vector {
+        VECTOR_SYSCALL => Trap::Syscall(frame.rax as usize,
+            [frame.rdi as usize, frame.rsi as usize, frame.rdx as usize,
+             frame.r10 as usize, frame.r8 as usize, frame.r9 as usize]),
+        VECTOR_TIMER => Trap::Timer,
+        vector => Trap::Fault {
+            vector: vector,
+            code: frame.error_code,
+            address: if vector == VECTOR_PAGE_FAULT { idt::cr2() as usize } else { 0 },
+        },
+    };
+    ::thread::user_trap(&context, trap)
+}
+
+impl ArchThread for X86_64 {
+    type Context = Context;
+    fn new_context(entry: usize, stack: usize) -> Context {
+        Context {
+            r15: 0, r14: 0, r13: 0, r12: 0, r11: 0, r10: 0, r9: 0, r8: 0,
+            rbp: 0, rdi: 0, rsi: 0, rdx: 0, rcx: 0, rbx: 0, rax: 0,
+            rip: entry as u64,
+            cs: USER_CS as u64,
+            rflags: RFLAGS_IF | RFLAGS_RESERVED,
+            rsp: stack as u64,
+            ss: USER_DS as u64,
+            fs_base: 0,
+            gs_base: 0,
+        }
+    }
+    fn set_result(context: &mut Context, result: usize) {
+        context.rax = result as u64;
+    }
+    fn set_message(context: &mut Context, badge: u64, message: usize) {
+        context.rdi = message as u64;
+        context.rsi = badge;
+    }
+    fn restart_syscall(context: &mut Context, number: usize) {
+        context.rip = context.rip.wrapping_sub(SYSCALL_INSN_SIZE);
+        context.rax = number as u64;
+    }
+    unsafe fn init_cpu() {
+        gdt::init();
+        enable_pcid();
+        match timer::init() {
+            Ok(()) => (),
+            Err(()) if timer::has_fallback() =>
+                warn!("Cannot use the local APIC timer, using the platform clock event device"),
+            Err(()) => error!("Cannot use the local APIC timer, budgets will not be enforced"),
+        }
+    }
+    unsafe fn new_vspace<A: FrameAllocator>(alloc: &mut A) -> Option<PAddr> {
+        paging::new_user_vspace(alloc)
+    }
+    unsafe fn map_frame<A: FrameAllocator>(vspace: PAddr, vaddr: usize, frame: PAddr, write: bool,
+            alloc: &mut A) -> bool {
+        paging::map_user_page(vspace, vaddr, frame, write, alloc)
+    }
+    fn initial_code() -> &'static [u8] {
+        unsafe {
+            let start = &initial_thread_start as *const u8;
+            let end = &initial_thread_end as *const u8;
+            slice::from_raw_parts(start, end as usize - start as usize)
+        }
+    }
+    unsafe fn switch_to(context: &Context, vspace: PAddr, tls_base: usize) -> ! {
+        load_vspace(vspace);
+        /* the registers are popped from the stack, and a copy here leaves
+         * anything that interrupts the return free to push below it */
+        let mut context = *context;
+        context.fs_base = tls_base as u64;
+        /* nothing user level asked for may take it out of ring 3 or raise
+         * its IO privilege */
+        context.cs = USER_CS as u64;
+        context.ss = USER_DS as u64;
+        context.rflags = (context.rflags & RFLAGS_USER) | RFLAGS_IF | RFLAGS_RESERVED;
+        wrmsr(IA32_FS_BASE, context.fs_base);
+        wrmsr(IA32_GS_BASE, context.gs_base);
+        return_to_user(&context)
+    }
+}
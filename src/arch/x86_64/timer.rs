@@ -0,0 +1,140 @@
+//! Local APIC timer
+//!
+//! The scheduler keeps time with the cycle counter, so deadlines are given
+//! in cycles and converted to counts of the local APIC timer. The ratio of
+//! the two is measured once. The platform measures both against its
+//! reference clock if it has one, otherwise the timer is counted down
+//! across a fixed number of cycles. The timer is only used in one shot
+//! mode, and is armed again by the scheduler every time the kernel is left.
+//! A deadline too far away for the count just interrupts early, after which
+//! the scheduler arms the timer again.
+//!
+//! If the timer cannot be calibrated the platform may provide a fallback
+//! clock event device that raises `VECTOR_TIMER` on this processor instead.
+use sched::ArchTimer;
+use ::core::cmp;
+use ::core::u32;
+use super::cpu::rdtsc;
+use super::lapic::LocalApic;
+use super::idt::VECTOR_TIMER;
+use super::thread::X86_64;
+
+/// Cycles to measure the timer across
+const CALIBRATION_CYCLES: u64 = 1 << 24;
+
+/// Timer counts, and the cycles they took, during calibration. Both are
+/// zero until the timer has been calibrated
+static mut TIMER_COUNTS: u64 = 0;
+static mut TIMER_CYCLES: u64 = 0;
+
+/// Arms the fallback clock event device with a deadline in cycles, or
+/// stops it. Used whilst the local APIC timer is uncalibrated
+static mut FALLBACK: Option<fn(Option<u64>)> = None;
+
+/// Measure the rate of the timer against a reference clock. `measure` is
+/// given a function reading a counter that advances with the timer, and
+/// returns how far that counter advances per second, or fails if the
+/// reference is not working. `cycles_hz` is the rate of the cycle counter,
+/// as measured against the same reference. Fails if the measurement does,
+/// or the timer does not count or runs out during it
+///
+/// # Safety
+///
+/// Must only be called on the boot processor, with interrupts disabled
+pub unsafe fn calibrate<F>(lapic: &LocalApic, cycles_hz: u64, measure: F) -> Result<u64, ()>
+        where F: FnOnce(&mut FnMut() -> u64) -> Result<u64, ()> {
+    lapic.timer_one_shot(None, u32::MAX);
+    let measured = measure(&mut || (u32::MAX - lapic.timer_count()) as u64);
+    let expired = lapic.timer_count() == 0;
+    lapic.timer_stop();
+    let hz = try!(measured);
+    if hz == 0 || cycles_hz == 0 || expired {
+        return Err(());
+    }
+    TIMER_COUNTS = hz;
+    TIMER_CYCLES = cycles_hz;
+    Ok(hz)
+}
+
+/// Use `arm` as the clock event device whenever the local APIC timer is
+/// uncalibrated. It must raise `VECTOR_TIMER` on the boot processor, as the
+/// timer interrupt is acknowledged at the local APIC
+///
+/// # Safety
+///
+/// Must only be called on the boot processor, with interrupts disabled
+pub unsafe fn set_fallback(arm: fn(Option<u64>)) {
+    FALLBACK = Some(arm);
+}
+
+/// True if a fallback clock event device has been provided
+pub fn has_fallback() -> bool {
+    unsafe{FALLBACK.is_some()}
+}
+
+/// Measure the rate of the timer on the calling processor, unless the
+/// platform has already calibrated it. Fails if the local APIC cannot be
+/// reached or its timer does not count
+///
+/// # Safety
+///
+/// Must only be called on the boot processor, with interrupts disabled
+pub unsafe fn init() -> Result<(), ()> {
+    if TIMER_CYCLES != 0 {
+        return Ok(());
+    }
+    let lapic = try!(LocalApic::current().ok_or(()));
+    lapic.timer_one_shot(None, u32::MAX);
+    let count_start = lapic.timer_count();
+    let start = rdtsc();
+    while rdtsc().wrapping_sub(start) < CALIBRATION_CYCLES {}
+    let count_end = lapic.timer_count();
+    let cycles = rdtsc().wrapping_sub(start);
+    lapic.timer_stop();
+    let counts = count_start.wrapping_sub(count_end) as u64;
+    if counts == 0 || count_end == 0 {
+        return Err(());
+    }
+    TIMER_COUNTS = counts;
+    TIMER_CYCLES = cycles;
+    info!("Local APIC timer {} counts per {} cycles", counts, cycles);
+    Ok(())
+}
+
+/// Acknowledge a timer interrupt
+pub fn acknowledge() {
+    if let Some(lapic) = unsafe{LocalApic::current()} {
+        lapic.eoi();
+    }
+}
+
+impl ArchTimer for X86_64 {
+    fn set_deadline(deadline: Option<u64>) {
+        let (counts, cycles) = unsafe{(TIMER_COUNTS, TIMER_CYCLES)};
+        if cycles == 0 {
+            if let Some(arm) = unsafe{FALLBACK} {
+                return arm(deadline);
+            }
+        }
+        let lapic = match unsafe{LocalApic::current()} {
+            Some(lapic) => lapic,
+            None => return,
+        };
+        match deadline {
+            Some(deadline) if cycles != 0 => {
+                let count = deadline.saturating_sub(rdtsc()).saturating_mul(counts) / cycles;
+                /* a count of zero would stop the timer rather than fire it */
+                let count = cmp::max(cmp::min(count, u32::MAX as u64), 1) as u32;
+                lapic.timer_one_shot(Some(VECTOR_TIMER as u8), count);
+            },
+            _ => lapic.timer_stop(),
+        }
+    }
+    unsafe fn wait_for_interrupt() {
+        /* an interrupt cannot arrive between sti and hlt, so one that is
+         * already pending still ends the wait */
+        asm!("sti
+              hlt
+              cli" : : : "memory" : "volatile");
+    }
+}
@@ -3,11 +3,14 @@
 use util;
 use ::core::marker::PhantomData;
 use ::core::ops::Deref;
+use ::core::cmp;
 use vspace::VSpaceWindow;
 use types::*;
-use steal_mem::StealMem;
-use plat::PlatInterfaceType;
+use steal_mem::FrameAllocator;
+use util::Volatile;
 use super::paging::*;
+use super::cpu;
+use super::x86::controlregs::{cr3, cr3_write};
 
 /// The low boot window is a 1-1 mapped 4GB window of the bottom of memory
 /// This window is used both as where the boot code initially runs before
@@ -27,6 +30,32 @@ const HIGH_BOOT_MAPPING: (usize, usize) = (0xffffffff80000000, util::GB);
 /// Final kernel window is the top 2^39 bits of memory
 const KERNEL_MAPPING: (usize, usize) = (0xffffff8000000000, 0x8000000000);
 
+/// Physical memory is mapped from the base of the kernel window up to the
+/// high window, which takes the last two gigabytes of it
+const KERNEL_PHYS_MAPPING: usize = util::GB * 510;
+
+/// The device window is a view of the bottom 4GB of physical memory at the
+/// start of the kernel window, which is where the majority of memory mapped
+/// devices live. The bootstrap address space already maps this region, and
+/// the final kernel window will map it as well, so references created
+/// from here remain valid across the switch of address spaces
+const DEVICE_MAPPING: (usize, usize) = (0xffffff8000000000, util::GB * 4);
+
+/// The write combining window maps a single device region, such as a
+/// framebuffer, with the write combining memory type. It has a top level
+/// slot of its own just below the kernel window, and its tables are part
+/// of the kernel image so that the final kernel window can share them
+const WRITE_COMBINING_MAPPING: (usize, usize) = (0xffffff0000000000, util::GB);
+
+extern {
+    /// Tables of the write combining window, reserved in head.S
+    static mut wc_pdpt: [u64; 512];
+    static mut wc_pd: [u64; 512];
+}
+
+/// Tables of the final kernel window, once they have been made
+static mut KERNEL_ROOT: Option<PAddr> = None;
+
 /// The low window should should only be constructed immediately on boot
 /// entry, and then dropped before switching away from the bootstrapping
 /// address space
@@ -39,6 +68,10 @@ pub struct BootHighWindow<'a>(PhantomData<&'a usize>);
 /// remains valid forever after
 pub struct KernelWindow<'a>(PhantomData<&'a usize>);
 
+/// The device window is valid from boot and remains valid forever after.
+/// It should only be used for creating references to device registers
+pub struct DeviceWindow<'a>(PhantomData<&'a usize>);
+
 /// Wrapper for an address in a high window
 #[derive(Ord, Eq, PartialEq, PartialOrd, Debug, Copy, Clone)]
 pub struct HighWindowAddr(usize);
@@ -65,6 +98,17 @@ impl Deref for LowWindowAddr{
     }
 }
 
+/// Wrapper for an address in the device window
+#[derive(Ord, Eq, PartialEq, PartialOrd, Debug, Copy, Clone)]
+pub struct DeviceWindowAddr(usize);
+
+impl Deref for DeviceWindowAddr{
+    type Target = usize;
+    fn deref(&self) -> &usize {
+        &self.0
+    }
+}
+
 impl Deref for KernelWindowAddr{
     type Target = usize;
     fn deref(&self) -> &usize {
@@ -88,6 +132,15 @@ unsafe impl<'a> VSpaceWindow<'a> for BootHighWindow<'a> {
         debug_assert!(self.range_valid(addr, 0));
         HighWindowAddr(addr)
     }
+    /// Checked before converting, as `to_addr` asserts the address is in
+    /// the window
+    fn try_from_paddr(&self, paddr: PAddr) -> Option<Self::Addr> {
+        if paddr.0 < HIGH_BOOT_MAPPING.1 {
+            Some(HighWindowAddr(paddr.0 + HIGH_BOOT_MAPPING.0))
+        } else {
+            None
+        }
+    }
     unsafe fn new(_: Self::InitData) -> Self {
         BootHighWindow(PhantomData)
     }
@@ -98,14 +151,31 @@ unsafe impl<'a> VSpaceWindow<'a> for KernelWindow<'a> {
     type InitData = ();
     fn base(&self) -> usize { KERNEL_MAPPING.0 }
     fn size(&self) -> usize { KERNEL_MAPPING.1 }
-    unsafe fn to_paddr(&self, _: Self::Addr) -> PAddr {
-        unimplemented!()
+    unsafe fn to_paddr(&self, addr: Self::Addr) -> PAddr {
+        debug_assert!(self.addr_range_valid(addr, 0));
+        if addr.0 >= HIGH_BOOT_MAPPING.0 {
+            PAddr(addr.0 - HIGH_BOOT_MAPPING.0)
+        } else {
+            PAddr(addr.0 - KERNEL_MAPPING.0)
+        }
     }
-    unsafe fn from_paddr(&self, _: PAddr) -> Self::Addr {
-        unimplemented!()
+    unsafe fn from_paddr(&self, paddr: PAddr) -> Self::Addr {
+        debug_assert!(paddr.0 < KERNEL_PHYS_MAPPING);
+        self.to_addr(paddr.0 + KERNEL_MAPPING.0)
     }
-    unsafe fn to_addr(&self, _: usize) -> Self::Addr {
-        unimplemented!()
+    unsafe fn to_addr(&self, addr: usize) -> Self::Addr {
+        debug_assert!(self.range_valid(addr, 0));
+        KernelWindowAddr(addr)
+    }
+    /// The top of the window is the high window, so memory above what is
+    /// mapped from the base is not in the window, even though the address
+    /// would be
+    fn try_from_paddr(&self, paddr: PAddr) -> Option<Self::Addr> {
+        if paddr.0 < KERNEL_PHYS_MAPPING {
+            Some(KernelWindowAddr(paddr.0 + KERNEL_MAPPING.0))
+        } else {
+            None
+        }
     }
     unsafe fn new(_: Self::InitData) -> Self {
         KernelWindow(PhantomData)
@@ -128,18 +198,160 @@ unsafe impl<'a> VSpaceWindow<'a> for BootLowWindow<'a> {
         debug_assert!(self.range_valid(addr, 0));
         LowWindowAddr(addr)
     }
+    /// Checked before converting, as `to_addr` asserts the address is in
+    /// the window
+    fn try_from_paddr(&self, paddr: PAddr) -> Option<Self::Addr> {
+        if self.range_valid(paddr.0, 0) {
+            Some(LowWindowAddr(paddr.0))
+        } else {
+            None
+        }
+    }
     unsafe fn new(_: Self::InitData) -> Self {
         BootLowWindow(PhantomData)
     }
 }
 
-pub fn make_kernel_window<'a, 'w, I, W>(plat: &mut PlatInterfaceType, alloc: &mut StealMem<'a, 'w, I, W>)
-        -> Result<(), ()>
-        where I: Iterator<Item=(PAddr,PAddr)>, W:VSpaceWindow<'a> {
-    /* allocate top level PML4 */
-    let pml4_mem = unsafe {alloc.alloc(PML4::mem_align()).unwrap()};
-    let pml = pml4_mem <- PML4::default();
-    /* map in all the frames for our kernel window, up until the
-     * region for devices */
-    unimplemented!()
+unsafe impl<'a> VSpaceWindow<'a> for DeviceWindow<'a> {
+    type Addr = DeviceWindowAddr;
+    type InitData = ();
+    fn base(&self) -> usize { DEVICE_MAPPING.0 }
+    fn size(&self) -> usize { DEVICE_MAPPING.1 }
+    unsafe fn to_paddr(&self, addr: Self::Addr) -> PAddr {
+        debug_assert!(self.addr_range_valid(addr, 0));
+        PAddr(addr.0 - DEVICE_MAPPING.0)
+    }
+    unsafe fn from_paddr(&self, paddr: PAddr) -> Self::Addr {
+        self.to_addr(paddr.0 + DEVICE_MAPPING.0)
+    }
+    unsafe fn to_addr(&self, addr: usize) -> Self::Addr {
+        debug_assert!(self.range_valid(addr, 0));
+        DeviceWindowAddr(addr)
+    }
+    /// Checked before converting, as `to_addr` asserts the address is in
+    /// the window. Anything at or above 4GB is not
+    fn try_from_paddr(&self, paddr: PAddr) -> Option<Self::Addr> {
+        if paddr.0 < DEVICE_MAPPING.1 {
+            Some(DeviceWindowAddr(paddr.0 + DEVICE_MAPPING.0))
+        } else {
+            None
+        }
+    }
+    unsafe fn new(_: Self::InitData) -> Self {
+        DeviceWindow(PhantomData)
+    }
+}
+
+/// Map `size` bytes of physical memory at `base` into the write combining
+/// window, replacing anything mapped there before, and return the virtual
+/// address of `base`. The memory type comes from `PAT_INDEX_WRITE_COMBINING`
+/// so only takes effect once `cpu::early_init` has programmed the PAT.
+/// Until then the reset value of that PAT entry is write back, which the
+/// MTRRs normally reduce to uncached for device memory
+///
+/// # Safety
+///
+/// Must be called from the bootstrap address space, and any references
+/// into a previous mapping of the window must no longer be used
+pub unsafe fn map_write_combining(base: PAddr, size: usize) -> Option<usize> {
+    let start = base.0 & !(LARGE_PAGE_SIZE - 1);
+    let pages = (base.0 + size - start + LARGE_PAGE_SIZE - 1) / LARGE_PAGE_SIZE;
+    if size == 0 || pages * LARGE_PAGE_SIZE > WRITE_COMBINING_MAPPING.1 {
+        return None;
+    }
+    /* the kernel image and the boot PML4 are both in the first gigabyte,
+     * which the high window maps */
+    let high: BootHighWindow<'static> = BootHighWindow::new(());
+    let pml4: &[Volatile<u64>] = match high.try_from_paddr(PAddr(cr3() as usize & !0xfff))
+            .and_then(|addr| high.make_slice(addr, 512)) {
+        Some(pml4) => pml4,
+        None => return None,
+    };
+    let pd = high.to_paddr(high.to_addr(&wc_pd as *const _ as usize));
+    let pdpt = high.to_paddr(high.to_addr(&wc_pdpt as *const _ as usize));
+    let attributes = PAGE_PRESENT | PAGE_WRITE | PAGE_LARGE
+        | large_page_pat_bits(cpu::PAT_INDEX_WRITE_COMBINING);
+    for (i, entry) in wc_pd.iter_mut().enumerate() {
+        *entry = if i < pages {
+            (start + i * LARGE_PAGE_SIZE) as u64 | attributes
+        } else {
+            0
+        };
+    }
+    wc_pdpt[0] = pd.0 as u64 | PAGE_PRESENT | PAGE_WRITE;
+    pml4[(WRITE_COMBINING_MAPPING.0 >> 39) & 511].write(pdpt.0 as u64 | PAGE_PRESENT | PAGE_WRITE);
+    /* flush any translations of a previous mapping */
+    cr3_write(cr3());
+    Some(WRITE_COMBINING_MAPPING.0 + base.0 - start)
+}
+
+/// Physical address of the root of the current address space
+pub fn page_table_root() -> PAddr {
+    PAddr(unsafe{cr3()} as usize & !0xfff)
+}
+
+/// Allocate a zeroed table, returning its physical address and entries
+///
+/// # Safety
+///
+/// The allocator must return frames in the device window
+unsafe fn alloc_table<A: FrameAllocator>(alloc: &mut A) -> Option<(PAddr, &'static [Volatile<u64>])> {
+    let window: DeviceWindow<'static> = DeviceWindow::new(());
+    alloc.alloc_frame().and_then(|frame| window.try_from_paddr(frame)
+        .and_then(|addr| window.make_slice(addr, 512))
+        .map(|table| (frame, table)))
+}
+
+/// Construct the tables of the final kernel window, mapping physical memory
+/// up to `ram_top`, and at least all of the device window, along with the
+/// high window and the write combining window. Unlike the boot tables
+/// nothing here is accessible from user level, and these are what user
+/// address spaces take their kernel mappings from. The tables are not
+/// loaded, as the low window is still needed until early boot is done,
+/// see `load_kernel_window`
+///
+/// # Safety
+///
+/// Must only be called once, and the allocator must return frames in the
+/// device window
+pub unsafe fn make_kernel_window<A: FrameAllocator>(ram_top: PAddr, alloc: &mut A)
+        -> Result<PAddr, ()> {
+    let (root, pml4) = try!(alloc_table(alloc).ok_or(()));
+    let (pdpt_paddr, pdpt) = try!(alloc_table(alloc).ok_or(()));
+    if ram_top.0 > KERNEL_PHYS_MAPPING {
+        warn!("Only the first {}GB of memory is in the kernel window",
+            KERNEL_PHYS_MAPPING / util::GB);
+    }
+    let phys_top = cmp::min(cmp::max(ram_top.0, DEVICE_MAPPING.1), KERNEL_PHYS_MAPPING);
+    /* physical memory is mapped in gigabyte pages, as the boot tables do */
+    let large = PAGE_PRESENT | PAGE_WRITE | PAGE_LARGE;
+    for (i, entry) in pdpt.iter().enumerate().take(util::round_up(phys_top, util::GB) / util::GB) {
+        entry.write((i * util::GB) as u64 | large);
+    }
+    /* the kernel image is in the first gigabyte */
+    pdpt[(HIGH_BOOT_MAPPING.0 >> 30) & 511].write(large);
+    pml4[(KERNEL_MAPPING.0 >> 39) & 511].write(pdpt_paddr.0 as u64 | PAGE_PRESENT | PAGE_WRITE);
+    /* the write combining tables are in the kernel image, so whatever was
+     * mapped there during boot stays mapped */
+    let high: BootHighWindow<'static> = BootHighWindow::new(());
+    let wc = high.to_paddr(high.to_addr(&wc_pdpt as *const _ as usize));
+    pml4[(WRITE_COMBINING_MAPPING.0 >> 39) & 511].write(wc.0 as u64 | PAGE_PRESENT | PAGE_WRITE);
+    KERNEL_ROOT = Some(root);
+    Ok(root)
+}
+
+/// Root of the tables built by `make_kernel_window`, if it has been called
+pub fn kernel_window_root() -> Option<PAddr> {
+    unsafe{KERNEL_ROOT}
+}
+
+/// Switch from the boot tables to those of the final kernel window, after
+/// which the low window is gone
+///
+/// # Safety
+///
+/// `root` must be from `make_kernel_window`, and nothing in the low window
+/// may be used again
+pub unsafe fn load_kernel_window(root: PAddr) {
+    cr3_write(root.0 as u64);
 }
@@ -0,0 +1,386 @@
+//! Capability derivation tree
+//!
+//! The tree is kept as a doubly linked list of slots, ordered so that every
+//! capability is followed by all of the capabilities derived from it, as in
+//! seL4. Rather than storing parent links, whether a capability is the
+//! parent of the one after it is worked out from the capabilities
+//! themselves: a revocable capability is the parent of the following
+//! capabilities to the same object, with badged capabilities only being
+//! parents of unbadged derivations that kept their badge. Original
+//! capabilities, and capabilities that were given a badge by `mint`, are
+//! revocable. Copies are not, so copies of copies end up as siblings.
+//!
+//! This ordering means everything a revoke removes is in one run straight
+//! after the revoked capability, so revoke repeatedly deletes the next slot
+//! until it is no longer a child. That can be any amount of work, so revoke
+//! takes a `Preempt` and stops at a restart point once its budget runs out,
+//! leaving the tree consistent. Invoking it again carries on from where it
+//! stopped. Every other operation here is constant time.
+//!
+//! The operations are unsafe as they work on raw slot pointers. Every slot
+//! given must be valid, and the cluster lock must be held.
+//!
+//! Deleting the last capability to an object does not yet destroy the
+//! object, as there is no untyped memory for it to be returned to.
+use ::core::{cmp, ptr};
+use ::core::usize;
+use super::{Cap, CapError, CapRights, Slot};
+
+/// Capabilities deleted by one step of a preemptible revoke before the
+/// caller gets a chance to handle interrupts
+pub const REVOKE_BATCH: usize = 32;
+
+/// Work budget for a preemptible operation. The operation calls `point`
+/// before each unit of work, and stops with `CapError::Preempted` once the
+/// budget is used up
+pub struct Preempt {
+    left: usize,
+}
+
+impl Preempt {
+    pub fn new(budget: usize) -> Preempt {
+        Preempt { left: budget }
+    }
+    /// Never preempt. For boot, where there is nothing else to do
+    pub fn never() -> Preempt {
+        Preempt { left: usize::MAX }
+    }
+    /// Restart point. Everything done so far must be complete
+    pub fn point(&mut self) -> Result<(), CapError> {
+        if self.left == 0 {
+            return Err(CapError::Preempted);
+        }
+        self.left -= 1;
+        Ok(())
+    }
+}
+
+/// True if `child` was derived from `parent`, given that it follows it in
+/// the list
+fn is_parent(parent: &Slot, child: &Slot) -> bool {
+    if !parent.revocable || !parent.cap().same_object(child.cap()) {
+        return false;
+    }
+    if parent.cap().is_badgeable() && parent.cap().badge != 0 {
+        return child.cap().badge == parent.cap().badge && !child.revocable;
+    }
+    true
+}
+
+/// Put `slot` into the list straight after `after`
+unsafe fn link_after(after: *mut Slot, slot: *mut Slot) {
+    let next = (*after).next;
+    (*slot).prev = after;
+    (*slot).next = next;
+    if !next.is_null() {
+        (*next).prev = slot;
+    }
+    (*after).next = slot;
+}
+
+/// Take `slot` out of the list, joining its neighbours together
+unsafe fn unlink(slot: *mut Slot) {
+    let (prev, next) = ((*slot).prev, (*slot).next);
+    if !prev.is_null() {
+        (*prev).next = next;
+    }
+    if !next.is_null() {
+        (*next).prev = prev;
+    }
+    (*slot).prev = ptr::null_mut();
+    (*slot).next = ptr::null_mut();
+}
+
+/// Check the slots of an operation that fills `dest` from `src`
+unsafe fn check_slots(src: *mut Slot, dest: *mut Slot) -> Result<(), CapError> {
+    if (*src).is_empty() {
+        return Err(CapError::SlotEmpty);
+    }
+    if !(*dest).is_empty() {
+        return Err(CapError::SlotOccupied);
+    }
+    Ok(())
+}
+
+/// Place `cap` in `dest` as derived from the capability in `src`
+unsafe fn insert_derived(src: *mut Slot, dest: *mut Slot, cap: Cap, revocable: bool) {
+    (*dest).set_cap(cap);
+    (*dest).revocable = revocable;
+    link_after(src, dest);
+}
+
+/// Place the original capability to a new object in `slot`. It is the root
+/// of its own tree, and everything derived from it can be revoked
+pub unsafe fn insert_original(slot: *mut Slot, cap: Cap) -> Result<(), CapError> {
+    if cap.is_null() {
+        return Err(CapError::CannotDerive);
+    }
+    if !(*slot).is_empty() {
+        return Err(CapError::SlotOccupied);
+    }
+    (*slot).set_cap(cap);
+    (*slot).revocable = true;
+    Ok(())
+}
+
+/// Copy the capability in `src` to the empty slot `dest`
+pub unsafe fn copy(src: *mut Slot, dest: *mut Slot) -> Result<(), CapError> {
+    try!(check_slots(src, dest));
+    let cap = try!((*src).cap().derive());
+    insert_derived(src, dest, cap, false);
+    Ok(())
+}
+
+/// Copy the capability in `src` to the empty slot `dest`, keeping only the
+/// rights in `rights` and with a time to live of at most `ttl`
+pub unsafe fn copy_limited(src: *mut Slot, dest: *mut Slot, rights: CapRights, ttl: u8)
+        -> Result<(), CapError> {
+    try!(check_slots(src, dest));
+    let mut cap = try!((*src).cap().derive());
+    cap.rights = cap.rights.mask(rights);
+    cap.ttl = cmp::min(cap.ttl, ttl);
+    insert_derived(src, dest, cap, false);
+    Ok(())
+}
+
+/// Copy the capability in `src` to the empty slot `dest`, keeping only the
+/// rights in `rights` and applying `data` as in `Cap::mint`. The result is
+/// revocable if it gained a badge, so that it can be revoked without
+/// revoking every other badge of the object
+pub unsafe fn mint(src: *mut Slot, dest: *mut Slot, rights: CapRights, data: u64)
+        -> Result<(), CapError> {
+    try!(check_slots(src, dest));
+    let cap = try!((*src).cap().mint(rights, data));
+    let revocable = cap.badge != (*src).cap().badge;
+    insert_derived(src, dest, cap, revocable);
+    Ok(())
+}
+
+/// Move the capability in `src` to the empty slot `dest`, which takes its
+/// place in the tree
+pub unsafe fn move_cap(src: *mut Slot, dest: *mut Slot) -> Result<(), CapError> {
+    try!(check_slots(src, dest));
+    let (prev, next) = ((*src).prev, (*src).next);
+    (*dest).set_cap(*(*src).cap());
+    (*dest).revocable = (*src).revocable;
+    (*dest).prev = prev;
+    (*dest).next = next;
+    if !prev.is_null() {
+        (*prev).next = dest;
+    }
+    if !next.is_null() {
+        (*next).prev = dest;
+    }
+    (*src).prev = ptr::null_mut();
+    (*src).next = ptr::null_mut();
+    (*src).set_cap(Cap::null());
+    (*src).revocable = false;
+    Ok(())
+}
+
+/// Empty `slot`. Anything derived from its capability stays, and is then
+/// treated as derived from whatever came before it
+pub unsafe fn delete(slot: *mut Slot) {
+    unlink(slot);
+    (*slot).set_cap(Cap::null());
+    (*slot).revocable = false;
+}
+
+/// Delete everything derived from the capability in `slot`, leaving the
+/// capability itself. Returns `CapError::Preempted` if `preempt` ran out,
+/// in which case some of the children are gone and revoke should be
+/// invoked again on the same slot
+pub unsafe fn revoke(slot: *mut Slot, preempt: &mut Preempt) -> Result<(), CapError> {
+    if (*slot).is_empty() {
+        return Err(CapError::SlotEmpty);
+    }
+    loop {
+        let next = (*slot).next;
+        if next.is_null() || !is_parent(&*slot, &*next) {
+            return Ok(());
+        }
+        try!(preempt.point());
+        delete(next);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Preempt, REVOKE_BATCH, insert_original, copy, mint, move_cap, delete, revoke};
+    use cap::{Cap, CapError, CapKind, CapRights, CNode, Slot};
+    use object::Endpoint;
+
+    /// A CNode of `1 << radix` slots, and the memory backing it
+    fn cnode(radix: u8) -> (CNode, Vec<Slot>) {
+        let mut slots: Vec<Slot> = (0..1 << radix).map(|_| Slot::empty()).collect();
+        let cnode = unsafe{CNode::new(slots.as_mut_ptr(), radix)}.unwrap();
+        (cnode, slots)
+    }
+
+    /// Original capability to a made up endpoint, which is never touched
+    fn endpoint(id: usize) -> Cap {
+        Cap::new(CapKind::Endpoint(id as *mut Endpoint))
+    }
+
+    /// Indices of the slots of `cnode` in tree order, starting from `first`
+    fn order(cnode: &CNode, first: usize) -> Vec<usize> {
+        let base = cnode.slot(0).unwrap() as usize;
+        let mut order = Vec::new();
+        let mut slot = cnode.slot(first).unwrap();
+        while !slot.is_null() {
+            order.push((slot as usize - base) / ::core::mem::size_of::<Slot>());
+            slot = unsafe{(*slot).next};
+        }
+        order
+    }
+
+    fn slot(cnode: &CNode, index: usize) -> *mut Slot {
+        cnode.slot(index).unwrap()
+    }
+
+    #[test]
+    fn children_follow_their_parent() {
+        let (cn, _mem) = cnode(3);
+        unsafe {
+            insert_original(slot(&cn, 0), endpoint(1)).unwrap();
+            copy(slot(&cn, 0), slot(&cn, 1)).unwrap();
+            copy(slot(&cn, 0), slot(&cn, 2)).unwrap();
+            mint(slot(&cn, 0), slot(&cn, 3), CapRights::all(), 7).unwrap();
+            copy(slot(&cn, 3), slot(&cn, 4)).unwrap();
+        }
+        assert_eq!(order(&cn, 0), vec![0, 3, 4, 2, 1]);
+        let prev = unsafe{(*slot(&cn, 4)).prev};
+        assert_eq!(prev, slot(&cn, 3));
+    }
+
+    #[test]
+    fn move_takes_the_place_in_the_tree() {
+        let (cn, _mem) = cnode(3);
+        unsafe {
+            insert_original(slot(&cn, 0), endpoint(1)).unwrap();
+            copy(slot(&cn, 0), slot(&cn, 1)).unwrap();
+            copy(slot(&cn, 0), slot(&cn, 2)).unwrap();
+            move_cap(slot(&cn, 2), slot(&cn, 5)).unwrap();
+            assert!((*slot(&cn, 2)).is_empty());
+            assert!((*slot(&cn, 2)).next.is_null());
+        }
+        assert_eq!(order(&cn, 0), vec![0, 5, 1]);
+    }
+
+    #[test]
+    fn occupied_and_empty_slots_are_refused() {
+        let (cn, _mem) = cnode(2);
+        unsafe {
+            insert_original(slot(&cn, 0), endpoint(1)).unwrap();
+            insert_original(slot(&cn, 1), endpoint(2)).unwrap();
+            assert_eq!(copy(slot(&cn, 0), slot(&cn, 1)), Err(CapError::SlotOccupied));
+            assert_eq!(copy(slot(&cn, 2), slot(&cn, 3)), Err(CapError::SlotEmpty));
+            assert_eq!(insert_original(slot(&cn, 0), endpoint(3)), Err(CapError::SlotOccupied));
+        }
+    }
+
+    #[test]
+    fn copies_of_copies_are_siblings() {
+        let (cn, _mem) = cnode(3);
+        unsafe {
+            insert_original(slot(&cn, 0), endpoint(1)).unwrap();
+            copy(slot(&cn, 0), slot(&cn, 1)).unwrap();
+            copy(slot(&cn, 1), slot(&cn, 2)).unwrap();
+            /* a copy is not revocable, so takes nothing with it */
+            revoke(slot(&cn, 1), &mut Preempt::never()).unwrap();
+            assert!(!(*slot(&cn, 2)).is_empty());
+            revoke(slot(&cn, 0), &mut Preempt::never()).unwrap();
+            assert!((*slot(&cn, 1)).is_empty());
+            assert!((*slot(&cn, 2)).is_empty());
+            assert!(!(*slot(&cn, 0)).is_empty());
+        }
+        assert_eq!(order(&cn, 0), vec![0]);
+    }
+
+    #[test]
+    fn revoking_a_badge_leaves_other_badges() {
+        let (cn, _mem) = cnode(3);
+        unsafe {
+            insert_original(slot(&cn, 0), endpoint(1)).unwrap();
+            mint(slot(&cn, 0), slot(&cn, 1), CapRights::all(), 1).unwrap();
+            mint(slot(&cn, 0), slot(&cn, 2), CapRights::all(), 2).unwrap();
+            copy(slot(&cn, 1), slot(&cn, 3)).unwrap();
+            copy(slot(&cn, 2), slot(&cn, 4)).unwrap();
+            revoke(slot(&cn, 2), &mut Preempt::never()).unwrap();
+            assert!((*slot(&cn, 4)).is_empty());
+            assert!(!(*slot(&cn, 3)).is_empty());
+            /* a badged capability cannot be given another badge */
+            assert_eq!(mint(slot(&cn, 1), slot(&cn, 5), CapRights::all(), 3),
+                Err(CapError::CannotDerive));
+        }
+        assert_eq!(order(&cn, 0), vec![0, 2, 1, 3]);
+    }
+
+    #[test]
+    fn delete_leaves_children_with_the_grandparent() {
+        let (cn, _mem) = cnode(3);
+        unsafe {
+            insert_original(slot(&cn, 0), endpoint(1)).unwrap();
+            mint(slot(&cn, 0), slot(&cn, 1), CapRights::all(), 1).unwrap();
+            copy(slot(&cn, 1), slot(&cn, 2)).unwrap();
+            delete(slot(&cn, 1));
+            assert_eq!(order(&cn, 0), vec![0, 2]);
+            revoke(slot(&cn, 0), &mut Preempt::never()).unwrap();
+            assert!((*slot(&cn, 2)).is_empty());
+        }
+    }
+
+    #[test]
+    fn revoke_stops_at_the_end_of_the_subtree() {
+        let (cn, _mem) = cnode(6);
+        let last = (1 << 6) - 1;
+        unsafe {
+            insert_original(slot(&cn, 0), endpoint(1)).unwrap();
+            /* minted first, so it ends up after the other badge's subtree */
+            mint(slot(&cn, 0), slot(&cn, last), CapRights::all(), 2).unwrap();
+            mint(slot(&cn, 0), slot(&cn, 1), CapRights::all(), 1).unwrap();
+            for i in 2..last {
+                copy(slot(&cn, 1), slot(&cn, i)).unwrap();
+            }
+            revoke(slot(&cn, 1), &mut Preempt::never()).unwrap();
+        }
+        assert_eq!(order(&cn, 0), vec![0, 1, last]);
+    }
+
+    #[test]
+    fn revoke_of_a_deep_subtree_is_preempted_and_restarts() {
+        let count = 1 << 9;
+        let (cn, _mem) = cnode(9);
+        unsafe {
+            insert_original(slot(&cn, 0), endpoint(1)).unwrap();
+            /* the original of another object, which must be left alone */
+            insert_original(slot(&cn, count - 1), endpoint(2)).unwrap();
+            /* every badge has a run of copies under it */
+            for i in 1..count - 1 {
+                if i % 8 == 1 {
+                    mint(slot(&cn, 0), slot(&cn, i), CapRights::all(), i as u64).unwrap();
+                } else {
+                    copy(slot(&cn, i - (i - 1) % 8), slot(&cn, i)).unwrap();
+                }
+            }
+            assert_eq!(order(&cn, 0).len(), count - 1);
+            let mut preemptions = 0;
+            loop {
+                match revoke(slot(&cn, 0), &mut Preempt::new(REVOKE_BATCH)) {
+                    Err(CapError::Preempted) => preemptions += 1,
+                    Ok(()) => break,
+                    Err(err) => panic!("revoke failed {:?}", err),
+                }
+                /* each restart point leaves a consistent tree */
+                assert_eq!(order(&cn, 0).len(), count - 1 - preemptions * REVOKE_BATCH);
+            }
+            /* a batch is only preempted if there is more left to delete */
+            assert_eq!(preemptions, (count - 3) / REVOKE_BATCH);
+            for i in 1..count - 1 {
+                assert!((*slot(&cn, i)).is_empty());
+            }
+            assert!(!(*slot(&cn, count - 1)).is_empty());
+        }
+        assert_eq!(order(&cn, 0), vec![0]);
+    }
+}
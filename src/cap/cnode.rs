@@ -0,0 +1,162 @@
+//! CNodes and capability addressing
+//!
+//! A CNode is a power of two sized array of slots. Slots are addressed by a
+//! capability pointer, a word that is translated a few bits at a time: each
+//! CNode capability has a guard, which must match the next bits of the
+//! address, and then the radix of the CNode gives how many bits after that
+//! index a slot. If address bits remain and the slot holds another CNode
+//! capability translation continues there. The depth given with an address
+//! says how many of its low bits to translate, and lookup must end on a
+//! slot after exactly that many.
+use ::core::mem::size_of;
+use ::core::ptr;
+use super::{Cap, CapKind, CapError};
+
+/// Bits in a capability pointer
+pub const CPTR_BITS: u32 = 64;
+/// Largest radix a CNode can have
+pub const MAX_RADIX: u8 = 20;
+/// Bits of mint data holding the size of a guard, the guard itself being
+/// in the bits above
+const GUARD_SIZE_BITS: u32 = 6;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// Address of a slot in a capability space
+pub struct CPtr(pub u64);
+
+/// A slot that can hold one capability
+pub struct Slot {
+    cap: Cap,
+    /// Neighbours in the derivation tree, which is kept as a list in which
+    /// every capability is followed by everything derived from it. See
+    /// `cdt` for how the list is read
+    pub prev: *mut Slot,
+    pub next: *mut Slot,
+    /// Later capabilities to the same object were derived from this one.
+    /// True for original capabilities and newly badged ones
+    pub revocable: bool,
+}
+
+impl Slot {
+    pub fn empty() -> Slot {
+        Slot { cap: Cap::null(), prev: ptr::null_mut(), next: ptr::null_mut(), revocable: false }
+    }
+    pub fn cap(&self) -> &Cap {
+        &self.cap
+    }
+    pub fn is_empty(&self) -> bool {
+        self.cap.is_null()
+    }
+    /// Replace the capability without touching the derivation tree. Only
+    /// for `cdt`, which keeps the two consistent
+    pub fn set_cap(&mut self, cap: Cap) {
+        self.cap = cap;
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// A CNode object, which is just its array of slots
+pub struct CNode {
+    slots: *mut Slot,
+    radix: u8,
+}
+
+impl CNode {
+    /// Bytes of memory needed for a CNode of `radix` bits
+    pub fn size(radix: u8) -> usize {
+        size_of::<Slot>() << radix
+    }
+    /// Construct a CNode with every slot empty
+    ///
+    /// # Safety
+    ///
+    /// `memory` must be `CNode::size(radix)` bytes of kernel memory, aligned
+    /// for a `Slot`, that nothing else uses and that outlives every
+    /// capability to the CNode
+    pub unsafe fn new(memory: *mut Slot, radix: u8) -> Option<CNode> {
+        if radix == 0 || radix > MAX_RADIX {
+            return None;
+        }
+        for i in 0..1isize << radix {
+            ptr::write(memory.offset(i), Slot::empty());
+        }
+        Some(CNode { slots: memory, radix: radix })
+    }
+    pub fn radix(&self) -> u8 {
+        self.radix
+    }
+    /// Slot at `index`, or `None` if it is past the end
+    pub fn slot(&self, index: usize) -> Option<*mut Slot> {
+        if index >> self.radix != 0 {
+            return None;
+        }
+        Some(unsafe{self.slots.offset(index as isize)})
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// Capability to a CNode, with the guard used when translating through it
+pub struct CNodeCap {
+    pub cnode: CNode,
+    pub guard: u64,
+    pub guard_bits: u8,
+}
+
+impl CNodeCap {
+    /// Capability to `cnode` with no guard
+    pub fn new(cnode: CNode) -> CNodeCap {
+        CNodeCap { cnode: cnode, guard: 0, guard_bits: 0 }
+    }
+    /// Copy with a guard decoded from mint data. The low 6 bits are the size
+    /// of the guard, and the guard is in the bits above
+    pub fn with_guard(&self, data: u64) -> Result<CNodeCap, CapError> {
+        let guard_bits = (data & ((1 << GUARD_SIZE_BITS) - 1)) as u32;
+        let guard = data >> GUARD_SIZE_BITS;
+        if guard_bits + self.cnode.radix as u32 > CPTR_BITS || guard != bits(guard, 0, guard_bits) {
+            return Err(CapError::InvalidArgument);
+        }
+        Ok(CNodeCap { cnode: self.cnode, guard: guard, guard_bits: guard_bits as u8 })
+    }
+    /// Bits of an address this translates
+    fn level_bits(&self) -> u32 {
+        self.guard_bits as u32 + self.cnode.radix as u32
+    }
+}
+
+/// `width` bits of `word` starting at bit `shift`
+fn bits(word: u64, shift: u32, width: u32) -> u64 {
+    if width == 0 {
+        return 0;
+    }
+    (word >> shift) & (!0 >> (CPTR_BITS - width))
+}
+
+/// Find the slot that the low `depth` bits of `cptr` address, starting
+/// with the CNode of `root`
+pub fn lookup_slot(root: &Cap, cptr: CPtr, depth: u32) -> Result<*mut Slot, CapError> {
+    if depth == 0 || depth > CPTR_BITS {
+        return Err(CapError::BadDepth);
+    }
+    let mut cap = *root;
+    let mut left = depth;
+    loop {
+        let cnode = match cap.kind {
+            CapKind::CNode(cnode) => cnode,
+            _ => return Err(CapError::NotCNode),
+        };
+        if cnode.level_bits() > left {
+            return Err(CapError::BadDepth);
+        }
+        if bits(cptr.0, left - cnode.guard_bits as u32, cnode.guard_bits as u32) != cnode.guard {
+            return Err(CapError::GuardMismatch);
+        }
+        left -= cnode.level_bits();
+        let index = bits(cptr.0, left, cnode.cnode.radix as u32) as usize;
+        /* the index is radix bits wide so is always in the CNode */
+        let slot = cnode.cnode.slot(index).unwrap();
+        if left == 0 {
+            return Ok(slot);
+        }
+        cap = unsafe{*(*slot).cap()};
+    }
+}
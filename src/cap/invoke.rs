@@ -0,0 +1,59 @@
+//! Finding what an invocation refers to
+//!
+//! An invocation always happens in the protection domain of the caller:
+//! capability pointers are looked up in its capability space and proxies
+//! are read from its address space. The exception is a proxy in another
+//! protection domain, which can only be named through a capability to that
+//! domain. Proxies in another domain are therefore only usable with
+//! authority the caller holds, rather than by guessing an identifier, and
+//! the domain that wrote the proxy has chosen to hand out what it refers
+//! to. The capability to the domain counts as part of the proxy chain, so
+//! the proxy must have a lower time to live than it.
+use object::{Pd, PD_PROXY_RIGHTS};
+use super::{CapError, CapKind, CPtr, Resolved, lookup_slot, resolve};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// Where the capability being invoked is
+pub enum InvokeAddr {
+    /// In the capability space of the caller, by address and depth
+    Cap(CPtr, u32),
+    /// A proxy in the address space of the caller
+    Proxy(usize),
+    /// A proxy in the address space of the protection domain that the
+    /// capability at `pd` and `depth` in the caller's capability space
+    /// refers to
+    Remote { pd: CPtr, depth: u32, proxy: usize },
+}
+
+/// Find the capability an invocation from `caller` refers to, and the
+/// rights it is invoked with
+pub fn lookup_invocation(caller: &Pd, addr: InvokeAddr) -> Result<Resolved, CapError> {
+    match addr {
+        InvokeAddr::Cap(cptr, depth) => {
+            let slot = try!(lookup_slot(caller.cspace(), cptr, depth));
+            let cap = unsafe{*(*slot).cap()};
+            if cap.is_null() {
+                return Err(CapError::SlotEmpty);
+            }
+            Ok(Resolved { slot: slot, rights: cap.rights, linkable: cap.rights.grant, ttl: cap.ttl })
+        },
+        InvokeAddr::Proxy(proxy) => resolve(caller.vspace(), caller.cspace(), proxy),
+        InvokeAddr::Remote { pd, depth, proxy } => {
+            let slot = try!(lookup_slot(caller.cspace(), pd, depth));
+            let cap = unsafe{*(*slot).cap()};
+            let remote = match cap.kind {
+                CapKind::Pd(remote) => unsafe{&*remote},
+                CapKind::Null => return Err(CapError::SlotEmpty),
+                _ => return Err(CapError::NotPd),
+            };
+            if !cap.rights.contains(PD_PROXY_RIGHTS) {
+                return Err(CapError::InsufficientRights);
+            }
+            let resolved = try!(resolve(remote.vspace(), remote.cspace(), proxy));
+            if resolved.ttl >= cap.ttl {
+                return Err(CapError::TtlNotDecreasing);
+            }
+            Ok(resolved)
+        },
+    }
+}
@@ -0,0 +1,202 @@
+//! Capabilities and capability spaces
+//!
+//! A capability is a reference to a kernel object along with the rights it
+//! grants over that object. Capabilities are stored in the slots of CNode
+//! objects, and the CNodes reachable from a root CNode capability make up a
+//! capability space, in which slots are found by address (see `cnode`).
+//!
+//! Every capability that is not null is also in the capability derivation
+//! tree, which records which capabilities were made from which others so
+//! that authority handed out can be taken back again (see `cdt`).
+//!
+//! Capabilities in CNodes can only be changed by the kernel. User level can
+//! also write proxy capabilities into its own memory, which refer back to a
+//! capability and pass on some of its rights (see `proxy`). Every
+//! capability has a time to live that must decrease along a chain of
+//! proxies, so chains are short and cannot loop.
+//!
+//! None of this does any locking. As with the rest of the state of a
+//! cluster, callers must hold the cluster lock (see RAVINGS.md).
+use object::{Endpoint, Notification, Pd, SchedContext, Tcb};
+
+pub mod cnode;
+pub mod cdt;
+pub mod proxy;
+pub mod invoke;
+
+pub use self::cnode::{CNode, CNodeCap, CPtr, Slot, lookup_slot};
+pub use self::cdt::{Preempt, REVOKE_BATCH, insert_original, copy, copy_limited, mint, move_cap,
+    delete, revoke};
+pub use self::proxy::{ProxyEntry, ProxySource, Resolved, resolve, link, mark_page};
+pub use self::invoke::{InvokeAddr, lookup_invocation};
+
+/// Time to live of an original capability
+pub const MAX_TTL: u8 = 15;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// Errors from looking up or manipulating capabilities
+pub enum CapError {
+    /// Lookup reached a capability that is not a CNode with bits of the
+    /// address left over
+    NotCNode,
+    /// The guard of a CNode did not match the address
+    GuardMismatch,
+    /// Depth was zero, too large, or did not end exactly on a slot
+    BadDepth,
+    /// The source slot has nothing in it
+    SlotEmpty,
+    /// The destination slot already has something in it
+    SlotOccupied,
+    /// This kind of capability cannot be copied or minted, or a badged
+    /// capability was given another badge
+    CannotDerive,
+    /// Argument of a mint did not make sense for the capability
+    InvalidArgument,
+    /// The operation ran out of budget and must be invoked again to finish
+    Preempted,
+    /// A proxy address is not in a user page marked as holding proxies
+    NotProxyPage,
+    /// A proxy is not valid, badly encoded or not aligned
+    InvalidProxy,
+    /// A proxy did not have a lower time to live than what it refers to
+    TtlNotDecreasing,
+    /// Something in the chain lacked a right the operation needs
+    InsufficientRights,
+    /// A capability that should have been to a protection domain was not
+    NotPd,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// Access rights a capability grants over its object
+pub struct CapRights {
+    pub read: bool,
+    pub write: bool,
+    /// Capabilities may be passed on through the object
+    pub grant: bool,
+}
+
+impl CapRights {
+    /// Every right, as held by the original capability to an object
+    pub fn all() -> CapRights {
+        CapRights { read: true, write: true, grant: true }
+    }
+    pub fn none() -> CapRights {
+        CapRights { read: false, write: false, grant: false }
+    }
+    /// Decode rights from the low bits of an invocation argument, read
+    /// being bit 0, write bit 1 and grant bit 2
+    pub fn from_word(word: u64) -> CapRights {
+        CapRights { read: word & 1 != 0, write: word & 2 != 0, grant: word & 4 != 0 }
+    }
+    /// Rights that are in both `self` and `mask`
+    pub fn mask(self, mask: CapRights) -> CapRights {
+        CapRights {
+            read: self.read && mask.read,
+            write: self.write && mask.write,
+            grant: self.grant && mask.grant,
+        }
+    }
+    /// True if every right in `other` is also in `self`
+    pub fn contains(self, other: CapRights) -> bool {
+        self.mask(other) == other
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// Object a capability refers to, and anything specific to its kind
+pub enum CapKind {
+    /// Nothing, the contents of an empty slot
+    Null,
+    CNode(CNodeCap),
+    Notification(*mut Notification),
+    Tcb(*mut Tcb),
+    Pd(*mut Pd),
+    Endpoint(*mut Endpoint),
+    SchedContext(*mut SchedContext),
+    /// Authority to set the budget and period of scheduling contexts on a
+    /// processor, by index
+    SchedControl(usize),
+    /// Authority to read the kernel log (see `klog`)
+    Log,
+    /// Authority to make IOMMU domains and bind devices to them
+    IommuControl,
+    /// An IOMMU domain, by the index the platform gave it
+    IommuDomain(usize),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// A capability as stored in a slot
+pub struct Cap {
+    pub kind: CapKind,
+    pub rights: CapRights,
+    /// Identifies which derived capability an object was invoked through,
+    /// for kinds that support it. Zero means unbadged
+    pub badge: u64,
+    /// Proxies of this capability must have a lower time to live
+    pub ttl: u8,
+}
+
+impl Cap {
+    /// The capability of an empty slot
+    pub fn null() -> Cap {
+        Cap { kind: CapKind::Null, rights: CapRights::none(), badge: 0, ttl: 0 }
+    }
+    /// An original capability to a new object, with every right
+    pub fn new(kind: CapKind) -> Cap {
+        Cap { kind: kind, rights: CapRights::all(), badge: 0, ttl: MAX_TTL }
+    }
+    pub fn is_null(&self) -> bool {
+        self.kind == CapKind::Null
+    }
+    /// True if both capabilities refer to the same object
+    pub fn same_object(&self, other: &Cap) -> bool {
+        match (self.kind, other.kind) {
+            (CapKind::CNode(a), CapKind::CNode(b)) => a.cnode == b.cnode,
+            (CapKind::Notification(a), CapKind::Notification(b)) => a == b,
+            (CapKind::Tcb(a), CapKind::Tcb(b)) => a == b,
+            (CapKind::Pd(a), CapKind::Pd(b)) => a == b,
+            (CapKind::Endpoint(a), CapKind::Endpoint(b)) => a == b,
+            (CapKind::SchedContext(a), CapKind::SchedContext(b)) => a == b,
+            (CapKind::SchedControl(a), CapKind::SchedControl(b)) => a == b,
+            (CapKind::Log, CapKind::Log) => true,
+            (CapKind::IommuControl, CapKind::IommuControl) => true,
+            (CapKind::IommuDomain(a), CapKind::IommuDomain(b)) => a == b,
+            _ => false,
+        }
+    }
+    /// True if this kind of capability can be badged by `mint`
+    pub fn is_badgeable(&self) -> bool {
+        match self.kind {
+            CapKind::Notification(_) | CapKind::Endpoint(_) => true,
+            CapKind::Null | CapKind::CNode(_) | CapKind::Tcb(_) | CapKind::Pd(_)
+                | CapKind::SchedContext(_) | CapKind::SchedControl(_) | CapKind::Log
+                | CapKind::IommuControl | CapKind::IommuDomain(_) => false,
+        }
+    }
+    /// A capability that may be placed in another slot as a copy of this
+    pub fn derive(&self) -> Result<Cap, CapError> {
+        match self.kind {
+            CapKind::Null => Err(CapError::CannotDerive),
+            CapKind::CNode(_) | CapKind::Notification(_) | CapKind::Tcb(_) | CapKind::Pd(_)
+                | CapKind::Endpoint(_) | CapKind::SchedContext(_) | CapKind::SchedControl(_)
+                | CapKind::Log | CapKind::IommuControl | CapKind::IommuDomain(_) => Ok(*self),
+        }
+    }
+    /// A copy of this capability with the rights restricted by `rights`,
+    /// and with `data` interpreted by the kind of capability. For CNodes it
+    /// is a new guard, encoded as for `CNodeCap::with_guard`, and for
+    /// badgeable kinds it is a badge, where zero leaves the badge as it was
+    pub fn mint(&self, rights: CapRights, data: u64) -> Result<Cap, CapError> {
+        let mut cap = try!(self.derive());
+        cap.rights = cap.rights.mask(rights);
+        if let CapKind::CNode(cnode) = cap.kind {
+            cap.kind = CapKind::CNode(try!(cnode.with_guard(data)));
+        } else if self.is_badgeable() && data != 0 {
+            if self.badge != 0 {
+                return Err(CapError::CannotDerive);
+            }
+            cap.badge = data;
+        }
+        Ok(cap)
+    }
+}
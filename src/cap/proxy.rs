@@ -0,0 +1,370 @@
+//! Proxy capabilities
+//!
+//! Capabilities in CNodes are only ever written by the kernel. Proxy
+//! capabilities are written by user level, into its own memory, and defer
+//! to another capability for their rights: either a capability in the
+//! capability space, or another proxy. A proxy can only take away rights,
+//! so what a chain of proxies grants is the intersection of everything in
+//! it.
+//!
+//! So that user level cannot point the kernel at arbitrary memory and have
+//! it taken as a proxy, proxies are only read from pages the kernel has
+//! marked as holding them (see `mark_page`). Each proxy also has a time to
+//! live, which must be lower than that of whatever it refers to. Chains are
+//! therefore at most `MAX_TTL` long and cannot loop.
+//!
+//! A proxy can be linked, turning it into a real capability derived from
+//! the one at the end of its chain, if every proxy in the chain has the
+//! link right and the capability has the grant right. The new capability
+//! does not depend on the proxies it was found through, so they can then
+//! be changed without affecting it.
+//!
+//! A proxy is two words, aligned to their size. The first is the source,
+//! a capability pointer or the address of another proxy. The second is
+//!
+//! * bit 0, the proxy is valid
+//! * bit 1, the source is another proxy rather than a capability pointer
+//! * bits 2 to 5, the read, write, grant and link rights
+//! * bits 8 to 15, the time to live
+//! * bits 16 to 23, the depth to look up a capability pointer to
+//!
+//! with every other bit zero.
+use arch;
+use types::PAddr;
+use util::Volatile;
+use vspace::VSpaceWindow;
+use arch::DeviceWindow;
+use ::core::mem::size_of;
+use super::{Cap, CapError, CapRights, CPtr, Slot, MAX_TTL, cdt, lookup_slot};
+
+/// Bytes in a proxy
+pub const PROXY_SIZE: usize = 16;
+
+const PROXY_VALID: u64 = 1 << 0;
+const PROXY_SOURCE_PROXY: u64 = 1 << 1;
+const PROXY_RIGHTS_SHIFT: u64 = 2;
+const PROXY_LINK: u64 = 1 << 5;
+const PROXY_TTL_SHIFT: u64 = 8;
+const PROXY_DEPTH_SHIFT: u64 = 16;
+/// Bits of the second word that have a meaning
+const PROXY_USED_BITS: u64 = 0xff_ff3f;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// What a proxy refers to
+pub enum ProxySource {
+    /// A capability, by address and depth in the capability space
+    Cap(CPtr, u32),
+    /// Another proxy, by user virtual address
+    Proxy(usize),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// A decoded proxy
+pub struct ProxyEntry {
+    pub source: ProxySource,
+    pub rights: CapRights,
+    pub link: bool,
+    pub ttl: u8,
+}
+
+impl ProxyEntry {
+    /// Decode the two words of a proxy
+    pub fn decode(source: u64, info: u64) -> Result<ProxyEntry, CapError> {
+        if info & PROXY_VALID == 0 || info & !PROXY_USED_BITS != 0 {
+            return Err(CapError::InvalidProxy);
+        }
+        let ttl = (info >> PROXY_TTL_SHIFT) as u8;
+        if ttl > MAX_TTL {
+            return Err(CapError::InvalidProxy);
+        }
+        let source = if info & PROXY_SOURCE_PROXY != 0 {
+            ProxySource::Proxy(source as usize)
+        } else {
+            ProxySource::Cap(CPtr(source), (info >> PROXY_DEPTH_SHIFT) as u8 as u32)
+        };
+        Ok(ProxyEntry {
+            source: source,
+            rights: CapRights::from_word(info >> PROXY_RIGHTS_SHIFT),
+            link: info & PROXY_LINK != 0,
+            ttl: ttl,
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+/// Result of following a chain of proxies
+pub struct Resolved {
+    /// Slot of the capability at the end of the chain
+    pub slot: *mut Slot,
+    /// Rights held by every proxy in the chain and the capability
+    pub rights: CapRights,
+    /// The proxy can be linked into a real capability
+    pub linkable: bool,
+    /// Time to live of the first proxy
+    pub ttl: u8,
+}
+
+/// Read the proxy at `addr` in the address space at `vspace`. The words
+/// are read once each, so user level changing them at the same time can at
+/// worst give a proxy it could have written anyway, and is decoded from
+/// that copy
+fn read_entry(vspace: PAddr, addr: usize) -> Result<ProxyEntry, CapError> {
+    if addr % PROXY_SIZE != 0 {
+        return Err(CapError::InvalidProxy);
+    }
+    let paddr = match arch::lookup_user_page(vspace, addr) {
+        Some(ref page) if page.proxy => page.paddr,
+        _ => return Err(CapError::NotProxyPage),
+    };
+    let window: DeviceWindow = unsafe{DeviceWindow::new(())};
+    let words: &[Volatile<u64>] = match window.try_from_paddr(paddr)
+            .and_then(|addr| unsafe{window.make_slice(addr, PROXY_SIZE / size_of::<u64>())}) {
+        Some(words) => words,
+        None => return Err(CapError::NotProxyPage),
+    };
+    ProxyEntry::decode(words[0].read(), words[1].read())
+}
+
+/// Follow the chain of proxies from the one at `addr` in the address space
+/// at `vspace`, finding its capability in the capability space at `cspace`
+pub fn resolve(vspace: PAddr, cspace: &Cap, addr: usize) -> Result<Resolved, CapError> {
+    resolve_with(|addr| read_entry(vspace, addr), cspace, addr)
+}
+
+/// As for `resolve`, reading each proxy with `read`
+fn resolve_with<F>(read: F, cspace: &Cap, addr: usize) -> Result<Resolved, CapError>
+        where F: Fn(usize) -> Result<ProxyEntry, CapError> {
+    let first = try!(read(addr));
+    let mut entry = first;
+    let mut rights = first.rights;
+    let mut linkable = first.link;
+    /* the time to live goes up with every step, so this is bounded */
+    loop {
+        match entry.source {
+            ProxySource::Proxy(next) => {
+                let parent = try!(read(next));
+                if parent.ttl <= entry.ttl {
+                    return Err(CapError::TtlNotDecreasing);
+                }
+                rights = rights.mask(parent.rights);
+                linkable = linkable && parent.link;
+                entry = parent;
+            },
+            ProxySource::Cap(cptr, depth) => {
+                let slot = try!(lookup_slot(cspace, cptr, depth));
+                let cap = unsafe{*(*slot).cap()};
+                if cap.is_null() {
+                    return Err(CapError::SlotEmpty);
+                }
+                if cap.ttl <= entry.ttl {
+                    return Err(CapError::TtlNotDecreasing);
+                }
+                return Ok(Resolved {
+                    slot: slot,
+                    rights: rights.mask(cap.rights),
+                    linkable: linkable && cap.rights.grant,
+                    ttl: first.ttl,
+                });
+            },
+        }
+    }
+}
+
+/// Turn the proxy at `addr` into a real capability in the empty slot
+/// `dest`, with the rights and time to live of the proxy
+///
+/// # Safety
+///
+/// As for the operations of `cdt`
+pub unsafe fn link(vspace: PAddr, cspace: &Cap, addr: usize, dest: *mut Slot)
+        -> Result<(), CapError> {
+    link_resolved(try!(resolve(vspace, cspace, addr)), dest)
+}
+
+/// Link the proxy that resolved to `resolved` into `dest`
+unsafe fn link_resolved(resolved: Resolved, dest: *mut Slot) -> Result<(), CapError> {
+    if !resolved.linkable {
+        return Err(CapError::InsufficientRights);
+    }
+    cdt::copy_limited(resolved.slot, dest, resolved.rights, resolved.ttl)
+}
+
+/// Mark or unmark the user page at `vaddr` in the address space at `vspace`
+/// as holding proxies. Any proxies in a page stop working once it is
+/// unmarked
+///
+/// # Safety
+///
+/// Nothing else may be changing the page tables
+pub unsafe fn mark_page(vspace: PAddr, vaddr: usize, marked: bool) -> Result<(), CapError> {
+    if arch::mark_proxy_page(vspace, vaddr, marked) {
+        Ok(())
+    } else {
+        Err(CapError::InvalidArgument)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ProxyEntry, ProxySource, resolve_with, link_resolved, PROXY_VALID,
+        PROXY_SOURCE_PROXY, PROXY_RIGHTS_SHIFT, PROXY_LINK, PROXY_TTL_SHIFT, PROXY_DEPTH_SHIFT};
+    use cap::{Cap, CapError, CapKind, CapRights, CNode, CNodeCap, CPtr, Slot, MAX_TTL,
+        insert_original};
+    use object::Endpoint;
+
+    /// Radix of the test capability space, whose guard makes its slots
+    /// addressable at the full depth of a capability pointer
+    const RADIX: u8 = 4;
+    const DEPTH: u32 = 64;
+
+    /// A capability space with a single CNode, and the memory backing it
+    fn cspace() -> (Cap, CNode, Vec<Slot>) {
+        let mut slots: Vec<Slot> = (0..1 << RADIX).map(|_| Slot::empty()).collect();
+        let cnode = unsafe{CNode::new(slots.as_mut_ptr(), RADIX)}.unwrap();
+        let cap = CNodeCap::new(cnode).with_guard((DEPTH - RADIX as u32) as u64).unwrap();
+        (Cap::new(CapKind::CNode(cap)), cnode, slots)
+    }
+
+    /// Encode a proxy with `rights` as for `CapRights::from_word`
+    fn proxy(source: ProxySource, rights: u64, link: bool, ttl: u8) -> (u64, u64) {
+        let mut info = PROXY_VALID | rights << PROXY_RIGHTS_SHIFT | (ttl as u64) << PROXY_TTL_SHIFT;
+        if link {
+            info |= PROXY_LINK;
+        }
+        match source {
+            ProxySource::Cap(cptr, depth) => (cptr.0, info | (depth as u64) << PROXY_DEPTH_SHIFT),
+            ProxySource::Proxy(addr) => (addr as u64, info | PROXY_SOURCE_PROXY),
+        }
+    }
+
+    /// Read the proxy at `addr` from `memory`, a list of proxies by address
+    fn read_from(memory: &[(usize, (u64, u64))], addr: usize) -> Result<ProxyEntry, CapError> {
+        match memory.iter().find(|&&(a, _)| a == addr) {
+            Some(&(_, (source, info))) => ProxyEntry::decode(source, info),
+            None => Err(CapError::NotProxyPage),
+        }
+    }
+
+    fn endpoint() -> Cap {
+        Cap::new(CapKind::Endpoint(0x1000 as *mut Endpoint))
+    }
+
+    const ALL: u64 = 0x7;
+    const READ: u64 = 0x1;
+
+    #[test]
+    fn badly_encoded_proxies_are_refused() {
+        let (source, info) = proxy(ProxySource::Proxy(0x10), ALL, true, 3);
+        assert!(ProxyEntry::decode(source, info).is_ok());
+        assert_eq!(ProxyEntry::decode(source, info & !PROXY_VALID), Err(CapError::InvalidProxy));
+        assert_eq!(ProxyEntry::decode(source, info | 1 << 40), Err(CapError::InvalidProxy));
+        let (source, info) = proxy(ProxySource::Proxy(0x10), ALL, true, MAX_TTL + 1);
+        assert_eq!(ProxyEntry::decode(source, info), Err(CapError::InvalidProxy));
+    }
+
+    #[test]
+    fn rights_narrow_along_a_chain() {
+        let (root, cnode, _mem) = cspace();
+        unsafe{insert_original(cnode.slot(1).unwrap(), endpoint())}.unwrap();
+        let memory = [
+            (0x100, proxy(ProxySource::Cap(CPtr(1), DEPTH), 0x3, true, 10)),
+            (0x200, proxy(ProxySource::Proxy(0x100), READ, true, 5)),
+        ];
+        let resolved = resolve_with(|addr| read_from(&memory, addr), &root, 0x200).unwrap();
+        assert_eq!(resolved.slot, cnode.slot(1).unwrap());
+        assert_eq!(resolved.rights, CapRights { read: true, write: false, grant: false });
+        assert!(resolved.linkable);
+        assert_eq!(resolved.ttl, 5);
+    }
+
+    #[test]
+    fn time_to_live_must_decrease() {
+        let (root, cnode, _mem) = cspace();
+        unsafe{insert_original(cnode.slot(1).unwrap(), endpoint())}.unwrap();
+        let memory = [
+            (0x100, proxy(ProxySource::Cap(CPtr(1), DEPTH), ALL, true, MAX_TTL)),
+            (0x200, proxy(ProxySource::Cap(CPtr(1), DEPTH), ALL, true, MAX_TTL - 1)),
+            (0x300, proxy(ProxySource::Proxy(0x200), ALL, true, MAX_TTL - 1)),
+            (0x400, proxy(ProxySource::Proxy(0x200), ALL, true, MAX_TTL - 2)),
+        ];
+        let read = |addr| read_from(&memory, addr);
+        assert_eq!(resolve_with(&read, &root, 0x100).err(), Some(CapError::TtlNotDecreasing));
+        assert!(resolve_with(&read, &root, 0x200).is_ok());
+        assert_eq!(resolve_with(&read, &root, 0x300).err(), Some(CapError::TtlNotDecreasing));
+        assert!(resolve_with(&read, &root, 0x400).is_ok());
+    }
+
+    #[test]
+    fn loops_are_refused() {
+        let (root, _cnode, _mem) = cspace();
+        let memory = [
+            (0x100, proxy(ProxySource::Proxy(0x200), ALL, true, 4)),
+            (0x200, proxy(ProxySource::Proxy(0x100), ALL, true, 5)),
+        ];
+        assert_eq!(resolve_with(|addr| read_from(&memory, addr), &root, 0x100).err(),
+            Some(CapError::TtlNotDecreasing));
+    }
+
+    #[test]
+    fn linking_derives_from_the_end_of_the_chain() {
+        let (root, cnode, _mem) = cspace();
+        let src = cnode.slot(1).unwrap();
+        let dest = cnode.slot(2).unwrap();
+        unsafe{insert_original(src, endpoint())}.unwrap();
+        let memory = [
+            (0x100, proxy(ProxySource::Cap(CPtr(1), DEPTH), ALL, true, 10)),
+            (0x200, proxy(ProxySource::Proxy(0x100), READ, true, 6)),
+            /* linking a linked capability decrements the time to live again */
+            (0x300, proxy(ProxySource::Cap(CPtr(2), DEPTH), ALL, true, 5)),
+            (0x400, proxy(ProxySource::Cap(CPtr(2), DEPTH), ALL, true, 6)),
+        ];
+        let read = |addr| read_from(&memory, addr);
+        unsafe {
+            link_resolved(resolve_with(&read, &root, 0x200).unwrap(), dest).unwrap();
+            let cap = *(*dest).cap();
+            assert!(cap.same_object(&endpoint()));
+            assert_eq!(cap.rights, CapRights { read: true, write: false, grant: false });
+            assert_eq!(cap.ttl, 6);
+            assert_eq!((*src).next, dest);
+            assert_eq!((*dest).prev, src);
+        }
+        /* the linked capability lacks grant, so cannot be linked again */
+        let resolved = resolve_with(&read, &root, 0x300).unwrap();
+        assert!(!resolved.linkable);
+        assert_eq!(unsafe{link_resolved(resolved, cnode.slot(3).unwrap())},
+            Err(CapError::InsufficientRights));
+        assert_eq!(resolve_with(&read, &root, 0x400).err(), Some(CapError::TtlNotDecreasing));
+    }
+
+    #[test]
+    fn proxies_expire_when_the_time_to_live_runs_out() {
+        let (root, cnode, _mem) = cspace();
+        unsafe{insert_original(cnode.slot(1).unwrap(), endpoint())}.unwrap();
+        let memory = [
+            (0x100, proxy(ProxySource::Cap(CPtr(1), DEPTH), ALL, true, 0)),
+            (0x200, proxy(ProxySource::Cap(CPtr(2), DEPTH), ALL, true, 0)),
+        ];
+        let read = |addr| read_from(&memory, addr);
+        unsafe {
+            link_resolved(resolve_with(&read, &root, 0x100).unwrap(), cnode.slot(2).unwrap())
+                .unwrap();
+            assert_eq!((*cnode.slot(2).unwrap()).cap().ttl, 0);
+        }
+        /* nothing can have a lower time to live than zero */
+        assert_eq!(resolve_with(&read, &root, 0x200).err(), Some(CapError::TtlNotDecreasing));
+    }
+
+    #[test]
+    fn linking_needs_every_link_right() {
+        let (root, cnode, _mem) = cspace();
+        unsafe{insert_original(cnode.slot(1).unwrap(), endpoint())}.unwrap();
+        let memory = [
+            (0x100, proxy(ProxySource::Cap(CPtr(1), DEPTH), ALL, false, 10)),
+            (0x200, proxy(ProxySource::Proxy(0x100), ALL, true, 9)),
+        ];
+        let resolved = resolve_with(|addr| read_from(&memory, addr), &root, 0x200).unwrap();
+        assert!(!resolved.linkable);
+        assert_eq!(unsafe{link_resolved(resolved, cnode.slot(2).unwrap())},
+            Err(CapError::InsufficientRights));
+    }
+}
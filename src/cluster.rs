@@ -0,0 +1,369 @@
+//! Hardware topology and cluster definitions
+//!
+//! A cluster is a set of hardware threads and memory that is considered
+//! uniform enough to present as a single environment to user level (see
+//! RAVINGS.md). Platforms describe the NUMA topology of the machine as a
+//! set of proximity domains, each with some CPUs and memory, and the
+//! cluster definition is derived from that. By default every proximity
+//! domain becomes its own cluster, but this can be overridden with the
+//! `clusters=` command line option, which takes one of
+//!
+//! + `numa` One cluster per proximity domain (the default)
+//! + `single` The whole machine is one cluster
+//! + A number. Domains no further apart than this distance, in ACPI SLIT
+//!   units where 10 is local, are merged into the same cluster
+//!
+//! Memory regions are tagged with the cluster they belong to, and boot
+//! allocations are made from the memory of the boot processor's cluster
+//! before that of any other (see `Clusters::local_first`).
+use config::BootConfig;
+use types::PAddr;
+use util::FromStrExt;
+use ::core::cmp;
+
+/// Maximum number of proximity domains we track
+pub const MAX_DOMAINS: usize = 8;
+/// Maximum number of CPUs we track the affinity of
+pub const MAX_CPUS: usize = 64;
+/// Maximum number of memory ranges we track the affinity of
+pub const MAX_MEMORY_RANGES: usize = 16;
+/// Distance of a domain to itself
+pub const LOCAL_DISTANCE: u8 = 10;
+/// Distance assumed between two different domains if the platform does
+/// not tell us
+pub const REMOTE_DISTANCE: u8 = 20;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+/// Identifier of a cluster
+pub struct ClusterId(pub u8);
+
+#[derive(Debug, Copy, Clone)]
+/// Assignment of a CPU, by APIC ID, to a proximity domain
+pub struct CpuAffinity {
+    pub apic_id: u32,
+    pub domain: u32,
+}
+
+#[derive(Debug, Copy, Clone)]
+/// Assignment of a physical memory range to a proximity domain
+pub struct MemoryAffinity {
+    pub start: PAddr,
+    pub end: PAddr,
+    pub domain: u32,
+}
+
+/// NUMA topology as described by the platform. An empty topology means
+/// the platform knows nothing, and the machine is treated as uniform
+pub struct Topology {
+    domains: [u32; MAX_DOMAINS],
+    num_domains: usize,
+    cpus: [CpuAffinity; MAX_CPUS],
+    num_cpus: usize,
+    memory: [MemoryAffinity; MAX_MEMORY_RANGES],
+    num_memory: usize,
+    /// Distances between domains, indexed by position in `domains`. Zero
+    /// means unknown
+    distances: [[u8; MAX_DOMAINS]; MAX_DOMAINS],
+    /// Number of entries that did not fit in the above arrays
+    dropped: usize,
+}
+
+impl Topology {
+    /// Construct an empty topology
+    pub fn new() -> Topology {
+        Topology {
+            domains: [0; MAX_DOMAINS],
+            num_domains: 0,
+            cpus: [CpuAffinity { apic_id: 0, domain: 0 }; MAX_CPUS],
+            num_cpus: 0,
+            memory: [MemoryAffinity { start: PAddr(0), end: PAddr(0), domain: 0 }; MAX_MEMORY_RANGES],
+            num_memory: 0,
+            distances: [[0; MAX_DOMAINS]; MAX_DOMAINS],
+            dropped: 0,
+        }
+    }
+    /// Find the index of a domain, adding it if it is new
+    fn domain_index(&mut self, domain: u32) -> Option<usize> {
+        if let Some(i) = self.index_of(domain) {
+            return Some(i);
+        }
+        if self.num_domains == MAX_DOMAINS {
+            return None;
+        }
+        self.domains[self.num_domains] = domain;
+        self.num_domains += 1;
+        Some(self.num_domains - 1)
+    }
+    /// Find the index of an existing domain
+    fn index_of(&self, domain: u32) -> Option<usize> {
+        self.domains().iter().position(|d| *d == domain)
+    }
+    /// Record that a CPU is in a domain
+    pub fn add_cpu(&mut self, apic_id: u32, domain: u32) {
+        if self.domain_index(domain).is_none() || self.num_cpus == MAX_CPUS {
+            self.dropped += 1;
+            return;
+        }
+        self.cpus[self.num_cpus] = CpuAffinity { apic_id: apic_id, domain: domain };
+        self.num_cpus += 1;
+    }
+    /// Record that a range of memory is in a domain
+    pub fn add_memory(&mut self, start: PAddr, end: PAddr, domain: u32) {
+        if start >= end {
+            return;
+        }
+        if self.domain_index(domain).is_none() || self.num_memory == MAX_MEMORY_RANGES {
+            self.dropped += 1;
+            return;
+        }
+        self.memory[self.num_memory] = MemoryAffinity { start: start, end: end, domain: domain };
+        self.num_memory += 1;
+    }
+    /// Record the distance between two domains. Distances for domains that
+    /// have no CPUs or memory are ignored
+    pub fn set_distance(&mut self, from: u32, to: u32, distance: u8) {
+        if let (Some(f), Some(t)) = (self.index_of(from), self.index_of(to)) {
+            self.distances[f][t] = distance;
+        }
+    }
+    /// Distance between two domains
+    pub fn distance(&self, from: u32, to: u32) -> u8 {
+        match (self.index_of(from), self.index_of(to)) {
+            (Some(f), Some(t)) if self.distances[f][t] != 0 => self.distances[f][t],
+            _ => if from == to { LOCAL_DISTANCE } else { REMOTE_DISTANCE },
+        }
+    }
+    /// All the known domains
+    pub fn domains(&self) -> &[u32] {
+        &self.domains[..self.num_domains]
+    }
+    /// All the known CPU affinities
+    pub fn cpus(&self) -> &[CpuAffinity] {
+        &self.cpus[..self.num_cpus]
+    }
+    /// All the known memory affinities
+    pub fn memory(&self) -> &[MemoryAffinity] {
+        &self.memory[..self.num_memory]
+    }
+    /// Number of CPUs, memory ranges or domains that could not be recorded
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// How to turn proximity domains into clusters
+pub enum ClusterPolicy {
+    /// Each domain is a cluster
+    Numa,
+    /// The whole machine is a single cluster
+    Single,
+    /// Domains no further apart than this are the same cluster
+    Distance(u8),
+}
+
+impl ClusterPolicy {
+    /// Determine the policy from the `clusters=` command line option
+    pub fn from_config(config: &BootConfig) -> ClusterPolicy {
+        match config.cmdline_option_find("clusters") {
+            None => ClusterPolicy::Numa,
+            Some(option) => match option.value {
+                "numa" => ClusterPolicy::Numa,
+                "single" => ClusterPolicy::Single,
+                value => u8::from_str_prefix(value)
+                    .map(ClusterPolicy::Distance)
+                    .unwrap_or(ClusterPolicy::Numa),
+            },
+        }
+    }
+}
+
+/// The cluster definition for the machine. This holds its own copy of the
+/// CPU and memory assignments so the platform topology is not needed to
+/// answer queries
+pub struct Clusters {
+    /// Number of clusters
+    count: usize,
+    cpus: [(u32, ClusterId); MAX_CPUS],
+    num_cpus: usize,
+    memory: [(PAddr, PAddr, ClusterId); MAX_MEMORY_RANGES],
+    num_memory: usize,
+}
+
+impl Clusters {
+    /// Derive the clusters from a topology
+    pub fn new(topology: &Topology, policy: ClusterPolicy) -> Clusters {
+        let domains = topology.domains();
+        let mut of_domain = [ClusterId(0); MAX_DOMAINS];
+        let mut count = 0;
+        for (i, domain) in domains.iter().enumerate() {
+            let merge = match policy {
+                ClusterPolicy::Numa => None,
+                ClusterPolicy::Single => if i == 0 { None } else { Some(0) },
+                ClusterPolicy::Distance(max) => (0..i).find(|j| {
+                    cmp::max(topology.distance(*domain, domains[*j]),
+                             topology.distance(domains[*j], *domain)) <= max
+                }),
+            };
+            of_domain[i] = match merge {
+                Some(j) => of_domain[j],
+                None => {
+                    count += 1;
+                    ClusterId((count - 1) as u8)
+                },
+            };
+        }
+        let cluster_of = |domain: u32| {
+            domains.iter().position(|d| *d == domain)
+                .map_or(ClusterId(0), |i| of_domain[i])
+        };
+        let mut clusters = Clusters {
+            count: cmp::max(count, 1),
+            cpus: [(0, ClusterId(0)); MAX_CPUS],
+            num_cpus: topology.cpus().len(),
+            memory: [(PAddr(0), PAddr(0), ClusterId(0)); MAX_MEMORY_RANGES],
+            num_memory: topology.memory().len(),
+        };
+        for (i, cpu) in topology.cpus().iter().enumerate() {
+            clusters.cpus[i] = (cpu.apic_id, cluster_of(cpu.domain));
+        }
+        for (i, mem) in topology.memory().iter().enumerate() {
+            clusters.memory[i] = (mem.start, mem.end, cluster_of(mem.domain));
+        }
+        clusters
+    }
+    /// Number of clusters
+    pub fn count(&self) -> usize {
+        self.count
+    }
+    /// True if there was no topology information and everything is in
+    /// cluster 0
+    fn uniform(&self) -> bool {
+        self.num_cpus == 0 && self.num_memory == 0
+    }
+    /// Cluster a CPU is in. CPUs the topology does not mention are placed
+    /// in cluster 0
+    pub fn cluster_of_cpu(&self, apic_id: u32) -> ClusterId {
+        self.cpus[..self.num_cpus].iter()
+            .find(|c| c.0 == apic_id)
+            .map_or(ClusterId(0), |c| c.1)
+    }
+    /// Cluster a physical address is in, if it is described by the
+    /// topology
+    pub fn cluster_of_paddr(&self, paddr: PAddr) -> Option<ClusterId> {
+        if self.uniform() {
+            return Some(ClusterId(0));
+        }
+        self.memory[..self.num_memory].iter()
+            .find(|m| paddr >= m.0 && paddr < m.1)
+            .map(|m| m.2)
+    }
+    /// The first address at or after `paddr` where the cluster changes
+    fn next_boundary(&self, paddr: PAddr) -> Option<PAddr> {
+        self.memory[..self.num_memory].iter()
+            .flat_map(|m| Some(m.0).into_iter().chain(Some(m.1)))
+            .filter(|b| *b > paddr)
+            .min()
+    }
+    /// Split the ranges yielded by an iterator of memory regions at cluster
+    /// boundaries, tagging each piece with its cluster
+    pub fn tag<'a, I>(&'a self, iter: I) -> TaggedRegions<'a, I>
+            where I: Iterator<Item=(PAddr, PAddr)> {
+        TaggedRegions { clusters: self, iter: iter, current: None }
+    }
+    /// Order memory regions so that the pieces in `cluster`, or not placed
+    /// by the topology, come before those in any other cluster. `first`
+    /// and `rest` must yield the same regions, as they are walked once for
+    /// each of the two passes
+    pub fn local_first<'a, I>(&'a self, cluster: ClusterId, first: I, rest: I)
+            -> LocalFirst<'a, I> where I: Iterator<Item=(PAddr, PAddr)> {
+        LocalFirst { local: self.tag(first), rest: self.tag(rest), cluster: cluster }
+    }
+}
+
+/// Iterator adapter that yields the memory of one cluster before the rest
+pub struct LocalFirst<'a, I> {
+    local: TaggedRegions<'a, I>,
+    rest: TaggedRegions<'a, I>,
+    cluster: ClusterId,
+}
+
+impl<'a, I> Iterator for LocalFirst<'a, I> where I: Iterator<Item=(PAddr, PAddr)> {
+    type Item = (PAddr, PAddr);
+    fn next(&mut self) -> Option<Self::Item> {
+        let cluster = self.cluster;
+        while let Some((start, end, tag)) = self.local.next() {
+            if tag.map_or(true, |c| c == cluster) {
+                return Some((start, end));
+            }
+        }
+        while let Some((start, end, tag)) = self.rest.next() {
+            if tag.map_or(false, |c| c != cluster) {
+                return Some((start, end));
+            }
+        }
+        None
+    }
+}
+
+/// Iterator adapter that tags memory regions with their cluster
+pub struct TaggedRegions<'a, I> {
+    clusters: &'a Clusters,
+    iter: I,
+    /// Remainder of the region being split
+    current: Option<(PAddr, PAddr)>,
+}
+
+impl<'a, I> Iterator for TaggedRegions<'a, I> where I: Iterator<Item=(PAddr, PAddr)> {
+    type Item = (PAddr, PAddr, Option<ClusterId>);
+    fn next(&mut self) -> Option<Self::Item> {
+        let (start, end) = match self.current.take().or_else(|| self.iter.next()) {
+            Some(r) => r,
+            None => return None,
+        };
+        let split = self.clusters.next_boundary(start)
+            .map_or(end, |b| cmp::min(b, end));
+        if split < end {
+            self.current = Some((split, end));
+        }
+        Some((start, split, self.clusters.cluster_of_paddr(start)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Topology, Clusters, ClusterPolicy, ClusterId};
+    use types::PAddr;
+
+    /// Two domains of one CPU each, with memory interleaved between them
+    fn two_domains() -> Topology {
+        let mut topology = Topology::new();
+        topology.add_cpu(0, 0);
+        topology.add_cpu(1, 1);
+        topology.add_memory(PAddr(0x0), PAddr(0x4000), 0);
+        topology.add_memory(PAddr(0x4000), PAddr(0x8000), 1);
+        topology.add_memory(PAddr(0x8000), PAddr(0xc000), 0);
+        topology.set_distance(0, 1, 21);
+        topology.set_distance(1, 0, 21);
+        topology
+    }
+
+    #[test]
+    fn distance_policy_merges_at_the_limit() {
+        let topology = two_domains();
+        assert_eq!(Clusters::new(&topology, ClusterPolicy::Distance(20)).count(), 2);
+        assert_eq!(Clusters::new(&topology, ClusterPolicy::Distance(21)).count(), 1);
+    }
+
+    #[test]
+    fn local_memory_comes_first() {
+        let clusters = Clusters::new(&two_domains(), ClusterPolicy::Numa);
+        let ram = [(PAddr(0x1000), PAddr(0xd000))];
+        let cluster = clusters.cluster_of_cpu(1);
+        assert_eq!(cluster, ClusterId(1));
+        let order: Vec<(PAddr, PAddr)> = clusters.local_first(cluster,
+            ram.iter().cloned(), ram.iter().cloned()).collect();
+        assert_eq!(order, vec![(PAddr(0x4000), PAddr(0x8000)), (PAddr(0xc000), PAddr(0xd000)),
+            (PAddr(0x1000), PAddr(0x4000)), (PAddr(0x8000), PAddr(0xc000))]);
+    }
+}
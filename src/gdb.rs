@@ -0,0 +1,489 @@
+//! GDB remote serial protocol stub
+//!
+//! Lets GDB debug the kernel over the serial port given with the `gdb=`
+//! option. Once there is a port, every exception enters the stub, which
+//! includes the breakpoints it places and the single steps it asks for,
+//! as does the end of a panic. Whilst the kernel runs the port is polled
+//! on timer interrupts and whilst idle, and any byte arriving breaks into
+//! the stub. That covers both the Ctrl-C GDB sends to interrupt the
+//! target and the first packet it sends on connecting. There are no
+//! device interrupts yet to notice these sooner.
+//!
+//! Only the processor that entered the stub stops, and it is shown to GDB
+//! as the only thread. Any other processor entering the stub waits until
+//! the first one resumes.
+//!
+//! The packets understood are `?`, `g`, `G`, `p`, `P`, `m`, `M`, `c`, `s`,
+//! `Z0`, `z0`, `D`, `k`, `H`, `T`, `qSupported` and `qAttached`. Anything
+//! else gets the empty reply that tells GDB it is not supported.
+use arch::{self, ExceptionFrame};
+use arch::debug::{self, BREAKPOINT, BREAKPOINT_LEN};
+use plat;
+use core::{cmp, ptr};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Largest packet in either direction, which is told to GDB
+const PACKET_SIZE: usize = 4096;
+/// Number of software breakpoints that can be placed at once
+const MAX_BREAKPOINTS: usize = 32;
+/// Value of `OWNER` when no processor is in the stub
+const NO_CPU: usize = !0;
+/// Hex digits, as used throughout the protocol
+const HEX: &'static [u8; 16] = b"0123456789abcdef";
+
+/// Processor currently in the stub. Only it may touch the statics below
+static OWNER: AtomicUsize = AtomicUsize::new(NO_CPU);
+
+static mut PACKET: [u8; PACKET_SIZE] = [0; PACKET_SIZE];
+static mut REPLY: Reply = Reply { buf: [0; PACKET_SIZE], len: 0 };
+static mut BREAKPOINTS: Breakpoints = Breakpoints([None; MAX_BREAKPOINTS]);
+
+/// What to do once a packet has been handled
+enum Next {
+    /// Send the reply and wait for the next packet
+    Reply,
+    /// Return to the kernel without replying
+    Resume,
+    /// Run a single instruction and then enter the stub again
+    Step,
+    /// Send the reply and return to the kernel
+    Detach,
+}
+
+/// Reply being built
+struct Reply {
+    buf: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Reply {
+    /// Append a byte. Anything past the packet size is dropped, but
+    /// replies are kept within it
+    fn push(&mut self, c: u8) {
+        if self.len < self.buf.len() {
+            self.buf[self.len] = c;
+            self.len += 1;
+        }
+    }
+    fn push_str(&mut self, s: &str) {
+        for c in s.bytes() {
+            self.push(c);
+        }
+    }
+    /// Append a byte as two hex digits
+    fn push_hex(&mut self, byte: u8) {
+        self.push(HEX[(byte >> 4) as usize]);
+        self.push(HEX[(byte & 0xf) as usize]);
+    }
+    /// Append the low `size` bytes of `value`, least significant first,
+    /// as registers are sent
+    fn push_value(&mut self, value: u64, size: usize) {
+        for i in 0..size {
+            self.push_hex((value >> (i * 8)) as u8);
+        }
+    }
+    fn data(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+/// A placed software breakpoint and the bytes it replaced
+#[derive(Copy, Clone)]
+struct Breakpoint {
+    addr: usize,
+    saved: [u8; BREAKPOINT_LEN],
+}
+
+struct Breakpoints([Option<Breakpoint>; MAX_BREAKPOINTS]);
+
+impl Breakpoints {
+    fn contains(&self, addr: usize) -> bool {
+        self.0.iter().any(|b| b.map_or(false, |b| b.addr == addr))
+    }
+    /// Place a breakpoint at `addr`. Placing the same one twice is not an
+    /// error. Returns false if `addr` is not accessible or there is no
+    /// room for another breakpoint
+    fn insert(&mut self, addr: usize) -> bool {
+        if self.contains(addr) {
+            return true;
+        }
+        if !debug::memory_valid(addr, BREAKPOINT_LEN) {
+            return false;
+        }
+        match self.0.iter_mut().find(|b| b.is_none()) {
+            Some(slot) => {
+                let mut saved = [0; BREAKPOINT_LEN];
+                unsafe {
+                    read_memory(addr, &mut saved);
+                    write_memory(addr, &BREAKPOINT);
+                }
+                *slot = Some(Breakpoint { addr: addr, saved: saved });
+                true
+            },
+            None => false,
+        }
+    }
+    /// Remove the breakpoint at `addr`, returning false if there is none
+    fn remove(&mut self, addr: usize) -> bool {
+        for slot in self.0.iter_mut() {
+            if let Some(b) = *slot {
+                if b.addr == addr {
+                    unsafe{write_memory(b.addr, &b.saved)};
+                    *slot = None;
+                    return true;
+                }
+            }
+        }
+        false
+    }
+    fn clear(&mut self) {
+        for slot in self.0.iter_mut() {
+            if let Some(b) = slot.take() {
+                unsafe{write_memory(b.addr, &b.saved)};
+            }
+        }
+    }
+}
+
+/// Copy memory at `addr` into `buf`
+///
+/// # Safety
+///
+/// The range must have been checked with `debug::memory_valid`
+unsafe fn read_memory(addr: usize, buf: &mut [u8]) {
+    for (i, byte) in buf.iter_mut().enumerate() {
+        *byte = ptr::read_volatile((addr + i) as *const u8);
+    }
+}
+
+/// Copy `buf` to memory at `addr`
+///
+/// # Safety
+///
+/// The range must have been checked with `debug::memory_valid`, and it is
+/// up to the debugger what this breaks
+unsafe fn write_memory(addr: usize, buf: &[u8]) {
+    for (i, byte) in buf.iter().enumerate() {
+        ptr::write_volatile((addr + i) as *mut u8, *byte);
+    }
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0' ... b'9' => Some(c - b'0'),
+        b'a' ... b'f' => Some(c - b'a' + 10),
+        b'A' ... b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Parse a hex number, as addresses, lengths and register numbers are
+/// sent
+fn parse_hex(s: &[u8]) -> Option<usize> {
+    if s.is_empty() || s.len() > 16 {
+        return None;
+    }
+    let mut value = 0;
+    for c in s {
+        match hex_digit(*c) {
+            Some(d) => value = value << 4 | d as usize,
+            None => return None,
+        }
+    }
+    Some(value)
+}
+
+/// Parse a byte sent as two hex digits
+fn parse_byte(pair: &[u8]) -> Option<u8> {
+    match (hex_digit(pair[0]), hex_digit(pair[1])) {
+        (Some(high), Some(low)) => Some(high << 4 | low),
+        _ => None,
+    }
+}
+
+/// Parse a register value, sent least significant byte first
+fn parse_value(s: &[u8]) -> Option<u64> {
+    if s.is_empty() || s.len() % 2 != 0 || s.len() > 16 {
+        return None;
+    }
+    let mut value = 0;
+    for (i, pair) in s.chunks(2).enumerate() {
+        match parse_byte(pair) {
+            Some(byte) => value |= (byte as u64) << (i * 8),
+            None => return None,
+        }
+    }
+    Some(value)
+}
+
+/// Split `s` at the first `sep`, which is dropped
+fn split(s: &[u8], sep: u8) -> (&[u8], &[u8]) {
+    match s.iter().position(|c| *c == sep) {
+        Some(i) => (&s[..i], &s[i + 1..]),
+        None => (s, &s[s.len()..]),
+    }
+}
+
+/// Parse the `addr,len` that starts memory packets
+fn parse_range(s: &[u8]) -> Option<(usize, usize)> {
+    let (addr, len) = split(s, b',');
+    match (parse_hex(addr), parse_hex(len)) {
+        (Some(addr), Some(len)) => Some((addr, len)),
+        _ => None,
+    }
+}
+
+/// Wait for a byte from GDB
+fn getchar() -> u8 {
+    loop {
+        if let Some(c) = plat::gdb_getchar() {
+            return c;
+        }
+    }
+}
+
+/// Wait for a packet with a good checksum, acknowledging it, and place
+/// its data in `buf`. Returns the length of the data. Packets that are too
+/// long are refused, like ones that are corrupt
+fn receive(buf: &mut [u8]) -> usize {
+    loop {
+        while getchar() != b'$' {}
+        let mut len = 0;
+        let mut sum = 0u8;
+        let mut overflow = false;
+        loop {
+            let c = getchar();
+            if c == b'#' {
+                break;
+            }
+            sum = sum.wrapping_add(c);
+            if len < buf.len() {
+                buf[len] = c;
+                len += 1;
+            } else {
+                overflow = true;
+            }
+        }
+        let check = [getchar(), getchar()];
+        if !overflow && parse_byte(&check) == Some(sum) {
+            plat::gdb_putchar(b'+');
+            return len;
+        }
+        plat::gdb_putchar(b'-');
+    }
+}
+
+/// Send a packet, repeating it until GDB acknowledges it. Anything other
+/// than a refusal is taken as acknowledgement, so that a GDB that has
+/// moved on is not waited for forever
+fn send(data: &[u8]) {
+    loop {
+        plat::gdb_putchar(b'$');
+        let mut sum = 0u8;
+        for c in data {
+            plat::gdb_putchar(*c);
+            sum = sum.wrapping_add(*c);
+        }
+        plat::gdb_putchar(b'#');
+        plat::gdb_putchar(HEX[(sum >> 4) as usize]);
+        plat::gdb_putchar(HEX[(sum & 0xf) as usize]);
+        if getchar() != b'-' {
+            return;
+        }
+    }
+}
+
+/// Handle one packet, building any reply in `reply`
+fn command(packet: &[u8], reply: &mut Reply, breakpoints: &mut Breakpoints,
+        frame: &mut ExceptionFrame, signal: u8) -> Next {
+    let (kind, args) = match packet.split_first() {
+        Some((kind, args)) => (*kind, args),
+        None => return Next::Reply,
+    };
+    match kind {
+        b'?' => {
+            reply.push(b'S');
+            reply.push_hex(signal);
+        },
+        b'g' => {
+            for n in 0..debug::NUM_REGISTERS {
+                if let Some((value, size)) = debug::register(frame, n) {
+                    reply.push_value(value, size);
+                }
+            }
+        },
+        b'G' => {
+            /* GDB may send fewer registers than it was given */
+            let mut rest = args;
+            for n in 0..debug::NUM_REGISTERS {
+                let size = match debug::register(frame, n) {
+                    Some((_, size)) if rest.len() >= size * 2 => size,
+                    _ => break,
+                };
+                match parse_value(&rest[..size * 2]) {
+                    Some(value) => { debug::set_register(frame, n, value); },
+                    None => {
+                        reply.push_str("E01");
+                        return Next::Reply;
+                    },
+                }
+                rest = &rest[size * 2..];
+            }
+            reply.push_str("OK");
+        },
+        b'p' => match parse_hex(args).and_then(|n| debug::register(frame, n)) {
+            Some((value, size)) => reply.push_value(value, size),
+            None => reply.push_str("E01"),
+        },
+        b'P' => {
+            let (n, value) = split(args, b'=');
+            let done = match (parse_hex(n), parse_value(value)) {
+                (Some(n), Some(value)) => debug::set_register(frame, n, value),
+                _ => false,
+            };
+            reply.push_str(if done { "OK" } else { "E01" });
+        },
+        b'm' => match parse_range(args) {
+            Some((addr, len)) => {
+                let len = cmp::min(len, PACKET_SIZE / 2);
+                if debug::memory_valid(addr, len) {
+                    for i in 0..len {
+                        let mut byte = [0];
+                        unsafe{read_memory(addr + i, &mut byte)};
+                        reply.push_hex(byte[0]);
+                    }
+                } else {
+                    reply.push_str("E14");
+                }
+            },
+            None => reply.push_str("E01"),
+        },
+        b'M' => {
+            let (range, data) = split(args, b':');
+            match parse_range(range) {
+                Some((addr, len)) if len.checked_mul(2) == Some(data.len())
+                        && data.iter().all(|c| hex_digit(*c).is_some()) => {
+                    if debug::memory_valid(addr, len) {
+                        for (i, pair) in data.chunks(2).enumerate() {
+                            let byte = [parse_byte(pair).unwrap_or(0)];
+                            unsafe{write_memory(addr + i, &byte)};
+                        }
+                        reply.push_str("OK");
+                    } else {
+                        reply.push_str("E14");
+                    }
+                },
+                _ => reply.push_str("E01"),
+            }
+        },
+        b'c' | b's' => {
+            if let Some(addr) = parse_hex(args) {
+                debug::set_pc(frame, addr);
+            }
+            return if kind == b's' { Next::Step } else { Next::Resume };
+        },
+        b'Z' | b'z' => {
+            let (breakpoint_type, rest) = split(args, b',');
+            let (addr, _) = split(rest, b',');
+            /* only software breakpoints are supported */
+            if breakpoint_type != &b"0"[..] {
+                return Next::Reply;
+            }
+            let done = match parse_hex(addr) {
+                Some(addr) if kind == b'Z' => breakpoints.insert(addr),
+                Some(addr) => breakpoints.remove(addr),
+                None => false,
+            };
+            reply.push_str(if done { "OK" } else { "E01" });
+        },
+        b'D' => {
+            breakpoints.clear();
+            reply.push_str("OK");
+            return Next::Detach;
+        },
+        /* the kernel cannot be killed, so this just lets it run */
+        b'k' => {
+            breakpoints.clear();
+            return Next::Resume;
+        },
+        /* there is only one thread to select, and it is alive */
+        b'H' | b'T' => reply.push_str("OK"),
+        b'q' if packet.starts_with(b"qSupported") => {
+            reply.push_str("PacketSize=");
+            for shift in (0..4).rev() {
+                reply.push(HEX[(PACKET_SIZE >> (shift * 4)) & 0xf]);
+            }
+        },
+        b'q' if packet.starts_with(b"qAttached") => reply.push(b'1'),
+        _ => (),
+    }
+    Next::Reply
+}
+
+/// Report the stop to GDB and handle packets until it resumes the kernel
+fn stopped(frame: &mut ExceptionFrame) {
+    let (packet, reply, breakpoints) = unsafe{(&mut PACKET, &mut REPLY, &mut BREAKPOINTS)};
+    /* the return address of one of our breakpoints is just after it, but
+     * GDB expects to be stopped at the breakpoint, with the instruction it
+     * replaced still to run */
+    if debug::is_breakpoint(frame) {
+        let addr = debug::pc(frame).wrapping_sub(BREAKPOINT_LEN);
+        if breakpoints.contains(addr) {
+            debug::set_pc(frame, addr);
+        }
+    }
+    debug::set_single_step(frame, false);
+    let signal = debug::signal(frame);
+    reply.len = 0;
+    reply.push(b'S');
+    reply.push_hex(signal);
+    send(reply.data());
+    loop {
+        let len = receive(packet);
+        reply.len = 0;
+        match command(&packet[..len], reply, breakpoints, frame, signal) {
+            Next::Reply => send(reply.data()),
+            Next::Resume => return,
+            Next::Step => {
+                debug::set_single_step(frame, true);
+                return;
+            },
+            Next::Detach => {
+                send(reply.data());
+                return;
+            },
+        }
+    }
+}
+
+/// True if there is a port for the stub, so that it will be entered
+pub fn enabled() -> bool {
+    plat::gdb_present()
+}
+
+/// Hand an exception to GDB, returning once it resumes the kernel with the
+/// registers in `frame`. Returns false, without doing anything, if there is
+/// no port for the stub or the exception happened in the stub itself
+pub fn enter(frame: &mut ExceptionFrame) -> bool {
+    if !enabled() {
+        return false;
+    }
+    let cpu = arch::current_cpu_id() as usize;
+    loop {
+        match OWNER.compare_and_swap(NO_CPU, cpu, Ordering::Acquire) {
+            NO_CPU => break,
+            owner if owner == cpu => return false,
+            _ => (),
+        }
+    }
+    stopped(frame);
+    OWNER.store(NO_CPU, Ordering::Release);
+    true
+}
+
+/// Break into the stub if anything has arrived from GDB
+pub fn poll() {
+    if OWNER.load(Ordering::Relaxed) == NO_CPU && plat::gdb_getchar().is_some() {
+        debug::breakpoint();
+    }
+}
@@ -0,0 +1,100 @@
+//! Queued IPC
+//!
+//! A call sends a message word through an endpoint and waits for a reply,
+//! which the receiver sends back with `reply`. If the receiver has no
+//! scheduling context of its own the caller lends it its own, along with
+//! the message, and gets it back with the reply. A passive server, one
+//! with no scheduling context, therefore only runs on the time of whoever
+//! it is serving, and a client cannot get more time through a server than
+//! its own scheduling context has. This is the caller sending its
+//! scheduling context to fulfil the request described in RAVINGS.md.
+//!
+//! A thread has at most one caller waiting for its reply, so it must reply
+//! before receiving again. Unqueued IPC, which only signals, is done with
+//! notifications.
+use arch::Arch;
+use object::{Endpoint, EndpointState, Tcb, ThreadState};
+use sched;
+use thread::ArchThread;
+use ::core::ptr;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// Errors from IPC
+pub enum IpcError {
+    /// A reply was sent with no caller waiting for one
+    NoCaller,
+    /// A receive was done with a caller still waiting for a reply
+    ReplyPending,
+}
+
+/// Hand the message of `caller` to `receiver`, along with the scheduling
+/// context if the receiver has none, and leave the caller waiting for the
+/// reply
+unsafe fn deliver(caller: *mut Tcb, receiver: *mut Tcb) {
+    let (badge, message) = (*caller).message();
+    Arch::set_message((*receiver).context_mut(), badge, message);
+    let donate = (*receiver).sched_context().is_null() && !(*caller).sched_context().is_null();
+    (*receiver).set_caller(caller, donate);
+    sched::block(caller, ThreadState::BlockedOnReply);
+    if donate {
+        sched::donate(caller, receiver);
+    }
+    sched::make_runnable(receiver);
+}
+
+/// Call through `ep` with `message`, and the badge of the capability it
+/// was invoked with. The caller waits, first for a receiver if none is
+/// queued, and then for the reply
+///
+/// # Safety
+///
+/// Both must be valid, and `caller` running
+pub unsafe fn call(caller: *mut Tcb, ep: *mut Endpoint, badge: u64, message: usize) {
+    (*caller).set_message(badge, message);
+    match (*ep).dequeue(EndpointState::Recv) {
+        Some(receiver) => deliver(caller, receiver),
+        None => {
+            sched::block(caller, ThreadState::BlockedOnSend);
+            (*ep).enqueue(caller, EndpointState::Send);
+        },
+    }
+}
+
+/// Receive a call on `ep`, waiting for one if none are queued
+///
+/// # Safety
+///
+/// Both must be valid, and `receiver` running
+pub unsafe fn receive(receiver: *mut Tcb, ep: *mut Endpoint) -> Result<(), IpcError> {
+    if !(*receiver).caller().0.is_null() {
+        return Err(IpcError::ReplyPending);
+    }
+    match (*ep).dequeue(EndpointState::Send) {
+        Some(caller) => deliver(caller, receiver),
+        None => {
+            sched::block(receiver, ThreadState::BlockedOnReceive);
+            (*ep).enqueue(receiver, EndpointState::Recv);
+        },
+    }
+    Ok(())
+}
+
+/// Reply to the caller of `tcb` with `message`, giving back its scheduling
+/// context if it was lent
+///
+/// # Safety
+///
+/// `tcb` must be valid and running
+pub unsafe fn reply(tcb: *mut Tcb, message: usize) -> Result<(), IpcError> {
+    let (caller, donated) = (*tcb).caller();
+    if caller.is_null() {
+        return Err(IpcError::NoCaller);
+    }
+    (*tcb).set_caller(ptr::null_mut(), false);
+    Arch::set_message((*caller).context_mut(), 0, message);
+    if donated {
+        sched::donate(tcb, caller);
+    }
+    sched::make_runnable(caller);
+    Ok(())
+}
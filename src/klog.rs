@@ -0,0 +1,73 @@
+//! In kernel log ring
+//!
+//! Everything the kernel prints is also kept in a fixed size ring, so that
+//! the root task can retrieve boot messages after the fact even if no
+//! console was attached. Bytes are addressed by their position in the
+//! stream of everything ever logged, which lets readers resume where they
+//! left off and detect when they have fallen so far behind that older
+//! bytes were overwritten.
+use util::SpinLock;
+use util::KB;
+use ::core::cmp;
+
+/// Number of bytes of log retained
+pub const LOG_SIZE: usize = 16 * KB;
+
+/// Fixed size ring of the most recent log output
+pub struct LogRing {
+    buf: [u8; LOG_SIZE],
+    /// Total number of bytes ever written
+    written: u64,
+}
+
+impl LogRing {
+    const fn new() -> LogRing {
+        LogRing { buf: [0; LOG_SIZE], written: 0 }
+    }
+    fn push(&mut self, c: u8) {
+        self.buf[(self.written % LOG_SIZE as u64) as usize] = c;
+        self.written += 1;
+    }
+    /// Position of the oldest byte still retained
+    fn oldest(&self) -> u64 {
+        self.written.saturating_sub(LOG_SIZE as u64)
+    }
+    fn read(&self, pos: u64, out: &mut [u8]) -> (u64, usize) {
+        let start = cmp::max(pos, self.oldest());
+        let count = cmp::min((self.written.saturating_sub(start)) as usize, out.len());
+        for (i, byte) in out[..count].iter_mut().enumerate() {
+            *byte = self.buf[((start + i as u64) % LOG_SIZE as u64) as usize];
+        }
+        (start, count)
+    }
+}
+
+/// The kernel log
+static LOG: SpinLock<LogRing> = SpinLock::new(LogRing::new());
+
+/// Append a byte to the kernel log
+pub fn log_byte(c: u8) {
+    LOG.lock().push(c);
+}
+
+/// Append a byte to the kernel log unless someone holds it, for the panic
+/// path where the holder may have been stopped and will never release it.
+/// Returns false if the byte was dropped
+pub fn try_log_byte(c: u8) -> bool {
+    match LOG.try_lock() {
+        Some(mut log) => {
+            log.push(c);
+            true
+        },
+        None => false,
+    }
+}
+
+/// Copy log output starting at position `pos` into `out`. If `pos` has
+/// already been overwritten copying starts at the oldest retained byte.
+/// Returns the position of the first byte copied and the number of bytes
+/// copied, so the next read should start at their sum. This is what backs
+/// `SYS_LOG_READ`
+pub fn read(pos: u64, out: &mut [u8]) -> (u64, usize) {
+    LOG.lock().read(pos, out)
+}
@@ -7,6 +7,9 @@
 //! This crate is purely the kernel implementation, and all documentation
 //! herein is targeting internal development. For user facing documentation
 //! see the r4bind crate
+//!
+//! Code that does not touch the hardware has unit tests, which are built
+//! against std for the host with `make test`
 #![feature(lang_items)]
 #![feature(asm)]
 #![feature(unique)]
@@ -14,8 +17,14 @@
 #![feature(placement_in_syntax)]
 #![feature(type_ascription)]
 #![feature(step_by)]
-#![no_std]
+#![feature(const_fn)]
+#![cfg_attr(not(test), no_std)]
+
+#[cfg(test)]
+extern crate core;
 
+#[macro_use]
+mod log;
 pub mod arch;
 mod plat;
 mod config;
@@ -24,6 +33,18 @@ mod util;
 mod panic;
 mod steal_mem;
 mod types;
+mod cluster;
+mod klog;
+mod symbols;
+mod gdb;
+mod monitor;
+mod cap;
+mod object;
+mod thread;
+mod sched;
+mod ipc;
 
+#[cfg(not(test))]
 #[lang = "eh_personality"] extern fn eh_personality() {}
+#[cfg(not(test))]
 #[lang = "eh_unwind_resume"] extern fn eh_unwind_resume() {}
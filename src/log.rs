@@ -0,0 +1,209 @@
+//! Levelled kernel logging
+//!
+//! The `error!`, `warn!`, `info!`, `debug!` and `trace!` macros format a
+//! message and send it to the platform console, prefixed with the time
+//! since reset, the CPU it was logged on and the module it came from. They
+//! need no platform reference, so can be used from any processor or
+//! interrupt handler, and before the platform is constructed, in which
+//! case messages only end up in the kernel log.
+//!
+//! Which messages are shown is set by the `log=` option, a comma separated
+//! list of `module:level` filters and an optional bare `level` for every
+//! other module, such as `log=acpi:debug,boot:info`. A filter applies to
+//! a module of that name anywhere in the module path, and the innermost
+//! one wins, so `pc99:warn` quietens the platform code except for `acpi`
+//! if that is also given. Without a filter the level is `info`.
+use config::BootConfig;
+use plat;
+use arch;
+use ::core::fmt;
+use ::core::fmt::Write;
+use ::core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Log a message at the given `Level`
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => (
+        $crate::log::log($level, module_path!(), format_args!($($arg)*))
+    )
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => (log!($crate::log::Level::Error, $($arg)*))
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => (log!($crate::log::Level::Warn, $($arg)*))
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => (log!($crate::log::Level::Info, $($arg)*))
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => (log!($crate::log::Level::Debug, $($arg)*))
+}
+
+macro_rules! trace {
+    ($($arg:tt)*) => (log!($crate::log::Level::Trace, $($arg)*))
+}
+
+/// Maximum number of module filters
+const MAX_FILTERS: usize = 16;
+/// Longest module name a filter can hold
+const MAX_NAME: usize = 16;
+
+/// Importance of a message. Lower levels are more important
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    fn from_str(s: &str) -> Option<Level> {
+        match s {
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            "trace" => Some(Level::Trace),
+            _ => None,
+        }
+    }
+    fn name(&self) -> &'static str {
+        match *self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+/// Level for a single module
+#[derive(Copy, Clone)]
+struct Filter {
+    name: [u8; MAX_NAME],
+    len: usize,
+    level: Level,
+}
+
+/// Parsed `log=` option. The command line does not outlive early boot, so
+/// the module names are copied
+struct Filters {
+    filters: [Filter; MAX_FILTERS],
+    count: usize,
+    default: Level,
+}
+
+impl Filters {
+    /// Level of the innermost module in `path` that has a filter
+    fn level(&self, path: &str) -> Level {
+        let filters = &self.filters[..self.count];
+        path.rsplit("::")
+            .filter_map(|module| filters.iter()
+                .find(|f| &f.name[..f.len] == module.as_bytes())
+                .map(|f| f.level))
+            .next()
+            .unwrap_or(self.default)
+    }
+}
+
+/// Filters from the command line. Only written by `init`, which happens
+/// during early boot before there are other processors or interrupts
+static mut FILTERS: Filters = Filters {
+    filters: [Filter { name: [0; MAX_NAME], len: 0, level: Level::Info }; MAX_FILTERS],
+    count: 0,
+    default: Level::Info,
+};
+
+/// Most verbose level of any filter, as a quick check before searching
+/// the filters
+static MAX_LEVEL: AtomicUsize = AtomicUsize::new(Level::Info as usize);
+
+/// Cycle counter frequency in Hz, or zero if it is not yet known
+static TSC_HZ: AtomicUsize = AtomicUsize::new(0);
+
+/// Set the filters from the `log=` option. Filters that do not parse, or
+/// do not fit, are ignored
+///
+/// # Safety
+///
+/// Must be called before any other processors are started or interrupts
+/// are enabled
+pub unsafe fn init(config: &BootConfig) {
+    let option = match config.cmdline_option_find("log") {
+        Some(option) => option.value,
+        None => return,
+    };
+    let filters = &mut FILTERS;
+    for item in option.split(',') {
+        let mut parts = item.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some(level), None) => if let Some(level) = Level::from_str(level) {
+                filters.default = level;
+            },
+            (Some(name), Some(level)) => match Level::from_str(level) {
+                Some(level) if name.len() <= MAX_NAME && filters.count < MAX_FILTERS => {
+                    let filter = &mut filters.filters[filters.count];
+                    filter.name[..name.len()].copy_from_slice(name.as_bytes());
+                    filter.len = name.len();
+                    filter.level = level;
+                    filters.count += 1;
+                },
+                _ => (),
+            },
+            _ => (),
+        }
+    }
+    let max = filters.filters[..filters.count].iter()
+        .map(|f| f.level)
+        .fold(filters.default, |a, b| if b > a { b } else { a });
+    MAX_LEVEL.store(max as usize, Ordering::Relaxed);
+}
+
+/// Set the frequency of the cycle counter, after which timestamps are
+/// shown in seconds rather than cycles. Called by the platform once it has
+/// calibrated the counter
+pub fn set_tsc_frequency(hz: u64) {
+    TSC_HZ.store(hz as usize, Ordering::Relaxed);
+}
+
+/// True if messages at `level` from the module at `path` are shown
+pub fn enabled(level: Level, path: &str) -> bool {
+    level as usize <= MAX_LEVEL.load(Ordering::Relaxed)
+        && level <= unsafe{FILTERS.level(path)}
+}
+
+/// Check the console for the debugger wanting to break in, or the monitor
+/// being asked for. There are no device interrupts to notice either, so
+/// this is done on every timer interrupt and regularly whilst idle, where
+/// no locks are held and nothing is half updated
+pub fn poll_console() {
+    ::gdb::poll();
+    ::monitor::poll();
+}
+
+/// Implementation of the logging macros
+pub fn log(level: Level, path: &str, args: fmt::Arguments) {
+    if !enabled(level, path) {
+        return;
+    }
+    let cycles = arch::rdtsc();
+    let hz = TSC_HZ.load(Ordering::Relaxed) as u64;
+    let cpu = arch::current_cpu_id();
+    let module = path.rsplit("::").next().unwrap_or(path);
+    plat::with_console(|out| {
+        /* there is nowhere to report a failure to log */
+        let _ = if hz != 0 {
+            write!(out, "[{:5}.{:06}] ", cycles / hz, cycles % hz * 1_000_000 / hz)
+        } else {
+            write!(out, "[{:12}] ", cycles)
+        };
+        let _ = write!(out, "cpu{} {:5} {}: {}\n", cpu, level.name(), module, args);
+    });
+}
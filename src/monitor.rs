@@ -0,0 +1,358 @@
+//! Interactive debugger monitor
+//!
+//! A small shell on the debug console, entered by typing Ctrl-X twice
+//! followed by `m`. Like the GDB stub, the console is only checked for
+//! this on timer interrupts and whilst idle (see `log::poll_console`), and
+//! any other input that arrives is thrown away.
+//! The `monitor=` option gives when the monitor is available
+//!
+//! * `on`, the default, from the key sequence
+//! * `panic` also once a panic has been printed
+//! * `off` never
+//!
+//! Whilst the monitor runs it holds the console, so other processors wait
+//! to log anything. Numbers given to commands are in hex.
+use arch;
+use plat;
+use plat::Terminal;
+use steal_mem::{self, FRAME_SIZE};
+use config::BootConfig;
+use types::PAddr;
+use util::Volatile;
+use vspace::VSpaceWindow;
+use arch::DeviceWindow;
+use core::{fmt, str};
+use core::fmt::Write;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Keys that enter the monitor
+const MAGIC: [u8; 3] = [0x18, 0x18, b'm'];
+/// Longest command line
+const MAX_LINE: usize = 80;
+/// Most words in a command line
+const MAX_ARGS: usize = 4;
+/// Most memory map regions that are remembered
+const MAX_REGIONS: usize = 32;
+/// Bytes shown by `peek` if no count is given
+const DEFAULT_PEEK: usize = 64;
+/// Most bytes shown by a single `peek`
+const MAX_PEEK: usize = 4096;
+
+/// Values of `MODE`
+const MODE_OFF: usize = 0;
+const MODE_ON: usize = 1;
+const MODE_PANIC: usize = 2;
+
+const HELP: &'static str = "\
+help                         this list
+pt [cr3]                     dump page tables, the current ones by default
+acpi                         list the ACPI tables
+cpus                         list processors and their state
+memmap                       show the physical memory map
+alloc                        show boot allocator statistics
+peek <paddr> [count]         dump physical memory
+poke <paddr> <value> [size]  write 1, 2, 4 or 8 bytes of physical memory
+exit                         leave the monitor
+";
+
+/// Set from the `monitor=` option
+static MODE: AtomicUsize = AtomicUsize::new(MODE_ON);
+/// Number of bytes of `MAGIC` that have been typed so far
+static MATCHED: AtomicUsize = AtomicUsize::new(0);
+
+/// RAM regions from the boot loader. Only written by `set_memory_map`
+static mut REGIONS: [(PAddr, PAddr); MAX_REGIONS] = [(PAddr(0), PAddr(0)); MAX_REGIONS];
+static mut REGION_COUNT: usize = 0;
+
+/// Read the `monitor=` option
+pub fn init(config: &BootConfig) {
+    if let Some(option) = config.cmdline_option_find("monitor") {
+        match option.value {
+            "on" => MODE.store(MODE_ON, Ordering::Relaxed),
+            "panic" => MODE.store(MODE_PANIC, Ordering::Relaxed),
+            "off" => MODE.store(MODE_OFF, Ordering::Relaxed),
+            _ => warn!("Ignoring unknown monitor mode {}", option.value),
+        }
+    }
+}
+
+/// Remember the RAM regions for the `memmap` command. Regions past
+/// `MAX_REGIONS` are dropped
+///
+/// # Safety
+///
+/// Must be called before any other processors are started
+pub unsafe fn set_memory_map<I: Iterator<Item=(PAddr, PAddr)>>(regions: I) {
+    REGION_COUNT = 0;
+    for region in regions.take(MAX_REGIONS) {
+        REGIONS[REGION_COUNT] = region;
+        REGION_COUNT += 1;
+    }
+}
+
+/// Check the console for the keys that enter the monitor, and run it if
+/// they have been typed
+pub fn poll() {
+    if MODE.load(Ordering::Relaxed) == MODE_OFF {
+        return;
+    }
+    plat::with_terminal(|term| {
+        while let Some(c) = term.getchar() {
+            let matched = advance(MATCHED.load(Ordering::Relaxed), c);
+            if matched == MAGIC.len() {
+                MATCHED.store(0, Ordering::Relaxed);
+                session(term);
+            } else {
+                MATCHED.store(matched, Ordering::Relaxed);
+            }
+        }
+    });
+}
+
+/// Number of bytes of `MAGIC` matched once `c` follows `matched` of them.
+/// On a mismatch the longest tail of what was typed that begins `MAGIC` is
+/// kept, so that pressing Ctrl-X one time too many still works
+fn advance(matched: usize, c: u8) -> usize {
+    let mut k = matched + 1;
+    while k > 0 {
+        if MAGIC[k - 1] == c && MAGIC[..k - 1] == MAGIC[matched + 1 - k..matched] {
+            return k;
+        }
+        k -= 1;
+    }
+    0
+}
+
+/// Run the monitor at the end of a panic, if `monitor=panic` was given
+///
+/// # Safety
+///
+/// Only for the panic path, once every other processor has been stopped
+pub unsafe fn panic() {
+    if MODE.load(Ordering::Relaxed) == MODE_PANIC {
+        plat::panic_terminal(session);
+    }
+}
+
+/// Read a line, echoing it and handling backspace. A line feed straight
+/// after a carriage return is ignored, so either or both can end lines.
+/// Returns the length of the line in `buf`
+fn read_line(term: &mut Terminal, buf: &mut [u8], last: &mut u8) -> usize {
+    let mut len = 0;
+    loop {
+        let c = match term.getchar() {
+            Some(c) => c,
+            None => continue,
+        };
+        let previous = *last;
+        *last = c;
+        match c {
+            b'\n' if previous == b'\r' => (),
+            b'\r' | b'\n' => {
+                let _ = term.output().write_str("\n");
+                return len;
+            },
+            0x08 | 0x7f => if len > 0 {
+                len -= 1;
+                let _ = term.output().write_str("\x08 \x08");
+            },
+            0x20 ... 0x7e if len < buf.len() => {
+                buf[len] = c;
+                len += 1;
+                let _ = term.output().write_char(c as char);
+            },
+            _ => (),
+        }
+    }
+}
+
+/// Handle commands until `exit`
+fn session(term: &mut Terminal) {
+    let _ = write!(term.output(), "\nR4 monitor, type help for commands\n");
+    let mut buf = [0u8; MAX_LINE];
+    let mut last = 0;
+    loop {
+        let _ = write!(term.output(), "r4> ");
+        let len = read_line(term, &mut buf, &mut last);
+        let line = str::from_utf8(&buf[..len]).unwrap_or("");
+        let mut args = [""; MAX_ARGS];
+        let mut count = 0;
+        for word in line.split(' ').filter(|word| !word.is_empty()).take(MAX_ARGS) {
+            args[count] = word;
+            count += 1;
+        }
+        match args[..count].first() {
+            None => continue,
+            Some(&"exit") => return,
+            Some(_) => (),
+        }
+        if command(term.output(), &args[..count]).is_err() {
+            let _ = write!(term.output(), "Output failed\n");
+        }
+    }
+}
+
+/// Parse a hex number, with or without a leading `0x`
+fn parse_number(s: &str) -> Option<usize> {
+    let digits = if s.starts_with("0x") { &s[2..] } else { s };
+    usize::from_str_radix(digits, 16).ok()
+}
+
+/// `value` for an optional argument `n` that was left out
+fn default(args: &[&str], n: usize, value: usize) -> Option<usize> {
+    if args.len() > n { None } else { Some(value) }
+}
+
+/// Run a single command line
+fn command(out: &mut Write, args: &[&str]) -> fmt::Result {
+    let numbers = {
+        let mut numbers = [None; MAX_ARGS];
+        for (n, arg) in numbers.iter_mut().zip(args.iter()) {
+            *n = parse_number(arg);
+        }
+        numbers
+    };
+    match (args[0], args.len()) {
+        ("help", 1) => out.write_str(HELP),
+        ("pt", 1) => arch::write_page_tables(out, arch::page_table_root()),
+        ("pt", 2) => match numbers[1] {
+            Some(root) if !reachable(PAddr(root), FRAME_SIZE) => write_unreachable(out),
+            Some(root) => arch::write_page_tables(out, PAddr(root)),
+            None => write!(out, "Bad address {}\n", args[1]),
+        },
+        ("acpi", 1) => plat::write_acpi_tables(out),
+        ("cpus", 1) => plat::write_cpus(out),
+        ("memmap", 1) => write_memory_map(out),
+        ("alloc", 1) => {
+            let stats = steal_mem::stats();
+            write!(out, "{} allocations, {} bytes allocated, {} bytes wasted, {} ranges used\n",
+                stats.allocations, stats.allocated, stats.wasted, stats.ranges)
+        },
+        ("peek", 2) | ("peek", 3) => match (numbers[1],
+                numbers[2].or(default(args, 2, DEFAULT_PEEK))) {
+            (Some(paddr), Some(count)) if count <= MAX_PEEK => peek(out, PAddr(paddr), count),
+            _ => write!(out, "Usage: peek <paddr> [count], at most {:#x} bytes\n", MAX_PEEK),
+        },
+        ("poke", 3) | ("poke", 4) => match (numbers[1], numbers[2],
+                numbers[3].or(default(args, 3, 4))) {
+            (Some(paddr), Some(value), Some(size)) => poke(out, PAddr(paddr), value as u64, size),
+            _ => write!(out, "Usage: poke <paddr> <value> [size]\n"),
+        },
+        _ => write!(out, "Unknown command, try help\n"),
+    }
+}
+
+fn write_memory_map(out: &mut Write) -> fmt::Result {
+    let regions = unsafe{&REGIONS[..REGION_COUNT]};
+    if regions.is_empty() {
+        return write!(out, "No memory map\n");
+    }
+    let mut total = 0;
+    for &(start, end) in regions {
+        try!(write!(out, "{:016x}-{:016x} {:8} KB\n", start.0, end.0.wrapping_sub(1),
+            (end.0 - start.0) / 1024));
+        total += end.0 - start.0;
+    }
+    write!(out, "{} MB of RAM\n", total / (1024 * 1024))
+}
+
+/// True if `len` bytes at `paddr` are all in the device window, which is
+/// how physical memory is accessed. It only covers the first 4GB
+fn reachable(paddr: PAddr, len: usize) -> bool {
+    let window: DeviceWindow = unsafe{DeviceWindow::new(())};
+    paddr.0 < window.size() && len <= window.size() - paddr.0
+}
+
+/// Report an address that `reachable` rejected
+fn write_unreachable(out: &mut Write) -> fmt::Result {
+    let window: DeviceWindow = unsafe{DeviceWindow::new(())};
+    write!(out, "Only the first {} MB of physical memory can be accessed\n",
+        window.size() / (1024 * 1024))
+}
+
+/// Dump `count` bytes of physical memory, 16 to a line
+fn peek(out: &mut Write, paddr: PAddr, count: usize) -> fmt::Result {
+    if !reachable(paddr, count) {
+        return write_unreachable(out);
+    }
+    let window: DeviceWindow = unsafe{DeviceWindow::new(())};
+    let bytes: &[Volatile<u8>] = match window.try_from_paddr(paddr)
+            .and_then(|addr| unsafe{window.make_slice(addr, count)}) {
+        Some(bytes) => bytes,
+        None => return write!(out, "Not in the device window\n"),
+    };
+    for (i, line) in bytes.chunks(16).enumerate() {
+        try!(write!(out, "{:016x}:", paddr.0 + i * 16));
+        for byte in line {
+            try!(write!(out, " {:02x}", byte.read()));
+        }
+        try!(write!(out, "\n"));
+    }
+    Ok(())
+}
+
+/// Write `size` bytes of `value` to physical memory, as a single access
+fn poke(out: &mut Write, paddr: PAddr, value: u64, size: usize) -> fmt::Result {
+    let window: DeviceWindow = unsafe{DeviceWindow::new(())};
+    match size {
+        1 | 2 | 4 | 8 if paddr.0 % size == 0 => (),
+        1 | 2 | 4 | 8 => return write!(out, "Address is not aligned to the size\n"),
+        _ => return write!(out, "Size must be 1, 2, 4 or 8\n"),
+    }
+    if !reachable(paddr, size) {
+        return write_unreachable(out);
+    }
+    let addr = match window.try_from_paddr(paddr) {
+        Some(addr) => addr,
+        None => return write!(out, "Not in the device window\n"),
+    };
+    let done = unsafe {
+        match size {
+            1 => window.make::<Volatile<u8>>(addr).map(|v| v.write(value as u8)),
+            2 => window.make::<Volatile<u16>>(addr).map(|v| v.write(value as u16)),
+            4 => window.make::<Volatile<u32>>(addr).map(|v| v.write(value as u32)),
+            _ => window.make::<Volatile<u64>>(addr).map(|v| v.write(value)),
+        }
+    };
+    match done {
+        Some(()) => Ok(()),
+        None => write!(out, "Not in the device window\n"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{advance, MAGIC};
+
+    /// True if typing `keys` enters the monitor
+    fn enters(keys: &[u8]) -> bool {
+        let mut matched = 0;
+        for c in keys {
+            matched = advance(matched, *c);
+            if matched == MAGIC.len() {
+                return true;
+            }
+        }
+        false
+    }
+
+    #[test]
+    fn magic_enters() {
+        assert!(enters(&MAGIC));
+        assert!(enters(b"ab\x18\x18m"));
+    }
+
+    #[test]
+    fn extra_ctrl_x_still_enters() {
+        assert!(enters(b"\x18\x18\x18m"));
+        assert!(enters(b"\x18\x18\x18\x18\x18m"));
+        assert!(enters(b"\x18a\x18\x18m"));
+    }
+
+    #[test]
+    fn other_keys_do_not_enter() {
+        assert!(!enters(b"\x18m"));
+        assert!(!enters(b"\x18\x18am"));
+        assert!(!enters(b"\x18\x18\x18"));
+    }
+}
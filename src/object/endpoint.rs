@@ -0,0 +1,74 @@
+//! Endpoint objects
+//!
+//! Endpoints carry queued IPC, as described in RAVINGS.md: a caller sends
+//! a message and waits for the reply, and may lend its scheduling context
+//! to the receiver to have the request done with (see `ipc`). An endpoint
+//! is a queue of threads that are all waiting to send, or all waiting to
+//! receive, as a sender and a receiver that meet are paired straight away.
+//! Endpoints are only ever touched by the kernel, so have no shared part.
+use ::core::ptr;
+use super::Tcb;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// What the threads queued on an endpoint are waiting to do
+pub enum EndpointState {
+    /// Nothing is queued
+    Idle,
+    /// Callers are waiting for a receiver
+    Send,
+    /// Receivers are waiting for a caller
+    Recv,
+}
+
+pub struct Endpoint {
+    state: EndpointState,
+    /// Queue of waiting threads, linked through `Tcb::ipc_next`
+    head: *mut Tcb,
+    tail: *mut Tcb,
+}
+
+impl Endpoint {
+    pub fn new() -> Endpoint {
+        Endpoint { state: EndpointState::Idle, head: ptr::null_mut(), tail: ptr::null_mut() }
+    }
+    pub fn state(&self) -> EndpointState {
+        self.state
+    }
+    /// Queue `tcb` at the back as waiting to do `state`, which must be what
+    /// anything already queued is waiting to do
+    ///
+    /// # Safety
+    ///
+    /// `tcb` must be valid and not queued on any endpoint
+    pub unsafe fn enqueue(&mut self, tcb: *mut Tcb, state: EndpointState) {
+        debug_assert!(state != EndpointState::Idle);
+        debug_assert!(self.state == EndpointState::Idle || self.state == state);
+        (*tcb).ipc_next = ptr::null_mut();
+        if self.tail.is_null() {
+            self.head = tcb;
+        } else {
+            (*self.tail).ipc_next = tcb;
+        }
+        self.tail = tcb;
+        self.state = state;
+    }
+    /// Take the thread at the front of the queue, if threads are waiting to
+    /// do `state`
+    ///
+    /// # Safety
+    ///
+    /// Every queued thread must still be valid
+    pub unsafe fn dequeue(&mut self, state: EndpointState) -> Option<*mut Tcb> {
+        if self.state != state || self.head.is_null() {
+            return None;
+        }
+        let tcb = self.head;
+        self.head = (*tcb).ipc_next;
+        (*tcb).ipc_next = ptr::null_mut();
+        if self.head.is_null() {
+            self.tail = ptr::null_mut();
+            self.state = EndpointState::Idle;
+        }
+        Some(tcb)
+    }
+}
@@ -0,0 +1,106 @@
+//! Kernel objects
+//!
+//! As suggested in RAVINGS.md, an object can be split into two parts. The
+//! first is in kernel memory and only ever touched by the kernel. The
+//! second is a small piece of state in a frame that user level may have
+//! mapped, so that it can read or change that state without a system call.
+//! Several objects may share one frame.
+//!
+//! User level can change the shared part at any time, so the kernel never
+//! keeps references into it. It copies the state out with a single volatile
+//! read, validates the copy, and only then acts on it (see `Shared`).
+//! Shared state must be made of plain integers so that whatever user level
+//! writes is at least a value of the type.
+use types::PAddr;
+use util::Volatile;
+use vspace::VSpaceWindow;
+use arch::DeviceWindow;
+use ::core::mem::{align_of, size_of};
+
+pub mod notification;
+pub mod tcb;
+pub mod pd;
+pub mod sched_context;
+pub mod endpoint;
+
+pub use self::notification::{Notification, NotificationState};
+pub use self::tcb::{Tcb, TcbShared, ThreadState, MAX_PRIORITY};
+pub use self::pd::{Pd, PD_PROXY_RIGHTS};
+pub use self::sched_context::SchedContext;
+pub use self::endpoint::{Endpoint, EndpointState};
+
+/// Size of the frames that shared state is placed in. State may not cross
+/// a frame boundary, so that it is always in a single mapping
+const SHARED_FRAME_SIZE: usize = 4096;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// Errors from constructing objects or reading their shared state
+pub enum ObjectError {
+    /// Shared state is not aligned or crosses a frame boundary
+    Misaligned,
+    /// Shared state is not in memory the kernel can reach
+    NotInWindow,
+    /// User level left the shared state with a value that does not make
+    /// sense
+    InvalidState,
+    /// A capability given as a capability space was not to a CNode
+    NotCSpace,
+    /// A parameter of the object did not make sense
+    InvalidArgument,
+}
+
+/// Object state that user level can write
+///
+/// # Safety
+///
+/// Every bit pattern must be a valid value of the type, as user level can
+/// write anything to it
+pub unsafe trait SharedState: Copy {
+    /// Check that a snapshot makes sense before the kernel uses it
+    fn validate(&self) -> bool;
+}
+
+/// The user shared part of an object
+pub struct Shared<T: SharedState + 'static> {
+    state: &'static Volatile<T>,
+    paddr: PAddr,
+}
+
+impl<T: SharedState> Shared<T> {
+    /// Refer to the shared state at `paddr`, which must be aligned for the
+    /// type, within a single frame, and in the device window
+    ///
+    /// # Safety
+    ///
+    /// The memory must not be used by the kernel for anything else for as
+    /// long as the object exists
+    pub unsafe fn new(paddr: PAddr) -> Result<Shared<T>, ObjectError> {
+        if paddr.0 % align_of::<T>() != 0
+                || paddr.0 % SHARED_FRAME_SIZE + size_of::<T>() > SHARED_FRAME_SIZE {
+            return Err(ObjectError::Misaligned);
+        }
+        let window: DeviceWindow<'static> = DeviceWindow::new(());
+        match window.try_from_paddr(paddr).and_then(|addr| window.make(addr)) {
+            Some(state) => Ok(Shared { state: state, paddr: paddr }),
+            None => Err(ObjectError::NotInWindow),
+        }
+    }
+    /// Physical address of the state, for mapping it to user level
+    pub fn paddr(&self) -> PAddr {
+        self.paddr
+    }
+    /// Take a copy of the state and validate it. Later changes by user
+    /// level do not affect the copy
+    pub fn snapshot(&self) -> Result<T, ObjectError> {
+        let state = self.state.read();
+        if state.validate() { Ok(state) } else { Err(ObjectError::InvalidState) }
+    }
+    /// Replace the state
+    pub fn store(&self, state: T) {
+        self.state.write(state);
+    }
+    /// Raw pointer to the state, for objects that need atomic access to it
+    pub fn as_ptr(&self) -> *const T {
+        self.state as *const Volatile<T> as *const T
+    }
+}
@@ -0,0 +1,60 @@
+//! Notification objects
+//!
+//! A notification is a word of signal bits. Signalling ORs a badge into the
+//! word and polling takes and clears every bit. The word is the shared part
+//! of the object, so user level can check for and acknowledge signals
+//! itself without a system call. Both sides must change the word with
+//! atomic operations so that neither loses an update of the other. The
+//! kernel part records the thread bound to the notification, if any.
+use ::core::ptr;
+use ::core::sync::atomic::{AtomicUsize, Ordering};
+use super::{Shared, SharedState, Tcb};
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+/// Shared part of a notification
+pub struct NotificationState {
+    pub word: usize,
+}
+
+unsafe impl SharedState for NotificationState {
+    /// Any word is a valid set of signals
+    fn validate(&self) -> bool {
+        true
+    }
+}
+
+pub struct Notification {
+    shared: Shared<NotificationState>,
+    /// Thread to wake when signalled, or null
+    bound: *mut Tcb,
+}
+
+impl Notification {
+    pub fn new(shared: Shared<NotificationState>) -> Notification {
+        Notification { shared: shared, bound: ptr::null_mut() }
+    }
+    fn word(&self) -> &AtomicUsize {
+        unsafe{&*(self.shared.as_ptr() as *const AtomicUsize)}
+    }
+    /// Set the bits of `badge`
+    pub fn signal(&self, badge: u64) {
+        self.word().fetch_or(badge as usize, Ordering::SeqCst);
+    }
+    /// Take every pending signal, which is zero if there were none
+    pub fn poll(&self) -> u64 {
+        self.word().swap(0, Ordering::SeqCst) as u64
+    }
+    pub fn bound(&self) -> *mut Tcb {
+        self.bound
+    }
+    /// Bind a thread, or unbind with null. Returns false if a different
+    /// thread is already bound
+    pub fn bind(&mut self, tcb: *mut Tcb) -> bool {
+        if !tcb.is_null() && !self.bound.is_null() && self.bound != tcb {
+            return false;
+        }
+        self.bound = tcb;
+        true
+    }
+}
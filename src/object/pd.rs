@@ -0,0 +1,38 @@
+//! Protection domain objects
+//!
+//! A protection domain bundles an address space with a capability space.
+//! Everything a thread invokes is found in the protection domain it is
+//! bound to, unless it names another protection domain, which it can only
+//! do with a capability to it (see `cap::invoke`). Protection domains are
+//! only ever touched by the kernel, so have no shared part.
+use cap::{Cap, CapKind, CapRights};
+use types::PAddr;
+use super::ObjectError;
+
+/// Rights a capability to a protection domain needs for proxies in that
+/// domain to be used through it
+pub const PD_PROXY_RIGHTS: CapRights = CapRights { read: false, write: false, grant: true };
+
+pub struct Pd {
+    /// Root of the page tables
+    vspace: PAddr,
+    /// Capability to the root CNode
+    cspace: Cap,
+}
+
+impl Pd {
+    /// Construct a protection domain from page tables and a capability to
+    /// the root CNode of its capability space
+    pub fn new(vspace: PAddr, cspace: Cap) -> Result<Pd, ObjectError> {
+        match cspace.kind {
+            CapKind::CNode(_) => Ok(Pd { vspace: vspace, cspace: cspace }),
+            _ => Err(ObjectError::NotCSpace),
+        }
+    }
+    pub fn vspace(&self) -> PAddr {
+        self.vspace
+    }
+    pub fn cspace(&self) -> &Cap {
+        &self.cspace
+    }
+}
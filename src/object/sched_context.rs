@@ -0,0 +1,99 @@
+//! Scheduling context objects
+//!
+//! A scheduling context is the right to run on a processor for a budget of
+//! time in every period. Threads only run whilst they have a scheduling
+//! context with budget left, so no thread can take more of the processor
+//! than its scheduling context allows, whatever its priority. Budgets are
+//! refilled to their full size at the start of every period, and budget
+//! left over at the end of one period is not carried into the next.
+//!
+//! A scheduling context is used by one thread at a time. A thread without
+//! one of its own runs on the scheduling context of whoever called it (see
+//! `ipc`), so work a passive server does for a client is charged to the
+//! client. Times are in cycles of `arch::rdtsc`. Scheduling contexts are
+//! only ever touched by the kernel, so have no shared part.
+use ::core::ptr;
+use super::{ObjectError, Tcb};
+
+pub struct SchedContext {
+    budget: u64,
+    period: u64,
+    /// Budget left in the current period
+    remaining: u64,
+    /// End of the current period, when the budget is refilled
+    release: u64,
+    /// Thread running on this scheduling context, or null
+    tcb: *mut Tcb,
+    /// Next in the scheduler's queue of contexts waiting to be refilled
+    pub next_release: *mut SchedContext,
+}
+
+impl SchedContext {
+    /// Construct a scheduling context, not used by any thread, with a full
+    /// budget and a period starting at `now`
+    pub fn new(budget: u64, period: u64, now: u64) -> Result<SchedContext, ObjectError> {
+        let mut sc = SchedContext {
+            budget: 0,
+            period: 0,
+            remaining: 0,
+            release: 0,
+            tcb: ptr::null_mut(),
+            next_release: ptr::null_mut(),
+        };
+        try!(sc.configure(budget, period, now));
+        Ok(sc)
+    }
+    /// Change the budget and period, starting a new period at `now` with a
+    /// full budget. The budget must be non zero and no more than the period
+    pub fn configure(&mut self, budget: u64, period: u64, now: u64) -> Result<(), ObjectError> {
+        if budget == 0 || budget > period {
+            return Err(ObjectError::InvalidArgument);
+        }
+        self.budget = budget;
+        self.period = period;
+        self.remaining = budget;
+        self.release = now.saturating_add(period);
+        Ok(())
+    }
+    pub fn budget(&self) -> u64 {
+        self.budget
+    }
+    pub fn period(&self) -> u64 {
+        self.period
+    }
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+    /// When the current period ends
+    pub fn release(&self) -> u64 {
+        self.release
+    }
+    pub fn tcb(&self) -> *mut Tcb {
+        self.tcb
+    }
+    pub fn set_tcb(&mut self, tcb: *mut Tcb) {
+        self.tcb = tcb;
+    }
+    /// Take `used` cycles from the budget. Returns true if that used up
+    /// what was left
+    pub fn charge(&mut self, used: u64) -> bool {
+        self.remaining = self.remaining.saturating_sub(used);
+        self.remaining == 0
+    }
+    /// Start the next period, with a full budget, if the current one has
+    /// ended by `now`. Returns true if it had
+    pub fn refill(&mut self, now: u64) -> bool {
+        if now < self.release {
+            return false;
+        }
+        /* periods missed entirely are not caught up on, as that would let
+         * a context that was not runnable for a while burst past its share */
+        self.release = if now - self.release < self.period {
+            self.release + self.period
+        } else {
+            now.saturating_add(self.period)
+        };
+        self.remaining = self.budget;
+        true
+    }
+}
@@ -0,0 +1,163 @@
+//! Thread control blocks
+//!
+//! The kernel part of a thread holds everything the kernel depends on, such
+//! as whether it may run. The shared part holds what a thread may change
+//! about itself without a system call: the base of its thread local
+//! storage, and where its IPC buffer is. Both are user addresses, and are
+//! checked to be so every time they are read.
+//!
+//! The saved registers of a thread are only ever in the kernel part, as user
+//! level must not be able to change the registers of a thread that is not
+//! running without a capability to it. So is everything the scheduler and
+//! IPC keep about a thread, such as the queues it is on and the scheduling
+//! context it runs on.
+use arch;
+use cap::CPtr;
+use thread::Context;
+use ::core::ptr;
+use super::{ObjectError, Pd, SchedContext, Shared, SharedState};
+
+/// Alignment of an IPC buffer
+pub const IPC_BUFFER_ALIGN: u64 = 512;
+/// Highest priority a thread can have
+pub const MAX_PRIORITY: u8 = 255;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// Whether a thread can be run
+pub enum ThreadState {
+    /// Not yet started, or stopped
+    Inactive,
+    /// Running or ready to run, once it has a scheduling context with
+    /// budget left
+    Runnable,
+    /// Waiting for a signal
+    Blocked,
+    /// Queued on an endpoint, waiting for a receiver to take its call
+    BlockedOnSend,
+    /// Queued on an endpoint, waiting for a call
+    BlockedOnReceive,
+    /// Waiting for the receiver of its call to reply
+    BlockedOnReply,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+/// Shared part of a thread
+pub struct TcbShared {
+    pub tls_base: u64,
+    pub ipc_buffer: u64,
+}
+
+unsafe impl SharedState for TcbShared {
+    fn validate(&self) -> bool {
+        self.tls_base < arch::USER_TOP as u64 && self.ipc_buffer < arch::USER_TOP as u64
+            && self.ipc_buffer % IPC_BUFFER_ALIGN == 0
+    }
+}
+
+pub struct Tcb {
+    state: ThreadState,
+    shared: Shared<TcbShared>,
+    /// Registers the thread will run with next
+    context: Context,
+    /// Protection domain the thread runs in
+    pd: *mut Pd,
+    /// Where faults are sent, in the capability space of the thread
+    fault_handler: Option<CPtr>,
+    priority: u8,
+    /// Scheduling context the thread runs on, or null
+    sched_context: *mut SchedContext,
+    /// Thread waiting for a reply from this one, or null
+    caller: *mut Tcb,
+    /// The scheduling context was lent by `caller`, and goes back with the
+    /// reply
+    donated: bool,
+    /// Badge and message of a call waiting to be received
+    message: (u64, usize),
+    /// Next in the ready queue of the scheduler
+    pub sched_next: *mut Tcb,
+    /// The thread is in a ready queue
+    pub queued: bool,
+    /// Next in the queue of an endpoint
+    pub ipc_next: *mut Tcb,
+}
+
+impl Tcb {
+    /// Construct an inactive thread in `pd`, which will start with `context`
+    pub fn new(shared: Shared<TcbShared>, pd: *mut Pd, context: Context, priority: u8) -> Tcb {
+        Tcb {
+            state: ThreadState::Inactive,
+            shared: shared,
+            context: context,
+            pd: pd,
+            fault_handler: None,
+            priority: priority,
+            sched_context: ptr::null_mut(),
+            caller: ptr::null_mut(),
+            donated: false,
+            message: (0, 0),
+            sched_next: ptr::null_mut(),
+            queued: false,
+            ipc_next: ptr::null_mut(),
+        }
+    }
+    pub fn state(&self) -> ThreadState {
+        self.state
+    }
+    pub fn set_state(&mut self, state: ThreadState) {
+        self.state = state;
+    }
+    pub fn context(&self) -> &Context {
+        &self.context
+    }
+    pub fn context_mut(&mut self) -> &mut Context {
+        &mut self.context
+    }
+    pub fn pd(&self) -> *mut Pd {
+        self.pd
+    }
+    pub fn fault_handler(&self) -> Option<CPtr> {
+        self.fault_handler
+    }
+    pub fn set_fault_handler(&mut self, handler: Option<CPtr>) {
+        self.fault_handler = handler;
+    }
+    pub fn priority(&self) -> u8 {
+        self.priority
+    }
+    pub fn set_priority(&mut self, priority: u8) {
+        self.priority = priority;
+    }
+    pub fn sched_context(&self) -> *mut SchedContext {
+        self.sched_context
+    }
+    /// Change the scheduling context, without changing which thread the
+    /// scheduling context thinks it is used by
+    pub fn set_sched_context(&mut self, sc: *mut SchedContext) {
+        self.sched_context = sc;
+    }
+    /// Thread waiting for a reply, and whether it lent its scheduling
+    /// context
+    pub fn caller(&self) -> (*mut Tcb, bool) {
+        (self.caller, self.donated)
+    }
+    pub fn set_caller(&mut self, caller: *mut Tcb, donated: bool) {
+        self.caller = caller;
+        self.donated = donated;
+    }
+    /// Badge and message word of a call that is waiting to be received
+    pub fn message(&self) -> (u64, usize) {
+        self.message
+    }
+    pub fn set_message(&mut self, badge: u64, message: usize) {
+        self.message = (badge, message);
+    }
+    /// Base of thread local storage, as last set by the thread
+    pub fn tls_base(&self) -> Result<usize, ObjectError> {
+        self.shared.snapshot().map(|shared| shared.tls_base as usize)
+    }
+    /// User address of the IPC buffer, as last set by the thread
+    pub fn ipc_buffer(&self) -> Result<usize, ObjectError> {
+        self.shared.snapshot().map(|shared| shared.ipc_buffer as usize)
+    }
+}
@@ -1,9 +1,124 @@
 //! Unsafe panic implementation for debugging
-use arch::halt;
+//!
+//! The first processor to panic stops all the others with an NMI and then
+//! takes the console, whether or not anyone held it, to print the reason
+//! and a backtrace. With `panic_regs=on` it also prints the registers each
+//! of the other processors was stopped with. Processors that panic after
+//! it just halt, as they are about to be stopped anyway. A panic whilst
+//! printing a panic only gets a one line report, and a panic whilst doing
+//! that halts silently.
+//!
+//! If there is a GDB stub it is entered once the panic has been printed,
+//! followed by the debugger monitor if `monitor=panic` was given.
+//! What happens after that is set by the `panic=` option:
+//!
+//! * `halt`, the default, stops the processor for good
+//! * `reboot[,seconds]` resets the machine, after 10 seconds if no delay
+//!   is given
+//! * `qemu[,code]` makes QEMU exit with status `(code << 1) | 1`, using
+//!   its `isa-debug-exit` device, with a code of 1 if none is given. If
+//!   there is no such device the processor halts
+use arch::{self, halt, current_frames, rdtsc};
+use config::BootConfig;
+use symbols;
+use gdb;
+use monitor;
 use plat::*;
+use core::mem;
 use core::ptr::Unique;
 use core::fmt::{Write, Arguments};
 use core::intrinsics::transmute;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Value of `PANIC_CPU` when no processor has panicked
+const NO_CPU: usize = !0;
+/// Delay before `panic=reboot` resets the machine, in seconds
+const DEFAULT_REBOOT_DELAY: u64 = 10;
+/// Code `panic=qemu` exits with, which QEMU turns into a status of 3
+const DEFAULT_QEMU_CODE: u8 = 1;
+/// Cycle counter frequency assumed for the reboot delay if the platform
+/// did not calibrate it
+const FALLBACK_TSC_HZ: u64 = 1_000_000_000;
+
+/// What to do once a panic has been printed
+#[derive(Debug, Copy, Clone)]
+enum PanicAction {
+    Halt,
+    /// Reset the machine after this many seconds
+    Reboot(u64),
+    /// Exit QEMU with this code
+    Qemu(u8),
+}
+
+impl PanicAction {
+    /// Parse the value of a `panic=` option
+    fn from_str(s: &str) -> Option<PanicAction> {
+        let mut parts = s.splitn(2, ',');
+        let action = parts.next().unwrap_or("");
+        let arg = parts.next();
+        match (action, arg) {
+            ("halt", None) => Some(PanicAction::Halt),
+            ("reboot", None) => Some(PanicAction::Reboot(DEFAULT_REBOOT_DELAY)),
+            ("reboot", Some(delay)) => delay.parse().ok().map(PanicAction::Reboot),
+            ("qemu", None) => Some(PanicAction::Qemu(DEFAULT_QEMU_CODE)),
+            ("qemu", Some(code)) => code.parse().ok().map(PanicAction::Qemu),
+            _ => None,
+        }
+    }
+}
+
+/// ID of the processor that panicked first
+static PANIC_CPU: AtomicUsize = AtomicUsize::new(NO_CPU);
+/// Number of panics on the processor in `PANIC_CPU`, to catch a panic
+/// inside the panic path
+static PANIC_DEPTH: AtomicUsize = AtomicUsize::new(0);
+/// Whether to print the registers of the stopped processors
+static PRINT_REGISTERS: AtomicBool = AtomicBool::new(false);
+/// Set from the `panic=` option. Only written by `panic_init`
+static mut PANIC_ACTION: PanicAction = PanicAction::Halt;
+
+/// Read the `panic_regs=on` and `panic=` options. A `panic=` option that
+/// does not parse is reported and otherwise ignored
+///
+/// # Safety
+///
+/// Must be called before any other processors are started
+pub unsafe fn panic_init(config: &BootConfig) {
+    if let Some(option) = config.cmdline_option_find("panic_regs") {
+        PRINT_REGISTERS.store(option.value == "on", Ordering::SeqCst);
+    }
+    if let Some(option) = config.cmdline_option_find("panic") {
+        match PanicAction::from_str(option.value) {
+            Some(action) => PANIC_ACTION = action,
+            None => warn!("Ignoring unknown panic action {}", option.value),
+        }
+    }
+}
+
+/// Carry out the `panic=` action. This is called with the console taken,
+/// to report what is happening
+fn finish(out: &mut Write) -> ! {
+    match unsafe{PANIC_ACTION} {
+        PanicAction::Halt => (),
+        PanicAction::Reboot(delay) => {
+            let _ = write!(out, "Rebooting in {} seconds\n", delay);
+            let hz = unsafe{PLAT_REF.as_ref()}
+                .and_then(|ptr| unsafe{ptr.get()}.tsc_frequency())
+                .unwrap_or(FALLBACK_TSC_HZ);
+            let start = rdtsc();
+            while rdtsc().wrapping_sub(start) < delay.saturating_mul(hz) {}
+            match unsafe{PLAT_REF.as_mut()} {
+                Some(ptr) => unsafe{ptr.get_mut()}.reboot(),
+                None => arch::triple_fault(),
+            }
+        },
+        PanicAction::Qemu(code) => {
+            emulator_exit(code);
+            let _ = write!(out, "No isa-debug-exit device to exit QEMU through\n");
+        },
+    }
+    halt();
+}
 
 /// Holds a raw pointer to the currently active platform
 /// This is an `Option` type purely because I could not manage to initialize
@@ -28,27 +143,57 @@ pub fn panic_set_plat(plat: &mut PlatInterfaceType) {
     }
 }
 
-/// Perform an emergency unsafe write using the panic platform reference
-#[allow(dead_code)]
-pub unsafe fn panic_write(fmt: Arguments) {
-    if let &mut Some(ref mut ptr) = &mut PLAT_REF {
-        let plat: &mut PlatInterfaceType;
-        plat = &mut *ptr.get_mut();
-        write!(plat, "{}\n", fmt).unwrap();
-    }
-}
-
-/// Attempts to print out a panic message before halting the system
-/// If `PLAT_REF` was not set correctly then this will crash, but we're
-/// already crashing so we cannot feel too bad for at least trying
+/// Attempts to print out a panic message before carrying out the `panic=`
+/// action. The console is taken without its lock, as its holder may have
+/// been stopped or be further up this processor's stack, so output could
+/// be garbled if some processor failed to stop
+#[cfg(not(test))]
 #[lang = "panic_fmt"] extern fn panic_fmt(fmt: Arguments, file: &str, line: usize) -> ! {
+    /* interrupts stay disabled until the processor halts */
+    mem::forget(arch::disable_interrupts());
+    let cpu = arch::current_cpu_id() as usize;
+    let first = PANIC_CPU.compare_and_swap(NO_CPU, cpu, Ordering::SeqCst);
+    if first != NO_CPU && first != cpu {
+        halt();
+    }
+    match PANIC_DEPTH.fetch_add(1, Ordering::SeqCst) {
+        0 => (),
+        1 => {
+            unsafe {
+                panic_console(|out| {
+                    let _ = write!(out, "\nnested panic at {}:{} {}\n", file, line, fmt);
+                    finish(out);
+                });
+            }
+            halt();
+        },
+        _ => halt(),
+    }
+    let stopped = arch::stop_other_cpus();
     unsafe {
-        if let &mut Some(ref mut ptr) = &mut PLAT_REF {
-            let plat: &mut PlatInterfaceType;
-            plat = &mut *ptr.get_mut();
-            write!(plat, "\nin panic function. Attempting to display reason then hlt'ing\n").unwrap();
-            write!(plat, "{}:{} {}\n", file, line, fmt).unwrap();
-        }
+        panic_console(|out| {
+            let _ = write!(out, "\nin panic function. Attempting to display reason\n");
+            let _ = write!(out, "CPU {} panicked at {}:{} {}\n", cpu, file, line, fmt);
+            /* an exception has a more useful backtrace than the panic it
+             * turned into */
+            if let Ok(false) = arch::write_exception(out) {
+                let _ = symbols::write_backtrace(out, current_frames());
+            }
+            if stopped.is_err() {
+                let _ = write!(out, "Failed to stop the other CPUs\n");
+            }
+            if PRINT_REGISTERS.load(Ordering::SeqCst) {
+                let _ = arch::write_stopped_cpus(out);
+            }
+            if gdb::enabled() {
+                let _ = write!(out, "Entering the GDB stub\n");
+                arch::debug::breakpoint();
+            }
+        });
+        /* the monitor takes the console for itself, so must not run whilst
+         * it is already taken above */
+        monitor::panic();
+        panic_console(|out| finish(out));
     }
     halt();
 }
@@ -11,9 +11,17 @@
 //! platform module is private it can still only be manipulated with the
 //! `PlatInterfaceType` trait defined here
 mod pc99;
-use self::pc99::plat_get_platform;
+use self::pc99::{plat_get_platform, plat_with_console, plat_panic_console, plat_emulator_exit};
+use self::pc99::{plat_gdb_present, plat_gdb_putchar, plat_gdb_getchar};
+use self::pc99::{plat_with_terminal, plat_panic_terminal, plat_write_acpi_tables, plat_write_cpus};
+use self::pc99::{plat_boot_device_count, plat_write_boot_device};
+use self::pc99::{plat_iommu_create_domain, plat_iommu_bind, plat_iommu_unbind, plat_iommu_map};
+use self::pc99::{plat_iommu_unmap, plat_iommu_invalidate};
 use config::BootConfig;
 use vspace::VSpaceWindow;
+use cluster::Topology;
+use types::PAddr;
+use steal_mem::FrameAllocator;
 use ::core::fmt;
 
 /// Re-export the current platform type. Any kernel code that wants to use
@@ -29,6 +37,38 @@ use ::core::fmt;
 /// ```
 pub use self::pc99::PlatInterfaceType;
 
+/// Errors from the DMA remapping hardware of the platform
+pub use self::pc99::DmaError;
+
+/// Information from the boot loader that the platform may need to find its
+/// hardware
+#[derive(Debug, Copy, Clone)]
+pub struct LoaderInfo {
+    /// Physical address of a copy of the ACPI RSDP
+    pub acpi_rsdp: Option<PAddr>,
+    /// Linear framebuffer the display was left in
+    pub framebuffer: Option<Framebuffer>,
+}
+
+/// A direct colour linear framebuffer set up by the boot loader
+#[derive(Debug, Copy, Clone)]
+pub struct Framebuffer {
+    /// Physical address of the first pixel
+    pub base: PAddr,
+    /// Bytes from the start of one row of pixels to the next
+    pub pitch: usize,
+    /// Width in pixels
+    pub width: usize,
+    /// Height in pixels
+    pub height: usize,
+    /// Bits per pixel
+    pub bpp: u8,
+    /// Bit position and size of each colour channel within a pixel
+    pub red: (u8, u8),
+    pub green: (u8, u8),
+    pub blue: (u8, u8),
+}
+
 /// Abstract platform interface
 pub trait PlatInterface {
     /// Initialize the debug serial interface for this platform
@@ -37,6 +77,10 @@ pub trait PlatInterface {
     /// If `init_serial` has not yet been called this will silently
     /// discard characters
     fn putchar(&mut self, c: u8);
+    /// Receive a single byte from the debug serial interface without
+    /// blocking. Returns `None` if nothing has arrived, or there is no
+    /// input available
+    fn getchar(&mut self) -> Option<u8>;
     /// Perform early platform initialization
     ///
     /// # Safety
@@ -47,6 +91,32 @@ pub trait PlatInterface {
     /// Perform device discovery. Takes a window that can provide access
     /// to any hardware structures to walk
     fn early_device_discovery<'a, W: VSpaceWindow<'a>>(&mut self, window: &'a W) -> Result<(), ()>;
+    /// Turn on any DMA remapping hardware found during device discovery.
+    /// Tables are allocated from `alloc` and accessed through `window`,
+    /// which must cover both them and the hardware registers. Lacking an
+    /// IOMMU is not an error
+    fn init_iommu<'a, W, A>(&mut self, window: &'a W, alloc: &mut A) -> Result<(), ()>
+            where W: VSpaceWindow<'a>, A: FrameAllocator;
+    /// NUMA topology of the machine as found during device discovery. This
+    /// is empty if the platform has no topology information
+    fn topology(&self) -> &Topology;
+    /// Frequency of the processor time stamp counter in Hz, if a reference
+    /// clock was found to calibrate it against during device discovery
+    fn tsc_frequency(&self) -> Option<u64>;
+    /// Reset the machine. Platforms should try increasingly heavy handed
+    /// methods until one of them works
+    fn reboot(&mut self) -> !;
+    /// Turn the machine off. If the platform does not know how to do this
+    /// then the system is halted instead
+    fn power_off(&mut self) -> !;
+}
+
+/// The debug console used interactively, as by the debugger monitor
+pub trait Terminal {
+    /// Where output to the console goes
+    fn output(&mut self) -> &mut fmt::Write;
+    /// Receive a single byte without blocking
+    fn getchar(&mut self) -> Option<u8>;
 }
 
 impl fmt::Write for PlatInterfaceType {
@@ -67,6 +137,121 @@ impl fmt::Write for PlatInterfaceType {
 ///
 /// This function should be called no more than once as the underlying
 /// platform implementation is allowed to assume it is a singleton
-pub unsafe fn get_platform(config: &BootConfig) -> PlatInterfaceType where PlatInterfaceType: PlatInterface {
-    plat_get_platform(config)
+pub unsafe fn get_platform(config: &BootConfig, loader: &LoaderInfo) -> PlatInterfaceType where PlatInterfaceType: PlatInterface {
+    plat_get_platform(config, loader)
+}
+
+/// Run `f` with exclusive access to the debug console, so that its output
+/// is not interleaved with that of other processors. This can be called
+/// from any context, including before the platform has been constructed,
+/// in which case output only goes to the kernel log
+pub fn with_console<F: FnOnce(&mut fmt::Write)>(f: F) {
+    plat_with_console(f)
+}
+
+/// Run `f` with the debug console even if another processor, or this one,
+/// holds it
+///
+/// # Safety
+///
+/// Only for the panic path, once every other processor has been stopped
+pub unsafe fn panic_console<F: FnOnce(&mut fmt::Write)>(f: F) {
+    plat_panic_console(f)
+}
+
+/// Ask the emulator the kernel is running under, if any, to exit with a
+/// status derived from `code`, so that automated test runs can see the
+/// result. Returns if there is no emulator to ask
+pub fn emulator_exit(code: u8) {
+    plat_emulator_exit(code)
+}
+
+/// True if the platform has a port for the GDB stub to talk through
+pub fn gdb_present() -> bool {
+    plat_gdb_present()
+}
+
+/// Send a byte to the GDB stub port
+pub fn gdb_putchar(c: u8) {
+    plat_gdb_putchar(c)
+}
+
+/// Receive a byte from the GDB stub port without blocking
+pub fn gdb_getchar() -> Option<u8> {
+    plat_gdb_getchar()
+}
+
+/// Run `f` with exclusive use of the debug console for both input and
+/// output. Nothing may be logged from `f`, as the console is already held
+pub fn with_terminal<F: FnOnce(&mut Terminal)>(f: F) {
+    plat_with_terminal(f)
+}
+
+/// Run `f` with the debug console for input and output even if it is held
+///
+/// # Safety
+///
+/// Only for the panic path, once every other processor has been stopped
+pub unsafe fn panic_terminal<F: FnOnce(&mut Terminal)>(f: F) {
+    plat_panic_terminal(f)
+}
+
+/// List the firmware tables describing the platform
+pub fn write_acpi_tables(out: &mut fmt::Write) -> fmt::Result {
+    plat_write_acpi_tables(out)
+}
+
+/// List the processors of the platform and what is known of their state
+pub fn write_cpus(out: &mut fmt::Write) -> fmt::Result {
+    plat_write_cpus(out)
+}
+
+/// Bytes each device takes in the boot information of the root task
+pub const BOOT_DEVICE_SIZE: usize = 256;
+
+/// Number of devices found during device discovery that can be described
+/// to the root task, and the number found that could not be recorded
+pub fn boot_device_count() -> (usize, usize) {
+    plat_boot_device_count()
+}
+
+/// Describe device `index` of those found during device discovery into
+/// `out`, in a format specific to the platform. Returns false if there is
+/// no such device
+pub fn write_boot_device(index: usize, out: &mut [u8; BOOT_DEVICE_SIZE]) -> bool {
+    plat_write_boot_device(index, out)
+}
+
+/// Make a DMA remapping domain with nothing mapped, returning the index
+/// that identifies it. Domains are never freed
+pub fn iommu_create_domain() -> Result<usize, DmaError> {
+    plat_iommu_create_domain()
+}
+
+/// Bind the PCI function with requester ID `rid` to domain `domain`, after
+/// which it can only DMA to what is mapped there
+pub fn iommu_bind(domain: usize, rid: u16) -> Result<(), DmaError> {
+    plat_iommu_bind(domain, rid)
+}
+
+/// Remove any binding of the PCI function with requester ID `rid`, after
+/// which all of its DMA is blocked
+pub fn iommu_unbind(rid: u16) -> Result<(), DmaError> {
+    plat_iommu_unbind(rid)
+}
+
+/// Map `frame` into domain `domain` at I/O virtual address `iova`
+pub fn iommu_map(domain: usize, iova: u64, frame: PAddr, write: bool) -> Result<(), DmaError> {
+    plat_iommu_map(domain, iova, frame, write)
+}
+
+/// Unmap whatever is at `iova` in domain `domain`. Devices can still use
+/// the mapping until `iommu_invalidate` is done
+pub fn iommu_unmap(domain: usize, iova: u64) -> Result<(), DmaError> {
+    plat_iommu_unmap(domain, iova)
+}
+
+/// Flush every cached translation of domain `domain`
+pub fn iommu_invalidate(domain: usize) -> Result<(), DmaError> {
+    plat_iommu_invalidate(domain)
 }
@@ -1,9 +1,20 @@
 //! ACPI table walker
+//!
+//! None of the tables found here are trusted. Every table has its length
+//! checked against the window it lives in, and against the size of the
+//! structure we are about to transmute it to, and has its checksum
+//! validated before being handed out. A table listed in the RSDT must also
+//! not overlap the RSDT or any table listed before it, so that one table
+//! cannot be taken for two. Tables that fail any of these checks are
+//! returned as an `Invalid` variant so the caller can report them, but are
+//! otherwise skipped.
 use vspace::VSpaceWindow;
 use ::core::slice;
+use ::core::cmp;
 use ::core::num::Wrapping;
 use ::core::mem::{size_of, transmute};
 use ::core::iter::FilterMap;
+use ::core::marker::PhantomData;
 use types::PAddr;
 
 #[repr(packed)]
@@ -21,6 +32,9 @@ struct RSDP {
     reserved: [u8; 3],
 }
 
+/// Number of bytes covered by the original ACPI 1.0 RSDP checksum
+const RSDP_V1_LENGTH: usize = 20;
+
 #[repr(packed)]
 #[derive(Debug)]
 /// General ACPI Header
@@ -36,14 +50,51 @@ pub struct ACPIHeader {
     creater_reivision: u32,
 }
 
+impl ACPIHeader {
+    /// Four character signature identifying the table
+    pub fn signature(&self) -> &[u8; 4] {
+        &self.signature
+    }
+    /// Length of the whole table, including this header
+    pub fn length(&self) -> usize {
+        self.length as usize
+    }
+    pub fn revision(&self) -> u8 {
+        self.revision
+    }
+    /// OEM and OEM table identifiers
+    pub fn oem(&self) -> (&[u8; 6], &[u8; 8]) {
+        (&self.oem_id, &self.oem_table_id)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// Reasons that a table, or an entry in a table, was rejected
+pub enum TableError {
+    /// Some part of the table is not accessible in the window
+    Unmappable,
+    /// Length is smaller than the structure it claims to be
+    TooShort,
+    /// Length runs beyond the end of the containing table
+    Overrun,
+    /// Bytes of the table do not sum to zero
+    Checksum,
+    /// Table overlaps the RSDT or a table listed before it
+    Overlap,
+}
+
 #[repr(packed)]
 #[derive(Debug)]
-/// General MADT header
-pub struct MADTHeader {
-    madt_type: u8,
+/// Header of the variable length entries that follow some tables, such as
+/// the MADT and SRAT
+pub struct EntryHeader {
+    entry_type: u8,
     length: u8,
 }
 
+/// General MADT header
+pub type MADTHeader = EntryHeader;
+
 #[repr(packed)]
 #[derive(Debug)]
 /// MADT entry describing a CPU
@@ -76,13 +127,135 @@ pub struct MADTISO {
     flags: u16,
 }
 
+#[repr(packed)]
+#[derive(Debug)]
+/// MADT entry describing which LINT pin of a processor's local APIC is
+/// connected to NMI
+pub struct MADTLocalNMI {
+    header: MADTHeader,
+    processor_uid: u8,
+    flags: u16,
+    lint: u8,
+}
+
+#[repr(packed)]
+#[derive(Debug)]
+/// MADT entry giving a 64-bit address for the local APICs, overriding the
+/// one in the MADT header
+pub struct MADTAddressOverride {
+    header: MADTHeader,
+    reserved: u16,
+    address: u64,
+}
+
+#[repr(packed)]
+#[derive(Debug)]
+/// MADT entry describing a CPU whose APIC ID does not fit in 8 bits
+pub struct MADTLocalX2APIC {
+    header: MADTHeader,
+    reserved: u16,
+    x2apic_id: u32,
+    flags: u32,
+    processor_uid: u32,
+}
+
+#[repr(packed)]
+#[derive(Debug)]
+/// MADT entry describing which LINT pin of an x2APIC is connected to NMI
+pub struct MADTX2APICNMI {
+    header: MADTHeader,
+    flags: u16,
+    processor_uid: u32,
+    lint: u8,
+    reserved: [u8; 3],
+}
+
+/// Local APIC entry flag indicating the processor is usable
+const MADT_APIC_ENABLED: u32 = 1 << 0;
+/// Processor UID in an 8-bit NMI entry meaning every processor
+const MADT_ALL_PROCESSORS: u8 = 0xff;
+/// Processor UID meaning every processor
+pub const ALL_PROCESSORS: u32 = 0xffff_ffff;
+
+impl MADTAPIC {
+    /// ACPI processor UID, as used by the NMI entries
+    pub fn processor_uid(&self) -> u32 {
+        self.cpu_id as u32
+    }
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id as u32
+    }
+    pub fn enabled(&self) -> bool {
+        self.flags & MADT_APIC_ENABLED != 0
+    }
+}
+
+impl MADTLocalNMI {
+    /// UID of the processor this applies to, or `ALL_PROCESSORS`
+    pub fn processor_uid(&self) -> u32 {
+        if self.processor_uid == MADT_ALL_PROCESSORS {
+            ALL_PROCESSORS
+        } else {
+            self.processor_uid as u32
+        }
+    }
+    /// MPS INTI flags describing the polarity and trigger mode
+    pub fn flags(&self) -> u16 {
+        self.flags
+    }
+    /// Which LINT pin, 0 or 1
+    pub fn lint(&self) -> u8 {
+        self.lint
+    }
+}
+
+impl MADTAddressOverride {
+    pub fn address(&self) -> PAddr {
+        PAddr(self.address as usize)
+    }
+}
+
+impl MADTLocalX2APIC {
+    /// ACPI processor UID, as used by the NMI entries
+    pub fn processor_uid(&self) -> u32 {
+        self.processor_uid
+    }
+    pub fn apic_id(&self) -> u32 {
+        self.x2apic_id
+    }
+    pub fn enabled(&self) -> bool {
+        self.flags & MADT_APIC_ENABLED != 0
+    }
+}
+
+impl MADTX2APICNMI {
+    /// UID of the processor this applies to, or `ALL_PROCESSORS`
+    pub fn processor_uid(&self) -> u32 {
+        self.processor_uid
+    }
+    /// MPS INTI flags describing the polarity and trigger mode
+    pub fn flags(&self) -> u16 {
+        self.flags
+    }
+    /// Which LINT pin, 0 or 1
+    pub fn lint(&self) -> u8 {
+        self.lint
+    }
+}
+
 #[derive(Debug)]
 /// Enumeration of different possible MADT tables
 pub enum MADTTable<'a> {
     APIC(&'a MADTAPIC),
     IOAPIC(&'a MADTIOAPIC),
     ISO(&'a MADTISO),
+    LocalNMI(&'a MADTLocalNMI),
+    AddressOverride(&'a MADTAddressOverride),
+    LocalX2APIC(&'a MADTLocalX2APIC),
+    X2APICNMI(&'a MADTX2APICNMI),
     Unknown(&'a MADTHeader),
+    /// Entry at the given physical address was malformed
+    Invalid(PAddr, TableError),
 }
 
 #[repr(packed)]
@@ -96,21 +269,69 @@ pub struct MADT {
 }
 
 impl MADT {
+    /// 32-bit physical address of the local APICs. This may be overridden
+    /// by a `MADTTable::AddressOverride` entry
+    pub fn local_apic_address(&self) -> PAddr {
+        PAddr(self.apic_addr as usize)
+    }
     /// Construct an iterator over entries inside this MADT entry
     /// A VSpaceWindow must be passed in order to access the memory that
-    /// is beyond the initial bounds of this struct
+    /// is beyond the initial bounds of this struct. This must be the same
+    /// window that this MADT was constructed from
     pub fn iter<'a, T:VSpaceWindow<'a>>(&self, window: &'a T) -> MADTIter<'a, T> {
-        let start = self as *const MADT as usize;
-        MADTIter {
-            window: window,
-            start: PAddr(start + size_of::<MADT>()),
-            end: PAddr(start + self.header.length as usize),
-        }
+        EntryIter::new(window, self as *const MADT as usize, size_of::<MADT>(),
+            self.header.length as usize)
     }
 }
 
-/// Helper struct for constructing an iterator over the entries in an MADT
-pub struct MADTIter<'a, T:VSpaceWindow<'a>> where T: 'a {
+/// Common header of variable length table entries
+pub trait EntryLength {
+    /// Length in bytes of the entry, including the header
+    fn entry_length(&self) -> usize;
+}
+
+impl EntryLength for EntryHeader {
+    fn entry_length(&self) -> usize {
+        self.length as usize
+    }
+}
+
+/// A type that variable length table entries can be decoded into
+pub trait Entry<'a>: Sized {
+    /// Header that all entries of this kind start with
+    type Header: EntryLength + 'a;
+    /// Decode an entry whose length has been checked to be within its
+    /// table. The entry may still be too short for what its type implies
+    fn decode(header: &'a Self::Header, at: PAddr) -> Self;
+    /// Construct the value representing a malformed entry
+    fn invalid(at: PAddr, err: TableError) -> Self;
+}
+
+impl<'a> Entry<'a> for MADTTable<'a> {
+    type Header = EntryHeader;
+    fn decode(header: &'a EntryHeader, at: PAddr) -> MADTTable<'a> {
+        let table = unsafe {
+            match header.entry_type {
+                0 => cast_entry(header).map(MADTTable::APIC),
+                1 => cast_entry(header).map(MADTTable::IOAPIC),
+                2 => cast_entry(header).map(MADTTable::ISO),
+                4 => cast_entry(header).map(MADTTable::LocalNMI),
+                5 => cast_entry(header).map(MADTTable::AddressOverride),
+                9 => cast_entry(header).map(MADTTable::LocalX2APIC),
+                0xa => cast_entry(header).map(MADTTable::X2APICNMI),
+                _ => Some(MADTTable::Unknown(header)),
+            }
+        };
+        table.unwrap_or(MADTTable::Invalid(at, TableError::TooShort))
+    }
+    fn invalid(at: PAddr, err: TableError) -> MADTTable<'a> {
+        MADTTable::Invalid(at, err)
+    }
+}
+
+/// Helper struct for constructing an iterator over the variable length
+/// entries that follow a table
+pub struct EntryIter<'a, T:VSpaceWindow<'a>, E> where T: 'a {
     /// Stored window for translating physical addressese of tables into
     /// valid pointers
     window: &'a T,
@@ -118,142 +339,1594 @@ pub struct MADTIter<'a, T:VSpaceWindow<'a>> where T: 'a {
     start: PAddr,
     /// Address just beyond the end of the last table
     end: PAddr,
+    phantom: PhantomData<E>,
+}
+
+/// Helper struct for constructing an iterator over the entries in an MADT
+pub type MADTIter<'a, T> = EntryIter<'a, T, MADTTable<'a>>;
+
+impl<'a, T:VSpaceWindow<'a>, E: Entry<'a>> EntryIter<'a, T, E> {
+    /// Construct an iterator over the entries of a validated table at
+    /// virtual address `table`, with the entries starting `offset` bytes in
+    fn new(window: &'a T, table: usize, offset: usize, length: usize) -> EntryIter<'a, T, E> {
+        let start = unsafe {
+            window.to_paddr(window.to_addr(table))
+        };
+        EntryIter {
+            window: window,
+            start: PAddr(start.0 + offset),
+            end: PAddr(start.0 + length),
+            phantom: PhantomData,
+        }
+    }
+    /// Validate and decode the entry at the current position. On success
+    /// returns the decoded entry along with its length. An entry whose
+    /// length is consistent with the table, but too short for its type,
+    /// decodes successfully as an invalid entry so it can be skipped.
+    /// Any other error means the rest of the table cannot be trusted
+    fn decode(&self) -> Result<(E, usize), TableError> {
+        if self.end.0 - self.start.0 < size_of::<E::Header>() {
+            return Err(TableError::Overrun);
+        }
+        let addr = try!(self.window.try_from_paddr(self.start)
+            .ok_or(TableError::Unmappable));
+        let header: &'a E::Header = try!(unsafe{self.window.make(addr)}
+            .ok_or(TableError::Unmappable));
+        let length = header.entry_length();
+        /* A length smaller than the header would never advance us */
+        if length < size_of::<E::Header>() {
+            return Err(TableError::TooShort);
+        }
+        if length > self.end.0 - self.start.0 {
+            return Err(TableError::Overrun);
+        }
+        if unsafe{self.window.make_slice::<u8>(addr, length)}.is_none() {
+            return Err(TableError::Unmappable);
+        }
+        Ok((E::decode(header, self.start), length))
+    }
+}
+
+/// Reinterpret a table entry as a specific type, provided the length the
+/// entry claims to have is large enough
+unsafe fn cast_entry<'a, H: EntryLength, E>(header: &'a H) -> Option<&'a E> {
+    if header.entry_length() < size_of::<E>() {
+        None
+    } else {
+        Some(transmute(header))
+    }
 }
 
-impl<'a, T:VSpaceWindow<'a>> Iterator for MADTIter<'a, T> {
-    type Item = MADTTable<'a>;
-    fn next(&mut self) -> Option<MADTTable<'a>> {
+impl<'a, T:VSpaceWindow<'a>, E: Entry<'a>> Iterator for EntryIter<'a, T, E> {
+    type Item = E;
+    fn next(&mut self) -> Option<E> {
         if self.start >= self.end {
-            None
+            return None;
+        }
+        match self.decode() {
+            Ok((table, length)) => {
+                self.start.0 += length;
+                Some(table)
+            },
+            Err(e) => {
+                /* Cannot trust anything after this point */
+                let at = self.start;
+                self.start = self.end;
+                Some(E::invalid(at, e))
+            },
+        }
+    }
+}
+
+#[repr(packed)]
+#[derive(Debug, Copy, Clone)]
+/// ACPI Generic Address Structure, describing a register in one of several
+/// address spaces
+pub struct GenericAddress {
+    pub space_id: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+/// `GenericAddress` space for system memory
+pub const GAS_SPACE_MEMORY: u8 = 0;
+/// `GenericAddress` space for system I/O ports
+pub const GAS_SPACE_IO: u8 = 1;
+/// `GenericAddress` space for PCI configuration space
+pub const GAS_SPACE_PCI_CONFIG: u8 = 2;
+
+#[repr(packed)]
+#[derive(Debug)]
+/// Fixed ACPI Description Table. The structure here is the ACPI 2.0+
+/// layout, but tables from older firmware may be as short as the original
+/// ACPI 1.0 layout. Any field beyond `flags` should only be accessed
+/// after checking the length of the table covers it, which is what the
+/// accessor functions do
+pub struct FADT {
+    header: ACPIHeader,
+    firmware_ctrl: u32,
+    dsdt: u32,
+    reserved0: u8,
+    preferred_pm_profile: u8,
+    sci_int: u16,
+    smi_cmd: u32,
+    acpi_enable: u8,
+    acpi_disable: u8,
+    s4bios_req: u8,
+    pstate_cnt: u8,
+    pm1a_evt_blk: u32,
+    pm1b_evt_blk: u32,
+    pm1a_cnt_blk: u32,
+    pm1b_cnt_blk: u32,
+    pm2_cnt_blk: u32,
+    pm_tmr_blk: u32,
+    gpe0_blk: u32,
+    gpe1_blk: u32,
+    pm1_evt_len: u8,
+    pm1_cnt_len: u8,
+    pm2_cnt_len: u8,
+    pm_tmr_len: u8,
+    gpe0_blk_len: u8,
+    gpe1_blk_len: u8,
+    gpe1_base: u8,
+    cst_cnt: u8,
+    p_lvl2_lat: u16,
+    p_lvl3_lat: u16,
+    flush_size: u16,
+    flush_stride: u16,
+    duty_offset: u8,
+    duty_width: u8,
+    day_alrm: u8,
+    mon_alrm: u8,
+    century: u8,
+    iapc_boot_arch: u16,
+    reserved1: u8,
+    flags: u32,
+    /* Everything below here is not present in ACPI 1.0 tables */
+    reset_reg: GenericAddress,
+    reset_value: u8,
+    arm_boot_arch: u16,
+    fadt_minor_version: u8,
+    x_firmware_ctrl: u64,
+    x_dsdt: u64,
+    x_pm1a_evt_blk: GenericAddress,
+    x_pm1b_evt_blk: GenericAddress,
+    x_pm1a_cnt_blk: GenericAddress,
+    x_pm1b_cnt_blk: GenericAddress,
+    x_pm2_cnt_blk: GenericAddress,
+    x_pm_tmr_blk: GenericAddress,
+    x_gpe0_blk: GenericAddress,
+    x_gpe1_blk: GenericAddress,
+}
+
+/// Length of an ACPI 1.0 FADT, which ends after the `flags` field
+const FADT_V1_LENGTH: usize = 116;
+/// Table length needed to cover `reset_reg` and `reset_value`
+const FADT_RESET_LENGTH: usize = 129;
+/// Table length needed to cover `x_dsdt`
+const FADT_X_DSDT_LENGTH: usize = 148;
+/// Table length needed to cover `x_pm_tmr_blk`
+const FADT_X_PM_TMR_LENGTH: usize = 220;
+/// FADT flag indicating `reset_reg` is supported
+const FADT_FLAG_RESET_REG_SUP: u32 = 1 << 10;
+
+impl FADT {
+    /// Test if the table is long enough to contain fields up to `len`
+    fn covers(&self, len: usize) -> bool {
+        self.header.length as usize >= len
+    }
+    /// Interrupt vector, in 8259 terms, of the SCI interrupt
+    pub fn sci_interrupt(&self) -> u16 {
+        self.sci_int
+    }
+    /// Physical address of the DSDT, preferring the 64-bit field if it
+    /// is present and set
+    pub fn dsdt_address(&self) -> PAddr {
+        if self.covers(FADT_X_DSDT_LENGTH) && self.x_dsdt != 0 {
+            PAddr(self.x_dsdt as usize)
         } else {
-            unsafe {
-                self.window.try_from_paddr(self.start)
-                    .and_then(|addr| self.window.make::<MADTHeader>(addr))
-                    .map(|t| {
-                        self.start.0 += t.length as usize;
-                        match t.madt_type {
-                            0 => MADTTable::APIC(transmute(t)),
-                            1 => MADTTable::IOAPIC(transmute(t)),
-                            2 => MADTTable::ISO(transmute(t)),
-                            _ => MADTTable::Unknown(t),
-                        }
-                    }
-                )
+            PAddr(self.dsdt as usize)
+        }
+    }
+    /// I/O port of the 24/32-bit ACPI PM timer, if there is one
+    pub fn pm_timer_port(&self) -> Option<u16> {
+        if self.covers(FADT_X_PM_TMR_LENGTH) && self.x_pm_tmr_blk.address != 0 {
+            if self.x_pm_tmr_blk.space_id == GAS_SPACE_IO {
+                return Some(self.x_pm_tmr_blk.address as u16);
             }
+            return None;
+        }
+        if self.pm_tmr_blk != 0 && self.pm_tmr_len == 4 {
+            Some(self.pm_tmr_blk as u16)
+        } else {
+            None
+        }
+    }
+    /// Register, and value to write to it, that will reset the system
+    pub fn reset_register(&self) -> Option<(GenericAddress, u8)> {
+        if self.covers(FADT_RESET_LENGTH) && (self.flags & FADT_FLAG_RESET_REG_SUP) != 0 {
+            Some((self.reset_reg, self.reset_value))
+        } else {
+            None
+        }
+    }
+    /// I/O ports of the PM1a and optional PM1b control blocks
+    pub fn pm1_control_ports(&self) -> Option<(u16, Option<u16>)> {
+        if self.pm1a_cnt_blk == 0 {
+            return None;
+        }
+        let b = if self.pm1b_cnt_blk != 0 { Some(self.pm1b_cnt_blk as u16) } else { None };
+        Some((self.pm1a_cnt_blk as u16, b))
+    }
+    /// SMI command port and the value to write to it to switch the
+    /// chipset into ACPI mode. `None` if the system is always in ACPI mode
+    pub fn acpi_enable(&self) -> Option<(u16, u8)> {
+        if self.smi_cmd != 0 && self.acpi_enable != 0 {
+            Some((self.smi_cmd as u16, self.acpi_enable))
+        } else {
+            None
         }
     }
 }
 
-/// ACPI walker state
-pub struct ACPI<'a, T> where T: VSpaceWindow<'a> + 'a {
-    /// VSpaceWindow where any ACPI tables must live
-    window: &'a T,
-    /// Reference to the RSDT header
-    rsdt_header: &'a ACPIHeader,
-    /// Raw reference to the first RSDT table
-    rsdt_table: PAddr,
+#[repr(packed)]
+#[derive(Debug)]
+/// HPET description table
+pub struct HPET {
+    header: ACPIHeader,
+    event_timer_block_id: u32,
+    base_address: GenericAddress,
+    hpet_number: u8,
+    minimum_tick: u16,
+    page_protection: u8,
 }
 
-/// Helper struct for iterating over the entires in the RSDT
-pub struct RSDTIter<'a, T: VSpaceWindow<'a>> where T: 'a {
-    window: &'a T,
-    iter: Option<slice::Iter<'a, u32>>,
+impl HPET {
+    /// Physical address of the HPET register block. The HPET must be
+    /// memory mapped, so any other address space is rejected
+    pub fn base(&self) -> Option<PAddr> {
+        if self.base_address.space_id == GAS_SPACE_MEMORY && self.base_address.address != 0 {
+            Some(PAddr(self.base_address.address as usize))
+        } else {
+            None
+        }
+    }
+    /// Sequence number of this HPET block
+    pub fn number(&self) -> u8 {
+        self.hpet_number
+    }
+    /// Minimum number of ticks that a periodic comparator can be programmed
+    /// with without losing interrupts
+    pub fn minimum_tick(&self) -> u16 {
+        self.minimum_tick
+    }
 }
 
+#[repr(packed)]
 #[derive(Debug)]
-/// Enumeration of different RSDT tables
-pub enum RSDTTable<'a> {
-    MADT(&'a MADT),
-    Unknown(&'a ACPIHeader),
+/// PCI Express memory mapped configuration space description. Followed by
+/// a variable number of `MCFGEntry`
+pub struct MCFG {
+    header: ACPIHeader,
+    reserved: u64,
 }
 
-impl<'a, T:VSpaceWindow<'a>> Iterator for RSDTIter<'a, T> {
-    type Item = RSDTTable<'a>;
-    fn next(&mut self) -> Option<RSDTTable<'a>> {
-        self.iter.as_mut()
-            .and_then(|i| i.next())
-            .and_then(|h| self.window.try_from_paddr(PAddr(*h as usize)))
-            .and_then(|addr| unsafe {
-                self.window.make::<ACPIHeader>(addr)
-            }).and_then(|h|
-                unsafe{Some(match &h.signature {
-                    b"APIC" => RSDTTable::MADT(transmute(h)),
-                    _ => RSDTTable::Unknown(h),
-                })}
-            )
-    }
-}
-
-/// Perform a checksum over the requested range. This is paramaterized over
-/// a type for convenience of calling, but a range still needs to be passed.
-/// We cannot use the size of the type provided, since we need to handle
-/// strutures that might get extended in future versions
-fn checksum<T>(base: &T, len: usize) -> bool {
-    unsafe {
-        slice::from_raw_parts(base as *const T as *const u8, len)
-    }.iter().fold(
-        0u8,
-        |p, v| (Wrapping(*&p) + Wrapping(*v)).0
-    ) == 0
+#[repr(packed)]
+#[derive(Debug)]
+/// An ECAM region for a range of buses in a PCI segment
+pub struct MCFGEntry {
+    base_address: u64,
+    segment: u16,
+    start_bus: u8,
+    end_bus: u8,
+    reserved: u32,
 }
 
-/// Find the RSDP by walking the various BIOS regions
-fn find_rsdp<'a, T: VSpaceWindow<'a>>(window: &'a T) -> Option<&'a RSDP> {
-    for addr in (0xE0_000..0x100_000).step_by(16) {
-        let candidate: &RSDP = match unsafe{window.try_from_paddr(PAddr(addr))
-                .and_then(|addr| window.make(addr))} {
-            Some(c) => c,
-            None => return None,
-        };
-        if &candidate.signature == b"RSD PTR " && checksum(candidate, 20) {
-            return Some(candidate);
+impl MCFGEntry {
+    /// Physical address of the configuration space of `start_bus`
+    pub fn base(&self) -> PAddr {
+        PAddr(self.base_address as usize)
+    }
+    /// PCI segment group this region is for
+    pub fn segment(&self) -> u16 {
+        self.segment
+    }
+    /// Inclusive range of buses decoded by this region
+    pub fn buses(&self) -> (u8, u8) {
+        (self.start_bus, self.end_bus)
+    }
+}
+
+impl MCFG {
+    /// Retrieve the ECAM regions described by this table. As the table
+    /// was validated in its entirety when found, the entries after the
+    /// structure are already known to be accessible
+    pub fn entries(&self) -> &[MCFGEntry] {
+        let count = (self.header.length as usize - size_of::<MCFG>()) / size_of::<MCFGEntry>();
+        unsafe {
+            slice::from_raw_parts(
+                (self as *const MCFG as usize + size_of::<MCFG>()) as *const MCFGEntry,
+                count)
         }
     }
-    None
 }
 
-/// Due to current limitations this cannot be a closure
-fn extract_madt<'a>(header:RSDTTable<'a>) -> Option<&'a MADT> {
-    if let RSDTTable::MADT(madt) = header {
-        Some(madt)
-    } else {
-        None
+#[repr(packed)]
+#[derive(Debug)]
+/// System Resource Affinity Table. Followed by a variable length list of
+/// entries assigning processors and memory to proximity domains
+pub struct SRAT {
+    header: ACPIHeader,
+    table_revision: u32,
+    reserved: u64,
+}
+
+#[repr(packed)]
+#[derive(Debug)]
+/// SRAT entry placing a local APIC in a proximity domain
+pub struct SRATLocalAPIC {
+    header: EntryHeader,
+    proximity_lo: u8,
+    apic_id: u8,
+    flags: u32,
+    local_sapic_eid: u8,
+    proximity_hi: [u8; 3],
+    clock_domain: u32,
+}
+
+#[repr(packed)]
+#[derive(Debug)]
+/// SRAT entry placing a range of memory in a proximity domain
+pub struct SRATMemory {
+    header: EntryHeader,
+    proximity: u32,
+    reserved0: u16,
+    base_lo: u32,
+    base_hi: u32,
+    length_lo: u32,
+    length_hi: u32,
+    reserved1: u32,
+    flags: u32,
+    reserved2: u64,
+}
+
+#[repr(packed)]
+#[derive(Debug)]
+/// SRAT entry placing an x2APIC in a proximity domain
+pub struct SRATLocalX2APIC {
+    header: EntryHeader,
+    reserved0: u16,
+    proximity: u32,
+    x2apic_id: u32,
+    flags: u32,
+    clock_domain: u32,
+    reserved1: u32,
+}
+
+/// SRAT entry flag indicating the entry is in use
+const SRAT_ENABLED: u32 = 1 << 0;
+
+impl SRATLocalAPIC {
+    pub fn proximity_domain(&self) -> u32 {
+        self.proximity_lo as u32
+            | (self.proximity_hi[0] as u32) << 8
+            | (self.proximity_hi[1] as u32) << 16
+            | (self.proximity_hi[2] as u32) << 24
+    }
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id as u32
+    }
+    pub fn enabled(&self) -> bool {
+        self.flags & SRAT_ENABLED != 0
     }
 }
 
-impl<'a, T: VSpaceWindow<'a>> ACPI<'a, T> {
-    /// Try and construct a new ACPI table reference. This will fail if
-    /// no reference to an ACPI table is found whilst scanning the BIOS
-    /// regions, or if the passed window cannot map the tables
-    pub fn new(window: &'a T) -> Option<ACPI<'a, T>> {
-        find_rsdp(window)
-            .and_then(|rsdp| window.try_from_paddr(PAddr(rsdp.rsdt_address as usize)))
-            .and_then(|addr| unsafe{window.make(addr)})
-            .map(|rsdt| ACPI { window: window,
-                rsdt_header: rsdt,
-                rsdt_table: PAddr(rsdt as *const ACPIHeader as usize + size_of::<ACPIHeader>())
-            })
+impl SRATMemory {
+    pub fn proximity_domain(&self) -> u32 {
+        self.proximity
     }
-    /// Constructs an iterator over all the RSDT entries
-    pub fn rsdt_iter(&self) -> RSDTIter<'a, T> {
-        RSDTIter { window: self.window,
-            iter: unsafe{
-                    self.window.try_from_paddr(self.rsdt_table).
-                        and_then(|addr|
-                            self.window.make_slice(addr,
-                                (self.rsdt_header.length as usize - size_of::<ACPIHeader>()) / size_of::<u32>()
-                            )
-                        )
-                }.map(|s| s.iter())
+    /// Physical range covered, as a (start, end) pair
+    pub fn range(&self) -> (PAddr, PAddr) {
+        let base = (self.base_hi as u64) << 32 | self.base_lo as u64;
+        let length = (self.length_hi as u64) << 32 | self.length_lo as u64;
+        (PAddr(base as usize), PAddr(base.saturating_add(length) as usize))
+    }
+    pub fn enabled(&self) -> bool {
+        self.flags & SRAT_ENABLED != 0
+    }
+}
+
+impl SRATLocalX2APIC {
+    pub fn proximity_domain(&self) -> u32 {
+        self.proximity
+    }
+    pub fn apic_id(&self) -> u32 {
+        self.x2apic_id
+    }
+    pub fn enabled(&self) -> bool {
+        self.flags & SRAT_ENABLED != 0
+    }
+}
+
+#[derive(Debug)]
+/// Enumeration of the different SRAT entries
+pub enum SRATEntry<'a> {
+    LocalAPIC(&'a SRATLocalAPIC),
+    Memory(&'a SRATMemory),
+    LocalX2APIC(&'a SRATLocalX2APIC),
+    Unknown(&'a EntryHeader),
+    /// Entry at the given physical address was malformed
+    Invalid(PAddr, TableError),
+}
+
+impl<'a> Entry<'a> for SRATEntry<'a> {
+    type Header = EntryHeader;
+    fn decode(header: &'a EntryHeader, at: PAddr) -> SRATEntry<'a> {
+        let entry = unsafe {
+            match header.entry_type {
+                0 => cast_entry(header).map(SRATEntry::LocalAPIC),
+                1 => cast_entry(header).map(SRATEntry::Memory),
+                2 => cast_entry(header).map(SRATEntry::LocalX2APIC),
+                _ => Some(SRATEntry::Unknown(header)),
+            }
+        };
+        entry.unwrap_or(SRATEntry::Invalid(at, TableError::TooShort))
+    }
+    fn invalid(at: PAddr, err: TableError) -> SRATEntry<'a> {
+        SRATEntry::Invalid(at, err)
+    }
+}
+
+/// Iterator over the entries of an SRAT
+pub type SRATIter<'a, T> = EntryIter<'a, T, SRATEntry<'a>>;
+
+impl SRAT {
+    /// Construct an iterator over the entries in this SRAT. As with the
+    /// MADT the window must be the one this table was found in
+    pub fn iter<'a, T:VSpaceWindow<'a>>(&self, window: &'a T) -> SRATIter<'a, T> {
+        EntryIter::new(window, self as *const SRAT as usize, size_of::<SRAT>(),
+            self.header.length as usize)
+    }
+}
+
+#[repr(packed)]
+#[derive(Debug)]
+/// System Locality Information Table. Followed by a square matrix of
+/// relative distances between proximity domains
+pub struct SLIT {
+    header: ACPIHeader,
+    localities: u64,
+}
+
+impl SLIT {
+    /// Number of localities, which is the dimension of the matrix
+    pub fn localities(&self) -> usize {
+        self.localities as usize
+    }
+    /// Relative distance from locality `from` to locality `to`. A distance
+    /// of 10 means local, with larger numbers being further away
+    pub fn distance(&self, from: usize, to: usize) -> Option<u8> {
+        let n = self.localities();
+        if from >= n || to >= n {
+            return None;
         }
+        /* `decode_table` already checked the matrix fits in the table */
+        let matrix = (self as *const SLIT as usize + size_of::<SLIT>()) as *const u8;
+        Some(unsafe{*matrix.offset((from * n + to) as isize)})
     }
-    /// Constructs an iterator over just the MADT entries in the RSDT
-    /// This is just filtering the results from `rsdt_iter`
-    pub fn madt_iter<>(&self)
-            -> FilterMap<RSDTIter<'a, T>,
-                fn(RSDTTable<'a>) -> Option<&'a MADT>>
-            {
-        self.rsdt_iter()
-            .filter_map(extract_madt as fn(RSDTTable<'a>) -> Option<&'a MADT>)
+}
+
+/// Check that a SLIT is long enough to contain its distance matrix
+fn slit_valid(slit: &SLIT) -> bool {
+    let n = slit.localities;
+    n <= 0xffff && (slit.header.length as u64) >= size_of::<SLIT>() as u64 + n * n
+}
+
+#[repr(packed)]
+#[derive(Debug)]
+/// DMA Remapping table, describing the VT-d remapping hardware. Followed
+/// by a variable length list of remapping structures
+pub struct DMAR {
+    header: ACPIHeader,
+    host_address_width: u8,
+    flags: u8,
+    reserved: [u8; 10],
+}
+
+#[repr(packed)]
+#[derive(Debug)]
+/// Header of a DMAR remapping structure. Unlike most tables these have
+/// 16-bit type and length fields
+pub struct DMARHeader {
+    entry_type: u16,
+    length: u16,
+}
+
+impl EntryLength for DMARHeader {
+    fn entry_length(&self) -> usize {
+        self.length as usize
+    }
+}
+
+#[repr(packed)]
+#[derive(Debug)]
+/// DMA Remapping Hardware unit Definition. Followed by device scopes
+pub struct DMARDRHD {
+    header: DMARHeader,
+    flags: u8,
+    reserved: u8,
+    segment: u16,
+    register_base: u64,
+}
+
+#[repr(packed)]
+#[derive(Debug)]
+/// Reserved Memory Region Reporting structure. Describes memory that the
+/// devices in its scope may DMA to behind the operating system's back, so
+/// must always be identity mapped for them. Followed by device scopes
+pub struct DMARRMRR {
+    header: DMARHeader,
+    reserved: u16,
+    segment: u16,
+    base: u64,
+    limit: u64,
+}
+
+#[repr(packed)]
+#[derive(Debug)]
+/// Device scope entry of a DRHD or RMRR. Followed by a path of
+/// (device, function) pairs from `start_bus`
+pub struct DMARDeviceScope {
+    header: EntryHeader,
+    reserved: u16,
+    enumeration_id: u8,
+    start_bus: u8,
+}
+
+/// DRHD flag indicating the unit covers all devices not covered by others
+const DRHD_INCLUDE_PCI_ALL: u8 = 1 << 0;
+
+/// Device scope type of a PCI endpoint
+pub const SCOPE_PCI_ENDPOINT: u8 = 1;
+/// Device scope type of a PCI bridge and everything behind it
+pub const SCOPE_PCI_SUB_HIERARCHY: u8 = 2;
+/// Device scope type of an I/O APIC
+pub const SCOPE_IOAPIC: u8 = 3;
+/// Device scope type of an HPET
+pub const SCOPE_HPET: u8 = 4;
+
+impl DMAR {
+    /// Width of physical addresses the platform supports, in bits
+    pub fn host_address_width(&self) -> u8 {
+        self.host_address_width + 1
+    }
+    /// Construct an iterator over the remapping structures in this table.
+    /// The window must be the one this table was found in
+    pub fn iter<'a, T:VSpaceWindow<'a>>(&self, window: &'a T) -> DMARIter<'a, T> {
+        EntryIter::new(window, self as *const DMAR as usize, size_of::<DMAR>(),
+            self.header.length as usize)
+    }
+}
+
+impl DMARDRHD {
+    /// Physical address of the unit's register block
+    pub fn register_base(&self) -> PAddr {
+        PAddr(self.register_base as usize)
+    }
+    pub fn segment(&self) -> u16 {
+        self.segment
+    }
+    /// True if this unit handles every device not claimed by another unit
+    pub fn include_pci_all(&self) -> bool {
+        self.flags & DRHD_INCLUDE_PCI_ALL != 0
+    }
+    /// Iterate over the device scopes of this unit
+    pub fn scopes<'a, T:VSpaceWindow<'a>>(&self, window: &'a T) -> DeviceScopeIter<'a, T> {
+        EntryIter::new(window, self as *const DMARDRHD as usize, size_of::<DMARDRHD>(),
+            self.header.length as usize)
+    }
+}
+
+impl DMARRMRR {
+    /// Physical range, as a (start, end) pair, that must be identity mapped
+    pub fn range(&self) -> (PAddr, PAddr) {
+        (PAddr(self.base as usize), PAddr(self.limit.saturating_add(1) as usize))
+    }
+    pub fn segment(&self) -> u16 {
+        self.segment
+    }
+    /// Iterate over the devices that use this region
+    pub fn scopes<'a, T:VSpaceWindow<'a>>(&self, window: &'a T) -> DeviceScopeIter<'a, T> {
+        EntryIter::new(window, self as *const DMARRMRR as usize, size_of::<DMARRMRR>(),
+            self.header.length as usize)
+    }
+}
+
+impl DMARDeviceScope {
+    /// Type of device, one of the `SCOPE_*` constants
+    pub fn scope_type(&self) -> u8 {
+        self.header.entry_type
+    }
+    /// I/O APIC or HPET number for those scope types
+    pub fn enumeration_id(&self) -> u8 {
+        self.enumeration_id
+    }
+    pub fn start_bus(&self) -> u8 {
+        self.start_bus
+    }
+    /// The (device, function) hops from the start bus to the device
+    pub fn path(&self) -> &[[u8; 2]] {
+        let count = (self.header.length as usize - size_of::<DMARDeviceScope>()) / 2;
+        unsafe {
+            slice::from_raw_parts(
+                (self as *const DMARDeviceScope as usize + size_of::<DMARDeviceScope>())
+                    as *const [u8; 2],
+                count)
+        }
+    }
+}
+
+#[derive(Debug)]
+/// Enumeration of the remapping structures in a DMAR
+pub enum DMAREntry<'a> {
+    DRHD(&'a DMARDRHD),
+    RMRR(&'a DMARRMRR),
+    Unknown(&'a DMARHeader),
+    /// Entry at the given physical address was malformed
+    Invalid(PAddr, TableError),
+}
+
+impl<'a> Entry<'a> for DMAREntry<'a> {
+    type Header = DMARHeader;
+    fn decode(header: &'a DMARHeader, at: PAddr) -> DMAREntry<'a> {
+        let entry = unsafe {
+            match header.entry_type {
+                0 => cast_entry(header).map(DMAREntry::DRHD),
+                1 => cast_entry(header).map(DMAREntry::RMRR),
+                _ => Some(DMAREntry::Unknown(header)),
+            }
+        };
+        entry.unwrap_or(DMAREntry::Invalid(at, TableError::TooShort))
+    }
+    fn invalid(at: PAddr, err: TableError) -> DMAREntry<'a> {
+        DMAREntry::Invalid(at, err)
+    }
+}
+
+#[derive(Debug)]
+/// Device scope entries, as found in DRHD and RMRR structures
+pub enum DeviceScope<'a> {
+    Scope(&'a DMARDeviceScope),
+    Unknown(&'a EntryHeader),
+    /// Entry at the given physical address was malformed
+    Invalid(PAddr, TableError),
+}
+
+impl<'a> Entry<'a> for DeviceScope<'a> {
+    type Header = EntryHeader;
+    fn decode(header: &'a EntryHeader, at: PAddr) -> DeviceScope<'a> {
+        unsafe {
+            match header.entry_type {
+                SCOPE_PCI_ENDPOINT ... SCOPE_HPET => cast_entry(header)
+                    .map_or(DeviceScope::Invalid(at, TableError::TooShort), DeviceScope::Scope),
+                _ => DeviceScope::Unknown(header),
+            }
+        }
+    }
+    fn invalid(at: PAddr, err: TableError) -> DeviceScope<'a> {
+        DeviceScope::Invalid(at, err)
+    }
+}
+
+/// Iterator over the remapping structures of a DMAR
+pub type DMARIter<'a, T> = EntryIter<'a, T, DMAREntry<'a>>;
+/// Iterator over the device scopes of a remapping structure
+pub type DeviceScopeIter<'a, T> = EntryIter<'a, T, DeviceScope<'a>>;
+
+#[repr(packed)]
+#[derive(Debug)]
+/// Serial Port Console Redirection table, describing the serial port the
+/// firmware used as its console. This is the revision 2 layout, later
+/// revisions add fields that are only read after checking the length
+pub struct SPCR {
+    header: ACPIHeader,
+    interface_type: u8,
+    reserved0: [u8; 3],
+    base_address: GenericAddress,
+    interrupt_type: u8,
+    irq: u8,
+    gsi: u32,
+    baud_rate: u8,
+    parity: u8,
+    stop_bits: u8,
+    flow_control: u8,
+    terminal_type: u8,
+    language: u8,
+    pci_device_id: u16,
+    pci_vendor_id: u16,
+    pci_bus: u8,
+    pci_device: u8,
+    pci_function: u8,
+    pci_flags: u32,
+    pci_segment: u8,
+}
+
+/// Offset of the UART clock frequency in revision 3 and later SPCRs
+const SPCR_UART_CLOCK_OFFSET: usize = 76;
+/// Offset of the precise baud rate in revision 4 and later SPCRs
+const SPCR_PRECISE_BAUD_OFFSET: usize = 80;
+
+/// Serial interface type of a full 16550
+pub const SERIAL_16550: u16 = 0x0000;
+/// Serial interface type of a 16450, which is a subset of the 16550
+pub const SERIAL_16450: u16 = 0x0001;
+/// Serial interface type of a 16550 with register layout given by the
+/// generic address structure
+pub const SERIAL_16550_GAS: u16 = 0x0012;
+
+impl SPCR {
+    /// Interface type, one of the `SERIAL_*` constants if it is a UART we
+    /// know how to drive
+    pub fn interface_type(&self) -> u16 {
+        self.interface_type as u16
+    }
+    /// Location of the UART registers. An address of zero means the UART
+    /// is on the PCI device given by `pci_device`
+    pub fn base_address(&self) -> GenericAddress {
+        self.base_address
+    }
+    /// Baud rate the firmware configured, or `None` if the UART should be
+    /// left as it is
+    pub fn baud_rate(&self) -> Option<u32> {
+        if let Some(precise) = self.read_u32(SPCR_PRECISE_BAUD_OFFSET, 4) {
+            if precise != 0 {
+                return Some(precise);
+            }
+        }
+        match self.baud_rate {
+            3 => Some(9600),
+            4 => Some(19200),
+            6 => Some(57600),
+            7 => Some(115200),
+            _ => None,
+        }
+    }
+    /// Frequency of the UART input clock in Hz, if the firmware says
+    pub fn uart_clock(&self) -> Option<u32> {
+        self.read_u32(SPCR_UART_CLOCK_OFFSET, 3).and_then(|c| if c != 0 { Some(c) } else { None })
+    }
+    /// PCI function of the UART as (bus, device, function), if it is one
+    pub fn pci_device(&self) -> Option<(u8, u8, u8)> {
+        if self.pci_device_id == 0xffff || self.pci_vendor_id == 0xffff {
+            None
+        } else {
+            Some((self.pci_bus, self.pci_device, self.pci_function))
+        }
+    }
+    /// Read a field added in a later revision, if the table has it
+    fn read_u32(&self, offset: usize, revision: u8) -> Option<u32> {
+        if self.header.revision < revision || (self.header.length as usize) < offset + 4 {
+            return None;
+        }
+        /* `validate_table` already checked the whole length is mapped */
+        let field = (self as *const SPCR as usize + offset) as *const [u8; 4];
+        let b = unsafe{*field};
+        Some(b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24)
+    }
+}
+
+#[repr(packed)]
+#[derive(Debug)]
+/// Debug Port Table 2, listing the ports available for use by a kernel
+/// debugger. Followed by a list of `DBG2Device`
+pub struct DBG2 {
+    header: ACPIHeader,
+    devices_offset: u32,
+    devices_count: u32,
+}
+
+#[repr(packed)]
+#[derive(Debug)]
+/// Common start of every DBG2 device information structure
+pub struct DBG2DeviceHeader {
+    revision: u8,
+    length: u16,
+}
+
+impl EntryLength for DBG2DeviceHeader {
+    fn entry_length(&self) -> usize {
+        self.length as usize
+    }
+}
+
+#[repr(packed)]
+#[derive(Debug)]
+/// A debug port. Followed, at the offsets given, by arrays of generic
+/// address structures and address sizes describing its registers
+pub struct DBG2Device {
+    header: DBG2DeviceHeader,
+    register_count: u8,
+    namespace_length: u16,
+    namespace_offset: u16,
+    oem_data_length: u16,
+    oem_data_offset: u16,
+    port_type: u16,
+    port_subtype: u16,
+    reserved: u16,
+    base_address_offset: u16,
+    address_size_offset: u16,
+}
+
+/// DBG2 port type of a serial port
+pub const DBG2_PORT_SERIAL: u16 = 0x8000;
+
+impl DBG2 {
+    /// Construct an iterator over the debug ports in this table. The
+    /// window must be the one this table was found in
+    pub fn iter<'a, T:VSpaceWindow<'a>>(&self, window: &'a T) -> DBG2Iter<'a, T> {
+        /* the devices cannot overlap the fixed part of the table */
+        let offset = cmp::max(self.devices_offset as usize, size_of::<DBG2>());
+        EntryIter::new(window, self as *const DBG2 as usize, offset, self.header.length as usize)
+    }
+}
+
+impl DBG2Device {
+    /// Port type, such as `DBG2_PORT_SERIAL`
+    pub fn port_type(&self) -> u16 {
+        self.port_type
+    }
+    /// Port subtype. For serial ports this is one of the `SERIAL_*`
+    /// interface types also used by the SPCR
+    pub fn port_subtype(&self) -> u16 {
+        self.port_subtype
+    }
+    /// Registers of the port. Returns `None` if the array does not fit in
+    /// the structure
+    pub fn registers(&self) -> Option<&[GenericAddress]> {
+        let offset = self.base_address_offset as usize;
+        let count = self.register_count as usize;
+        if offset < size_of::<DBG2Device>()
+                || offset + count * size_of::<GenericAddress>() > self.header.length as usize {
+            return None;
+        }
+        Some(unsafe {
+            slice::from_raw_parts(
+                (self as *const DBG2Device as usize + offset) as *const GenericAddress,
+                count)
+        })
+    }
+}
+
+#[derive(Debug)]
+/// Enumeration of the debug ports in a DBG2
+pub enum DBG2Entry<'a> {
+    Device(&'a DBG2Device),
+    /// Entry at the given physical address was malformed
+    Invalid(PAddr, TableError),
+}
+
+impl<'a> Entry<'a> for DBG2Entry<'a> {
+    type Header = DBG2DeviceHeader;
+    fn decode(header: &'a DBG2DeviceHeader, at: PAddr) -> DBG2Entry<'a> {
+        unsafe{cast_entry(header)}
+            .map_or(DBG2Entry::Invalid(at, TableError::TooShort), DBG2Entry::Device)
+    }
+    fn invalid(at: PAddr, err: TableError) -> DBG2Entry<'a> {
+        DBG2Entry::Invalid(at, err)
+    }
+}
+
+/// Iterator over the debug ports of a DBG2
+pub type DBG2Iter<'a, T> = EntryIter<'a, T, DBG2Entry<'a>>;
+
+/// ACPI walker state
+pub struct ACPI<'a, T> where T: VSpaceWindow<'a> + 'a {
+    /// VSpaceWindow where any ACPI tables must live
+    window: &'a T,
+    /// Physical addresses of every table listed in the RSDT
+    rsdt_entries: &'a [u32],
+    /// Physical address and length of the RSDT
+    rsdt: (usize, usize),
+}
+
+/// Number of valid tables whose memory an `RSDTIter` remembers, to check
+/// later tables against without validating the earlier ones again
+const MAX_SEEN_TABLES: usize = 32;
+
+/// Helper struct for iterating over the entires in the RSDT
+pub struct RSDTIter<'a, T: VSpaceWindow<'a>> where T: 'a {
+    window: &'a T,
+    entries: &'a [u32],
+    /// Index of the next entry
+    next: usize,
+    rsdt: (usize, usize),
+    /// Physical address and length of the valid tables yielded so far
+    seen: [(usize, usize); MAX_SEEN_TABLES],
+    num_seen: usize,
+    /// Index of the first valid table that did not fit in `seen`. Entries
+    /// from here on are validated again to check for overlaps
+    unseen_from: usize,
+}
+
+#[derive(Debug)]
+/// Enumeration of different RSDT tables
+pub enum RSDTTable<'a> {
+    MADT(&'a MADT),
+    FADT(&'a FADT),
+    HPET(&'a HPET),
+    MCFG(&'a MCFG),
+    SRAT(&'a SRAT),
+    SLIT(&'a SLIT),
+    DMAR(&'a DMAR),
+    SPCR(&'a SPCR),
+    DBG2(&'a DBG2),
+    /// Secondary System Description Table, holding more AML
+    SSDT(&'a ACPIHeader),
+    Unknown(&'a ACPIHeader),
+    /// Table at the given physical address failed validation
+    Invalid(PAddr, TableError),
+}
+
+impl<'a> RSDTTable<'a> {
+    /// Common header of the table, unless it failed validation
+    pub fn header(&self) -> Option<&'a ACPIHeader> {
+        match *self {
+            RSDTTable::MADT(t) => Some(&t.header),
+            RSDTTable::FADT(t) => Some(&t.header),
+            RSDTTable::HPET(t) => Some(&t.header),
+            RSDTTable::MCFG(t) => Some(&t.header),
+            RSDTTable::SRAT(t) => Some(&t.header),
+            RSDTTable::SLIT(t) => Some(&t.header),
+            RSDTTable::DMAR(t) => Some(&t.header),
+            RSDTTable::SPCR(t) => Some(&t.header),
+            RSDTTable::DBG2(t) => Some(&t.header),
+            RSDTTable::SSDT(h) | RSDTTable::Unknown(h) => Some(h),
+            RSDTTable::Invalid(..) => None,
+        }
+    }
+}
+
+/// Reinterpret a validated table as a specific type, ensuring that the
+/// length of the table covers at least that type
+unsafe fn cast_table<'a, E>(header: &'a ACPIHeader) -> Result<&'a E, TableError> {
+    if (header.length as usize) < size_of::<E>() {
+        Err(TableError::TooShort)
+    } else {
+        Ok(transmute(header))
+    }
+}
+
+/// Decode a table that has already passed `validate_table`
+fn decode_table<'a>(header: &'a ACPIHeader) -> Result<RSDTTable<'a>, TableError> {
+    unsafe {
+        Ok(match &header.signature {
+            b"APIC" => RSDTTable::MADT(try!(cast_table(header))),
+            b"FACP" => {
+                if (header.length as usize) < FADT_V1_LENGTH {
+                    return Err(TableError::TooShort);
+                }
+                RSDTTable::FADT(transmute(header))
+            },
+            b"HPET" => RSDTTable::HPET(try!(cast_table(header))),
+            b"MCFG" => RSDTTable::MCFG(try!(cast_table(header))),
+            b"SRAT" => RSDTTable::SRAT(try!(cast_table(header))),
+            b"DMAR" => RSDTTable::DMAR(try!(cast_table(header))),
+            b"SPCR" => RSDTTable::SPCR(try!(cast_table(header))),
+            b"DBG2" => RSDTTable::DBG2(try!(cast_table(header))),
+            b"SSDT" => RSDTTable::SSDT(header),
+            b"SLIT" => {
+                let slit: &'a SLIT = try!(cast_table(header));
+                if !slit_valid(slit) {
+                    return Err(TableError::TooShort);
+                }
+                RSDTTable::SLIT(slit)
+            },
+            _ => RSDTTable::Unknown(header),
+        })
+    }
+}
+
+impl<'a, T:VSpaceWindow<'a>> RSDTIter<'a, T> {
+    /// Check that the table at `paddr`, entry `index` of the RSDT, is not
+    /// in the same memory as the RSDT or any earlier table that is valid
+    fn check_overlap(&self, index: usize, paddr: usize, header: &ACPIHeader)
+            -> Result<(), TableError> {
+        let overlaps = |(start, length): (usize, usize)|
+            paddr < start.saturating_add(length)
+                && start < paddr.saturating_add(header.length as usize);
+        /* only an unusually long RSDT has earlier tables that were not
+         * remembered */
+        let mut unseen = self.entries[cmp::min(self.unseen_from, index)..index].iter()
+            .filter_map(|&e| validate_table(self.window, PAddr(e as usize)).ok()
+                .map(|h| (e as usize, h.length as usize)));
+        if overlaps(self.rsdt) || self.seen[..self.num_seen].iter().any(|&e| overlaps(e))
+                || unseen.any(|e| overlaps(e)) {
+            Err(TableError::Overlap)
+        } else {
+            Ok(())
+        }
+    }
+    /// Remember the memory of a valid table, entry `index` of the RSDT
+    fn record(&mut self, index: usize, paddr: usize, header: &ACPIHeader) {
+        if self.num_seen < MAX_SEEN_TABLES {
+            self.seen[self.num_seen] = (paddr, header.length as usize);
+            self.num_seen += 1;
+        } else {
+            self.unseen_from = cmp::min(self.unseen_from, index);
+        }
+    }
+}
+
+impl<'a, T:VSpaceWindow<'a>> Iterator for RSDTIter<'a, T> {
+    type Item = RSDTTable<'a>;
+    fn next(&mut self) -> Option<RSDTTable<'a>> {
+        let index = self.next;
+        let paddr = match self.entries.get(index) {
+            Some(&entry) => PAddr(entry as usize),
+            None => return None,
+        };
+        self.next += 1;
+        let header = match validate_table(self.window, paddr) {
+            Ok(header) => header,
+            Err(e) => return Some(RSDTTable::Invalid(paddr, e)),
+        };
+        /* a table that validates is remembered even if it overlaps, as
+         * anything it overlaps later is still suspect */
+        let overlap = self.check_overlap(index, paddr.0, header);
+        self.record(index, paddr.0, header);
+        match overlap.and_then(|_| decode_table(header)) {
+            Ok(table) => Some(table),
+            Err(e) => Some(RSDTTable::Invalid(paddr, e)),
+        }
+    }
+}
+
+/// Perform a checksum over a range of bytes, returning true if they sum
+/// to zero as ACPI requires
+fn checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(
+        0u8,
+        |p, v| (Wrapping(p) + Wrapping(*v)).0
+    ) == 0
+}
+
+/// Retrieve the header of the table at a physical address, ensuring that
+/// the entire table as described by its length is accessible from the
+/// window and that its checksum is correct
+fn validate_table<'a, T: VSpaceWindow<'a>>(window: &'a T, paddr: PAddr)
+        -> Result<&'a ACPIHeader, TableError> {
+    let addr = try!(window.try_from_paddr(paddr).ok_or(TableError::Unmappable));
+    let header: &'a ACPIHeader = try!(unsafe{window.make(addr)}
+        .ok_or(TableError::Unmappable));
+    let length = header.length as usize;
+    if length < size_of::<ACPIHeader>() {
+        return Err(TableError::TooShort);
+    }
+    let bytes = try!(unsafe{window.make_slice::<u8>(addr, length)}
+        .ok_or(TableError::Unmappable));
+    if !checksum(bytes) {
+        return Err(TableError::Checksum);
+    }
+    Ok(header)
+}
+
+/// Check if a candidate RSDP is valid. For revision 2 and higher the
+/// extended checksum over the full structure must also be correct
+fn rsdp_valid<'a, T: VSpaceWindow<'a>>(window: &'a T, candidate: &'a RSDP) -> bool {
+    let bytes: &[u8] = unsafe {
+        slice::from_raw_parts(candidate as *const RSDP as *const u8, RSDP_V1_LENGTH)
+    };
+    if &candidate.signature != b"RSD PTR " || !checksum(bytes) {
+        return false;
+    }
+    if candidate.revision < 2 {
+        return true;
+    }
+    let length = candidate.length as usize;
+    if length < size_of::<RSDP>() {
+        return false;
+    }
+    unsafe {
+        window.make_slice::<u8>(window.to_addr(candidate as *const RSDP as usize), length)
+    }.map_or(false, checksum)
+}
+
+/// Physical address of the BIOS data area word holding the real mode
+/// segment of the extended BIOS data area
+const BDA_EBDA_SEGMENT: usize = 0x40E;
+/// Number of bytes at the start of the EBDA that may hold the RSDP
+const EBDA_SEARCH_LENGTH: usize = 1024;
+/// Main BIOS area that may hold the RSDP
+const BIOS_SEARCH_AREA: (usize, usize) = (0xE0_000, 0x100_000);
+
+/// Check for a valid RSDP at a single address
+fn rsdp_at<'a, T: VSpaceWindow<'a>>(window: &'a T, paddr: PAddr) -> Option<&'a RSDP> {
+    unsafe{window.try_from_paddr(paddr)
+        .and_then(|addr| window.make::<RSDP>(addr))}
+        .and_then(|candidate| if rsdp_valid(window, candidate) { Some(candidate) } else { None })
+}
+
+/// Scan a region on 16 byte boundaries for the RSDP. Addresses that cannot
+/// be mapped are skipped, rather than ending the search
+fn scan_rsdp<'a, T: VSpaceWindow<'a>>(window: &'a T, start: usize, end: usize) -> Option<&'a RSDP> {
+    (start..end).step_by(16)
+        .filter_map(|addr| rsdp_at(window, PAddr(addr)))
+        .next()
+}
+
+/// Find the RSDP. An address provided by the boot loader, or the user, is
+/// tried first, followed by the first KiB of the EBDA and the BIOS area as
+/// the specification describes
+fn find_rsdp<'a, T: VSpaceWindow<'a>>(window: &'a T, hint: Option<PAddr>) -> Option<&'a RSDP> {
+    if let Some(rsdp) = hint.and_then(|paddr| rsdp_at(window, paddr)) {
+        return Some(rsdp);
+    }
+    let ebda = unsafe{window.try_from_paddr(PAddr(BDA_EBDA_SEGMENT))
+        .and_then(|addr| window.make::<u16>(addr))}
+        .map_or(0, |segment| (*segment as usize) << 4);
+    if ebda != 0 {
+        if let Some(rsdp) = scan_rsdp(window, ebda, ebda + EBDA_SEARCH_LENGTH) {
+            return Some(rsdp);
+        }
+    }
+    scan_rsdp(window, BIOS_SEARCH_AREA.0, BIOS_SEARCH_AREA.1)
+}
+
+/// Due to current limitations this cannot be a closure
+fn extract_spcr<'a>(header:RSDTTable<'a>) -> Option<&'a SPCR> {
+    if let RSDTTable::SPCR(spcr) = header {
+        Some(spcr)
+    } else {
+        None
+    }
+}
+
+/// Due to current limitations this cannot be a closure
+fn extract_dbg2<'a>(header:RSDTTable<'a>) -> Option<&'a DBG2> {
+    if let RSDTTable::DBG2(dbg2) = header {
+        Some(dbg2)
+    } else {
+        None
+    }
+}
+
+/// Due to current limitations this cannot be a closure
+fn extract_ssdt<'a>(header:RSDTTable<'a>) -> Option<&'a ACPIHeader> {
+    if let RSDTTable::SSDT(ssdt) = header {
+        Some(ssdt)
+    } else {
+        None
+    }
+}
+
+/// Due to current limitations this cannot be a closure
+fn extract_madt<'a>(header:RSDTTable<'a>) -> Option<&'a MADT> {
+    if let RSDTTable::MADT(madt) = header {
+        Some(madt)
+    } else {
+        None
+    }
+}
+
+/// Due to current limitations this cannot be a closure
+fn extract_fadt<'a>(header:RSDTTable<'a>) -> Option<&'a FADT> {
+    if let RSDTTable::FADT(fadt) = header {
+        Some(fadt)
+    } else {
+        None
+    }
+}
+
+/// Due to current limitations this cannot be a closure
+fn extract_hpet<'a>(header:RSDTTable<'a>) -> Option<&'a HPET> {
+    if let RSDTTable::HPET(hpet) = header {
+        Some(hpet)
+    } else {
+        None
+    }
+}
+
+/// Due to current limitations this cannot be a closure
+fn extract_mcfg<'a>(header:RSDTTable<'a>) -> Option<&'a MCFG> {
+    if let RSDTTable::MCFG(mcfg) = header {
+        Some(mcfg)
+    } else {
+        None
+    }
+}
+
+/// Due to current limitations this cannot be a closure
+fn extract_srat<'a>(header:RSDTTable<'a>) -> Option<&'a SRAT> {
+    if let RSDTTable::SRAT(srat) = header {
+        Some(srat)
+    } else {
+        None
+    }
+}
+
+/// Due to current limitations this cannot be a closure
+fn extract_slit<'a>(header:RSDTTable<'a>) -> Option<&'a SLIT> {
+    if let RSDTTable::SLIT(slit) = header {
+        Some(slit)
+    } else {
+        None
+    }
+}
+
+/// Due to current limitations this cannot be a closure
+fn extract_dmar<'a>(header:RSDTTable<'a>) -> Option<&'a DMAR> {
+    if let RSDTTable::DMAR(dmar) = header {
+        Some(dmar)
+    } else {
+        None
+    }
+}
+
+impl<'a, T: VSpaceWindow<'a>> ACPI<'a, T> {
+    /// Try and construct a new ACPI table reference. The RSDP is looked
+    /// for at `rsdp_hint`, if given, and then in the BIOS regions. This will
+    /// fail if no valid RSDP is found, if the passed window cannot map the
+    /// tables, or if the RSDT itself is malformed
+    pub fn new(window: &'a T, rsdp_hint: Option<PAddr>) -> Option<ACPI<'a, T>> {
+        let address = match find_rsdp(window, rsdp_hint) {
+            Some(rsdp) => rsdp.rsdt_address as usize,
+            None => return None,
+        };
+        validate_table(window, PAddr(address)).ok()
+            .and_then(|rsdt| {
+                let count = (rsdt.length as usize - size_of::<ACPIHeader>()) / size_of::<u32>();
+                let first = rsdt as *const ACPIHeader as usize + size_of::<ACPIHeader>();
+                unsafe{window.make_slice(window.to_addr(first), count)}
+                    .map(|entries| (entries, rsdt.length as usize))
+            })
+            .map(|(entries, length)| ACPI {
+                window: window,
+                rsdt_entries: entries,
+                rsdt: (address, length),
+            })
+    }
+    /// Constructs an iterator over all the RSDT entries. Entries that fail
+    /// validation are returned as `RSDTTable::Invalid`
+    pub fn rsdt_iter(&self) -> RSDTIter<'a, T> {
+        RSDTIter {
+            window: self.window,
+            entries: self.rsdt_entries,
+            next: 0,
+            rsdt: self.rsdt,
+            seen: [(0, 0); MAX_SEEN_TABLES],
+            num_seen: 0,
+            unseen_from: self.rsdt_entries.len(),
+        }
+    }
+    /// Constructs an iterator over just the MADT entries in the RSDT
+    /// This is just filtering the results from `rsdt_iter`
+    pub fn madt_iter<>(&self)
+            -> FilterMap<RSDTIter<'a, T>,
+                fn(RSDTTable<'a>) -> Option<&'a MADT>>
+            {
+        self.rsdt_iter()
+            .filter_map(extract_madt as fn(RSDTTable<'a>) -> Option<&'a MADT>)
+    }
+    /// Find the FADT. There should only ever be one of these, so the
+    /// first valid one is returned
+    pub fn fadt(&self) -> Option<&'a FADT> {
+        self.rsdt_iter()
+            .filter_map(extract_fadt as fn(RSDTTable<'a>) -> Option<&'a FADT>)
+            .next()
+    }
+    /// Constructs an iterator over all the HPET blocks described in the RSDT
+    pub fn hpet_iter(&self)
+            -> FilterMap<RSDTIter<'a, T>,
+                fn(RSDTTable<'a>) -> Option<&'a HPET>>
+            {
+        self.rsdt_iter()
+            .filter_map(extract_hpet as fn(RSDTTable<'a>) -> Option<&'a HPET>)
+    }
+    /// Find the MCFG, if the system has PCI Express memory mapped
+    /// configuration space
+    pub fn mcfg(&self) -> Option<&'a MCFG> {
+        self.rsdt_iter()
+            .filter_map(extract_mcfg as fn(RSDTTable<'a>) -> Option<&'a MCFG>)
+            .next()
+    }
+    /// Find the SRAT, if the system describes its NUMA topology
+    pub fn srat(&self) -> Option<&'a SRAT> {
+        self.rsdt_iter()
+            .filter_map(extract_srat as fn(RSDTTable<'a>) -> Option<&'a SRAT>)
+            .next()
+    }
+    /// Find the SLIT, if the system describes distances between domains
+    pub fn slit(&self) -> Option<&'a SLIT> {
+        self.rsdt_iter()
+            .filter_map(extract_slit as fn(RSDTTable<'a>) -> Option<&'a SLIT>)
+            .next()
+    }
+    /// Find the DMAR, if the system has VT-d remapping hardware
+    pub fn dmar(&self) -> Option<&'a DMAR> {
+        self.rsdt_iter()
+            .filter_map(extract_dmar as fn(RSDTTable<'a>) -> Option<&'a DMAR>)
+            .next()
+    }
+    /// Find the SPCR, if the firmware designates a serial console
+    pub fn spcr(&self) -> Option<&'a SPCR> {
+        self.rsdt_iter()
+            .filter_map(extract_spcr as fn(RSDTTable<'a>) -> Option<&'a SPCR>)
+            .next()
+    }
+    /// Find the DBG2, if the firmware lists debug ports
+    pub fn dbg2(&self) -> Option<&'a DBG2> {
+        self.rsdt_iter()
+            .filter_map(extract_dbg2 as fn(RSDTTable<'a>) -> Option<&'a DBG2>)
+            .next()
+    }
+    /// Find the DSDT. This is not in the RSDT, but is pointed to by the
+    /// FADT, and is validated the same as any other table
+    pub fn dsdt(&self, fadt: &FADT) -> Result<&'a ACPIHeader, TableError> {
+        validate_table(self.window, fadt.dsdt_address())
+    }
+    /// Constructs an iterator over the SSDTs in the RSDT
+    pub fn ssdt_iter(&self)
+            -> FilterMap<RSDTIter<'a, T>,
+                fn(RSDTTable<'a>) -> Option<&'a ACPIHeader>>
+            {
+        self.rsdt_iter()
+            .filter_map(extract_ssdt as fn(RSDTTable<'a>) -> Option<&'a ACPIHeader>)
+    }
+    /// The AML byte code of a validated definition block, that is the DSDT
+    /// or an SSDT
+    pub fn definition_block(&self, header: &'a ACPIHeader) -> Option<&'a [u8]> {
+        let start = header as *const ACPIHeader as usize + size_of::<ACPIHeader>();
+        let length = header.length as usize - size_of::<ACPIHeader>();
+        unsafe{self.window.make_slice(self.window.to_addr(start), length)}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::core::ops::Deref;
+    use vspace::VSpaceWindow;
+    use types::PAddr;
+    use super::{ACPI, RSDTTable, MADTTable, TableError, FADT_V1_LENGTH, MAX_SEEN_TABLES};
+
+    /// Bytes of physical memory in a test image
+    const IMAGE_SIZE: usize = 0x2000;
+    const RSDP: usize = 0x40;
+    const RSDT: usize = 0x100;
+
+    #[derive(Debug, Copy, Clone)]
+    struct TestAddr(usize);
+
+    impl Deref for TestAddr {
+        type Target = usize;
+        fn deref(&self) -> &usize {
+            &self.0
+        }
+    }
+
+    /// Window over an image of physical memory, which starts at zero
+    struct TestWindow {
+        base: usize,
+    }
+
+    unsafe impl<'a> VSpaceWindow<'a> for TestWindow {
+        type Addr = TestAddr;
+        type InitData = usize;
+        fn base(&self) -> usize {
+            self.base
+        }
+        fn size(&self) -> usize {
+            IMAGE_SIZE
+        }
+        unsafe fn new(base: usize) -> TestWindow {
+            TestWindow { base: base }
+        }
+        unsafe fn from_paddr(&self, paddr: PAddr) -> TestAddr {
+            TestAddr(self.base.wrapping_add(paddr.0))
+        }
+        unsafe fn to_paddr(&self, addr: TestAddr) -> PAddr {
+            PAddr(addr.0 - self.base)
+        }
+        unsafe fn to_addr(&self, addr: usize) -> TestAddr {
+            TestAddr(addr)
+        }
+    }
+
+    /// Physical memory holding an RSDP and an RSDT, kept in words so that
+    /// it is aligned as the tables expect
+    struct Image {
+        words: Vec<u64>,
+    }
+
+    impl Image {
+        /// Image whose RSDT lists the tables at `entries`
+        fn new(entries: &[usize]) -> Image {
+            let mut image = Image { words: vec![0; IMAGE_SIZE / 8] };
+            image.put(RSDP, b"RSD PTR ");
+            image.put_u32(RSDP + 16, RSDT as u32);
+            image.fix_checksum(RSDP, 20, RSDP + 8);
+            let mut body = Vec::new();
+            for &entry in entries {
+                body.extend_from_slice(&[entry as u8, (entry >> 8) as u8, 0, 0]);
+            }
+            image.table(RSDT, b"RSDT", &body);
+            image
+        }
+        fn bytes(&mut self) -> &mut [u8] {
+            unsafe{::core::slice::from_raw_parts_mut(self.words.as_mut_ptr() as *mut u8,
+                IMAGE_SIZE)}
+        }
+        fn put(&mut self, at: usize, bytes: &[u8]) {
+            self.bytes()[at..at + bytes.len()].copy_from_slice(bytes);
+        }
+        fn put_u32(&mut self, at: usize, value: u32) {
+            self.put(at, &[value as u8, (value >> 8) as u8, (value >> 16) as u8,
+                (value >> 24) as u8]);
+        }
+        /// Set the byte at `sum` so that `length` bytes from `at` sum to zero
+        fn fix_checksum(&mut self, at: usize, length: usize, sum: usize) {
+            self.bytes()[sum] = 0;
+            let total = self.bytes()[at..at + length].iter()
+                .fold(0u8, |total, &b| total.wrapping_add(b));
+            self.bytes()[sum] = total.wrapping_neg();
+        }
+        /// Put a table with a correct checksum at `at`
+        fn table(&mut self, at: usize, signature: &[u8; 4], body: &[u8]) {
+            let length = 36 + body.len();
+            self.put(at, signature);
+            self.put_u32(at + 4, length as u32);
+            self.put(at + 36, body);
+            self.fix_checksum(at, length, at + 9);
+        }
+        fn window(&self) -> TestWindow {
+            unsafe{TestWindow::new(self.words.as_ptr() as usize)}
+        }
+    }
+
+    /// What the RSDT iterator makes of each entry, as the signature of
+    /// valid tables or the error of invalid ones
+    fn tables(image: &Image) -> Vec<Result<[u8; 4], TableError>> {
+        let window = image.window();
+        let acpi = ACPI::new(&window, Some(PAddr(RSDP))).unwrap();
+        acpi.rsdt_iter().map(|table| match table {
+            RSDTTable::Invalid(_, err) => Err(err),
+            table => Ok(*table.header().unwrap().signature()),
+        }).collect()
+    }
+
+    /// Where the MADT of the MADT tests is, and where its entries start
+    const MADT: usize = 0x200;
+    const MADT_ENTRIES: usize = MADT + 44;
+
+    /// What the entry iterator makes of an MADT with the given entries, as
+    /// the type of valid entries or where and why invalid ones failed
+    fn madt_entries(entries: &[u8]) -> Vec<Result<u8, (usize, TableError)>> {
+        let mut image = Image::new(&[MADT]);
+        let mut body = vec![0, 0, 0xe0, 0xfe, 0, 0, 0, 0];
+        body.extend_from_slice(entries);
+        image.table(MADT, b"APIC", &body);
+        let window = image.window();
+        let acpi = ACPI::new(&window, Some(PAddr(RSDP))).unwrap();
+        let madt = acpi.madt_iter().next().unwrap();
+        madt.iter(&window).map(|entry| match entry {
+            MADTTable::APIC(_) => Ok(0),
+            MADTTable::IOAPIC(_) => Ok(1),
+            MADTTable::Invalid(at, err) => Err((at.0, err)),
+            _ => Ok(0xff),
+        }).collect()
+    }
+
+    /// An enabled local APIC entry
+    const LAPIC: [u8; 8] = [0, 8, 0, 0, 1, 0, 0, 0];
+
+    #[test]
+    fn zero_length_madt_entry_ends_the_walk() {
+        let mut entries = LAPIC.to_vec();
+        entries.extend_from_slice(&[0, 0]);
+        entries.extend_from_slice(&LAPIC);
+        assert_eq!(madt_entries(&entries),
+            vec![Ok(0), Err((MADT_ENTRIES + 8, TableError::TooShort))]);
+    }
+
+    #[test]
+    fn madt_entry_past_the_table_end_is_rejected() {
+        let mut entries = LAPIC.to_vec();
+        /* an I/O APIC entry is 12 bytes, but only 6 are left */
+        entries.extend_from_slice(&[1, 12, 0, 0, 0, 0]);
+        assert_eq!(madt_entries(&entries),
+            vec![Ok(0), Err((MADT_ENTRIES + 8, TableError::Overrun))]);
+        /* not even room for the entry header */
+        let mut entries = LAPIC.to_vec();
+        entries.push(1);
+        assert_eq!(madt_entries(&entries),
+            vec![Ok(0), Err((MADT_ENTRIES + 8, TableError::Overrun))]);
+    }
+
+    #[test]
+    fn madt_entry_too_short_for_its_type_is_skipped() {
+        /* an I/O APIC entry of 4 bytes is a consistent length for the
+         * table, so the entries after it can still be used */
+        let mut entries = vec![1, 4, 0, 0];
+        entries.extend_from_slice(&LAPIC);
+        entries.extend_from_slice(&[1, 12, 0, 0, 0, 0, 0xc0, 0xfe, 0, 0, 0, 0]);
+        assert_eq!(madt_entries(&entries),
+            vec![Err((MADT_ENTRIES, TableError::TooShort)), Ok(0), Ok(1)]);
+    }
+
+    #[test]
+    fn valid_tables_are_found() {
+        let mut image = Image::new(&[0x200, 0x300]);
+        image.table(0x200, b"TEST", &[1, 2, 3]);
+        image.table(0x300, b"FACP", &[0; FADT_V1_LENGTH - 36]);
+        assert_eq!(tables(&image), vec![Ok(*b"TEST"), Ok(*b"FACP")]);
+        let window = image.window();
+        assert!(ACPI::new(&window, Some(PAddr(RSDP))).unwrap().fadt().is_some());
+    }
+
+    #[test]
+    fn bad_checksums_are_rejected() {
+        let mut image = Image::new(&[0x200, 0x300]);
+        image.table(0x200, b"TEST", &[1, 2, 3]);
+        image.table(0x300, b"TEST", &[4, 5, 6]);
+        image.bytes()[0x200 + 37] ^= 0x10;
+        assert_eq!(tables(&image), vec![Err(TableError::Checksum), Ok(*b"TEST")]);
+        /* without a valid RSDT, or an RSDP to find it by, there are no
+         * tables at all */
+        image.bytes()[RSDT + 36] ^= 0x1;
+        assert!(ACPI::new(&image.window(), Some(PAddr(RSDP))).is_none());
+        image.bytes()[RSDT + 36] ^= 0x1;
+        image.bytes()[RSDP + 15] ^= 0x1;
+        assert!(ACPI::new(&image.window(), Some(PAddr(RSDP))).is_none());
+    }
+
+    #[test]
+    fn truncated_tables_are_rejected() {
+        let mut image = Image::new(&[0x200, 0x300, 0x400, IMAGE_SIZE - 0x40]);
+        /* shorter than its own header */
+        image.table(0x200, b"TEST", &[]);
+        image.put_u32(0x204, 20);
+        image.fix_checksum(0x200, 20, 0x209);
+        /* shorter than the fixed part of an FADT */
+        image.table(0x300, b"FACP", &[0; 24]);
+        /* a correct table after those */
+        image.table(0x400, b"TEST", &[7]);
+        /* running off the end of memory */
+        image.table(IMAGE_SIZE - 0x40, b"TEST", &[0; 0x1c]);
+        image.put_u32(IMAGE_SIZE - 0x40 + 4, 0x100);
+        assert_eq!(tables(&image), vec![Err(TableError::TooShort), Err(TableError::TooShort),
+            Ok(*b"TEST"), Err(TableError::Unmappable)]);
+        let window = image.window();
+        assert!(ACPI::new(&window, Some(PAddr(RSDP))).unwrap().fadt().is_none());
+    }
+
+    #[test]
+    fn overlapping_tables_are_rejected() {
+        /* the same table twice, a table inside another one, and the RSDT
+         * listing itself */
+        let mut image = Image::new(&[0x200, 0x200, 0x400, 0x420, RSDT]);
+        image.table(0x200, b"TEST", &[1]);
+        /* a table that sums to zero leaves the checksum of one it is put
+         * inside correct */
+        image.table(0x400, b"OUTR", &[0; 0x60]);
+        image.table(0x420, b"INNR", &[2]);
+        assert_eq!(tables(&image), vec![Ok(*b"TEST"), Err(TableError::Overlap), Ok(*b"OUTR"),
+            Err(TableError::Overlap), Err(TableError::Overlap)]);
+    }
+
+    #[test]
+    fn overlaps_are_found_past_the_remembered_tables() {
+        let count = MAX_SEEN_TABLES + 2;
+        let mut entries: Vec<usize> = (0..count).map(|i| 0x200 + i * 0x40).collect();
+        /* repeat one table that was remembered and one that was not */
+        entries.push(0x200);
+        entries.push(0x200 + (count - 1) * 0x40);
+        let mut image = Image::new(&entries);
+        for i in 0..count {
+            image.table(0x200 + i * 0x40, b"TEST", &[i as u8]);
+        }
+        let found = tables(&image);
+        assert!(found[..count].iter().all(|t| *t == Ok(*b"TEST")));
+        assert_eq!(found[count..].to_vec(), vec![Err(TableError::Overlap); 2]);
     }
 }
@@ -0,0 +1,1999 @@
+//! Minimal AML interpreter
+//!
+//! Interrupt routing of PCI devices and the sleep type values for powering
+//! off are only described by objects in the DSDT and SSDTs, so some amount
+//! of AML has to be executed to get at them. This interpreter implements
+//! just enough of the language to evaluate `_PRT`, `_S5_`, `_HID` and
+//! `_CRS` on real firmware and QEMU, and fails cleanly with an `AmlError`
+//! on anything it does not understand.
+//!
+//! Loading a definition block walks its top level, recording named objects
+//! in a flat namespace and remembering where in the byte code each method
+//! body and data object lives. Nothing is executed at load time, so
+//! conditional definitions at the top level are skipped. Data objects are
+//! only built when first used, at which point they are moved into an arena
+//! so that they can be modified.
+//!
+//! There is no heap, so the namespace and all values live in an
+//! `AmlStorage` of fixed size that the caller provides. Values are never
+//! freed, which is fine as only a handful of objects are ever evaluated.
+//! Operation regions in system memory, system I/O and PCI configuration
+//! space can be accessed through fields, with every write using the
+//! `Preserve` update rule.
+use vspace::VSpaceWindow;
+use util::Volatile;
+use types::PAddr;
+use arch::x86_64::x86::io::*;
+use super::pci::{Bdf, ConfigAccess, PortConfig};
+use ::core::cmp;
+
+/// Maximum number of namespace objects
+pub const MAX_NODES: usize = 1024;
+/// Maximum number of values, counting each package element
+pub const MAX_VALUES: usize = 2048;
+/// Maximum number of bytes in buffers created at run time
+pub const MAX_BYTES: usize = 4096;
+/// Maximum number of definition blocks that can be loaded
+pub const MAX_TABLES: usize = 8;
+/// Maximum number of routes recorded from a `_PRT`
+pub const MAX_ROUTES: usize = 128;
+/// Maximum depth of nested method calls
+const MAX_CALL_DEPTH: usize = 16;
+/// Maximum iterations of a single `While` before we decide it is stuck
+const MAX_LOOP: usize = 65536;
+
+/// Value AML uses for true, and for `Ones`
+const ONES: u64 = !0;
+
+/* Opcodes. Those following `EXT_PREFIX` are given as 0x5b00 | op */
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const ALIAS_OP: u8 = 0x06;
+const NAME_OP: u8 = 0x08;
+const BYTE_PREFIX: u8 = 0x0a;
+const WORD_PREFIX: u8 = 0x0b;
+const DWORD_PREFIX: u8 = 0x0c;
+const STRING_PREFIX: u8 = 0x0d;
+const QWORD_PREFIX: u8 = 0x0e;
+const SCOPE_OP: u8 = 0x10;
+const BUFFER_OP: u8 = 0x11;
+const PACKAGE_OP: u8 = 0x12;
+const VAR_PACKAGE_OP: u8 = 0x13;
+const METHOD_OP: u8 = 0x14;
+const DUAL_NAME_PREFIX: u8 = 0x2e;
+const MULTI_NAME_PREFIX: u8 = 0x2f;
+const EXT_PREFIX: u8 = 0x5b;
+const ROOT_CHAR: u8 = b'\\';
+const PARENT_PREFIX: u8 = b'^';
+const LOCAL0_OP: u8 = 0x60;
+const LOCAL7_OP: u8 = 0x67;
+const ARG0_OP: u8 = 0x68;
+const ARG6_OP: u8 = 0x6e;
+const STORE_OP: u8 = 0x70;
+const REF_OF_OP: u8 = 0x71;
+const ADD_OP: u8 = 0x72;
+const SUBTRACT_OP: u8 = 0x74;
+const INCREMENT_OP: u8 = 0x75;
+const DECREMENT_OP: u8 = 0x76;
+const MULTIPLY_OP: u8 = 0x77;
+const DIVIDE_OP: u8 = 0x78;
+const SHIFT_LEFT_OP: u8 = 0x79;
+const SHIFT_RIGHT_OP: u8 = 0x7a;
+const AND_OP: u8 = 0x7b;
+const NAND_OP: u8 = 0x7c;
+const OR_OP: u8 = 0x7d;
+const NOR_OP: u8 = 0x7e;
+const XOR_OP: u8 = 0x7f;
+const NOT_OP: u8 = 0x80;
+const FIND_SET_LEFT_BIT_OP: u8 = 0x81;
+const FIND_SET_RIGHT_BIT_OP: u8 = 0x82;
+const DEREF_OF_OP: u8 = 0x83;
+const MOD_OP: u8 = 0x85;
+const NOTIFY_OP: u8 = 0x86;
+const SIZE_OF_OP: u8 = 0x87;
+const INDEX_OP: u8 = 0x88;
+const CREATE_DWORD_FIELD_OP: u8 = 0x8a;
+const CREATE_WORD_FIELD_OP: u8 = 0x8b;
+const CREATE_BYTE_FIELD_OP: u8 = 0x8c;
+const CREATE_BIT_FIELD_OP: u8 = 0x8d;
+const OBJECT_TYPE_OP: u8 = 0x8e;
+const CREATE_QWORD_FIELD_OP: u8 = 0x8f;
+const LAND_OP: u8 = 0x90;
+const LOR_OP: u8 = 0x91;
+const LNOT_OP: u8 = 0x92;
+const LEQUAL_OP: u8 = 0x93;
+const LGREATER_OP: u8 = 0x94;
+const LLESS_OP: u8 = 0x95;
+const TO_INTEGER_OP: u8 = 0x99;
+const COPY_OBJECT_OP: u8 = 0x9d;
+const CONTINUE_OP: u8 = 0x9f;
+const IF_OP: u8 = 0xa0;
+const ELSE_OP: u8 = 0xa1;
+const WHILE_OP: u8 = 0xa2;
+const NOOP_OP: u8 = 0xa3;
+const RETURN_OP: u8 = 0xa4;
+const BREAK_OP: u8 = 0xa5;
+const BREAKPOINT_OP: u8 = 0xcc;
+const ONES_OP: u8 = 0xff;
+const MUTEX_OP: u16 = 0x5b01;
+const EVENT_OP: u16 = 0x5b02;
+const COND_REF_OF_OP: u16 = 0x5b12;
+const CREATE_FIELD_OP: u16 = 0x5b13;
+const STALL_OP: u16 = 0x5b21;
+const SLEEP_OP: u16 = 0x5b22;
+const ACQUIRE_OP: u16 = 0x5b23;
+const SIGNAL_OP: u16 = 0x5b24;
+const WAIT_OP: u16 = 0x5b25;
+const RESET_OP: u16 = 0x5b26;
+const RELEASE_OP: u16 = 0x5b27;
+const REVISION_OP: u16 = 0x5b30;
+const DEBUG_OP: u16 = 0x5b31;
+const FATAL_OP: u16 = 0x5b32;
+const TIMER_OP: u16 = 0x5b33;
+const OP_REGION_OP: u16 = 0x5b80;
+const FIELD_OP: u16 = 0x5b81;
+const DEVICE_OP: u16 = 0x5b82;
+const PROCESSOR_OP: u16 = 0x5b83;
+const POWER_RES_OP: u16 = 0x5b84;
+const THERMAL_ZONE_OP: u16 = 0x5b85;
+const INDEX_FIELD_OP: u16 = 0x5b86;
+const DATA_REGION_OP: u16 = 0x5b88;
+
+/// Operation region space of system memory
+const SPACE_SYSTEM_MEMORY: u8 = 0;
+/// Operation region space of I/O ports
+const SPACE_SYSTEM_IO: u8 = 1;
+/// Operation region space of PCI configuration space
+const SPACE_PCI_CONFIG: u8 = 2;
+
+/// Field access type of any width
+const ACCESS_ANY: u8 = 0;
+/// Field access type of buffer, treated as byte access
+const ACCESS_BUFFER: u8 = 5;
+
+/// Small resource descriptor type of an IRQ
+const RESOURCE_IRQ: u8 = 0x04;
+/// Small resource descriptor type that ends a template
+const RESOURCE_END: u8 = 0x0f;
+/// Large resource descriptor type of an extended interrupt
+const RESOURCE_EXTENDED_IRQ: u8 = 0x09;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// Reasons AML could not be loaded or evaluated
+pub enum AmlError {
+    /// A term ran past the end of its enclosing object
+    Truncated,
+    /// An opcode, or use of one, that is not implemented
+    Unsupported(u16),
+    /// A name did not resolve to an object
+    NotFound,
+    /// A value was not of the type an operation needed
+    Type,
+    /// Division by zero
+    Arithmetic,
+    /// Package or buffer index out of range
+    Bounds,
+    /// Ran out of space for namespace objects or values
+    NoSpace,
+    /// Call depth or loop iteration limit exceeded
+    Limit,
+    /// Operation region in an address space we cannot access
+    Region,
+    /// The firmware executed a `Fatal` operation
+    Fatal,
+}
+
+/// Result of loading or evaluating AML
+pub type AmlResult<T> = Result<T, AmlError>;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// Location of some bytes within a loaded definition block
+pub struct Span {
+    table: u8,
+    start: u32,
+    end: u32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// Range of entries in the value or byte arena
+pub struct Range {
+    start: u16,
+    len: u16,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// An AML value. Strings refer directly into the byte code, whilst
+/// buffers and packages live in the arenas of the `AmlStorage` and are
+/// only valid for as long as the interpreter that created them
+pub enum Value {
+    Uninitialized,
+    Integer(u64),
+    String(Span),
+    Buffer(Range),
+    Package(Range),
+    /// Reference to a namespace object, as found in packages
+    Reference(u16),
+    /// Reference to an element of a package, as created by `Index`
+    Element(Range, u16),
+    /// Reference to a byte of a buffer, as created by `Index`
+    Byte(Range, u16),
+}
+
+#[derive(Copy, Clone)]
+/// What a namespace object is
+enum NodeKind {
+    /// Scope with no value, such as the root or a `Processor`
+    Scope,
+    Device,
+    /// Named data object that has not been used yet
+    Name(Span),
+    /// Named object whose value is in the value arena
+    Value(u16),
+    Method { body: Span, args: u8 },
+    Region { space: u8, offset: Span, length: Span },
+    Field { region: u16, bit_offset: u32, bit_length: u32, access: u8 },
+    IndexField { index: u16, data: u16, bit_offset: u32, bit_length: u32, access: u8 },
+    BufferField { buffer: Range, bit_offset: u32, bit_length: u32 },
+    Alias(u16),
+    /// Objects that only need to exist so their names resolve, such as
+    /// mutexes and events
+    Other,
+}
+
+#[derive(Copy, Clone)]
+/// A namespace object
+struct Node {
+    /// Index of the enclosing scope. The root is its own parent
+    parent: u16,
+    name: [u8; 4],
+    kind: NodeKind,
+}
+
+/// Fixed size storage for the namespace and values. This is far too large
+/// for the boot stack so is expected to be in a static
+pub struct AmlStorage {
+    nodes: [Node; MAX_NODES],
+    num_nodes: usize,
+    values: [Value; MAX_VALUES],
+    num_values: usize,
+    bytes: [u8; MAX_BYTES],
+    num_bytes: usize,
+}
+
+const EMPTY_NODE: Node = Node { parent: 0, name: *b"\\___", kind: NodeKind::Scope };
+
+/// Storage with nothing in it, for initializing statics
+pub const EMPTY_STORAGE: AmlStorage = AmlStorage {
+    nodes: [EMPTY_NODE; MAX_NODES],
+    num_nodes: 0,
+    values: [Value::Uninitialized; MAX_VALUES],
+    num_values: 0,
+    bytes: [0; MAX_BYTES],
+    num_bytes: 0,
+};
+
+/// Names of the scopes that exist before any table is loaded
+const PREDEFINED_SCOPES: [&'static [u8; 4]; 5] = [b"_GPE", b"_PR_", b"_SB_", b"_SI_", b"_TZ_"];
+
+/// Position within a definition block
+#[derive(Copy, Clone)]
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    table: u8,
+    pos: usize,
+    /// End of the object being parsed
+    end: usize,
+}
+
+/// A parsed name string, referring to the byte code
+#[derive(Copy, Clone)]
+struct NameRef<'a> {
+    root: bool,
+    /// Number of parent prefixes
+    parents: usize,
+    /// Name segments, four bytes each
+    segs: &'a [u8],
+}
+
+impl<'a> Cursor<'a> {
+    fn peek(&self) -> AmlResult<u8> {
+        if self.pos < self.end {
+            Ok(self.bytes[self.pos])
+        } else {
+            Err(AmlError::Truncated)
+        }
+    }
+    fn byte(&mut self) -> AmlResult<u8> {
+        let b = try!(self.peek());
+        self.pos += 1;
+        Ok(b)
+    }
+    /// Read a little endian value of `count` bytes
+    fn bytes_le(&mut self, count: usize) -> AmlResult<u64> {
+        let mut val = 0;
+        for i in 0..count {
+            val |= (try!(self.byte()) as u64) << (i * 8);
+        }
+        Ok(val)
+    }
+    fn skip(&mut self, count: usize) -> AmlResult<()> {
+        if self.pos + count > self.end {
+            return Err(AmlError::Truncated);
+        }
+        self.pos += count;
+        Ok(())
+    }
+    /// Read an opcode, combining extended opcodes with their prefix
+    fn opcode(&mut self) -> AmlResult<u16> {
+        let op = try!(self.byte());
+        if op == EXT_PREFIX {
+            Ok((EXT_PREFIX as u16) << 8 | try!(self.byte()) as u16)
+        } else {
+            Ok(op as u16)
+        }
+    }
+    /// Read an encoded package length and return its value
+    fn pkg_length_value(&mut self) -> AmlResult<usize> {
+        let lead = try!(self.byte());
+        let count = (lead >> 6) as usize;
+        if count == 0 {
+            return Ok((lead & 0x3f) as usize);
+        }
+        let mut len = (lead & 0xf) as usize;
+        for i in 0..count {
+            len |= (try!(self.byte()) as usize) << (4 + i * 8);
+        }
+        Ok(len)
+    }
+    /// Read a package length and return the absolute end of the package,
+    /// which is measured from the start of the length
+    fn pkg_length(&mut self) -> AmlResult<usize> {
+        let start = self.pos;
+        let end = start + try!(self.pkg_length_value());
+        if end > self.end || end < self.pos {
+            return Err(AmlError::Truncated);
+        }
+        Ok(end)
+    }
+    /// A cursor over the next part of this one, ending at `end`
+    fn sub(&self, end: usize) -> Cursor<'a> {
+        Cursor { bytes: self.bytes, table: self.table, pos: self.pos, end: end }
+    }
+    fn span(&self, start: usize, end: usize) -> Span {
+        Span { table: self.table, start: start as u32, end: end as u32 }
+    }
+    /// True if the next byte starts a name string
+    fn at_name(&self) -> bool {
+        match self.peek() {
+            Ok(b) => is_lead_name_char(b) || b == ROOT_CHAR || b == PARENT_PREFIX
+                || b == DUAL_NAME_PREFIX || b == MULTI_NAME_PREFIX,
+            Err(_) => false,
+        }
+    }
+    fn name_string(&mut self) -> AmlResult<NameRef<'a>> {
+        let mut name = NameRef { root: false, parents: 0, segs: &[] };
+        if try!(self.peek()) == ROOT_CHAR {
+            name.root = true;
+            self.pos += 1;
+        } else {
+            while try!(self.peek()) == PARENT_PREFIX {
+                name.parents += 1;
+                self.pos += 1;
+            }
+        }
+        let count = match try!(self.byte()) {
+            ZERO_OP => 0,
+            DUAL_NAME_PREFIX => 2,
+            MULTI_NAME_PREFIX => try!(self.byte()) as usize,
+            _ => {
+                self.pos -= 1;
+                1
+            },
+        };
+        let start = self.pos;
+        try!(self.skip(count * 4));
+        name.segs = &self.bytes[start..self.pos];
+        Ok(name)
+    }
+}
+
+fn is_lead_name_char(b: u8) -> bool {
+    (b >= b'A' && b <= b'Z') || b == b'_'
+}
+
+/// Encode a seven character EISA ID, such as `PNP0A03`, in the form that
+/// the `EisaId` macro of ASL produces
+pub fn eisa_id(id: &[u8; 7]) -> u64 {
+    let hex = |c: u8| -> u16 {
+        match c {
+            b'0' ... b'9' => (c - b'0') as u16,
+            b'A' ... b'F' => (c - b'A' + 10) as u16,
+            _ => 0,
+        }
+    };
+    let vendor = ((id[0] - 0x40) as u16 & 0x1f) << 10
+        | ((id[1] - 0x40) as u16 & 0x1f) << 5
+        | ((id[2] - 0x40) as u16 & 0x1f);
+    let product = hex(id[3]) << 12 | hex(id[4]) << 8 | hex(id[5]) << 4 | hex(id[6]);
+    /* the ID is stored big endian */
+    ((vendor >> 8) as u64) | ((vendor & 0xff) as u64) << 8
+        | ((product >> 8) as u64) << 16 | ((product & 0xff) as u64) << 24
+}
+
+/// Width in bytes used to access a field
+fn access_width(access: u8) -> usize {
+    match access {
+        ACCESS_ANY | ACCESS_BUFFER => 1,
+        n if n <= 4 => 1 << (n - 1),
+        _ => 1,
+    }
+}
+
+/// Operand kinds, used for skipping over terms without evaluating them
+#[derive(Copy, Clone)]
+enum Operand {
+    /// A term argument
+    T,
+    /// A target or super name
+    S,
+    /// A name string
+    N,
+    /// A byte of data
+    B,
+    /// A word of data
+    W,
+    /// A double word of data
+    D,
+}
+
+/// Operands of opcodes that do not have a package length
+fn operands(op: u16) -> Option<&'static [Operand]> {
+    use self::Operand::*;
+    Some(match op {
+        0x06 => &[N, N],
+        0x08 => &[N, T],
+        0x15 => &[N, B, B],
+        0x70 | 0x80 | 0x81 | 0x82 | 0x96 ... 0x99 | 0x9d => &[T, S],
+        0x71 | 0x75 | 0x76 | 0x87 | 0x8e => &[S],
+        0x72 ... 0x74 | 0x77 | 0x79 ... 0x7f | 0x84 | 0x85 | 0x88 | 0x9c => &[T, T, S],
+        0x78 => &[T, T, S, S],
+        0x83 | 0x92 | 0xa4 => &[T],
+        0x86 => &[S, T],
+        0x89 => &[T, B, T, B, T, T],
+        0x8a ... 0x8d | 0x8f => &[T, T, N],
+        0x90 | 0x91 | 0x93 ... 0x95 => &[T, T],
+        0x9e => &[T, T, T, S],
+        0x9f | 0xa3 | 0xa5 | 0xcc => &[],
+        0x5b01 => &[N, B],
+        0x5b02 => &[N],
+        0x5b12 => &[S, S],
+        0x5b13 => &[T, T, T, N],
+        0x5b21 | 0x5b22 => &[T],
+        0x5b23 => &[S, W],
+        0x5b24 | 0x5b26 | 0x5b27 => &[S],
+        0x5b25 => &[S, T],
+        0x5b28 | 0x5b29 => &[T, S],
+        0x5b30 | 0x5b31 | 0x5b33 => &[],
+        0x5b32 => &[B, D, T],
+        0x5b80 => &[N, B, T, T],
+        0x5b88 => &[N, T, T, T],
+        _ => return None,
+    })
+}
+
+/// Opcodes that are followed by a package length covering the rest of
+/// the object
+fn has_pkg_length(op: u16) -> bool {
+    match op {
+        0x10 ... 0x14 | 0xa0 ... 0xa2 | 0x5b81 ... 0x5b87 => true,
+        _ => false,
+    }
+}
+
+/// Storage location that a value can be written to
+#[derive(Copy, Clone)]
+enum Target {
+    /// Discard the value
+    Null,
+    Local(usize),
+    Arg(usize),
+    Node(u16),
+    Element(Range, u16),
+    Byte(Range, u16),
+}
+
+/// Outcome of executing a list of terms
+#[derive(Copy, Clone)]
+enum Flow {
+    Next,
+    Return(Value),
+    Break,
+    Continue,
+}
+
+/// Execution state of a method
+struct Frame {
+    /// Scope names are resolved relative to
+    scope: u16,
+    locals: [Value; 8],
+    args: [Value; 7],
+}
+
+impl Frame {
+    fn new(scope: u16) -> Frame {
+        Frame { scope: scope, locals: [Value::Uninitialized; 8], args: [Value::Uninitialized; 7] }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+/// Routing of a PCI interrupt pin to a global system interrupt
+pub struct PciRoute {
+    /// Device number on the root bus
+    pub device: u8,
+    /// Interrupt pin, 0 for INTA through 3 for INTD
+    pub pin: u8,
+    pub gsi: u32,
+    pub level: bool,
+    pub active_low: bool,
+}
+
+/// Routes from the `_PRT` of the root PCI bridge
+pub struct PciRoutes {
+    routes: [PciRoute; MAX_ROUTES],
+    count: usize,
+    /// Number of routes that did not fit, or could not be evaluated
+    dropped: usize,
+}
+
+/// Empty route table, for initializing statics
+pub const EMPTY_ROUTES: PciRoutes = PciRoutes {
+    routes: [PciRoute { device: 0, pin: 0, gsi: 0, level: false, active_low: false }; MAX_ROUTES],
+    count: 0,
+    dropped: 0,
+};
+
+impl PciRoutes {
+    fn push(&mut self, route: PciRoute) {
+        if self.count == MAX_ROUTES {
+            self.dropped += 1;
+            return;
+        }
+        self.routes[self.count] = route;
+        self.count += 1;
+    }
+    pub fn as_slice(&self) -> &[PciRoute] {
+        &self.routes[..self.count]
+    }
+    /// Find the route of an interrupt pin of a device on the root bus
+    pub fn find(&self, device: u8, pin: u8) -> Option<&PciRoute> {
+        self.as_slice().iter().find(|r| r.device == device && r.pin == pin)
+    }
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+}
+
+/// AML interpreter over a set of loaded definition blocks
+pub struct Aml<'a, 's, W: VSpaceWindow<'a> + 'a> {
+    /// Window for accessing system memory operation regions
+    window: &'a W,
+    tables: [&'a [u8]; MAX_TABLES],
+    num_tables: usize,
+    s: &'s mut AmlStorage,
+    /// Current method call depth
+    depth: usize,
+}
+
+impl<'a, 's, W: VSpaceWindow<'a>> Aml<'a, 's, W> {
+    /// Construct an interpreter with an empty namespace in `storage`
+    pub fn new(window: &'a W, storage: &'s mut AmlStorage) -> Aml<'a, 's, W> {
+        storage.nodes[0] = EMPTY_NODE;
+        storage.num_nodes = 1;
+        storage.num_values = 0;
+        storage.num_bytes = 0;
+        for name in PREDEFINED_SCOPES.iter() {
+            storage.nodes[storage.num_nodes] = Node { parent: 0, name: **name, kind: NodeKind::Scope };
+            storage.num_nodes += 1;
+        }
+        let empty: &'a [u8] = &[];
+        Aml { window: window, tables: [empty; MAX_TABLES], num_tables: 0, s: storage, depth: 0 }
+    }
+    /// Load the objects of a definition block into the namespace. On
+    /// failure any objects before the problem remain loaded
+    pub fn load(&mut self, aml: &'a [u8]) -> AmlResult<()> {
+        if self.num_tables == MAX_TABLES {
+            return Err(AmlError::NoSpace);
+        }
+        self.tables[self.num_tables] = aml;
+        let c = Cursor { bytes: aml, table: self.num_tables as u8, pos: 0, end: aml.len() };
+        self.num_tables += 1;
+        self.load_list(0, c)
+    }
+    fn cursor(&self, span: Span) -> Cursor<'a> {
+        Cursor {
+            bytes: self.tables[span.table as usize],
+            table: span.table,
+            pos: span.start as usize,
+            end: span.end as usize,
+        }
+    }
+    fn parent(&self, node: u16) -> u16 {
+        self.s.nodes[node as usize].parent
+    }
+    /// Find a direct child of a scope. Later objects are found first so
+    /// that objects created by a running method shadow any others
+    fn child(&self, scope: u16, seg: &[u8]) -> Option<u16> {
+        (1..self.s.num_nodes).rev()
+            .find(|i| self.s.nodes[*i].parent == scope && &self.s.nodes[*i].name[..] == seg)
+            .map(|i| i as u16)
+    }
+    /// Follow any aliases
+    fn target_of(&self, mut node: u16) -> u16 {
+        for _ in 0..MAX_CALL_DEPTH {
+            match self.s.nodes[node as usize].kind {
+                NodeKind::Alias(to) => node = to,
+                _ => break,
+            }
+        }
+        node
+    }
+    /// Scope that the first segment of a name is relative to
+    fn name_base(&self, scope: u16, name: &NameRef) -> u16 {
+        let mut base = if name.root { 0 } else { scope };
+        for _ in 0..name.parents {
+            base = self.parent(base);
+        }
+        base
+    }
+    /// Resolve a name. Single segment relative names are searched for in
+    /// each enclosing scope in turn, as the specification requires
+    fn resolve(&self, scope: u16, name: &NameRef) -> Option<u16> {
+        let base = self.name_base(scope, name);
+        if !name.root && name.parents == 0 && name.segs.len() == 4 {
+            let mut s = base;
+            loop {
+                if let Some(n) = self.child(s, name.segs) {
+                    return Some(self.target_of(n));
+                }
+                if s == 0 {
+                    return None;
+                }
+                s = self.parent(s);
+            }
+        }
+        let mut node = base;
+        for seg in name.segs.chunks(4) {
+            node = match self.child(self.target_of(node), seg) {
+                Some(n) => n,
+                None => return None,
+            };
+        }
+        Some(self.target_of(node))
+    }
+    /// Resolve a dotted path such as `\_SB.PCI0._PRT`. Segments shorter
+    /// than four characters are padded with underscores
+    fn resolve_path(&self, path: &str) -> Option<u16> {
+        let path = path.trim_left_matches('\\');
+        let mut node = 0;
+        for part in path.split('.').filter(|p| !p.is_empty()) {
+            let mut seg = *b"____";
+            for (d, s) in seg.iter_mut().zip(part.bytes()) {
+                *d = s;
+            }
+            node = match self.child(self.target_of(node), &seg) {
+                Some(n) => n,
+                None => return None,
+            };
+        }
+        Some(self.target_of(node))
+    }
+    /// Add an object to the namespace, or return the existing object of
+    /// that name. All but the last segment of the name must already exist
+    fn create(&mut self, scope: u16, name: &NameRef, kind: NodeKind) -> AmlResult<u16> {
+        if name.segs.is_empty() {
+            return Ok(self.name_base(scope, name));
+        }
+        let split = name.segs.len() - 4;
+        let mut parent = self.name_base(scope, name);
+        for seg in name.segs[..split].chunks(4) {
+            parent = try!(self.child(self.target_of(parent), seg).ok_or(AmlError::NotFound));
+        }
+        parent = self.target_of(parent);
+        let last = &name.segs[split..];
+        if let Some(existing) = self.child(parent, last) {
+            return Ok(existing);
+        }
+        if self.s.num_nodes == MAX_NODES {
+            return Err(AmlError::NoSpace);
+        }
+        let mut seg = [0; 4];
+        seg.copy_from_slice(last);
+        self.s.nodes[self.s.num_nodes] = Node { parent: parent, name: seg, kind: kind };
+        self.s.num_nodes += 1;
+        Ok((self.s.num_nodes - 1) as u16)
+    }
+    fn alloc_values(&mut self, count: usize) -> AmlResult<Range> {
+        if self.s.num_values + count > MAX_VALUES {
+            return Err(AmlError::NoSpace);
+        }
+        let start = self.s.num_values;
+        for v in self.s.values[start..start + count].iter_mut() {
+            *v = Value::Uninitialized;
+        }
+        self.s.num_values += count;
+        Ok(Range { start: start as u16, len: count as u16 })
+    }
+    fn alloc_bytes(&mut self, count: usize) -> AmlResult<Range> {
+        if self.s.num_bytes + count > MAX_BYTES {
+            return Err(AmlError::NoSpace);
+        }
+        let start = self.s.num_bytes;
+        for b in self.s.bytes[start..start + count].iter_mut() {
+            *b = 0;
+        }
+        self.s.num_bytes += count;
+        Ok(Range { start: start as u16, len: count as u16 })
+    }
+    /// Bytes of a buffer in the byte arena
+    fn buffer(&self, r: Range) -> &[u8] {
+        &self.s.bytes[r.start as usize..(r.start + r.len) as usize]
+    }
+    /// Bytes of a string or buffer value
+    fn bytes_of(&self, v: Value) -> Option<&[u8]> {
+        match v {
+            Value::String(span) =>
+                Some(&self.tables[span.table as usize][span.start as usize..span.end as usize]),
+            Value::Buffer(r) => Some(self.buffer(r)),
+            _ => None,
+        }
+    }
+
+    /* Loading */
+
+    /// Load the objects in a list of terms
+    fn load_list(&mut self, scope: u16, mut c: Cursor<'a>) -> AmlResult<()> {
+        while c.pos < c.end {
+            let start = c.pos;
+            let op = try!(c.opcode());
+            match op {
+                op if op == SCOPE_OP as u16 => {
+                    let end = try!(c.pkg_length());
+                    let name = try!(c.name_string());
+                    let node = try!(self.create(scope, &name, NodeKind::Scope));
+                    try!(self.load_list(node, c.sub(end)));
+                    c.pos = end;
+                },
+                op if op == NAME_OP as u16 => {
+                    let name = try!(c.name_string());
+                    let data = c.pos;
+                    try!(self.skip_term(scope, &mut c));
+                    try!(self.create(scope, &name, NodeKind::Name(c.span(data, c.pos))));
+                },
+                op if op == METHOD_OP as u16 => {
+                    let end = try!(c.pkg_length());
+                    let name = try!(c.name_string());
+                    let flags = try!(c.byte());
+                    let body = c.span(c.pos, end);
+                    try!(self.create(scope, &name, NodeKind::Method { body: body, args: flags & 0x7 }));
+                    c.pos = end;
+                },
+                op if op == ALIAS_OP as u16 => {
+                    let source = try!(c.name_string());
+                    let alias = try!(c.name_string());
+                    if let Some(to) = self.resolve(scope, &source) {
+                        try!(self.create(scope, &alias, NodeKind::Alias(to)));
+                    }
+                },
+                DEVICE_OP | PROCESSOR_OP | POWER_RES_OP | THERMAL_ZONE_OP => {
+                    let end = try!(c.pkg_length());
+                    let name = try!(c.name_string());
+                    /* skip the fixed data that some of these have */
+                    try!(c.skip(match op {
+                        PROCESSOR_OP => 6,
+                        POWER_RES_OP => 3,
+                        _ => 0,
+                    }));
+                    let kind = if op == DEVICE_OP { NodeKind::Device } else { NodeKind::Scope };
+                    let node = try!(self.create(scope, &name, kind));
+                    try!(self.load_list(node, c.sub(end)));
+                    c.pos = end;
+                },
+                OP_REGION_OP => {
+                    let name = try!(c.name_string());
+                    let space = try!(c.byte());
+                    let offset = c.pos;
+                    try!(self.skip_term(scope, &mut c));
+                    let length = c.pos;
+                    try!(self.skip_term(scope, &mut c));
+                    try!(self.create(scope, &name, NodeKind::Region {
+                        space: space,
+                        offset: c.span(offset, length),
+                        length: c.span(length, c.pos),
+                    }));
+                },
+                FIELD_OP | INDEX_FIELD_OP => {
+                    let end = try!(c.pkg_length());
+                    let first = try!(c.name_string());
+                    let second = if op == INDEX_FIELD_OP {
+                        Some(try!(c.name_string()))
+                    } else {
+                        None
+                    };
+                    let mut list = c.sub(end);
+                    /* fields of a region we cannot find are left undefined,
+                     * so anything using them fails to resolve */
+                    let first = self.resolve(scope, &first);
+                    let second = second.and_then(|n| self.resolve(scope, &n));
+                    if let Some(first) = first {
+                        try!(self.load_fields(scope, &mut list, first, second));
+                    }
+                    c.pos = end;
+                },
+                MUTEX_OP | EVENT_OP | DATA_REGION_OP => {
+                    c.pos = start;
+                    let mut name_cursor = c;
+                    try!(name_cursor.opcode());
+                    let name = try!(name_cursor.name_string());
+                    try!(self.skip_term(scope, &mut c));
+                    try!(self.create(scope, &name, NodeKind::Other));
+                },
+                _ => {
+                    /* anything else is executable, or unsupported, and is
+                     * not evaluated at load time */
+                    c.pos = start;
+                    try!(self.skip_term(scope, &mut c));
+                },
+            }
+        }
+        Ok(())
+    }
+    /// Load the field list of a `Field` or `IndexField`. For an
+    /// `IndexField` `first` is the index register and `second` the data
+    fn load_fields(&mut self, scope: u16, c: &mut Cursor<'a>, first: u16, second: Option<u16>)
+            -> AmlResult<()> {
+        let flags = try!(c.byte());
+        let mut access = flags & 0xf;
+        let mut bit_offset = 0;
+        while c.pos < c.end {
+            match try!(c.peek()) {
+                0x00 => {
+                    c.pos += 1;
+                    bit_offset += try!(c.pkg_length_value()) as u32;
+                },
+                0x01 => {
+                    c.pos += 1;
+                    access = try!(c.byte()) & 0xf;
+                    try!(c.skip(1));
+                },
+                0x03 => {
+                    c.pos += 1;
+                    access = try!(c.byte()) & 0xf;
+                    try!(c.skip(2));
+                },
+                0x02 => return Err(AmlError::Unsupported(0x02)),
+                _ => {
+                    let start = c.pos;
+                    try!(c.skip(4));
+                    let name = NameRef { root: false, parents: 0, segs: &c.bytes[start..c.pos] };
+                    let bit_length = try!(c.pkg_length_value()) as u32;
+                    let kind = match second {
+                        Some(data) => NodeKind::IndexField {
+                            index: first, data: data,
+                            bit_offset: bit_offset, bit_length: bit_length, access: access,
+                        },
+                        None => NodeKind::Field {
+                            region: first,
+                            bit_offset: bit_offset, bit_length: bit_length, access: access,
+                        },
+                    };
+                    try!(self.create(scope, &name, kind));
+                    bit_offset += bit_length;
+                },
+            }
+        }
+        Ok(())
+    }
+    /// Skip over a single term without evaluating it. Names that resolve
+    /// to methods have their arguments skipped as well
+    fn skip_term(&self, scope: u16, c: &mut Cursor<'a>) -> AmlResult<()> {
+        if c.at_name() {
+            let name = try!(c.name_string());
+            if let Some(node) = self.resolve(scope, &name) {
+                if let NodeKind::Method { args, .. } = self.s.nodes[node as usize].kind {
+                    for _ in 0..args {
+                        try!(self.skip_term(scope, c));
+                    }
+                }
+            }
+            return Ok(());
+        }
+        let op = try!(c.opcode());
+        if has_pkg_length(op) {
+            c.pos = try!(c.pkg_length());
+            return Ok(());
+        }
+        match op {
+            op if op == ZERO_OP as u16 || op == ONE_OP as u16 || op == ONES_OP as u16 => Ok(()),
+            op if op >= LOCAL0_OP as u16 && op <= ARG6_OP as u16 => Ok(()),
+            op if op == BYTE_PREFIX as u16 => c.skip(1),
+            op if op == WORD_PREFIX as u16 => c.skip(2),
+            op if op == DWORD_PREFIX as u16 => c.skip(4),
+            op if op == QWORD_PREFIX as u16 => c.skip(8),
+            op if op == STRING_PREFIX as u16 => {
+                while try!(c.byte()) != 0 {}
+                Ok(())
+            },
+            op => {
+                let list = try!(operands(op).ok_or(AmlError::Unsupported(op)));
+                for operand in list {
+                    match *operand {
+                        Operand::T => try!(self.skip_term(scope, c)),
+                        Operand::S => try!(self.skip_target(scope, c)),
+                        Operand::N => { try!(c.name_string()); },
+                        Operand::B => try!(c.skip(1)),
+                        Operand::W => try!(c.skip(2)),
+                        Operand::D => try!(c.skip(4)),
+                    }
+                }
+                Ok(())
+            },
+        }
+    }
+    /// Skip over a target, which unlike a term argument may be a null name
+    /// and never invokes a method
+    fn skip_target(&self, scope: u16, c: &mut Cursor<'a>) -> AmlResult<()> {
+        if try!(c.peek()) == ZERO_OP {
+            c.pos += 1;
+            Ok(())
+        } else if c.at_name() {
+            c.name_string().map(|_| ())
+        } else {
+            self.skip_term(scope, c)
+        }
+    }
+
+    /* Execution */
+
+    /// Run a method with the given arguments
+    fn invoke(&mut self, method: u16, args: &[Value]) -> AmlResult<Value> {
+        let body = match self.s.nodes[method as usize].kind {
+            NodeKind::Method { body, .. } => body,
+            _ => return Err(AmlError::Type),
+        };
+        if self.depth == MAX_CALL_DEPTH {
+            return Err(AmlError::Limit);
+        }
+        let mut frame = Frame::new(method);
+        for (slot, arg) in frame.args.iter_mut().zip(args) {
+            *slot = *arg;
+        }
+        /* objects a method creates only exist whilst it runs */
+        let mark = self.s.num_nodes;
+        self.depth += 1;
+        let c = self.cursor(body);
+        let result = self.exec(&mut frame, c);
+        self.depth -= 1;
+        self.s.num_nodes = mark;
+        match try!(result) {
+            Flow::Return(v) => Ok(v),
+            _ => Ok(Value::Integer(0)),
+        }
+    }
+    /// Execute a list of terms
+    fn exec(&mut self, f: &mut Frame, mut c: Cursor<'a>) -> AmlResult<Flow> {
+        while c.pos < c.end {
+            if c.at_name() {
+                try!(self.eval(f, &mut c));
+                continue;
+            }
+            let start = c.pos;
+            let op = try!(c.opcode());
+            let flow = match op {
+                op if op == IF_OP as u16 => {
+                    let end = try!(c.pkg_length());
+                    let mut body = c.sub(end);
+                    let taken = try!(self.eval_integer(f, &mut body)) != 0;
+                    c.pos = end;
+                    let mut flow = if taken { try!(self.exec(f, body)) } else { Flow::Next };
+                    if c.pos < c.end && try!(c.peek()) == ELSE_OP {
+                        c.pos += 1;
+                        let end = try!(c.pkg_length());
+                        if !taken {
+                            flow = try!(self.exec(f, c.sub(end)));
+                        }
+                        c.pos = end;
+                    }
+                    flow
+                },
+                op if op == ELSE_OP as u16 => {
+                    /* an else that did not follow an if */
+                    c.pos = try!(c.pkg_length());
+                    Flow::Next
+                },
+                op if op == WHILE_OP as u16 => {
+                    let end = try!(c.pkg_length());
+                    let predicate = c.sub(end);
+                    c.pos = end;
+                    let mut flow = Flow::Next;
+                    let mut iterations = 0;
+                    loop {
+                        let mut body = predicate;
+                        if try!(self.eval_integer(f, &mut body)) == 0 {
+                            break;
+                        }
+                        match try!(self.exec(f, body)) {
+                            Flow::Break => break,
+                            Flow::Return(v) => {
+                                flow = Flow::Return(v);
+                                break;
+                            },
+                            Flow::Next | Flow::Continue => (),
+                        }
+                        iterations += 1;
+                        if iterations == MAX_LOOP {
+                            return Err(AmlError::Limit);
+                        }
+                    }
+                    flow
+                },
+                op if op == RETURN_OP as u16 => Flow::Return(try!(self.eval(f, &mut c))),
+                op if op == BREAK_OP as u16 => Flow::Break,
+                op if op == CONTINUE_OP as u16 => Flow::Continue,
+                op if op == NOOP_OP as u16 || op == BREAKPOINT_OP as u16 => Flow::Next,
+                op if op == NAME_OP as u16 => {
+                    let name = try!(c.name_string());
+                    let v = try!(self.eval(f, &mut c));
+                    let slot = try!(self.alloc_values(1));
+                    self.s.values[slot.start as usize] = v;
+                    try!(self.create(f.scope, &name, NodeKind::Value(slot.start)));
+                    Flow::Next
+                },
+                op if op == NOTIFY_OP as u16 => {
+                    try!(self.target(f, &mut c));
+                    try!(self.eval(f, &mut c));
+                    Flow::Next
+                },
+                op if op == CREATE_BIT_FIELD_OP as u16 || op == CREATE_BYTE_FIELD_OP as u16
+                        || op == CREATE_WORD_FIELD_OP as u16 || op == CREATE_DWORD_FIELD_OP as u16
+                        || op == CREATE_QWORD_FIELD_OP as u16 || op == CREATE_FIELD_OP => {
+                    try!(self.create_buffer_field(f, op, &mut c));
+                    Flow::Next
+                },
+                STALL_OP | SLEEP_OP => {
+                    /* nothing we evaluate needs to actually wait */
+                    try!(self.eval(f, &mut c));
+                    Flow::Next
+                },
+                SIGNAL_OP | RESET_OP | RELEASE_OP => {
+                    try!(self.target(f, &mut c));
+                    Flow::Next
+                },
+                FATAL_OP => return Err(AmlError::Fatal),
+                _ => {
+                    /* everything else is an expression whose value is
+                     * discarded */
+                    c.pos = start;
+                    try!(self.eval(f, &mut c));
+                    Flow::Next
+                },
+            };
+            match flow {
+                Flow::Next => (),
+                other => return Ok(other),
+            }
+        }
+        Ok(Flow::Next)
+    }
+    /// Evaluate a term argument and convert it to an integer
+    fn eval_integer(&mut self, f: &mut Frame, c: &mut Cursor<'a>) -> AmlResult<u64> {
+        let v = try!(self.eval(f, c));
+        self.to_integer(v)
+    }
+    /// Evaluate a term argument
+    fn eval(&mut self, f: &mut Frame, c: &mut Cursor<'a>) -> AmlResult<Value> {
+        if c.at_name() {
+            let name = try!(c.name_string());
+            let node = try!(self.resolve(f.scope, &name).ok_or(AmlError::NotFound));
+            if let NodeKind::Method { args, .. } = self.s.nodes[node as usize].kind {
+                let mut values = [Value::Uninitialized; 7];
+                for v in values[..args as usize].iter_mut() {
+                    *v = try!(self.eval(f, c));
+                }
+                return self.invoke(node, &values[..args as usize]);
+            }
+            return self.read_node(node);
+        }
+        let op = try!(c.opcode());
+        let int = |v: u64| Ok(Value::Integer(v));
+        let boolean = |b: bool| Ok(Value::Integer(if b { ONES } else { 0 }));
+        match op {
+            op if op == ZERO_OP as u16 => int(0),
+            op if op == ONE_OP as u16 => int(1),
+            op if op == ONES_OP as u16 => int(ONES),
+            op if op == BYTE_PREFIX as u16 => int(try!(c.bytes_le(1))),
+            op if op == WORD_PREFIX as u16 => int(try!(c.bytes_le(2))),
+            op if op == DWORD_PREFIX as u16 => int(try!(c.bytes_le(4))),
+            op if op == QWORD_PREFIX as u16 => int(try!(c.bytes_le(8))),
+            op if op == STRING_PREFIX as u16 => {
+                let start = c.pos;
+                while try!(c.byte()) != 0 {}
+                Ok(Value::String(c.span(start, c.pos - 1)))
+            },
+            REVISION_OP => int(2),
+            TIMER_OP => int(0),
+            op if op >= LOCAL0_OP as u16 && op <= LOCAL7_OP as u16 =>
+                Ok(f.locals[(op - LOCAL0_OP as u16) as usize]),
+            op if op >= ARG0_OP as u16 && op <= ARG6_OP as u16 =>
+                Ok(f.args[(op - ARG0_OP as u16) as usize]),
+            op if op == BUFFER_OP as u16 => {
+                let end = try!(c.pkg_length());
+                let mut inner = c.sub(end);
+                let size = try!(self.eval_integer(f, &mut inner)) as usize;
+                let init = &inner.bytes[inner.pos..end];
+                c.pos = end;
+                let r = try!(self.alloc_bytes(cmp::max(size, init.len())));
+                self.s.bytes[r.start as usize..r.start as usize + init.len()].copy_from_slice(init);
+                Ok(Value::Buffer(r))
+            },
+            op if op == PACKAGE_OP as u16 || op == VAR_PACKAGE_OP as u16 => {
+                let end = try!(c.pkg_length());
+                let mut inner = c.sub(end);
+                let count = if op == PACKAGE_OP as u16 {
+                    try!(inner.byte()) as usize
+                } else {
+                    try!(self.eval_integer(f, &mut inner)) as usize
+                };
+                let r = try!(self.alloc_values(count));
+                let mut i = 0;
+                while inner.pos < end && i < count {
+                    let v = try!(self.package_element(f, &mut inner));
+                    self.s.values[r.start as usize + i] = v;
+                    i += 1;
+                }
+                c.pos = end;
+                Ok(Value::Package(r))
+            },
+            op if op == STORE_OP as u16 || op == COPY_OBJECT_OP as u16 => {
+                let v = try!(self.eval(f, c));
+                let t = try!(self.target(f, c));
+                try!(self.store(f, t, v));
+                Ok(v)
+            },
+            op if op == ADD_OP as u16 || op == SUBTRACT_OP as u16 || op == MULTIPLY_OP as u16
+                    || op == SHIFT_LEFT_OP as u16 || op == SHIFT_RIGHT_OP as u16
+                    || op == AND_OP as u16 || op == NAND_OP as u16 || op == OR_OP as u16
+                    || op == NOR_OP as u16 || op == XOR_OP as u16 || op == MOD_OP as u16 => {
+                let a = try!(self.eval_integer(f, c));
+                let b = try!(self.eval_integer(f, c));
+                let result = match op as u8 {
+                    ADD_OP => a.wrapping_add(b),
+                    SUBTRACT_OP => a.wrapping_sub(b),
+                    MULTIPLY_OP => a.wrapping_mul(b),
+                    SHIFT_LEFT_OP => if b >= 64 { 0 } else { a << b },
+                    SHIFT_RIGHT_OP => if b >= 64 { 0 } else { a >> b },
+                    AND_OP => a & b,
+                    NAND_OP => !(a & b),
+                    OR_OP => a | b,
+                    NOR_OP => !(a | b),
+                    XOR_OP => a ^ b,
+                    _ => try!(a.checked_rem(b).ok_or(AmlError::Arithmetic)),
+                };
+                let t = try!(self.target(f, c));
+                try!(self.store(f, t, Value::Integer(result)));
+                int(result)
+            },
+            op if op == DIVIDE_OP as u16 => {
+                let a = try!(self.eval_integer(f, c));
+                let b = try!(self.eval_integer(f, c));
+                if b == 0 {
+                    return Err(AmlError::Arithmetic);
+                }
+                let remainder = try!(self.target(f, c));
+                try!(self.store(f, remainder, Value::Integer(a % b)));
+                let quotient = try!(self.target(f, c));
+                try!(self.store(f, quotient, Value::Integer(a / b)));
+                int(a / b)
+            },
+            op if op == NOT_OP as u16 || op == FIND_SET_LEFT_BIT_OP as u16
+                    || op == FIND_SET_RIGHT_BIT_OP as u16 || op == TO_INTEGER_OP as u16 => {
+                let a = try!(self.eval_integer(f, c));
+                let result = match op as u8 {
+                    NOT_OP => !a,
+                    FIND_SET_LEFT_BIT_OP => if a == 0 { 0 } else { 64 - a.leading_zeros() as u64 },
+                    FIND_SET_RIGHT_BIT_OP => if a == 0 { 0 } else { a.trailing_zeros() as u64 + 1 },
+                    _ => a,
+                };
+                let t = try!(self.target(f, c));
+                try!(self.store(f, t, Value::Integer(result)));
+                int(result)
+            },
+            op if op == INCREMENT_OP as u16 || op == DECREMENT_OP as u16 => {
+                let t = try!(self.target(f, c));
+                let v = try!(self.load_target(f, t));
+                let v = try!(self.to_integer(v));
+                let result = if op == INCREMENT_OP as u16 { v.wrapping_add(1) } else { v.wrapping_sub(1) };
+                try!(self.store(f, t, Value::Integer(result)));
+                int(result)
+            },
+            op if op == LAND_OP as u16 || op == LOR_OP as u16 => {
+                let a = try!(self.eval_integer(f, c)) != 0;
+                let b = try!(self.eval_integer(f, c)) != 0;
+                boolean(if op == LAND_OP as u16 { a && b } else { a || b })
+            },
+            op if op == LNOT_OP as u16 => {
+                let a = try!(self.eval_integer(f, c));
+                boolean(a == 0)
+            },
+            op if op == LEQUAL_OP as u16 || op == LGREATER_OP as u16 || op == LLESS_OP as u16 => {
+                let a = try!(self.eval(f, c));
+                let b = try!(self.eval(f, c));
+                let ordering = try!(self.compare(a, b));
+                boolean(match op as u8 {
+                    LEQUAL_OP => ordering == cmp::Ordering::Equal,
+                    LGREATER_OP => ordering == cmp::Ordering::Greater,
+                    _ => ordering == cmp::Ordering::Less,
+                })
+            },
+            op if op == SIZE_OF_OP as u16 => {
+                let t = try!(self.target(f, c));
+                let v = try!(self.load_target(f, t));
+                let v = try!(self.deref(v));
+                match v {
+                    Value::Package(r) | Value::Buffer(r) => int(r.len as u64),
+                    Value::String(span) => int((span.end - span.start) as u64),
+                    _ => Err(AmlError::Type),
+                }
+            },
+            op if op == INDEX_OP as u16 => {
+                let source = try!(self.eval(f, c));
+                let source = try!(self.deref(source));
+                let index = try!(self.eval_integer(f, c));
+                let result = match source {
+                    Value::Package(r) if index < r.len as u64 => Value::Element(r, index as u16),
+                    Value::Buffer(r) if index < r.len as u64 => Value::Byte(r, index as u16),
+                    Value::Package(_) | Value::Buffer(_) => return Err(AmlError::Bounds),
+                    _ => return Err(AmlError::Type),
+                };
+                let t = try!(self.target(f, c));
+                try!(self.store(f, t, result));
+                Ok(result)
+            },
+            op if op == DEREF_OF_OP as u16 => {
+                let v = try!(self.eval(f, c));
+                self.deref(v)
+            },
+            op if op == REF_OF_OP as u16 => {
+                match try!(self.target(f, c)) {
+                    Target::Node(n) => Ok(Value::Reference(n)),
+                    Target::Element(r, i) => Ok(Value::Element(r, i)),
+                    Target::Byte(r, i) => Ok(Value::Byte(r, i)),
+                    _ => Err(AmlError::Unsupported(op)),
+                }
+            },
+            COND_REF_OF_OP => {
+                let found = if c.at_name() {
+                    let name = try!(c.name_string());
+                    self.resolve(f.scope, &name).map(Value::Reference)
+                } else {
+                    match try!(self.target(f, c)) {
+                        Target::Node(n) => Some(Value::Reference(n)),
+                        _ => None,
+                    }
+                };
+                let t = try!(self.target(f, c));
+                match found {
+                    Some(r) => {
+                        try!(self.store(f, t, r));
+                        boolean(true)
+                    },
+                    None => boolean(false),
+                }
+            },
+            op if op == OBJECT_TYPE_OP as u16 => {
+                let t = try!(self.target(f, c));
+                let kind = match t {
+                    Target::Node(n) => match self.s.nodes[n as usize].kind {
+                        NodeKind::Device => 6,
+                        NodeKind::Method { .. } => 8,
+                        NodeKind::Region { .. } => 10,
+                        NodeKind::Field { .. } | NodeKind::IndexField { .. } => 5,
+                        NodeKind::BufferField { .. } => 14,
+                        NodeKind::Scope | NodeKind::Other | NodeKind::Alias(_) => 0,
+                        NodeKind::Name(_) | NodeKind::Value(_) => {
+                            let v = try!(self.read_node(n));
+                            self.type_of(v)
+                        },
+                    },
+                    other => {
+                        let v = try!(self.load_target(f, other));
+                        self.type_of(v)
+                    },
+                };
+                int(kind)
+            },
+            ACQUIRE_OP => {
+                try!(self.target(f, c));
+                try!(c.skip(2));
+                /* there is no contention, so acquiring always succeeds */
+                boolean(false)
+            },
+            WAIT_OP => {
+                try!(self.target(f, c));
+                try!(self.eval(f, c));
+                boolean(false)
+            },
+            DEBUG_OP => Ok(Value::Uninitialized),
+            op => Err(AmlError::Unsupported(op)),
+        }
+    }
+    /// Evaluate an element of a package, where names are references to
+    /// objects rather than being evaluated
+    fn package_element(&mut self, f: &mut Frame, c: &mut Cursor<'a>) -> AmlResult<Value> {
+        if c.at_name() {
+            let name = try!(c.name_string());
+            return Ok(self.resolve(f.scope, &name)
+                .map_or(Value::Uninitialized, Value::Reference));
+        }
+        self.eval(f, c)
+    }
+    /// Parse a target or super name
+    fn target(&mut self, f: &mut Frame, c: &mut Cursor<'a>) -> AmlResult<Target> {
+        let b = try!(c.peek());
+        if b == ZERO_OP {
+            c.pos += 1;
+            return Ok(Target::Null);
+        }
+        if c.at_name() {
+            let name = try!(c.name_string());
+            return self.resolve(f.scope, &name).map(Target::Node).ok_or(AmlError::NotFound);
+        }
+        if b >= LOCAL0_OP && b <= LOCAL7_OP {
+            c.pos += 1;
+            return Ok(Target::Local((b - LOCAL0_OP) as usize));
+        }
+        if b >= ARG0_OP && b <= ARG6_OP {
+            c.pos += 1;
+            return Ok(Target::Arg((b - ARG0_OP) as usize));
+        }
+        if b == EXT_PREFIX {
+            let mut peek = *c;
+            if try!(peek.opcode()) == DEBUG_OP {
+                *c = peek;
+                return Ok(Target::Null);
+            }
+        }
+        /* otherwise it is an expression producing a reference */
+        match try!(self.eval(f, c)) {
+            Value::Reference(n) => Ok(Target::Node(n)),
+            Value::Element(r, i) => Ok(Target::Element(r, i)),
+            Value::Byte(r, i) => Ok(Target::Byte(r, i)),
+            _ => Err(AmlError::Type),
+        }
+    }
+    /// Read the current value of a target
+    fn load_target(&mut self, f: &Frame, t: Target) -> AmlResult<Value> {
+        match t {
+            Target::Null => Ok(Value::Uninitialized),
+            Target::Local(i) => Ok(f.locals[i]),
+            Target::Arg(i) => Ok(f.args[i]),
+            Target::Node(n) => self.read_node(n),
+            Target::Element(r, i) => self.deref(Value::Element(r, i)),
+            Target::Byte(r, i) => self.deref(Value::Byte(r, i)),
+        }
+    }
+    /// Write a value to a target
+    fn store(&mut self, f: &mut Frame, t: Target, v: Value) -> AmlResult<()> {
+        match t {
+            Target::Null => (),
+            Target::Local(i) => f.locals[i] = v,
+            Target::Arg(i) => f.args[i] = v,
+            Target::Node(n) => try!(self.store_node(n, v)),
+            Target::Element(r, i) => self.s.values[(r.start + i) as usize] = v,
+            Target::Byte(r, i) => {
+                let b = try!(self.to_integer(v)) as u8;
+                self.s.bytes[(r.start + i) as usize] = b;
+            },
+        }
+        Ok(())
+    }
+    /// Turn references to elements into their values
+    fn deref(&mut self, v: Value) -> AmlResult<Value> {
+        match v {
+            Value::Element(r, i) => Ok(self.s.values[(r.start + i) as usize]),
+            Value::Byte(r, i) => Ok(Value::Integer(self.s.bytes[(r.start + i) as usize] as u64)),
+            Value::Reference(n) => self.read_node(n),
+            other => Ok(other),
+        }
+    }
+    fn to_integer(&mut self, v: Value) -> AmlResult<u64> {
+        match try!(self.deref(v)) {
+            Value::Integer(i) => Ok(i),
+            Value::Buffer(r) => Ok(self.buffer(r).iter().take(8).enumerate()
+                .fold(0, |acc, (i, b)| acc | (*b as u64) << (i * 8))),
+            Value::String(span) => {
+                /* strings are implicitly converted as hexadecimal */
+                let bytes = self.bytes_of(Value::String(span)).unwrap_or(&[]);
+                let mut val = 0u64;
+                for b in bytes.iter().skip_while(|b| **b == b' ') {
+                    let digit = match *b {
+                        b'0' ... b'9' => b - b'0',
+                        b'a' ... b'f' => b - b'a' + 10,
+                        b'A' ... b'F' => b - b'A' + 10,
+                        _ => break,
+                    };
+                    val = val << 4 | digit as u64;
+                }
+                Ok(val)
+            },
+            _ => Err(AmlError::Type),
+        }
+    }
+    /// Compare two values. Strings and buffers compare their contents,
+    /// anything else is compared as integers
+    fn compare(&mut self, a: Value, b: Value) -> AmlResult<cmp::Ordering> {
+        let a = try!(self.deref(a));
+        let b = try!(self.deref(b));
+        if let (Some(x), Some(y)) = (self.bytes_of(a), self.bytes_of(b)) {
+            return Ok(x.cmp(y));
+        }
+        let x = try!(self.to_integer(a));
+        let y = try!(self.to_integer(b));
+        Ok(x.cmp(&y))
+    }
+    /// Object type code as returned by `ObjectType`
+    fn type_of(&self, v: Value) -> u64 {
+        match v {
+            Value::Uninitialized => 0,
+            Value::Integer(_) => 1,
+            Value::String(_) => 2,
+            Value::Buffer(_) => 3,
+            Value::Package(_) => 4,
+            _ => 0,
+        }
+    }
+    /// Handle `CreateBitField` and friends, and `CreateField`
+    fn create_buffer_field(&mut self, f: &mut Frame, op: u16, c: &mut Cursor<'a>) -> AmlResult<()> {
+        let source = try!(self.eval(f, c));
+        let buffer = match try!(self.deref(source)) {
+            Value::Buffer(r) => r,
+            _ => return Err(AmlError::Type),
+        };
+        let index = try!(self.eval_integer(f, c)) as u32;
+        let (bit_offset, bit_length) = match op {
+            CREATE_FIELD_OP => (index, try!(self.eval_integer(f, c)) as u32),
+            op if op == CREATE_BIT_FIELD_OP as u16 => (index, 1),
+            op if op == CREATE_BYTE_FIELD_OP as u16 => (index * 8, 8),
+            op if op == CREATE_WORD_FIELD_OP as u16 => (index * 8, 16),
+            op if op == CREATE_DWORD_FIELD_OP as u16 => (index * 8, 32),
+            _ => (index * 8, 64),
+        };
+        if bit_length > 64 || bit_offset + bit_length > buffer.len as u32 * 8 {
+            return Err(AmlError::Bounds);
+        }
+        let name = try!(c.name_string());
+        try!(self.create(f.scope, &name, NodeKind::BufferField {
+            buffer: buffer, bit_offset: bit_offset, bit_length: bit_length,
+        }));
+        Ok(())
+    }
+    /// Evaluate a term stored elsewhere in the byte code, such as the
+    /// data of a `Name`, in the given scope
+    fn eval_span(&mut self, scope: u16, span: Span) -> AmlResult<Value> {
+        let mut frame = Frame::new(scope);
+        let mut c = self.cursor(span);
+        self.eval(&mut frame, &mut c)
+    }
+    /// Value of a namespace object. Named data is built the first time it
+    /// is used, and kept so that modifications persist
+    fn read_node(&mut self, node: u16) -> AmlResult<Value> {
+        let node = self.target_of(node);
+        match self.s.nodes[node as usize].kind {
+            NodeKind::Name(span) => {
+                let parent = self.parent(node);
+                let mut frame = Frame::new(parent);
+                let mut c = self.cursor(span);
+                let v = try!(self.package_element(&mut frame, &mut c));
+                let slot = try!(self.alloc_values(1));
+                self.s.values[slot.start as usize] = v;
+                self.s.nodes[node as usize].kind = NodeKind::Value(slot.start);
+                Ok(v)
+            },
+            NodeKind::Value(slot) => Ok(self.s.values[slot as usize]),
+            NodeKind::Field { .. } | NodeKind::IndexField { .. } | NodeKind::BufferField { .. } =>
+                self.read_field(node).map(Value::Integer),
+            NodeKind::Method { args: 0, .. } => self.invoke(node, &[]),
+            _ => Ok(Value::Reference(node)),
+        }
+    }
+    /// Write to a namespace object
+    fn store_node(&mut self, node: u16, v: Value) -> AmlResult<()> {
+        let node = self.target_of(node);
+        match self.s.nodes[node as usize].kind {
+            NodeKind::Name(_) => {
+                let slot = try!(self.alloc_values(1));
+                self.s.values[slot.start as usize] = v;
+                self.s.nodes[node as usize].kind = NodeKind::Value(slot.start);
+                Ok(())
+            },
+            NodeKind::Value(slot) => {
+                self.s.values[slot as usize] = v;
+                Ok(())
+            },
+            NodeKind::Field { .. } | NodeKind::IndexField { .. } | NodeKind::BufferField { .. } => {
+                let v = try!(self.to_integer(v));
+                self.write_field(node, v)
+            },
+            _ => Err(AmlError::Type),
+        }
+    }
+
+    /* Fields and operation regions */
+
+    /// Read or write a field, one access unit at a time. For writes each
+    /// unit is read first so that bits outside the field are preserved
+    fn field_access(&mut self, node: u16, write: Option<u64>) -> AmlResult<u64> {
+        let (bit_offset, bit_length, access) = match self.s.nodes[node as usize].kind {
+            NodeKind::Field { bit_offset, bit_length, access, .. } => (bit_offset, bit_length, access),
+            NodeKind::IndexField { bit_offset, bit_length, access, .. } =>
+                (bit_offset, bit_length, access),
+            NodeKind::BufferField { bit_offset, bit_length, .. } => (bit_offset, bit_length, ACCESS_ANY),
+            _ => return Err(AmlError::Type),
+        };
+        if bit_length > 64 {
+            return Err(AmlError::Unsupported(FIELD_OP));
+        }
+        let width = access_width(access);
+        let unit_bits = width as u32 * 8;
+        let mut result = 0;
+        let mut done = 0;
+        while done < bit_length {
+            let bit = bit_offset + done;
+            let unit = (bit / unit_bits) as usize * width;
+            let shift = bit % unit_bits;
+            let count = cmp::min(unit_bits - shift, bit_length - done);
+            let mask = if count == 64 { ONES } else { (1 << count) - 1 };
+            let raw = try!(self.unit_access(node, unit, width, None));
+            result |= ((raw >> shift) & mask) << done;
+            if let Some(v) = write {
+                let bits = (v >> done) & mask;
+                let new = (raw & !(mask << shift)) | bits << shift;
+                try!(self.unit_access(node, unit, width, Some(new)));
+            }
+            done += count;
+        }
+        Ok(result)
+    }
+    fn read_field(&mut self, node: u16) -> AmlResult<u64> {
+        self.field_access(node, None)
+    }
+    fn write_field(&mut self, node: u16, v: u64) -> AmlResult<()> {
+        self.field_access(node, Some(v)).map(|_| ())
+    }
+    /// Access a single aligned unit of the storage behind a field, at
+    /// `offset` bytes from the start of the region, buffer or index range
+    fn unit_access(&mut self, node: u16, offset: usize, width: usize, write: Option<u64>)
+            -> AmlResult<u64> {
+        match self.s.nodes[node as usize].kind {
+            NodeKind::Field { region, .. } => self.region_access(region, offset, width, write),
+            NodeKind::IndexField { index, data, .. } => {
+                try!(self.write_field(index, offset as u64));
+                match write {
+                    Some(v) => self.write_field(data, v).map(|_| v),
+                    None => self.read_field(data),
+                }
+            },
+            NodeKind::BufferField { buffer, .. } => {
+                let start = buffer.start as usize + offset;
+                match write {
+                    Some(v) => {
+                        self.s.bytes[start] = v as u8;
+                        Ok(v)
+                    },
+                    None => Ok(self.s.bytes[start] as u64),
+                }
+            },
+            _ => Err(AmlError::Type),
+        }
+    }
+    /// Find the PCI function an operation region in PCI configuration
+    /// space belongs to, from the `_ADR` of its device and `_BBN` of its
+    /// bridge
+    fn region_bdf(&mut self, region: u16) -> AmlResult<Bdf> {
+        let mut scope = self.parent(region);
+        let mut adr = None;
+        let mut bus = 0;
+        loop {
+            if adr.is_none() {
+                if let Some(n) = self.child(scope, b"_ADR") {
+                    adr = Some(try!(self.read_node(n).and_then(|v| self.to_integer(v))));
+                }
+            }
+            if let Some(n) = self.child(scope, b"_BBN") {
+                bus = try!(self.read_node(n).and_then(|v| self.to_integer(v)));
+                break;
+            }
+            if scope == 0 {
+                break;
+            }
+            scope = self.parent(scope);
+        }
+        let adr = try!(adr.ok_or(AmlError::NotFound));
+        Ok(Bdf::new(bus as u8, (adr >> 16) as u8, adr as u8))
+    }
+    /// Access an operation region
+    fn region_access(&mut self, region: u16, offset: usize, width: usize, write: Option<u64>)
+            -> AmlResult<u64> {
+        let (space, base) = match self.s.nodes[region as usize].kind {
+            NodeKind::Region { space, offset: base, length } => {
+                let scope = self.parent(region);
+                let base = try!(self.eval_span(scope, base).and_then(|v| self.to_integer(v)));
+                let length = try!(self.eval_span(scope, length).and_then(|v| self.to_integer(v)));
+                if (offset + width) as u64 > length {
+                    return Err(AmlError::Bounds);
+                }
+                (space, base as usize)
+            },
+            _ => return Err(AmlError::Type),
+        };
+        let addr = base + offset;
+        match space {
+            SPACE_SYSTEM_IO => unsafe {
+                let port = addr as u16;
+                Ok(match (width, write) {
+                    (1, Some(v)) => { outb(port, v as u8); v },
+                    (2, Some(v)) => { outw(port, v as u16); v },
+                    (4, Some(v)) => { outl(port, v as u32); v },
+                    (1, None) => inb(port) as u64,
+                    (2, None) => inw(port) as u64,
+                    (4, None) => inl(port) as u64,
+                    _ => return Err(AmlError::Region),
+                })
+            },
+            SPACE_SYSTEM_MEMORY => {
+                macro_rules! mmio {
+                    ($t:ty) => {{
+                        let reg: &Volatile<$t> = try!(unsafe{self.window.try_from_paddr(PAddr(addr))
+                            .and_then(|a| self.window.make(a))}.ok_or(AmlError::Region));
+                        match write {
+                            Some(v) => { reg.write(v as $t); v },
+                            None => reg.read() as u64,
+                        }
+                    }}
+                }
+                Ok(match width {
+                    1 => mmio!(u8),
+                    2 => mmio!(u16),
+                    4 => mmio!(u32),
+                    _ => mmio!(u64),
+                })
+            },
+            SPACE_PCI_CONFIG => {
+                let bdf = try!(self.region_bdf(region));
+                let config = PortConfig;
+                let reg = addr as u16;
+                if reg >= 0x100 {
+                    return Err(AmlError::Region);
+                }
+                match write {
+                    None => Ok(match width {
+                        1 => config.read8(bdf, reg) as u64,
+                        2 => config.read16(bdf, reg) as u64,
+                        _ => config.read32(bdf, reg) as u64,
+                    }),
+                    Some(v) => {
+                        match width {
+                            1 => {
+                                let shift = (reg & 0x3) * 8;
+                                let old = config.read32(bdf, reg & !0x3) & !(0xff << shift);
+                                config.write32(bdf, reg & !0x3, old | (v as u8 as u32) << shift);
+                            },
+                            2 => config.write16(bdf, reg, v as u16),
+                            _ => config.write32(bdf, reg, v as u32),
+                        }
+                        Ok(v)
+                    },
+                }
+            },
+            _ => Err(AmlError::Region),
+        }
+    }
+
+    /* Public interface */
+
+    /// Evaluate the object at an absolute path, such as `\_SB.PCI0._PRT`.
+    /// Methods are invoked with the given arguments. Buffers and packages
+    /// in the result are only valid until this interpreter is dropped
+    pub fn evaluate(&mut self, path: &str, args: &[Value]) -> AmlResult<Value> {
+        let node = try!(self.resolve_path(path).ok_or(AmlError::NotFound));
+        match self.s.nodes[node as usize].kind {
+            NodeKind::Method { .. } => self.invoke(node, args),
+            _ => self.read_node(node),
+        }
+    }
+    /// Retrieve an element of a package as an integer
+    pub fn package_integer(&mut self, package: Value, index: usize) -> AmlResult<u64> {
+        match try!(self.deref(package)) {
+            Value::Package(r) if index < r.len as usize =>
+                self.to_integer(Value::Element(r, index as u16)),
+            Value::Package(_) => Err(AmlError::Bounds),
+            _ => Err(AmlError::Type),
+        }
+    }
+    /// The SLP_TYPa and SLP_TYPb values for entering the S5 soft off state
+    pub fn s5_sleep_type(&mut self) -> AmlResult<(u16, u16)> {
+        let s5 = try!(self.evaluate("\\_S5", &[]));
+        let a = try!(self.package_integer(s5, 0));
+        /* some firmware only provides a single value for both */
+        let b = self.package_integer(s5, 1).unwrap_or(a);
+        if a > 0xffff || b > 0xffff {
+            return Err(AmlError::Bounds);
+        }
+        Ok((a as u16, b as u16))
+    }
+    /// Tell the firmware we will be routing interrupts through the I/O
+    /// APIC, which changes what `_PRT` returns on many machines
+    pub fn use_apic_mode(&mut self) -> AmlResult<()> {
+        match self.evaluate("\\_PIC", &[Value::Integer(1)]) {
+            Ok(_) | Err(AmlError::NotFound) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+    /// True if a device has the given hardware or compatible ID
+    fn device_is(&mut self, device: u16, id: u64) -> bool {
+        for name in [b"_HID", b"_CID"].iter() {
+            if let Some(n) = self.child(device, *name) {
+                match self.read_node(n).and_then(|v| self.deref(v)) {
+                    Ok(Value::Integer(v)) if v == id => return true,
+                    _ => (),
+                }
+            }
+        }
+        false
+    }
+    /// Find the first device with the given hardware or compatible ID
+    pub fn find_device(&mut self, id: u64) -> Option<u16> {
+        (0..self.s.num_nodes as u16).find(|n| {
+            match self.s.nodes[*n as usize].kind {
+                NodeKind::Device => self.device_is(*n, id),
+                _ => false,
+            }
+        })
+    }
+    /// Find the interrupt described by the `_CRS` of an interrupt link
+    /// device, returning (interrupt, level triggered, active low)
+    fn link_interrupt(&mut self, link: u16) -> AmlResult<(u32, bool, bool)> {
+        let crs = try!(self.child(link, b"_CRS").ok_or(AmlError::NotFound));
+        let v = try!(self.read_node(crs));
+        let bytes = try!(self.bytes_of(v).ok_or(AmlError::Type));
+        parse_interrupt_resource(bytes).ok_or(AmlError::NotFound)
+    }
+    /// Find the PCI interrupt routing of the root bus, from the `_PRT` of
+    /// the PCI or PCI Express root bridge
+    pub fn pci_routes(&mut self, routes: &mut PciRoutes) -> AmlResult<()> {
+        try!(self.use_apic_mode());
+        let bridge = try!(self.find_device(eisa_id(b"PNP0A08"))
+            .or_else(|| self.find_device(eisa_id(b"PNP0A03")))
+            .ok_or(AmlError::NotFound));
+        let prt_node = try!(self.child(bridge, b"_PRT").ok_or(AmlError::NotFound));
+        let prt = match try!(self.read_node(prt_node).and_then(|v| self.deref(v))) {
+            Value::Package(r) => r,
+            _ => return Err(AmlError::Type),
+        };
+        for i in 0..prt.len {
+            match self.prt_entry(Value::Element(prt, i)) {
+                Ok(route) => routes.push(route),
+                Err(_) => routes.dropped += 1,
+            }
+        }
+        Ok(())
+    }
+    /// Decode a single `_PRT` entry
+    fn prt_entry(&mut self, entry: Value) -> AmlResult<PciRoute> {
+        let address = try!(self.package_integer(entry, 0));
+        let pin = try!(self.package_integer(entry, 1));
+        let index = try!(self.package_integer(entry, 3));
+        let source = match try!(self.deref(entry)) {
+            Value::Package(r) if r.len >= 4 => self.s.values[r.start as usize + 2],
+            _ => return Err(AmlError::Type),
+        };
+        let (gsi, level, active_low) = match source {
+            /* a source of zero means the index is the GSI, and the
+             * interrupt is PCI's level triggered active low */
+            Value::Integer(0) => (index as u32, true, true),
+            Value::Reference(link) => try!(self.link_interrupt(link)),
+            _ => return Err(AmlError::Type),
+        };
+        Ok(PciRoute {
+            device: (address >> 16) as u8,
+            pin: pin as u8,
+            gsi: gsi,
+            level: level,
+            active_low: active_low,
+        })
+    }
+}
+
+/// Find the first interrupt in a resource template, returning
+/// (interrupt, level triggered, active low)
+fn parse_interrupt_resource(bytes: &[u8]) -> Option<(u32, bool, bool)> {
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let tag = bytes[pos];
+        if tag & 0x80 == 0 {
+            /* small descriptor */
+            let kind = (tag >> 3) & 0xf;
+            let len = (tag & 0x7) as usize;
+            let data = match bytes.get(pos + 1..pos + 1 + len) {
+                Some(d) => d,
+                None => return None,
+            };
+            match kind {
+                RESOURCE_IRQ if len >= 2 => {
+                    let mask = data[0] as u16 | (data[1] as u16) << 8;
+                    if mask != 0 {
+                        /* without the information byte this is edge
+                         * triggered active high */
+                        let info = if len >= 3 { data[2] } else { 0x01 };
+                        return Some((mask.trailing_zeros(), info & 0x01 == 0, info & 0x08 != 0));
+                    }
+                },
+                RESOURCE_END => return None,
+                _ => (),
+            }
+            pos += 1 + len;
+        } else {
+            /* large descriptor */
+            let len = match bytes.get(pos + 1..pos + 3) {
+                Some(l) => l[0] as usize | (l[1] as usize) << 8,
+                None => return None,
+            };
+            let data = match bytes.get(pos + 3..pos + 3 + len) {
+                Some(d) => d,
+                None => return None,
+            };
+            if tag & 0x7f == RESOURCE_EXTENDED_IRQ && len >= 6 && data[1] >= 1 {
+                let flags = data[0];
+                let irq = data[2] as u32 | (data[3] as u32) << 8
+                    | (data[4] as u32) << 16 | (data[5] as u32) << 24;
+                return Some((irq, flags & 0x02 == 0, flags & 0x04 != 0));
+            }
+            pos += 3 + len;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use ::core::ops::Deref;
+    use vspace::VSpaceWindow;
+    use types::PAddr;
+    use super::{Aml, AmlStorage, Value, EMPTY_STORAGE, EMPTY_ROUTES, eisa_id};
+
+    /// DSDT with the interrupt routing, `_PIC` and sleep state objects of
+    /// QEMU's i440fx machine, laid out as QEMU generates them. Its `_PRT`
+    /// is built by a loop over the PIIX interrupt links
+    static PC_DSDT: &'static [u8] = include_bytes!("testdata/qemu-pc-dsdt.aml");
+    /// As above for QEMU's q35 machine. Its `_PRT` picks between two fixed
+    /// tables depending on `_PIC`
+    static Q35_DSDT: &'static [u8] = include_bytes!("testdata/qemu-q35-dsdt.aml");
+    /// Size of the table header that precedes the AML
+    const HEADER_SIZE: usize = 36;
+
+    #[derive(Debug, Copy, Clone)]
+    struct TestAddr(usize);
+
+    impl Deref for TestAddr {
+        type Target = usize;
+        fn deref(&self) -> &usize {
+            &self.0
+        }
+    }
+
+    /// Window with nothing in it, as the tables do not use system memory
+    struct TestWindow;
+
+    unsafe impl<'a> VSpaceWindow<'a> for TestWindow {
+        type Addr = TestAddr;
+        type InitData = ();
+        fn base(&self) -> usize {
+            0
+        }
+        fn size(&self) -> usize {
+            0
+        }
+        unsafe fn new(_: ()) -> TestWindow {
+            TestWindow
+        }
+        unsafe fn from_paddr(&self, paddr: PAddr) -> TestAddr {
+            TestAddr(paddr.0)
+        }
+        unsafe fn to_paddr(&self, addr: TestAddr) -> PAddr {
+            PAddr(addr.0)
+        }
+        unsafe fn to_addr(&self, addr: usize) -> TestAddr {
+            TestAddr(addr)
+        }
+    }
+
+    /// Check that a table is a well formed DSDT and return its AML
+    fn definition_block(table: &'static [u8]) -> &'static [u8] {
+        assert_eq!(&table[..4], b"DSDT");
+        let length = table[4] as usize | (table[5] as usize) << 8
+            | (table[6] as usize) << 16 | (table[7] as usize) << 24;
+        assert_eq!(length, table.len());
+        assert_eq!(table.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)), 0);
+        &table[HEADER_SIZE..]
+    }
+
+    /// Interpreter with a table loaded
+    fn load<'s>(window: &'static TestWindow, storage: &'s mut AmlStorage, table: &'static [u8])
+            -> Aml<'static, 's, TestWindow> {
+        let mut aml = Aml::new(window, storage);
+        aml.load(definition_block(table)).unwrap();
+        aml
+    }
+
+    static WINDOW: TestWindow = TestWindow;
+
+    #[test]
+    fn pc_sleep_type_and_bridge() {
+        let mut storage = Box::new(EMPTY_STORAGE);
+        let mut aml = load(&WINDOW, &mut storage, PC_DSDT);
+        assert_eq!(aml.s5_sleep_type(), Ok((0, 0)));
+        let bridge = aml.find_device(eisa_id(b"PNP0A03")).unwrap();
+        assert_eq!(&aml.s.nodes[bridge as usize].name, b"PCI0");
+        assert_eq!(aml.find_device(eisa_id(b"PNP0A08")), None);
+    }
+
+    #[test]
+    fn pc_prt_is_built_by_its_loop() {
+        let mut storage = Box::new(EMPTY_STORAGE);
+        let mut aml = load(&WINDOW, &mut storage, PC_DSDT);
+        aml.use_apic_mode().unwrap();
+        assert_eq!(aml.evaluate("\\PICF", &[]), Ok(Value::Integer(1)));
+        let prt = aml.evaluate("\\_SB.PCI0._PRT", &[]).unwrap();
+        let entries = match prt {
+            Value::Package(r) => r,
+            v => panic!("_PRT is {:?}", v),
+        };
+        assert_eq!(entries.len, 128);
+        /* slot 1 INTA is the SCI link, the rest rotate through the four
+         * PIIX links */
+        for &(i, link) in [(0, b"LNKD"), (4, b"LNKS"), (5, b"LNKB"), (12, b"LNKC"),
+                (127, b"LNKB")].iter() {
+            let entry = Value::Element(entries, i);
+            assert_eq!(aml.package_integer(entry, 0), Ok(((i as u64 >> 2) << 16) | 0xffff));
+            assert_eq!(aml.package_integer(entry, 1), Ok(i as u64 & 0x3));
+            match aml.deref(entry).unwrap() {
+                Value::Package(r) => match aml.s.values[r.start as usize + 2] {
+                    Value::Reference(node) => assert_eq!(&aml.s.nodes[node as usize].name, link),
+                    v => panic!("source of entry {} is {:?}", i, v),
+                },
+                v => panic!("entry {} is {:?}", i, v),
+            }
+        }
+    }
+
+    #[test]
+    fn q35_routes_use_the_gsi_links() {
+        let mut storage = Box::new(EMPTY_STORAGE);
+        let mut aml = load(&WINDOW, &mut storage, Q35_DSDT);
+        assert_eq!(aml.s5_sleep_type(), Ok((0, 0)));
+        let mut routes = EMPTY_ROUTES;
+        aml.pci_routes(&mut routes).unwrap();
+        assert_eq!(routes.as_slice().len(), 128);
+        assert_eq!(routes.dropped(), 0);
+        /* the LPC bridge and anything in slots 0x18 to 0x1d use PIRQA to
+         * PIRQD, which are GSIs 16 to 19, and other slots rotate through
+         * PIRQE to PIRQH */
+        for &(device, pin, gsi) in [(0x1f, 0, 16), (0x1f, 3, 19), (0x19, 1, 17), (0x00, 0, 20),
+                (0x02, 0, 22), (0x03, 2, 21), (0x1e, 3, 23)].iter() {
+            let route = routes.find(device, pin).unwrap();
+            assert_eq!(route.gsi, gsi);
+            assert!(route.level);
+            assert!(!route.active_low);
+        }
+        assert!(routes.find(0x20, 0).is_none());
+    }
+}
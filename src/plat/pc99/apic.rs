@@ -0,0 +1,126 @@
+//! Processor and local APIC description from the MADT
+//!
+//! The MADT lists every processor by APIC ID along with an ACPI processor
+//! UID, which is what the NMI entries refer to. Processors with APIC IDs
+//! that fit in 8 bits are normally described by `APIC` entries and the
+//! rest by `LocalX2APIC` entries, but we accept either for any ID.
+use vspace::VSpaceWindow;
+use types::PAddr;
+use arch::x86_64::lapic::LocalApic;
+use cluster::MAX_CPUS;
+use super::acpi;
+use super::acpi::{MADT, MADTTable};
+
+/// Maximum number of NMI connections we record
+const MAX_NMIS: usize = 16;
+/// Largest APIC ID that can be addressed in xAPIC mode. 0xff is the
+/// broadcast ID
+pub const MAX_XAPIC_ID: u32 = 0xfe;
+
+#[derive(Debug, Copy, Clone)]
+/// A processor described in the MADT
+pub struct Cpu {
+    pub apic_id: u32,
+    /// ACPI processor UID
+    pub uid: u32,
+}
+
+#[derive(Debug, Copy, Clone)]
+/// A LINT pin connected to NMI
+struct LintNmi {
+    /// Processor UID, or `acpi::ALL_PROCESSORS`
+    uid: u32,
+    lint: u8,
+    /// MPS INTI flags
+    flags: u16,
+}
+
+/// Processors and local APIC configuration of the machine
+pub struct ApicInfo {
+    /// Physical address of the local APIC registers
+    lapic_base: PAddr,
+    cpus: [Cpu; MAX_CPUS],
+    num_cpus: usize,
+    nmis: [LintNmi; MAX_NMIS],
+    num_nmis: usize,
+    /// Number of entries that did not fit in the above arrays
+    dropped: usize,
+}
+
+impl ApicInfo {
+    /// Construct with no processors and the architectural default local
+    /// APIC address
+    pub fn new() -> ApicInfo {
+        ApicInfo {
+            lapic_base: PAddr(0xfee0_0000),
+            cpus: [Cpu { apic_id: 0, uid: 0 }; MAX_CPUS],
+            num_cpus: 0,
+            nmis: [LintNmi { uid: 0, lint: 0, flags: 0 }; MAX_NMIS],
+            num_nmis: 0,
+            dropped: 0,
+        }
+    }
+    fn add_cpu(&mut self, apic_id: u32, uid: u32) {
+        if self.num_cpus == MAX_CPUS {
+            self.dropped += 1;
+            return;
+        }
+        self.cpus[self.num_cpus] = Cpu { apic_id: apic_id, uid: uid };
+        self.num_cpus += 1;
+    }
+    fn add_nmi(&mut self, uid: u32, lint: u8, flags: u16) {
+        if self.num_nmis == MAX_NMIS {
+            self.dropped += 1;
+            return;
+        }
+        self.nmis[self.num_nmis] = LintNmi { uid: uid, lint: lint, flags: flags };
+        self.num_nmis += 1;
+    }
+    /// Record the processors, NMI connections and local APIC address of
+    /// an MADT. Malformed entries are skipped, it is up to the caller to
+    /// report them. The window must be the one the MADT was found in
+    pub fn add_madt<'a, W: VSpaceWindow<'a>>(&mut self, window: &'a W, madt: &MADT) {
+        let mut base = madt.local_apic_address();
+        for entry in madt.iter(window) {
+            match entry {
+                MADTTable::APIC(cpu) if cpu.enabled() =>
+                    self.add_cpu(cpu.apic_id(), cpu.processor_uid()),
+                MADTTable::LocalX2APIC(cpu) if cpu.enabled() =>
+                    self.add_cpu(cpu.apic_id(), cpu.processor_uid()),
+                MADTTable::LocalNMI(nmi) =>
+                    self.add_nmi(nmi.processor_uid(), nmi.lint(), nmi.flags()),
+                MADTTable::X2APICNMI(nmi) =>
+                    self.add_nmi(nmi.processor_uid(), nmi.lint(), nmi.flags()),
+                MADTTable::AddressOverride(over) => base = over.address(),
+                _ => (),
+            }
+        }
+        self.lapic_base = base;
+    }
+    /// Physical address of the local APIC registers
+    pub fn lapic_base(&self) -> PAddr {
+        self.lapic_base
+    }
+    /// All usable processors
+    pub fn cpus(&self) -> &[Cpu] {
+        &self.cpus[..self.num_cpus]
+    }
+    /// True if any processor can only be addressed in x2APIC mode
+    pub fn needs_x2apic(&self) -> bool {
+        self.cpus().iter().any(|c| c.apic_id > MAX_XAPIC_ID)
+    }
+    /// Number of entries that did not fit
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+    /// Program the LINT pins of the local APIC of the calling processor
+    /// that are connected to NMI. Returns the number of pins programmed
+    pub fn program_nmis(&self, lapic: &LocalApic) -> usize {
+        let id = lapic.id();
+        let uid = self.cpus().iter().find(|c| c.apic_id == id).map(|c| c.uid);
+        self.nmis[..self.num_nmis].iter()
+            .filter(|n| n.uid == acpi::ALL_PROCESSORS || Some(n.uid) == uid)
+            .filter(|n| lapic.set_lint_nmi(n.lint, n.flags).is_ok())
+            .count()
+    }
+}
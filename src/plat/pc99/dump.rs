@@ -0,0 +1,86 @@
+//! Descriptions of the platform for the debugger monitor
+//!
+//! The monitor has no reference to the platform, so the ACPI tables are
+//! found again each time, through the device window, from the RSDP hint
+//! recorded when the platform was constructed.
+use vspace::VSpaceWindow;
+use arch;
+use arch::x86_64::DeviceWindow;
+use types::PAddr;
+use ::core::{fmt, str};
+use ::core::fmt::Write;
+use ::core::sync::atomic::{AtomicUsize, Ordering};
+use super::acpi::{ACPI, ACPIHeader, MADTTable, RSDTTable};
+
+/// Physical address of the RSDP given by the boot loader or command line,
+/// or zero if there was none
+static RSDP_HINT: AtomicUsize = AtomicUsize::new(0);
+
+/// Remember where the RSDP is for later walks of the tables
+pub fn set_rsdp_hint(hint: Option<PAddr>) {
+    RSDP_HINT.store(hint.map_or(0, |paddr| paddr.0), Ordering::Relaxed);
+}
+
+/// Find the ACPI tables through `window`
+fn find_acpi<'a>(window: &'a DeviceWindow<'a>) -> Option<ACPI<'a, DeviceWindow<'a>>> {
+    let hint = match RSDP_HINT.load(Ordering::Relaxed) {
+        0 => None,
+        paddr => Some(PAddr(paddr)),
+    };
+    ACPI::new(window, hint)
+}
+
+/// Print the header of a table on one line
+fn write_header(out: &mut Write, window: &DeviceWindow, header: &ACPIHeader) -> fmt::Result {
+    let paddr = unsafe{window.to_paddr(window.to_addr(header as *const ACPIHeader as usize))};
+    let (oem, table) = header.oem();
+    write!(out, "{:08x} {} rev {} length {:5} OEM {} {}\n", paddr.0,
+        str::from_utf8(header.signature()).unwrap_or("????"), header.revision(), header.length(),
+        str::from_utf8(oem).unwrap_or("?"), str::from_utf8(table).unwrap_or("?"))
+}
+
+/// List every table in the RSDT, and the DSDT
+pub fn write_acpi_tables(out: &mut Write) -> fmt::Result {
+    let window: DeviceWindow = unsafe{DeviceWindow::new(())};
+    let acpi = match find_acpi(&window) {
+        Some(acpi) => acpi,
+        None => return write!(out, "No ACPI tables found\n"),
+    };
+    for table in acpi.rsdt_iter() {
+        match table.header() {
+            Some(header) => try!(write_header(out, &window, header)),
+            None => if let RSDTTable::Invalid(paddr, err) = table {
+                try!(write!(out, "{:08x} invalid: {:?}\n", paddr.0, err));
+            },
+        }
+    }
+    match acpi.fadt().map(|fadt| acpi.dsdt(fadt)) {
+        Some(Ok(dsdt)) => write_header(out, &window, dsdt),
+        Some(Err(err)) => write!(out, "DSDT invalid: {:?}\n", err),
+        None => Ok(()),
+    }
+}
+
+/// List the processors in the MADT along with what is known of their state
+pub fn write_cpus(out: &mut Write) -> fmt::Result {
+    let window: DeviceWindow = unsafe{DeviceWindow::new(())};
+    let acpi = match find_acpi(&window) {
+        Some(acpi) => acpi,
+        None => return write!(out, "No ACPI tables found\n"),
+    };
+    for madt in acpi.madt_iter() {
+        for entry in madt.iter(&window) {
+            let (apic_id, uid, enabled) = match entry {
+                MADTTable::APIC(cpu) => (cpu.apic_id(), cpu.processor_uid(), cpu.enabled()),
+                MADTTable::LocalX2APIC(cpu) =>
+                    (cpu.apic_id(), cpu.processor_uid(), cpu.enabled()),
+                _ => continue,
+            };
+            try!(write!(out, "APIC {:3} UID {:3} {:8} ", apic_id, uid,
+                if enabled { "enabled" } else { "disabled" }));
+            try!(arch::write_cpu_state(out, apic_id));
+            try!(write!(out, "\n"));
+        }
+    }
+    Ok(())
+}
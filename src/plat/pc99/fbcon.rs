@@ -0,0 +1,184 @@
+//! Framebuffer text console
+//!
+//! Draws text with the built in font into a linear framebuffer that the
+//! boot loader set up. Glyphs are scaled up by a whole factor so that the
+//! text stays readable on large displays, whilst still fitting at least
+//! `MIN_COLUMNS` by `MIN_ROWS` characters.
+//!
+//! The framebuffer is mapped write combining, which makes writes cheap but
+//! reads very slow, so the characters on screen are also kept in `TEXT`.
+//! Scrolling then redraws only the cells whose character changes, instead
+//! of copying pixels out of the framebuffer.
+use plat::Framebuffer;
+use arch::x86_64::map_write_combining;
+use util::Volatile;
+use ::core::{cmp, slice};
+use super::font;
+
+/// Width of a character cell in font pixels, leaving a gap after the glyph
+const CELL_WIDTH: usize = font::WIDTH + 1;
+/// Height of a character cell in font pixels, leaving a gap above and
+/// below the glyph
+const CELL_HEIGHT: usize = font::HEIGHT + 2;
+/// Largest scale that still fits this many columns is used
+const MIN_COLUMNS: usize = 80;
+/// Largest scale that still fits this many rows is used
+const MIN_ROWS: usize = 25;
+/// Size of `TEXT`. Characters beyond this are not used on huge displays
+const MAX_COLUMNS: usize = 256;
+const MAX_ROWS: usize = 128;
+/// Intensity of the text, out of 255, to match the light grey of the VGA
+/// text console
+const INTENSITY: u32 = 0xaa;
+
+/// Characters currently on screen. This is far too large for the boot
+/// stack, and there is only ever one framebuffer console
+static mut TEXT: [u8; MAX_COLUMNS * MAX_ROWS] = [b' '; MAX_COLUMNS * MAX_ROWS];
+
+/// Output to a linear framebuffer
+pub struct FramebufferConsole {
+    pixels: &'static [Volatile<u8>],
+    text: &'static mut [u8],
+    pitch: usize,
+    /// Bytes per pixel
+    depth: usize,
+    /// Pixel value of the text colour
+    foreground: u32,
+    /// Screen pixels per font pixel
+    scale: usize,
+    columns: usize,
+    rows: usize,
+    row: usize,
+    column: usize,
+}
+
+/// Value of a colour channel at `INTENSITY`
+fn channel(field: (u8, u8)) -> u32 {
+    let (position, size) = field;
+    (((1u32 << size) - 1) * INTENSITY / 0xff) << position
+}
+
+impl FramebufferConsole {
+    /// Map the framebuffer and clear the screen. Returns `None` if the
+    /// pixel format is not understood or the framebuffer cannot be mapped
+    ///
+    /// # Safety
+    ///
+    /// Must be called at most once, from the bootstrap address space, and
+    /// nothing else may be drawing to the framebuffer
+    pub unsafe fn new(fb: &Framebuffer) -> Option<FramebufferConsole> {
+        let depth = match fb.bpp {
+            15 | 16 => 2,
+            24 => 3,
+            32 => 4,
+            _ => return None,
+        };
+        if fb.pitch < fb.width * depth || fb.red.1 > 8 || fb.green.1 > 8 || fb.blue.1 > 8 {
+            return None;
+        }
+        let size = fb.pitch * fb.height;
+        let pixels = match map_write_combining(fb.base, size) {
+            Some(addr) => slice::from_raw_parts(addr as *const Volatile<u8>, size),
+            None => return None,
+        };
+        let scale = cmp::max(1, cmp::min(fb.width / (MIN_COLUMNS * CELL_WIDTH),
+            fb.height / (MIN_ROWS * CELL_HEIGHT)));
+        let columns = cmp::min(MAX_COLUMNS, fb.width / (CELL_WIDTH * scale));
+        let rows = cmp::min(MAX_ROWS, fb.height / (CELL_HEIGHT * scale));
+        if columns == 0 || rows == 0 {
+            return None;
+        }
+        for pixel in pixels.iter() {
+            pixel.write(0);
+        }
+        let text = &mut TEXT[..columns * rows];
+        for c in text.iter_mut() {
+            *c = b' ';
+        }
+        Some(FramebufferConsole {
+            pixels: pixels,
+            text: text,
+            pitch: fb.pitch,
+            depth: depth,
+            foreground: channel(fb.red) | channel(fb.green) | channel(fb.blue),
+            scale: scale,
+            columns: columns,
+            rows: rows,
+            row: 0,
+            column: 0,
+        })
+    }
+    /// Draw the character `c` into the cell at `column`, `row`
+    fn draw(&self, column: usize, row: usize, c: u8) {
+        let glyph = font::glyph(c);
+        let left = column * CELL_WIDTH * self.scale;
+        let top = row * CELL_HEIGHT * self.scale;
+        for y in 0..CELL_HEIGHT * self.scale {
+            /* the first font row of the cell is the gap above the glyph */
+            let bits = match (y / self.scale).checked_sub(1) {
+                Some(i) if i < font::HEIGHT => glyph[i],
+                _ => 0,
+            };
+            let line = (top + y) * self.pitch + left * self.depth;
+            for x in 0..CELL_WIDTH * self.scale {
+                let bit = x / self.scale;
+                let value = if bit < font::WIDTH && bits & (1 << (font::WIDTH - 1 - bit)) != 0 {
+                    self.foreground
+                } else {
+                    0
+                };
+                let offset = line + x * self.depth;
+                for (i, byte) in self.pixels[offset..offset + self.depth].iter().enumerate() {
+                    byte.write((value >> (i * 8)) as u8);
+                }
+            }
+        }
+    }
+    /// Place `c` in the cell at index `i` of `text`, drawing it only if it
+    /// differs from what is already there
+    fn set(&mut self, i: usize, c: u8) {
+        if self.text[i] != c {
+            self.text[i] = c;
+            self.draw(i % self.columns, i / self.columns, c);
+        }
+    }
+    /// Move everything up a row, clearing the bottom one
+    fn scroll(&mut self) {
+        let columns = self.columns;
+        for i in 0..(self.rows - 1) * columns {
+            let c = self.text[i + columns];
+            self.set(i, c);
+        }
+        for i in (self.rows - 1) * columns..self.rows * columns {
+            self.set(i, b' ');
+        }
+    }
+    fn newline(&mut self) {
+        self.column = 0;
+        if self.row == self.rows - 1 {
+            self.scroll();
+        } else {
+            self.row += 1;
+        }
+    }
+    /// Display a single character, wrapping at the end of the row
+    pub fn putchar(&mut self, c: u8) {
+        match c {
+            b'\n' => self.newline(),
+            b'\r' => self.column = 0,
+            b'\t' => {
+                for _ in 0..(8 - self.column % 8) {
+                    self.putchar(b' ');
+                }
+            },
+            c => {
+                if self.column == self.columns {
+                    self.newline();
+                }
+                let i = self.row * self.columns + self.column;
+                self.set(i, c);
+                self.column += 1;
+            },
+        }
+    }
+}
@@ -0,0 +1,68 @@
+//! Serial port for the GDB stub
+//!
+//! Chosen with the `gdb=` option, which takes a port in the same form as
+//! `console=`, such as `gdb=ttyS1,115200`. The port is kept apart from the
+//! console, as any output mixed into the protocol would corrupt it, so it
+//! may not be the console UART.
+use config::BootConfig;
+use util::SpinLock;
+use super::serial;
+
+/// The UART, if one was given and found
+struct Port(Option<serial::Uart>);
+
+/// The UART is only ever used with the `PORT` lock held
+unsafe impl Send for Port {}
+
+static PORT: SpinLock<Port> = SpinLock::new(Port(None));
+
+/// Set up the port from the `gdb=` option. `console` is the console UART,
+/// which cannot also be used
+pub fn init(config: &BootConfig, console: Option<serial::UartConfig>) {
+    let option = match config.cmdline_option_find("gdb") {
+        Some(option) => option,
+        None => return,
+    };
+    let uart_config = match serial::UartConfig::from_console_option(option.value) {
+        Some(uart_config) => uart_config,
+        None => {
+            warn!("Ignoring unknown GDB port {}", option.value);
+            return;
+        },
+    };
+    if console.map_or(false, |console| console.address == uart_config.address) {
+        warn!("GDB port {} is the console, not using it", option.value);
+        return;
+    }
+    let mut uart = match unsafe{serial::Uart::new(uart_config)} {
+        Some(uart) => uart,
+        None => {
+            warn!("Failed to map GDB port {}", option.value);
+            return;
+        },
+    };
+    match uart.init() {
+        Ok(()) => {
+            info!("GDB stub listening on {}", option.value);
+            PORT.lock().0 = Some(uart);
+        },
+        Err(err) => warn!("GDB port {} not usable: {:?}", option.value, err),
+    }
+}
+
+/// True if there is a port for the stub to talk through
+pub fn present() -> bool {
+    PORT.lock().0.is_some()
+}
+
+/// Send a byte, which is dropped if there is no port or it times out
+pub fn putchar(c: u8) {
+    if let Some(ref mut uart) = PORT.lock().0 {
+        let _ = uart.putchar(c);
+    }
+}
+
+/// Receive a byte without blocking
+pub fn getchar() -> Option<u8> {
+    PORT.lock().0.as_ref().and_then(|uart| uart.getchar())
+}
@@ -0,0 +1,378 @@
+//! High Precision Event Timer driver
+//!
+//! The HPET is used as the reference clock for calibrating other time
+//! sources, such as the TSC and local APIC timer, and can act as a clock
+//! event device itself if nothing better is available. Register blocks
+//! are created from a window, so during early boot this can be the low
+//! window, and afterwards the `DeviceWindow`
+//!
+//! The main counter may only be 32 bits wide, in which case it wraps every
+//! few minutes. Intervals are accumulated from readings close enough
+//! together that at most one wrap can come between them, so waits of any
+//! length work with either width.
+use vspace::VSpaceWindow;
+use util::Volatile;
+use types::PAddr;
+use ::core::{cmp, u32};
+
+/// Capabilities: counter is 64 bits wide
+const CAP_COUNT_SIZE: u64 = 1 << 13;
+/// Capabilities: shift of the (number of comparators - 1) field
+const CAP_NUM_TIM_SHIFT: u64 = 8;
+/// Capabilities: shift of the counter period, in femtoseconds
+const CAP_PERIOD_SHIFT: u64 = 32;
+/// Largest period allowed by the specification, 100ns
+const MAX_PERIOD_FS: u64 = 100_000_000;
+/// Femtoseconds in a second
+const FS_PER_SECOND: u64 = 1_000_000_000_000_000;
+/// Femtoseconds in a nanosecond
+const FS_PER_NS: u64 = 1_000_000;
+/// Nanoseconds in a second
+pub const NS_PER_SECOND: u64 = 1_000_000_000;
+/// Fewest ticks a one shot interrupt is armed for. A comparator only fires
+/// when the counter passes it, so one armed too close might be missed, in
+/// which case it is armed again further out
+const MIN_ONE_SHOT_TICKS: u64 = 128;
+/// Consecutive reads of the main counter that return the same value
+/// before it is taken to have stopped. A tick is at most 100ns, far less
+/// than this many reads take
+const STALL_READS: usize = 100_000;
+/// Configuration: overall enable
+const CONF_ENABLE: u64 = 1 << 0;
+/// Configuration: legacy replacement routing
+const CONF_LEGACY: u64 = 1 << 1;
+
+/// Comparator configuration: level triggered interrupts
+const TN_INT_TYPE_LEVEL: u64 = 1 << 1;
+/// Comparator configuration: interrupt enable
+const TN_INT_ENABLE: u64 = 1 << 2;
+/// Comparator configuration: periodic mode
+const TN_TYPE_PERIODIC: u64 = 1 << 3;
+/// Comparator capability: periodic mode supported
+const TN_PERIODIC_CAP: u64 = 1 << 4;
+/// Comparator capability: comparator is 64 bits wide
+const TN_SIZE_CAP: u64 = 1 << 5;
+/// Comparator configuration: allow the accumulator to be set directly
+const TN_VAL_SET: u64 = 1 << 6;
+/// Comparator configuration: shift of the I/O APIC routing field
+const TN_INT_ROUTE_SHIFT: u64 = 9;
+/// Comparator configuration: mask of the I/O APIC routing field
+const TN_INT_ROUTE_MASK: u64 = 0x1f << TN_INT_ROUTE_SHIFT;
+/// Comparator configuration: deliver interrupts as messages
+const TN_FSB_ENABLE: u64 = 1 << 14;
+/// Comparator capability: message delivery supported
+const TN_FSB_CAP: u64 = 1 << 15;
+/// Comparator capability: shift of the allowed I/O APIC routes
+const TN_INT_ROUTE_CAP_SHIFT: u64 = 32;
+
+/// `value * mul / div` without the product overflowing, as long as
+/// `mul * div` fits in 64 bits. Saturates if the result does not, and
+/// rounds up if `round_up` is set
+pub fn mul_div(value: u64, mul: u64, div: u64, round_up: bool) -> u64 {
+    let rem = (value % div) * mul;
+    let rem = if round_up { (rem + div - 1) / div } else { rem / div };
+    (value / div).saturating_mul(mul).saturating_add(rem)
+}
+
+/// General register block of an HPET
+#[repr(C)]
+struct HpetRegs {
+    capabilities: Volatile<u64>,
+    reserved0: u64,
+    config: Volatile<u64>,
+    reserved1: u64,
+    interrupt_status: Volatile<u64>,
+    reserved2: [u64; 25],
+    main_counter: Volatile<u64>,
+    reserved3: u64,
+}
+
+/// Register block of a single HPET comparator
+#[repr(C)]
+struct ComparatorRegs {
+    config: Volatile<u64>,
+    comparator: Volatile<u64>,
+    fsb_route: Volatile<u64>,
+    reserved: u64,
+}
+
+/// An HPET that has been located and mapped
+pub struct Hpet<'a> {
+    regs: &'a HpetRegs,
+    comparators: &'a [ComparatorRegs],
+    /// Period of the main counter in femtoseconds
+    period_fs: u64,
+    /// Largest value of the main counter, after which it wraps to zero
+    counter_mask: u64,
+}
+
+/// A single comparator of an HPET
+pub struct Comparator<'a> {
+    hpet: &'a Hpet<'a>,
+    regs: &'a ComparatorRegs,
+    index: usize,
+}
+
+impl<'a> Hpet<'a> {
+    /// Construct an HPET from its physical address. Returns `None` if the
+    /// registers cannot be created in the window, or the HPET reports a
+    /// nonsensical configuration
+    ///
+    /// # Safety
+    ///
+    /// There must be an HPET at the provided address
+    pub unsafe fn new<W: VSpaceWindow<'a>>(window: &'a W, paddr: PAddr) -> Option<Hpet<'a>> {
+        let regs: &'a HpetRegs = match window.try_from_paddr(paddr)
+                .and_then(|addr| window.make(addr)) {
+            Some(r) => r,
+            None => return None,
+        };
+        let caps = regs.capabilities.read();
+        let period = caps >> CAP_PERIOD_SHIFT;
+        if period == 0 || period > MAX_PERIOD_FS {
+            return None;
+        }
+        let count = ((caps >> CAP_NUM_TIM_SHIFT) & 0x1f) as usize + 1;
+        let comparators = match window.try_from_paddr(PAddr(paddr.0 + 0x100))
+                .and_then(|addr| window.make_slice(addr, count)) {
+            Some(c) => c,
+            None => return None,
+        };
+        let counter_mask = if caps & CAP_COUNT_SIZE != 0 { !0 } else { u32::MAX as u64 };
+        Some(Hpet { regs: regs, comparators: comparators, period_fs: period,
+            counter_mask: counter_mask })
+    }
+    /// Start the main counter running. Legacy replacement routing is
+    /// always turned off as we use the I/O APIC
+    pub fn enable(&self) {
+        let conf = self.regs.config.read();
+        self.regs.config.write((conf & !CONF_LEGACY) | CONF_ENABLE);
+    }
+    /// Stop the main counter
+    pub fn disable(&self) {
+        let conf = self.regs.config.read();
+        self.regs.config.write(conf & !CONF_ENABLE);
+    }
+    /// True if the main counter is 64 bits wide
+    pub fn is_64bit(&self) -> bool {
+        self.counter_mask == !0
+    }
+    /// Read the main counter
+    pub fn counter(&self) -> u64 {
+        self.regs.main_counter.read() & self.counter_mask
+    }
+    /// Ticks from one reading of the main counter to a later one, allowing
+    /// for the counter wrapping once in between
+    fn ticks_between(&self, start: u64, end: u64) -> u64 {
+        end.wrapping_sub(start) & self.counter_mask
+    }
+    /// Wait for the counter to move on from reading `last`, returning the
+    /// new reading. Fails if the counter has stopped
+    fn next_reading(&self, last: u64) -> Result<u64, ()> {
+        for _ in 0..STALL_READS {
+            let now = self.counter();
+            if now != last {
+                return Ok(now);
+            }
+        }
+        Err(())
+    }
+    /// Wait until `ticks` have passed since the counter read `start`.
+    /// Returns the reading at which the wait ended, and the number of
+    /// ticks that had passed by then. Fails if the counter has stopped
+    fn wait_ticks(&self, start: u64, ticks: u64) -> Result<(u64, u64), ()> {
+        let mut last = start;
+        let mut elapsed = 0;
+        while elapsed < ticks {
+            let now = try!(self.next_reading(last));
+            elapsed = elapsed.saturating_add(self.ticks_between(last, now));
+            last = now;
+        }
+        Ok((last, elapsed))
+    }
+    /// Period of a counter tick in femtoseconds
+    pub fn period_fs(&self) -> u64 {
+        self.period_fs
+    }
+    /// Frequency of the main counter in Hz
+    pub fn frequency(&self) -> u64 {
+        FS_PER_SECOND / self.period_fs
+    }
+    /// Convert nanoseconds into counter ticks, rounding up
+    pub fn ns_to_ticks(&self, ns: u64) -> u64 {
+        mul_div(ns, FS_PER_NS, self.period_fs, true)
+    }
+    /// Convert counter ticks into nanoseconds
+    pub fn ticks_to_ns(&self, ticks: u64) -> u64 {
+        mul_div(ticks, self.period_fs, FS_PER_NS, false)
+    }
+    /// Busy wait for the given number of nanoseconds. The counter must
+    /// have been enabled. Fails if the counter is not running
+    pub fn spin_ns(&self, ns: u64) -> Result<(), ()> {
+        let start = self.counter();
+        self.wait_ticks(start, self.ns_to_ticks(ns)).map(|_| ())
+    }
+    /// Measure how much some other counter, as read by `read`, advances
+    /// per second. This is done by sampling it across `ns` nanoseconds of
+    /// HPET time, which should be no more than a few seconds. The HPET must
+    /// have been enabled. Fails if its counter is not running
+    pub fn calibrate<F: FnMut() -> u64>(&self, ns: u64, mut read: F) -> Result<u64, ()> {
+        let ticks = self.ns_to_ticks(ns);
+        /* Wait for a counter edge so we start on a tick boundary */
+        let edge = self.counter();
+        let hpet_start = try!(self.next_reading(edge));
+        let other_start = read();
+        let (last, waited) = try!(self.wait_ticks(hpet_start, ticks));
+        let other_end = read();
+        let hpet_end = self.counter();
+        let elapsed_ticks = waited + self.ticks_between(last, hpet_end);
+        let elapsed_ns = cmp::max(self.ticks_to_ns(elapsed_ticks), 1);
+        Ok(mul_div(other_end.wrapping_sub(other_start), NS_PER_SECOND, elapsed_ns, false))
+    }
+    /// Number of comparators this HPET has
+    pub fn num_comparators(&self) -> usize {
+        self.comparators.len()
+    }
+    /// Retrieve a comparator by index
+    pub fn comparator<'b>(&'b self, index: usize) -> Option<Comparator<'b>> {
+        self.comparators.get(index)
+            .map(|regs| Comparator { hpet: self, regs: regs, index: index })
+    }
+}
+
+impl<'a> Comparator<'a> {
+    /// Bitmask of the I/O APIC inputs this comparator can be routed to
+    pub fn route_capabilities(&self) -> u32 {
+        (self.regs.config.read() >> TN_INT_ROUTE_CAP_SHIFT) as u32
+    }
+    /// True if this comparator can generate periodic interrupts
+    pub fn supports_periodic(&self) -> bool {
+        self.regs.config.read() & TN_PERIODIC_CAP != 0
+    }
+    /// True if this comparator can deliver its interrupt as a message
+    /// straight to a local APIC, rather than through an I/O APIC
+    pub fn supports_fsb(&self) -> bool {
+        self.regs.config.read() & TN_FSB_CAP != 0
+    }
+    /// Largest value the comparator holds. A 32 bit comparator only
+    /// compares against the low half of a 64 bit counter
+    fn mask(&self) -> u64 {
+        if self.regs.config.read() & TN_SIZE_CAP != 0 {
+            self.hpet.counter_mask
+        } else {
+            u32::MAX as u64
+        }
+    }
+    /// Stop this comparator from generating interrupts
+    pub fn disable(&self) {
+        let conf = self.regs.config.read();
+        self.regs.config.write(conf & !(TN_INT_ENABLE | TN_TYPE_PERIODIC));
+    }
+    /// Build a configuration value for routing to a given I/O APIC input
+    fn route(&self, ioapic_input: u8) -> Result<u64, ()> {
+        if ioapic_input >= 32 || self.route_capabilities() & (1 << ioapic_input) == 0 {
+            return Err(());
+        }
+        let conf = self.regs.config.read()
+            & !(TN_INT_ROUTE_MASK | TN_INT_TYPE_LEVEL | TN_TYPE_PERIODIC | TN_INT_ENABLE
+                | TN_FSB_ENABLE);
+        Ok(conf | ((ioapic_input as u64) << TN_INT_ROUTE_SHIFT))
+    }
+    /// Build a configuration value for delivering interrupts by writing
+    /// `data` to `address`, as for an MSI
+    fn route_fsb(&self, address: u32, data: u32) -> Result<u64, ()> {
+        if !self.supports_fsb() {
+            return Err(());
+        }
+        self.regs.fsb_route.write((data as u64) << 32 | address as u64);
+        let conf = self.regs.config.read()
+            & !(TN_INT_TYPE_LEVEL | TN_TYPE_PERIODIC | TN_INT_ENABLE);
+        Ok(conf | TN_FSB_ENABLE)
+    }
+    /// Enable a one shot interrupt with configuration `conf` once `ns`
+    /// nanoseconds have passed. A comparator cannot be armed further out
+    /// than its width allows, so a longer wait interrupts early
+    fn arm(&self, conf: u64, ns: u64) {
+        let mask = self.mask();
+        let mut ticks = cmp::min(cmp::max(self.hpet.ns_to_ticks(ns), MIN_ONE_SHOT_TICKS), mask);
+        self.regs.config.write(conf);
+        loop {
+            let start = self.hpet.counter();
+            self.regs.comparator.write(start.wrapping_add(ticks) & mask);
+            self.regs.config.write(conf | TN_INT_ENABLE);
+            /* if the counter already went past the comparator the interrupt
+             * never comes, so try again with more headroom */
+            if self.hpet.ticks_between(start, self.hpet.counter()) < ticks || ticks >= mask / 2 {
+                break;
+            }
+            ticks *= 2;
+        }
+    }
+    /// Generate a single edge triggered interrupt on the given I/O APIC
+    /// input once `ns` nanoseconds have passed
+    pub fn one_shot(&self, ioapic_input: u8, ns: u64) -> Result<(), ()> {
+        let conf = try!(self.route(ioapic_input));
+        self.arm(conf, ns);
+        Ok(())
+    }
+    /// Generate a single interrupt by writing `data` to `address` once `ns`
+    /// nanoseconds have passed
+    pub fn one_shot_fsb(&self, address: u32, data: u32, ns: u64) -> Result<(), ()> {
+        let conf = try!(self.route_fsb(address, data));
+        self.arm(conf, ns);
+        Ok(())
+    }
+    /// Generate an edge triggered interrupt on the given I/O APIC input
+    /// every `ns` nanoseconds. Fails if the period does not fit in the
+    /// comparator
+    pub fn periodic(&self, ioapic_input: u8, ns: u64) -> Result<(), ()> {
+        if !self.supports_periodic() {
+            return Err(());
+        }
+        let conf = try!(self.route(ioapic_input));
+        let mask = self.mask();
+        let ticks = self.hpet.ns_to_ticks(ns);
+        if ticks == 0 || ticks > mask {
+            return Err(());
+        }
+        self.regs.config.write(conf | TN_TYPE_PERIODIC | TN_VAL_SET);
+        /* With VAL_SET the first write sets the time of the next interrupt
+         * and the second write sets the period */
+        self.regs.comparator.write(self.hpet.counter().wrapping_add(ticks) & mask);
+        self.regs.comparator.write(ticks);
+        self.regs.config.write(conf | TN_TYPE_PERIODIC | TN_INT_ENABLE);
+        Ok(())
+    }
+    /// Acknowledge an interrupt from this comparator. Only needed for level
+    /// triggered interrupts, but harmless otherwise
+    pub fn acknowledge(&self) {
+        self.hpet.regs.interrupt_status.write(1 << self.index);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{mul_div, FS_PER_NS};
+
+    /// Period of the usual 14.318180MHz HPET
+    const PERIOD_FS: u64 = 69_841_279;
+
+    #[test]
+    fn mul_div_matches_wide_arithmetic() {
+        assert_eq!(mul_div(10, 3, 4, false), 7);
+        assert_eq!(mul_div(10, 3, 4, true), 8);
+        assert_eq!(mul_div(12, 3, 4, true), 9);
+        assert_eq!(mul_div(0, 3, 4, true), 0);
+    }
+
+    #[test]
+    fn long_intervals_do_not_overflow() {
+        /* a day is far past where the femtosecond product overflows */
+        let day_ns = 86_400 * 1_000_000_000;
+        let ticks = mul_div(day_ns, FS_PER_NS, PERIOD_FS, true);
+        assert_eq!(ticks, 1_237_090_746_864);
+        let ns = mul_div(ticks, PERIOD_FS, FS_PER_NS, false);
+        assert!(ns >= day_ns && ns - day_ns < 70);
+        assert_eq!(mul_div(!0, PERIOD_FS, FS_PER_NS, false), !0);
+    }
+}
@@ -1,74 +1,119 @@
 //! PC99 platform definition
 mod pic;
 mod acpi;
-use plat::{PlatInterface};
-use ::core::fmt::Write;
+mod power;
+mod hpet;
+mod pci;
+mod vtd;
+mod apic;
+mod aml;
+mod serial;
+mod output;
+mod vga;
+mod font;
+mod fbcon;
+mod gdbport;
+mod dump;
+use plat::{PlatInterface, LoaderInfo, Terminal, BOOT_DEVICE_SIZE};
+use arch::DeviceWindow;
+use ::core::fmt;
 use config::{BootConfig};
-use arch::x86_64::x86::io::*;
 use vspace::VSpaceWindow;
+use arch::x86_64::cpu::rdtsc;
+use arch::x86_64::lapic;
+use arch::x86_64::lapic::LocalApic;
+use arch::x86_64::timer;
+use arch::x86_64::VECTOR_TIMER;
+use types::PAddr;
+use cluster::Topology;
+use steal_mem::FrameAllocator;
+use log;
 
 /// Declare the concrete platform type for re-exporting by the parent `plat`
 /// module
 pub type PlatInterfaceType = PC99Interface;
 
-/// By default we use serial port 0x3f8 for debug output
+pub use self::vtd::DmaError;
+
+/// By default we use serial port 0x3f8 for debug output, until the
+/// firmware tells us otherwise
 const DEFAULT_DEBUG_PORT: u16 = 0x3f8;
 
+/// Length of time, in nanoseconds, to spend calibrating the TSC, and then
+/// the local APIC timer, against the HPET
+const TSC_CALIBRATION_NS: u64 = 10_000_000;
+
+/// Address an HPET comparator writes to in order to interrupt a local APIC,
+/// to which the destination APIC ID is added at bit 12
+const MSI_ADDRESS: u32 = 0xfee0_0000;
+
+/// HPET, comparator, destination APIC ID and TSC frequency of the clock
+/// event device used if the local APIC timer cannot be calibrated
+static mut CLOCK_EVENT: Option<(PAddr, usize, u32, u64)> = None;
+
+/// Discovered PCI functions. This is too large to live on the boot stack
+/// as part of the platform struct, and as the platform is a singleton we
+/// can just as well keep it in a static
+static mut PCI_DEVICES: pci::PciDevices = pci::EMPTY_DEVICES;
+
+/// Namespace and values of the AML interpreter. Like `PCI_DEVICES` this is
+/// far too large for the boot stack
+static mut AML_STORAGE: aml::AmlStorage = aml::EMPTY_STORAGE;
+
+/// Interrupt routing of the root PCI bus, as found in its `_PRT`
+static mut PCI_ROUTES: aml::PciRoutes = aml::EMPTY_ROUTES;
+
+/// Most IOMMU domains user level can make
+const MAX_IOMMU_DOMAINS: usize = 64;
+
+/// DMA remapping hardware once translation is on. The platform struct
+/// cannot be reached after boot, so the IOMMU moves here for the domains
+/// user level makes
+static mut IOMMU: Option<vtd::Iommu> = None;
+
+/// Frames for the tables of domains made after boot
+static mut IOMMU_POOL: vtd::FramePool = vtd::EMPTY_POOL;
+
+/// Domains made after boot, by the index their capabilities hold
+static mut IOMMU_DOMAINS: [Option<vtd::IommuDomain>; MAX_IOMMU_DOMAINS] =
+    [None; MAX_IOMMU_DOMAINS];
+
 /// Run time state for the platform
 pub struct PC99Interface {
-    /// Optional debug port. Tuple is of the form
-    /// (serial initialized, io port base)
-    debug_port: Option<(bool, u16)>,
-}
-
-/// Helper function that waits for space on the FIFO of a standard uart
-unsafe fn serial_wait_ready(port: u16) {
-    while (inb(port + 5) & 0x60) == 0 {}
+    /// True if the debug port was given on the command line, in which
+    /// case the one designated by the firmware is not used
+    debug_port_fixed: bool,
+    /// Where to look for the RSDP before searching the BIOS areas
+    rsdp_hint: Option<PAddr>,
+    /// Methods for resetting and powering off, as found in the FADT
+    power: power::PowerControl,
+    /// Physical address of the HPET used as the reference clock. This can
+    /// be turned back into an `hpet::Hpet` with the `DeviceWindow`
+    hpet: Option<PAddr>,
+    /// Calibrated TSC frequency in Hz
+    tsc_hz: Option<u64>,
+    /// NUMA topology from the SRAT and SLIT
+    topology: Topology,
+    /// Processors and local APIC configuration from the MADT
+    apic: apic::ApicInfo,
+    /// VT-d remapping units from the DMAR. `None` if disabled with
+    /// `iommu=off`
+    iommu: Option<vtd::Iommu>,
 }
 
 /// Implementation of the generic platform interface for pc99
 impl PlatInterface for PC99Interface {
-    /// Initialize the debug serial port
-    /// We currently make no effort to construct a nice type and
-    /// interface for the serial. Considering init and putchar should
-    /// be the extent of all serial interaction for the forseeable future
-    /// this should be okay */
+    /// Initialize the debug serial port, and any other selected output
+    /// sinks. If there turns out to be no UART there then serial output
+    /// is discarded
     fn init_serial(&mut self) {
-        if let Some((false, port)) = self.debug_port {
-            unsafe {
-                serial_wait_ready(port);
-                // disable interrupts
-                outb(port + 1, 0);
-                // set divisor to 0x1:0x00 = 115200
-                outb(port + 3, 0x80);
-                outb(port, 0x01);
-                outb(port + 1, 0x00);
-                // 8 bit no parity 1 stop
-                outb(port + 3, 0x03);
-                // set DTR/RTS/OUT2
-                outb(port + 4, 0x0b);
-                // clear receive
-                inb(port);
-                // clear line status
-                inb(port + 5);
-                // clear modem status
-                inb(port + 6);
-            }
-            self.debug_port = Some((true, port));
-        }
+        output::with_console(|console| console.init());
     }
     fn putchar(&mut self, c: u8) {
-        if let Some((have_serial, port)) = self.debug_port {
-            if have_serial {
-                unsafe {
-                    serial_wait_ready(port);
-                    outb(port, c);
-                    if c == b'\n' {
-                        self.putchar(b'\r')
-                    }
-                }
-            }
-        }
+        output::with_console(|console| console.putchar(c));
+    }
+    fn getchar(&mut self) -> Option<u8> {
+        output::with_console(|console| console.getchar())
     }
     unsafe fn early_init(&mut self) -> Result<(), ()> {
         /* Need to disable the legacy PIC */
@@ -77,26 +122,499 @@ impl PlatInterface for PC99Interface {
     }
     fn early_device_discovery<'a, W: VSpaceWindow<'a>>(&mut self, window: &'a W) -> Result<(), ()> {
         /* initialize ACPI */
-        let acpi = match acpi::ACPI::new(window) {
+        let acpi = match acpi::ACPI::new(window, self.rsdp_hint) {
             Some(a) => a,
             None => {
-                    write!(self, "Failed to find ACPI tables\n").unwrap();
+                    error!("Failed to find ACPI tables");
                     return Err(())
                 },
         };
+        /* report, and otherwise ignore, any tables that failed validation */
+        for table in acpi.rsdt_iter() {
+            if let acpi::RSDTTable::Invalid(paddr, err) = table {
+                warn!("Skipping malformed ACPI table at {:x}: {:?}", paddr.0, err);
+            }
+        }
+        /* grab reset and power off information */
+        match acpi.fadt() {
+            Some(fadt) => {
+                info!("FADT: SCI {} DSDT {:x} PM timer {:?}",
+                    fadt.sci_interrupt(), fadt.dsdt_address().0, fadt.pm_timer_port());
+                self.power = power::PowerControl::from_fadt(fadt);
+            },
+            None => warn!("No FADT found, reset and power off may not work"),
+        }
+        /* find a reference clock and calibrate the TSC against it */
+        for base in acpi.hpet_iter().filter_map(|h| h.base()) {
+            if let Some(hpet) = unsafe{hpet::Hpet::new(window, base)} {
+                hpet.enable();
+                let tsc_hz = match hpet.calibrate(TSC_CALIBRATION_NS, rdtsc) {
+                    Ok(hz) => hz,
+                    Err(()) => {
+                        warn!("HPET at {:x} is not counting", base.0);
+                        continue;
+                    },
+                };
+                info!("HPET at {:x} running at {}Hz with {} comparators, TSC {}Hz",
+                    base.0, hpet.frequency(), hpet.num_comparators(), tsc_hz);
+                self.hpet = Some(base);
+                self.tsc_hz = Some(tsc_hz);
+                log::set_tsc_frequency(tsc_hz);
+                break;
+            }
+        }
+        if self.hpet.is_none() {
+            warn!("No usable HPET found, TSC is uncalibrated");
+        }
+        /* build the NUMA topology */
+        if let Some(srat) = acpi.srat() {
+            for entry in srat.iter(window) {
+                match entry {
+                    acpi::SRATEntry::LocalAPIC(cpu) if cpu.enabled() =>
+                        self.topology.add_cpu(cpu.apic_id(), cpu.proximity_domain()),
+                    acpi::SRATEntry::LocalX2APIC(cpu) if cpu.enabled() =>
+                        self.topology.add_cpu(cpu.apic_id(), cpu.proximity_domain()),
+                    acpi::SRATEntry::Memory(mem) if mem.enabled() => {
+                        let (start, end) = mem.range();
+                        self.topology.add_memory(start, end, mem.proximity_domain());
+                    },
+                    acpi::SRATEntry::Invalid(paddr, err) =>
+                        warn!("Skipping malformed SRAT entry at {:x}: {:?}", paddr.0, err),
+                    _ => (),
+                }
+            }
+            if let Some(slit) = acpi.slit() {
+                /* only bother with distances between domains we know of */
+                let count = self.topology.domains().len();
+                for i in 0..count {
+                    for j in 0..count {
+                        let from = self.topology.domains()[i];
+                        let to = self.topology.domains()[j];
+                        if let Some(distance) = slit.distance(from as usize, to as usize) {
+                            self.topology.set_distance(from, to, distance);
+                        }
+                    }
+                }
+            }
+            info!("NUMA: {} proximity domains, {} CPUs, {} memory ranges",
+                self.topology.domains().len(), self.topology.cpus().len(),
+                self.topology.memory().len());
+            if self.topology.dropped() != 0 {
+                warn!("NUMA: {} SRAT entries did not fit", self.topology.dropped());
+            }
+        }
+        /* enumerate PCI, preferring memory mapped configuration space */
+        let devices = unsafe{&mut PCI_DEVICES};
+        match acpi.mcfg().and_then(|mcfg| pci::EcamConfig::new(window, mcfg.entries())) {
+            Some(ecam) => {
+                let buses = ecam.buses();
+                pci::enumerate(&ecam, buses, devices);
+            },
+            None => {
+                info!("No MCFG, using legacy PCI configuration mechanism");
+                pci::enumerate(&pci::PortConfig, (0, 255), devices);
+            },
+        }
+        for dev in devices.as_slice() {
+            debug!("PCI {:02x}:{:02x}.{} {:04x}:{:04x} class {:02x}.{:02x}",
+                dev.bdf.bus, dev.bdf.device, dev.bdf.function, dev.vendor, dev.device,
+                dev.class, dev.subclass);
+            for bar in dev.bars.iter().filter(|b| **b != pci::Bar::None) {
+                debug!("\t{:?}", bar);
+            }
+        }
+        if devices.dropped() != 0 {
+            warn!("{} PCI functions did not fit in the device list", devices.dropped());
+        }
+        /* evaluate the DSDT and SSDTs for the sleep type of soft off and
+         * the interrupt routing of the root PCI bus */
+        if let Some(fadt) = acpi.fadt() {
+            let mut interp = aml::Aml::new(window, unsafe{&mut AML_STORAGE});
+            match acpi.dsdt(fadt) {
+                Ok(dsdt) => {
+                    for block in acpi.definition_block(dsdt).into_iter()
+                            .chain(acpi.ssdt_iter().filter_map(|t| acpi.definition_block(t))) {
+                        if let Err(e) = interp.load(block) {
+                            warn!("Failed to load AML: {:?}", e);
+                        }
+                    }
+                    match interp.s5_sleep_type() {
+                        Ok(typ) => if !self.power.set_s5_sleep_type(typ) {
+                            warn!("\\_S5 has sleep types {:?} out of range, cannot power off",
+                                typ);
+                        },
+                        Err(e) => warn!("Cannot evaluate \\_S5, cannot power off: {:?}", e),
+                    }
+                    let routes = unsafe{&mut PCI_ROUTES};
+                    match interp.pci_routes(routes) {
+                        Ok(()) => for route in routes.as_slice() {
+                            debug!("PCI route {:02x} INT{} -> GSI {}{}{}",
+                                route.device, (b'A' + route.pin) as char, route.gsi,
+                                if route.level { " level" } else { " edge" },
+                                if route.active_low { " low" } else { " high" });
+                        },
+                        Err(e) => warn!("Cannot evaluate PCI routing: {:?}", e),
+                    }
+                    if routes.dropped() != 0 {
+                        warn!("{} PCI routes were dropped", routes.dropped());
+                    }
+                },
+                Err(e) => warn!("Skipping malformed DSDT: {:?}", e),
+            }
+        }
+        /* switch to the console the firmware designates, preferring the
+         * SPCR which also gives the baud rate */
+        if !self.debug_port_fixed {
+            let console = acpi.spcr()
+                .and_then(|spcr| serial::from_spcr(spcr, &*devices))
+                .or_else(|| acpi.dbg2().and_then(|dbg2| dbg2.iter(window)
+                    .filter_map(|entry| match entry {
+                        acpi::DBG2Entry::Device(dev) => serial::from_dbg2(dev),
+                        acpi::DBG2Entry::Invalid(..) => None,
+                    })
+                    .next()));
+            let current = output::with_console(|console| console.uart_config());
+            match console {
+                Some(config) if Some(config) != current => {
+                    match unsafe{serial::Uart::new(config)} {
+                        Some(mut uart) => match uart.init() {
+                            Ok(()) => {
+                                info!("Switching console to {:?}", config);
+                                output::with_console(|console| console.set_uart(uart));
+                                info!("Console on {:?}", config);
+                            },
+                            Err(e) => warn!("Firmware console {:?} unusable: {:?}",
+                                config, e),
+                        },
+                        None => warn!("Cannot map firmware console {:?}", config),
+                    }
+                },
+                _ => (),
+            }
+        }
+        /* find DMA remapping hardware. Device scopes are resolved with the
+         * legacy configuration mechanism as only bridge bus numbers are
+         * needed */
+        let invalid = match (acpi.dmar(), self.iommu.as_mut()) {
+            (Some(dmar), Some(iommu)) => iommu.discover(window, dmar, &pci::PortConfig),
+            _ => 0,
+        };
+        if invalid != 0 {
+            warn!("Skipping {} malformed DMAR structures", invalid);
+        }
+        /* find the processors and program the NMI pins of our local APIC */
+        for madt in acpi.madt_iter() {
+            self.apic.add_madt(window, madt);
+        }
+        info!("{} CPUs, local APIC at {:x}",
+            self.apic.cpus().len(), self.apic.lapic_base().0);
+        if self.apic.dropped() != 0 {
+            warn!("{} MADT entries did not fit", self.apic.dropped());
+        }
+        let local = if lapic::has_x2apic() {
+            unsafe{LocalApic::new_x2apic()}
+        } else {
+            if self.apic.needs_x2apic() {
+                warn!("CPUs with APIC IDs above {} cannot be used without x2APIC",
+                    apic::MAX_XAPIC_ID);
+            }
+            unsafe{LocalApic::new_xapic(window, self.apic.lapic_base())}
+        };
+        match local {
+            Some(local) => {
+                local.enable();
+                let nmis = self.apic.program_nmis(&local);
+                info!("Local APIC {} in {} mode, {} NMI pins", local.id(),
+                    if local.is_x2apic() { "x2APIC" } else { "xAPIC" }, nmis);
+                if let (Some(base), Some(tsc_hz)) = (self.hpet, self.tsc_hz) {
+                    unsafe{init_clock_event(window, base, tsc_hz, &local)};
+                }
+            },
+            None => error!("Cannot access the local APIC"),
+        }
         /* find any IOAPICs */
         for table in acpi.madt_iter()
-                .flat_map(|s| s.iter(window))
-                .filter(|s| if let &acpi::MADTTable::IOAPIC(_) = s { true } else { false }) {
-            write!(self, "Table {:?}\n", table).unwrap();
+                .flat_map(|s| s.iter(window)) {
+            match table {
+                acpi::MADTTable::IOAPIC(_) => debug!("Table {:?}", table),
+                acpi::MADTTable::Invalid(paddr, err) =>
+                    warn!("Skipping malformed MADT entry at {:x}: {:?}", paddr.0, err),
+                _ => (),
+            }
         }
         Ok(())
     }
+    fn init_iommu<'a, W, A>(&mut self, window: &'a W, alloc: &mut A) -> Result<(), ()>
+            where W: VSpaceWindow<'a>, A: FrameAllocator {
+        let result = match self.iommu {
+            Some(ref mut iommu) => iommu.init(window, alloc),
+            None => return Ok(()),
+        };
+        match result {
+            Ok(()) => {
+                info!("VT-d DMA remapping enabled");
+                unsafe {
+                    IOMMU_POOL.fill(alloc);
+                    debug!("{} frames set aside for IOMMU domains", IOMMU_POOL.len());
+                    IOMMU = self.iommu.take();
+                }
+                Ok(())
+            },
+            /* not having an IOMMU is not fatal, it just means user level
+             * drivers are not isolated */
+            Err(vtd::DmaError::NoUnit) => {
+                warn!("No usable VT-d units, DMA is not isolated");
+                Ok(())
+            },
+            Err(e) => {
+                error!("Failed to initialize VT-d: {:?}", e);
+                Err(())
+            },
+        }
+    }
+    fn topology(&self) -> &Topology {
+        &self.topology
+    }
+    fn tsc_frequency(&self) -> Option<u64> {
+        self.tsc_hz
+    }
+    fn reboot(&mut self) -> ! {
+        self.power.reboot()
+    }
+    fn power_off(&mut self) -> ! {
+        self.power.power_off()
+    }
+}
+
+/// Calibrate the local APIC timer against the HPET. If the timer does not
+/// count properly, use an HPET comparator that interrupts the local APIC
+/// directly as the clock event device instead
+///
+/// # Safety
+///
+/// Must only be called on the boot processor, with interrupts disabled
+unsafe fn init_clock_event<'a, W: VSpaceWindow<'a>>(window: &'a W, base: PAddr, tsc_hz: u64,
+        local: &LocalApic) {
+    let hpet = match hpet::Hpet::new(window, base) {
+        Some(hpet) => hpet,
+        None => return,
+    };
+    match timer::calibrate(local, tsc_hz, |read| hpet.calibrate(TSC_CALIBRATION_NS, || read())) {
+        Ok(hz) => {
+            info!("Local APIC timer {}Hz", hz);
+            return;
+        },
+        Err(()) => warn!("Cannot calibrate the local APIC timer against the HPET"),
+    }
+    /* an HPET that has stopped is no use as a clock event device either,
+     * in which case the timer is calibrated against the TSC instead */
+    if hpet.spin_ns(1000).is_err() {
+        warn!("HPET at {:x} has stopped counting", base.0);
+        return;
+    }
+    /* the message address only holds an 8 bit destination */
+    let id = local.id();
+    if id > 0xff {
+        warn!("No clock event device for local APIC {}", id);
+        return;
+    }
+    let index = (0..hpet.num_comparators())
+        .find(|&index| hpet.comparator(index).map_or(false, |c| c.supports_fsb()));
+    match index {
+        Some(index) => {
+            info!("HPET comparator {} is the clock event device", index);
+            CLOCK_EVENT = Some((base, index, id, tsc_hz));
+            timer::set_fallback(hpet_deadline);
+        },
+        None => warn!("No HPET comparator can interrupt the local APIC directly"),
+    }
+}
+
+/// Arm the HPET clock event device for a deadline in cycles, or stop it
+fn hpet_deadline(deadline: Option<u64>) {
+    let (base, index, id, tsc_hz) = match unsafe{CLOCK_EVENT} {
+        Some(event) => event,
+        None => return,
+    };
+    let window: DeviceWindow = unsafe{DeviceWindow::new(())};
+    let hpet = match unsafe{hpet::Hpet::new(&window, base)} {
+        Some(hpet) => hpet,
+        None => return,
+    };
+    let comparator = match hpet.comparator(index) {
+        Some(comparator) => comparator,
+        None => return,
+    };
+    match deadline {
+        Some(deadline) => {
+            let ns = hpet::mul_div(deadline.saturating_sub(rdtsc()), hpet::NS_PER_SECOND,
+                tsc_hz, false);
+            let _ = comparator.one_shot_fsb(MSI_ADDRESS | (id << 12), VECTOR_TIMER as u32, ns);
+        },
+        None => comparator.disable(),
+    }
 }
 
 /// Construct and return the public interface
-pub fn plat_get_platform(config: &BootConfig) -> PC99Interface {
-    let port = config.cmdline_option_from_str("--debug-port")
-        .unwrap_or(DEFAULT_DEBUG_PORT);
-    PC99Interface { debug_port: Some((false, port))}
+pub fn plat_get_platform(config: &BootConfig, loader: &LoaderInfo) -> PC99Interface {
+    /* a console given on the command line, in either form, overrides
+     * the one the firmware designates */
+    let explicit = config.cmdline_option_find("console")
+        .and_then(|option| serial::UartConfig::from_console_option(option.value))
+        .or_else(|| config.cmdline_option_from_str("--debug-port").map(serial::UartConfig::port));
+    let console = explicit.unwrap_or(serial::UartConfig::port(DEFAULT_DEBUG_PORT));
+    /* an explicit RSDP on the command line overrides the boot loader */
+    let rsdp_hint = config.cmdline_option_from_str("acpi_rsdp")
+        .map(PAddr)
+        .or(loader.acpi_rsdp);
+    let iommu = match config.cmdline_option_find("iommu") {
+        Some(ref option) if option.value == "off" => None,
+        _ => Some(vtd::Iommu::new()),
+    };
+    output::with_console(|c| c.configure(unsafe{serial::Uart::new(console)},
+        output::Sinks::from_config(config), loader.framebuffer));
+    gdbport::init(config, Some(console));
+    dump::set_rsdp_hint(rsdp_hint);
+    PC99Interface {
+        debug_port_fixed: explicit.is_some(),
+        rsdp_hint: rsdp_hint,
+        power: power::PowerControl::new(),
+        hpet: None,
+        tsc_hz: None,
+        topology: Topology::new(),
+        apic: apic::ApicInfo::new(),
+        iommu: iommu,
+    }
+}
+
+/// Run `f` with exclusive access to the console
+pub fn plat_with_console<F: FnOnce(&mut fmt::Write)>(f: F) {
+    output::with_console(|console| f(console))
+}
+
+/// Run `f` with the console whether or not anyone holds it
+pub unsafe fn plat_panic_console<F: FnOnce(&mut fmt::Write)>(f: F) {
+    output::force_console(|console| f(console))
+}
+
+/// Make QEMU exit with a status derived from `code`
+pub fn plat_emulator_exit(code: u8) {
+    power::qemu_exit(code)
+}
+
+/// True if a port was given for the GDB stub
+pub fn plat_gdb_present() -> bool {
+    gdbport::present()
+}
+
+/// Send a byte to the GDB stub port
+pub fn plat_gdb_putchar(c: u8) {
+    gdbport::putchar(c)
+}
+
+/// Receive a byte from the GDB stub port without blocking
+pub fn plat_gdb_getchar() -> Option<u8> {
+    gdbport::getchar()
+}
+
+/// Run `f` with exclusive use of the console for input and output
+pub fn plat_with_terminal<F: FnOnce(&mut Terminal)>(f: F) {
+    output::with_console(|console| f(console))
+}
+
+/// Run `f` with the console for input and output whether or not anyone
+/// holds it
+pub unsafe fn plat_panic_terminal<F: FnOnce(&mut Terminal)>(f: F) {
+    output::force_console(|console| f(console))
+}
+
+/// List the ACPI tables
+pub fn plat_write_acpi_tables(out: &mut fmt::Write) -> fmt::Result {
+    dump::write_acpi_tables(out)
+}
+
+/// List the processors
+pub fn plat_write_cpus(out: &mut fmt::Write) -> fmt::Result {
+    dump::write_cpus(out)
+}
+
+/// PCI functions found during device discovery, which are the devices
+/// described to the root task
+pub fn plat_boot_device_count() -> (usize, usize) {
+    let devices = unsafe{&PCI_DEVICES};
+    (devices.as_slice().len(), devices.dropped())
+}
+
+/// The IOMMU and domain `index` of those made after boot
+fn iommu_domain(index: usize) -> Result<(&'static vtd::Iommu, vtd::IommuDomain), DmaError> {
+    let iommu = try!(unsafe{IOMMU.as_ref()}.ok_or(DmaError::NoUnit));
+    match unsafe{&IOMMU_DOMAINS}.get(index) {
+        Some(&Some(domain)) => Ok((iommu, domain)),
+        _ => Err(DmaError::NoDomain),
+    }
+}
+
+/// Make an empty domain with tables from the pool set aside during boot
+pub fn plat_iommu_create_domain() -> Result<usize, DmaError> {
+    let iommu = try!(unsafe{IOMMU.as_mut()}.ok_or(DmaError::NoUnit));
+    let domains = unsafe{&mut IOMMU_DOMAINS};
+    let index = try!(domains.iter().position(|d| d.is_none()).ok_or(DmaError::NoDomain));
+    domains[index] = Some(try!(iommu.create_domain(unsafe{&mut IOMMU_POOL})));
+    Ok(index)
+}
+
+/// Bind the PCI function with requester ID `rid` to domain `index`
+pub fn plat_iommu_bind(index: usize, rid: u16) -> Result<(), DmaError> {
+    let (iommu, domain) = try!(iommu_domain(index));
+    let window: DeviceWindow = unsafe{DeviceWindow::new(())};
+    iommu.bind(&window, unsafe{&mut IOMMU_POOL}, pci::Bdf::from_rid(rid), &domain)
+}
+
+/// Remove any binding of the PCI function with requester ID `rid`
+pub fn plat_iommu_unbind(rid: u16) -> Result<(), DmaError> {
+    let iommu = try!(unsafe{IOMMU.as_ref()}.ok_or(DmaError::NoUnit));
+    let window: DeviceWindow = unsafe{DeviceWindow::new(())};
+    iommu.unbind(&window, pci::Bdf::from_rid(rid))
+}
+
+/// Map `frame` at `iova` in domain `index`, readable and optionally
+/// writable by devices
+pub fn plat_iommu_map(index: usize, iova: u64, frame: PAddr, write: bool)
+        -> Result<(), DmaError> {
+    let (_, domain) = try!(iommu_domain(index));
+    let window: DeviceWindow = unsafe{DeviceWindow::new(())};
+    domain.map(&window, unsafe{&mut IOMMU_POOL}, iova, frame,
+        vtd::DmaRights { read: true, write: write })
+}
+
+/// Remove the mapping at `iova` in domain `index`. Devices may keep using
+/// it until the domain is invalidated
+pub fn plat_iommu_unmap(index: usize, iova: u64) -> Result<(), DmaError> {
+    let (_, domain) = try!(iommu_domain(index));
+    let window: DeviceWindow = unsafe{DeviceWindow::new(())};
+    domain.unmap(&window, iova).map(|_| ())
+}
+
+/// Flush translations of domain `index` cached by any remapping unit
+pub fn plat_iommu_invalidate(index: usize) -> Result<(), DmaError> {
+    let (iommu, domain) = try!(iommu_domain(index));
+    let window: DeviceWindow = unsafe{DeviceWindow::new(())};
+    iommu.invalidate_domain(&window, &domain);
+    Ok(())
+}
+
+/// Describe PCI function `index` as for `pci::PciDevice::encode`
+pub fn plat_write_boot_device(index: usize, out: &mut [u8; BOOT_DEVICE_SIZE]) -> bool {
+    let devices = unsafe{&PCI_DEVICES};
+    match devices.as_slice().get(index) {
+        Some(device) => {
+            /* the routes from the _PRT only cover the root bus, so follow
+             * the pin through any bridges first */
+            let route = devices.root_pin(device)
+                .and_then(|(dev, pin)| unsafe{&PCI_ROUTES}.find(dev, pin));
+            device.encode(route, out);
+            true
+        },
+        None => false,
+    }
 }
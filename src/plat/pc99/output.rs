@@ -0,0 +1,224 @@
+//! Selection of where debug output goes
+//!
+//! Output can be sent to any combination of sinks, chosen with the
+//! `output=` command line option as a comma separated list of
+//!
+//! + `serial` The debug UART
+//! + `debugcon` The Bochs and QEMU debug console port
+//! + `vga` The VGA text buffer
+//! + `fb` The linear framebuffer the boot loader set up, if any
+//! + `log` The in kernel log ring that the root task can read
+//!
+//! If the option is not given output goes to the serial port and the log.
+//!
+//! The sinks live in a global `Console` behind a lock, rather than in the
+//! platform struct, so that the logging macros can write to them from any
+//! processor or interrupt handler.
+use arch::x86_64::x86::io::*;
+use arch::x86_64::cpu;
+use config::BootConfig;
+use plat::{Framebuffer, Terminal};
+use util::SpinLock;
+use klog;
+use ::core::fmt;
+use super::serial;
+use super::vga;
+use super::fbcon;
+
+/// I/O port of the Bochs and QEMU debug console
+const DEBUGCON_PORT: u16 = 0xe9;
+
+/// Which sinks output is sent to
+pub struct Sinks {
+    pub serial: bool,
+    pub debugcon: bool,
+    pub vga: bool,
+    pub fb: bool,
+    pub log: bool,
+}
+
+impl Sinks {
+    /// Output that arrives before the console is configured is only kept
+    /// in the log
+    const fn early() -> Sinks {
+        Sinks { serial: false, debugcon: false, vga: false, fb: false, log: true }
+    }
+    /// Parse the `output=` option. Unknown sink names are ignored
+    pub fn from_config(config: &BootConfig) -> Sinks {
+        let option = match config.cmdline_option_find("output") {
+            Some(option) => option.value,
+            None => return Sinks { serial: true, debugcon: false, vga: false, fb: false, log: true },
+        };
+        let mut sinks = Sinks { serial: false, debugcon: false, vga: false, fb: false, log: false };
+        for name in option.split(',') {
+            match name {
+                "serial" => sinks.serial = true,
+                "debugcon" => sinks.debugcon = true,
+                "vga" => sinks.vga = true,
+                "fb" => sinks.fb = true,
+                "log" => sinks.log = true,
+                _ => (),
+            }
+        }
+        sinks
+    }
+}
+
+/// Check for the debug console, which reads back as its own port number
+pub fn debugcon_present() -> bool {
+    unsafe{inb(DEBUGCON_PORT)} == DEBUGCON_PORT as u8
+}
+
+/// Send a character to the debug console
+pub fn debugcon_putchar(c: u8) {
+    unsafe{outb(DEBUGCON_PORT, c)};
+}
+
+/// Every output sink
+pub struct Console {
+    /// Optional debug port. Tuple is of the form
+    /// (serial initialized, uart)
+    debug_port: Option<(bool, serial::Uart)>,
+    /// Where output is sent
+    sinks: Sinks,
+    /// VGA text output, if selected as a sink
+    vga: Option<vga::VgaText>,
+    /// Framebuffer the boot loader left the display in
+    framebuffer: Option<Framebuffer>,
+    /// Framebuffer console, if selected as a sink and there is a
+    /// framebuffer
+    fb: Option<fbcon::FramebufferConsole>,
+    /// Set once the console has been taken by the panic path, after which
+    /// nothing may be waited for
+    panicking: bool,
+}
+
+/// The device references in the console are only ever used with the
+/// `CONSOLE` lock held
+unsafe impl Send for Console {}
+
+/// The console of the system
+static CONSOLE: SpinLock<Console> = SpinLock::new(Console::new());
+
+/// Run `f` with exclusive access to the console. Interrupts are disabled
+/// whilst the lock is held so that interrupt handlers can also print
+pub fn with_console<R, F: FnOnce(&mut Console) -> R>(f: F) -> R {
+    let _irq = cpu::disable_interrupts();
+    let mut console = CONSOLE.lock();
+    f(&mut *console)
+}
+
+/// Run `f` with the console whether or not anyone holds its lock
+///
+/// # Safety
+///
+/// Only for the panic path, once every other processor has been stopped
+pub unsafe fn force_console<R, F: FnOnce(&mut Console) -> R>(f: F) -> R {
+    let console = CONSOLE.force_get();
+    console.panicking = true;
+    f(console)
+}
+
+impl Console {
+    const fn new() -> Console {
+        Console {
+            debug_port: None,
+            sinks: Sinks::early(),
+            vga: None,
+            framebuffer: None,
+            fb: None,
+            panicking: false,
+        }
+    }
+    /// Choose the sinks and debug port. Nothing is sent to the port until
+    /// `init` is called
+    pub fn configure(&mut self, uart: Option<serial::Uart>, sinks: Sinks, framebuffer: Option<Framebuffer>) {
+        self.debug_port = uart.map(|uart| (false, uart));
+        self.sinks = sinks;
+        self.framebuffer = framebuffer;
+    }
+    /// Initialize the debug port and any other selected sinks. If there
+    /// turns out to be no UART there then serial output is discarded
+    pub fn init(&mut self) {
+        if let Some((false, mut uart)) = self.debug_port {
+            self.debug_port = match uart.init() {
+                Ok(()) => Some((true, uart)),
+                Err(_) => None,
+            };
+        }
+        if self.sinks.debugcon && !debugcon_present() {
+            self.sinks.debugcon = false;
+        }
+        if self.sinks.vga && self.vga.is_none() {
+            self.vga = unsafe{vga::VgaText::new()};
+        }
+        if self.sinks.fb && self.fb.is_none() {
+            self.fb = self.framebuffer
+                .and_then(|fb| unsafe{fbcon::FramebufferConsole::new(&fb)});
+        }
+    }
+    /// Configuration of the debug port, if it is in use
+    pub fn uart_config(&self) -> Option<serial::UartConfig> {
+        self.debug_port.map(|(_, uart)| uart.config())
+    }
+    /// Replace the debug port with an already initialized one
+    pub fn set_uart(&mut self, uart: serial::Uart) {
+        self.debug_port = Some((true, uart));
+    }
+    /// Send a character to every selected sink
+    pub fn putchar(&mut self, c: u8) {
+        if self.sinks.serial {
+            if let Some((true, ref mut uart)) = self.debug_port {
+                /* a character that times out is lost, there is nowhere to
+                 * report it */
+                let _ = uart.putchar(c);
+                if c == b'\n' {
+                    let _ = uart.putchar(b'\r');
+                }
+            }
+        }
+        if self.sinks.debugcon {
+            debugcon_putchar(c);
+        }
+        if let Some(ref mut vga) = self.vga {
+            vga.putchar(c);
+        }
+        if let Some(ref mut fb) = self.fb {
+            fb.putchar(c);
+        }
+        if self.sinks.log {
+            /* a processor stopped whilst holding the log would otherwise
+             * hang the panic, so the log just misses out */
+            if self.panicking {
+                klog::try_log_byte(c);
+            } else {
+                klog::log_byte(c);
+            }
+        }
+    }
+    /// Receive a character from the debug port without blocking
+    pub fn getchar(&self) -> Option<u8> {
+        match self.debug_port {
+            Some((true, ref uart)) => uart.getchar(),
+            _ => None,
+        }
+    }
+}
+
+impl Terminal for Console {
+    fn output(&mut self) -> &mut fmt::Write {
+        self
+    }
+    fn getchar(&mut self) -> Option<u8> {
+        Console::getchar(self)
+    }
+}
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.putchar(byte);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,549 @@
+//! PCI bus enumeration
+//!
+//! Configuration space is accessed either through the PCI Express ECAM
+//! regions described by the MCFG, or failing that through the legacy
+//! `0xcf8`/`0xcfc` port mechanism. Enumeration is done once during device
+//! discovery and the result recorded in a fixed size list, as we have no
+//! allocator, so that it can later be described to the root task
+use arch::x86_64::x86::io::*;
+use vspace::VSpaceWindow;
+use util::Volatile;
+use types::PAddr;
+use plat::BOOT_DEVICE_SIZE;
+use super::acpi::MCFGEntry;
+use super::aml::PciRoute;
+
+/// Legacy configuration address port
+const CONFIG_ADDRESS: u16 = 0xcf8;
+/// Legacy configuration data port
+const CONFIG_DATA: u16 = 0xcfc;
+
+/// Maximum number of functions we will record
+pub const MAX_DEVICES: usize = 64;
+/// Maximum number of capabilities recorded per function
+pub const MAX_CAPABILITIES: usize = 16;
+/// Maximum number of ECAM regions we will use
+const MAX_ECAM_REGIONS: usize = 4;
+
+/// Configuration space offsets
+const CFG_VENDOR: u16 = 0x00;
+const CFG_DEVICE: u16 = 0x02;
+const CFG_COMMAND: u16 = 0x04;
+const CFG_STATUS: u16 = 0x06;
+const CFG_CLASS: u16 = 0x08;
+const CFG_HEADER_TYPE: u16 = 0x0e;
+const CFG_SECONDARY_BUS: u16 = 0x19;
+const CFG_BAR0: u16 = 0x10;
+const CFG_CAPABILITIES: u16 = 0x34;
+const CFG_INTERRUPT_LINE: u16 = 0x3c;
+const CFG_INTERRUPT_PIN: u16 = 0x3d;
+
+/// Command register bits that enable I/O and memory decode
+const COMMAND_DECODE: u16 = 0x3;
+/// Status register bit indicating a capability list is present
+const STATUS_CAP_LIST: u16 = 1 << 4;
+/// Header type bit indicating a multi function device
+const HEADER_MULTIFUNCTION: u8 = 0x80;
+/// Header type of a PCI to PCI bridge
+const HEADER_TYPE_BRIDGE: u8 = 0x01;
+/// Upper bound on capability list length. Stops us looping forever on a
+/// broken (or malicious) device whose list is circular
+const MAX_CAPABILITY_WALK: usize = 48;
+/// Upper bound on the number of bridges between a function and the root
+/// bus, in case the bridges' bus numbers form a loop
+const MAX_BRIDGE_DEPTH: usize = 256;
+/// Flags in the boot information record of a function with an interrupt
+/// route, and the level triggered and active low polarity of the route
+const ROUTE_VALID: u8 = 1 << 0;
+const ROUTE_LEVEL: u8 = 1 << 1;
+const ROUTE_ACTIVE_LOW: u8 = 1 << 2;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// Address of a function on a PCI bus
+pub struct Bdf {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl Bdf {
+    /// Construct a new address
+    pub fn new(bus: u8, device: u8, function: u8) -> Bdf {
+        Bdf { bus: bus, device: device, function: function }
+    }
+    /// Address from a requester ID, which has the bus in the high byte,
+    /// then 5 bits of device and 3 of function
+    pub fn from_rid(rid: u16) -> Bdf {
+        Bdf::new((rid >> 8) as u8, (rid >> 3) as u8 & 0x1f, rid as u8 & 0x7)
+    }
+}
+
+/// Access to PCI configuration space
+pub trait ConfigAccess {
+    /// Read an aligned 32-bit value. Reads of functions that do not exist
+    /// return all ones
+    fn read32(&self, bdf: Bdf, offset: u16) -> u32;
+    /// Write an aligned 32-bit value
+    fn write32(&self, bdf: Bdf, offset: u16, val: u32);
+    /// Read a 16-bit value
+    fn read16(&self, bdf: Bdf, offset: u16) -> u16 {
+        (self.read32(bdf, offset & !0x3) >> ((offset & 0x2) * 8)) as u16
+    }
+    /// Read an 8-bit value
+    fn read8(&self, bdf: Bdf, offset: u16) -> u8 {
+        (self.read32(bdf, offset & !0x3) >> ((offset & 0x3) * 8)) as u8
+    }
+    /// Write a 16-bit value. Performed as a read-modify-write of the
+    /// containing 32-bit value
+    fn write16(&self, bdf: Bdf, offset: u16, val: u16) {
+        let shift = (offset & 0x2) * 8;
+        let old = self.read32(bdf, offset & !0x3) & !(0xffff << shift);
+        self.write32(bdf, offset & !0x3, old | ((val as u32) << shift));
+    }
+}
+
+/// Legacy port I/O configuration mechanism. Only reaches the first 256
+/// bytes of configuration space, and only segment 0
+pub struct PortConfig;
+
+impl PortConfig {
+    /// Select a configuration register for the next data port access
+    unsafe fn select(&self, bdf: Bdf, offset: u16) {
+        outl(CONFIG_ADDRESS, 0x8000_0000
+            | ((bdf.bus as u32) << 16)
+            | ((bdf.device as u32 & 0x1f) << 11)
+            | ((bdf.function as u32 & 0x7) << 8)
+            | (offset as u32 & 0xfc));
+    }
+}
+
+impl ConfigAccess for PortConfig {
+    fn read32(&self, bdf: Bdf, offset: u16) -> u32 {
+        if offset >= 0x100 {
+            return !0;
+        }
+        unsafe {
+            self.select(bdf, offset);
+            inl(CONFIG_DATA)
+        }
+    }
+    fn write32(&self, bdf: Bdf, offset: u16, val: u32) {
+        if offset >= 0x100 {
+            return;
+        }
+        unsafe {
+            self.select(bdf, offset);
+            outl(CONFIG_DATA, val);
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+/// A region of ECAM configuration space for a range of buses
+struct EcamRegion {
+    base: PAddr,
+    start_bus: u8,
+    end_bus: u8,
+}
+
+/// PCI Express enhanced configuration access. Only segment 0 is used, as
+/// that is all the port fallback can support and no machine we care about
+/// has more than one segment
+pub struct EcamConfig<'a, W: VSpaceWindow<'a> + 'a> {
+    window: &'a W,
+    regions: [EcamRegion; MAX_ECAM_REGIONS],
+    num_regions: usize,
+}
+
+impl<'a, W: VSpaceWindow<'a>> EcamConfig<'a, W> {
+    /// Construct from the entries of the MCFG. Returns `None` if there are
+    /// no regions for segment 0
+    pub fn new(window: &'a W, entries: &[MCFGEntry]) -> Option<EcamConfig<'a, W>> {
+        let mut ecam = EcamConfig {
+            window: window,
+            regions: [EcamRegion { base: PAddr(0), start_bus: 0, end_bus: 0 }; MAX_ECAM_REGIONS],
+            num_regions: 0,
+        };
+        for entry in entries.iter().filter(|e| e.segment() == 0) {
+            if ecam.num_regions == MAX_ECAM_REGIONS {
+                break;
+            }
+            let (start, end) = entry.buses();
+            if end < start {
+                continue;
+            }
+            ecam.regions[ecam.num_regions] = EcamRegion {
+                base: entry.base(),
+                start_bus: start,
+                end_bus: end,
+            };
+            ecam.num_regions += 1;
+        }
+        if ecam.num_regions == 0 { None } else { Some(ecam) }
+    }
+    /// Find the register for an access, if it is covered by a region and
+    /// can be created in the window
+    fn register(&self, bdf: Bdf, offset: u16) -> Option<&'a Volatile<u32>> {
+        if offset >= 0x1000 {
+            return None;
+        }
+        self.regions[..self.num_regions].iter()
+            .find(|r| bdf.bus >= r.start_bus && bdf.bus <= r.end_bus)
+            .and_then(|r| {
+                let paddr = PAddr(r.base.0
+                    + (((bdf.bus - r.start_bus) as usize) << 20)
+                    + ((bdf.device as usize & 0x1f) << 15)
+                    + ((bdf.function as usize & 0x7) << 12)
+                    + (offset as usize & !0x3));
+                unsafe {
+                    self.window.try_from_paddr(paddr)
+                        .and_then(|addr| self.window.make(addr))
+                }
+            })
+    }
+    /// Inclusive range of buses covered by all of the regions
+    pub fn buses(&self) -> (u8, u8) {
+        let regions = &self.regions[..self.num_regions];
+        (regions.iter().map(|r| r.start_bus).min().unwrap_or(0),
+         regions.iter().map(|r| r.end_bus).max().unwrap_or(0))
+    }
+}
+
+impl<'a, W: VSpaceWindow<'a>> ConfigAccess for EcamConfig<'a, W> {
+    fn read32(&self, bdf: Bdf, offset: u16) -> u32 {
+        self.register(bdf, offset).map_or(!0, |r| r.read())
+    }
+    fn write32(&self, bdf: Bdf, offset: u16, val: u32) {
+        if let Some(r) = self.register(bdf, offset) {
+            r.write(val);
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// A decoded base address register
+pub enum Bar {
+    /// BAR is not implemented
+    None,
+    /// I/O port range
+    Io { base: u16, size: u16 },
+    /// Memory range
+    Memory { base: PAddr, size: usize, prefetchable: bool, is_64bit: bool },
+}
+
+#[derive(Debug, Copy, Clone)]
+/// A capability in a function's capability list
+pub struct Capability {
+    /// Capability ID
+    pub id: u8,
+    /// Offset in configuration space of the capability
+    pub offset: u8,
+}
+
+/// Placeholder for unused list entries
+const EMPTY_DEVICE: PciDevice = PciDevice {
+    bdf: Bdf { bus: 0, device: 0, function: 0 },
+    vendor: 0xffff,
+    device: 0xffff,
+    class: 0,
+    subclass: 0,
+    prog_if: 0,
+    header_type: 0,
+    interrupt_line: 0,
+    interrupt_pin: 0,
+    secondary_bus: 0,
+    bars: [Bar::None; 6],
+    capabilities: [Capability { id: 0, offset: 0 }; MAX_CAPABILITIES],
+    num_capabilities: 0,
+};
+
+/// An empty device list. This is a constant, rather than a constructor, so
+/// that the (rather large) list can be placed in a static instead of
+/// being moved around on the stack
+pub const EMPTY_DEVICES: PciDevices = PciDevices {
+    devices: [EMPTY_DEVICE; MAX_DEVICES],
+    count: 0,
+    dropped: 0,
+};
+
+#[derive(Copy, Clone)]
+/// Everything recorded about a single PCI function
+pub struct PciDevice {
+    pub bdf: Bdf,
+    pub vendor: u16,
+    pub device: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub header_type: u8,
+    pub interrupt_line: u8,
+    /// Interrupt pin used, 1 for INTA through 4 for INTD, or 0 for none
+    pub interrupt_pin: u8,
+    /// Bus behind a PCI to PCI bridge, zero for other functions
+    pub secondary_bus: u8,
+    pub bars: [Bar; 6],
+    capabilities: [Capability; MAX_CAPABILITIES],
+    num_capabilities: usize,
+}
+
+impl PciDevice {
+    /// Recorded capabilities of this function
+    pub fn capabilities(&self) -> &[Capability] {
+        &self.capabilities[..self.num_capabilities]
+    }
+    /// Find a capability by its ID
+    pub fn find_capability(&self, id: u8) -> Option<Capability> {
+        self.capabilities().iter().find(|c| c.id == id).map(|c| *c)
+    }
+    /// Describe this function for the root task. Values are little endian,
+    /// and the record is
+    ///
+    /// * bytes 0 to 3, the bus, device, function and header type
+    /// * bytes 4 to 7, the vendor and device IDs
+    /// * bytes 8 to 11, the class, subclass, programming interface and
+    ///   interrupt line
+    /// * byte 12, the number of capabilities recorded
+    /// * byte 13, the interrupt pin, 1 for INTA through 4 for INTD or 0 if
+    ///   none is used
+    /// * byte 14, flags for the interrupt route of the pin. Bit 0 is set if
+    ///   the route is known, bit 1 if it is level triggered and bit 2 if it
+    ///   is active low
+    /// * bytes 16 to 111, the six BARs, each a base and then a size of 8
+    ///   bytes. The low 4 bits of the base are as in the BAR register, and
+    ///   an unimplemented BAR, or the upper half of a 64 bit one, is zero
+    /// * bytes 112 to 143, the ID and offset of each capability
+    /// * bytes 144 to 147, the global system interrupt the pin is routed
+    ///   to, if the route is known
+    ///
+    /// with every other byte zero
+    pub fn encode(&self, route: Option<&PciRoute>, out: &mut [u8; BOOT_DEVICE_SIZE]) {
+        for byte in out.iter_mut() {
+            *byte = 0;
+        }
+        out[0] = self.bdf.bus;
+        out[1] = self.bdf.device;
+        out[2] = self.bdf.function;
+        out[3] = self.header_type;
+        put(out, 4, self.vendor as u64, 2);
+        put(out, 6, self.device as u64, 2);
+        out[8] = self.class;
+        out[9] = self.subclass;
+        out[10] = self.prog_if;
+        out[11] = self.interrupt_line;
+        out[12] = self.num_capabilities as u8;
+        out[13] = self.interrupt_pin;
+        if let Some(route) = route {
+            out[14] = ROUTE_VALID | if route.level { ROUTE_LEVEL } else { 0 }
+                | if route.active_low { ROUTE_ACTIVE_LOW } else { 0 };
+            put(out, 144, route.gsi as u64, 4);
+        }
+        for (i, bar) in self.bars.iter().enumerate() {
+            let (base, size) = match *bar {
+                Bar::None => (0, 0),
+                Bar::Io { base, size } => (base as u64 | 0x1, size as u64),
+                Bar::Memory { base, size, prefetchable, is_64bit } =>
+                    (base.0 as u64 | if is_64bit { 0x4 } else { 0 }
+                        | if prefetchable { 0x8 } else { 0 }, size as u64),
+            };
+            put(out, 16 + i * 16, base, 8);
+            put(out, 24 + i * 16, size, 8);
+        }
+        for (i, cap) in self.capabilities().iter().enumerate() {
+            out[112 + i * 2] = cap.id;
+            out[113 + i * 2] = cap.offset;
+        }
+    }
+}
+
+/// Store the low `bytes` bytes of `value` at `offset` of `out`, little
+/// endian
+fn put(out: &mut [u8], offset: usize, value: u64, bytes: usize) {
+    for i in 0..bytes {
+        out[offset + i] = (value >> (i * 8)) as u8;
+    }
+}
+
+/// Fixed size list of discovered PCI functions
+pub struct PciDevices {
+    devices: [PciDevice; MAX_DEVICES],
+    count: usize,
+    /// Number of functions found that did not fit in the list
+    dropped: usize,
+}
+
+impl PciDevices {
+    /// The recorded functions
+    pub fn as_slice(&self) -> &[PciDevice] {
+        &self.devices[..self.count]
+    }
+    /// Number of functions that were found but could not be recorded
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+    /// Find the function at a particular address
+    pub fn find(&self, bdf: Bdf) -> Option<&PciDevice> {
+        self.as_slice().iter().find(|d| d.bdf == bdf)
+    }
+    /// Follow the interrupt pin of a function up through any bridges, which
+    /// rotate the pin by the device number below them, to the bus that no
+    /// recorded bridge leads to. Returns the device number on that bus and
+    /// the pin there, 0 for INTA through 3 for INTD
+    pub fn root_pin(&self, device: &PciDevice) -> Option<(u8, u8)> {
+        if device.interrupt_pin == 0 || device.interrupt_pin > 4 {
+            return None;
+        }
+        let mut bdf = device.bdf;
+        let mut pin = device.interrupt_pin - 1;
+        for _ in 0..MAX_BRIDGE_DEPTH {
+            let bus = bdf.bus;
+            match self.as_slice().iter()
+                    .find(|d| d.header_type == HEADER_TYPE_BRIDGE && d.secondary_bus == bus) {
+                Some(bridge) => {
+                    pin = (pin + bdf.device) % 4;
+                    bdf = bridge.bdf;
+                },
+                None => return Some((bdf.device, pin)),
+            }
+        }
+        None
+    }
+    fn push(&mut self, device: PciDevice) {
+        if self.count == MAX_DEVICES {
+            self.dropped += 1;
+        } else {
+            self.devices[self.count] = device;
+            self.count += 1;
+        }
+    }
+}
+
+/// Size a single BAR by writing all ones and seeing which bits stick.
+/// Returns the decoded BAR and how many BAR slots it consumed
+fn size_bar<C: ConfigAccess>(config: &C, bdf: Bdf, index: usize, max: usize) -> (Bar, usize) {
+    let offset = CFG_BAR0 + index as u16 * 4;
+    let orig = config.read32(bdf, offset);
+    config.write32(bdf, offset, !0);
+    let mask = config.read32(bdf, offset);
+    config.write32(bdf, offset, orig);
+    if orig & 0x1 == 0x1 {
+        /* I/O space, only the low 16 bits are meaningful */
+        let mask_io = mask & 0xfffc;
+        if mask_io == 0 {
+            return (Bar::None, 1);
+        }
+        return (Bar::Io {
+            base: (orig & 0xfffc) as u16,
+            size: ((!mask_io).wrapping_add(1) & 0xffff) as u16,
+        }, 1);
+    }
+    let prefetchable = orig & 0x8 != 0;
+    let is_64bit = (orig >> 1) & 0x3 == 0x2;
+    if is_64bit && index + 1 < max {
+        let hi_offset = offset + 4;
+        let orig_hi = config.read32(bdf, hi_offset);
+        config.write32(bdf, hi_offset, !0);
+        let mask_hi = config.read32(bdf, hi_offset);
+        config.write32(bdf, hi_offset, orig_hi);
+        let mask64 = ((mask_hi as u64) << 32) | (mask & !0xf) as u64;
+        if mask64 == 0 {
+            return (Bar::None, 2);
+        }
+        let base = ((orig_hi as u64) << 32) | (orig & !0xf) as u64;
+        return (Bar::Memory {
+            base: PAddr(base as usize),
+            size: (!mask64).wrapping_add(1) as usize,
+            prefetchable: prefetchable,
+            is_64bit: true,
+        }, 2);
+    }
+    let mask32 = mask & !0xf;
+    if mask32 == 0 {
+        return (Bar::None, 1);
+    }
+    (Bar::Memory {
+        base: PAddr((orig & !0xf) as usize),
+        size: (!mask32).wrapping_add(1) as usize,
+        prefetchable: prefetchable,
+        is_64bit: false,
+    }, 1)
+}
+
+/// Read everything we want to know about a function that is known to exist
+fn probe_function<C: ConfigAccess>(config: &C, bdf: Bdf) -> PciDevice {
+    let class = config.read32(bdf, CFG_CLASS);
+    let header_type = config.read8(bdf, CFG_HEADER_TYPE) & !HEADER_MULTIFUNCTION;
+    let mut device = PciDevice {
+        bdf: bdf,
+        vendor: config.read16(bdf, CFG_VENDOR),
+        device: config.read16(bdf, CFG_DEVICE),
+        class: (class >> 24) as u8,
+        subclass: (class >> 16) as u8,
+        prog_if: (class >> 8) as u8,
+        header_type: header_type,
+        interrupt_line: config.read8(bdf, CFG_INTERRUPT_LINE),
+        interrupt_pin: config.read8(bdf, CFG_INTERRUPT_PIN),
+        secondary_bus: if header_type == HEADER_TYPE_BRIDGE {
+            config.read8(bdf, CFG_SECONDARY_BUS)
+        } else {
+            0
+        },
+        .. EMPTY_DEVICE
+    };
+    /* Turn off decode whilst sizing BARs so the device does not respond
+     * to the all ones address we temporarily program */
+    let num_bars = match header_type {
+        0 => 6,
+        HEADER_TYPE_BRIDGE => 2,
+        _ => 0,
+    };
+    let command = config.read16(bdf, CFG_COMMAND);
+    config.write16(bdf, CFG_COMMAND, command & !COMMAND_DECODE);
+    let mut index = 0;
+    while index < num_bars {
+        let (bar, used) = size_bar(config, bdf, index, num_bars);
+        device.bars[index] = bar;
+        index += used;
+    }
+    config.write16(bdf, CFG_COMMAND, command);
+    /* Walk the capability list */
+    if config.read16(bdf, CFG_STATUS) & STATUS_CAP_LIST != 0 {
+        let mut ptr = config.read8(bdf, CFG_CAPABILITIES) & !0x3;
+        for _ in 0..MAX_CAPABILITY_WALK {
+            if ptr < 0x40 || device.num_capabilities == MAX_CAPABILITIES {
+                break;
+            }
+            let id = config.read8(bdf, ptr as u16);
+            device.capabilities[device.num_capabilities] = Capability { id: id, offset: ptr };
+            device.num_capabilities += 1;
+            ptr = config.read8(bdf, ptr as u16 + 1) & !0x3;
+        }
+    }
+    device
+}
+
+/// Walk every device on every bus in the given range, recording all the
+/// functions that exist
+pub fn enumerate<C: ConfigAccess>(config: &C, buses: (u8, u8), devices: &mut PciDevices) {
+    let (first, last) = buses;
+    let mut bus = first as usize;
+    while bus <= last as usize {
+        for dev in 0..32 {
+            let bdf = Bdf::new(bus as u8, dev, 0);
+            if config.read16(bdf, CFG_VENDOR) == 0xffff {
+                continue;
+            }
+            let functions = if config.read8(bdf, CFG_HEADER_TYPE) & HEADER_MULTIFUNCTION != 0 {
+                8
+            } else {
+                1
+            };
+            for function in 0..functions {
+                let bdf = Bdf::new(bus as u8, dev, function);
+                if config.read16(bdf, CFG_VENDOR) != 0xffff {
+                    devices.push(probe_function(config, bdf));
+                }
+            }
+        }
+        bus += 1;
+    }
+}
@@ -0,0 +1,168 @@
+//! System reset and power off
+//!
+//! Information for these is pulled out of the FADT during device discovery
+//! and stashed here, so that no reference to the ACPI tables needs to be
+//! kept around. Reset is attempted through the ACPI reset register, then
+//! the keyboard controller, and finally by triple faulting the processor.
+//! Power off needs the sleep type of S5 from the `\_S5` object in the
+//! DSDT as well, as its value is specific to the chipset
+use arch::x86_64::x86::io::*;
+use arch::{halt, triple_fault};
+use super::acpi::{FADT, GenericAddress, GAS_SPACE_IO, GAS_SPACE_PCI_CONFIG};
+
+/// Keyboard controller status and command port
+const KBC_COMMAND: u16 = 0x64;
+/// Status bit indicating the keyboard controller input buffer is full
+const KBC_INPUT_FULL: u8 = 0x02;
+/// Keyboard controller command to pulse the CPU reset line
+const KBC_PULSE_RESET: u8 = 0xfe;
+/// PCI configuration space address port
+const PCI_CONFIG_ADDRESS: u16 = 0xcf8;
+/// PCI configuration space data port
+const PCI_CONFIG_DATA: u16 = 0xcfc;
+/// Bit in PM1 control indicating the chipset is in ACPI mode
+const PM1_SCI_EN: u16 = 1 << 0;
+/// Shift of the SLP_TYP field in PM1 control
+const PM1_SLP_TYP_SHIFT: u16 = 10;
+/// Bit in PM1 control to enter the sleep state given in SLP_TYP
+const PM1_SLP_EN: u16 = 1 << 13;
+/// Largest value that fits in the SLP_TYP field
+const PM1_SLP_TYP_MAX: u16 = 0x7;
+/// Port of QEMU's `isa-debug-exit` device at its default `iobase`
+const QEMU_DEBUG_EXIT_PORT: u16 = 0xf4;
+/// Number of times to poll hardware before giving up on it. There is no
+/// time source available when resetting, so this is a crude spin count
+const POLL_LIMIT: usize = 100000;
+
+/// Make QEMU exit with status `(code << 1) | 1`, if it was started with an
+/// `isa-debug-exit` device. Without one this does nothing and returns
+pub fn qemu_exit(code: u8) {
+    unsafe { outb(QEMU_DEBUG_EXIT_PORT, code) };
+}
+
+/// Values from the FADT needed to reset or power off the machine
+pub struct PowerControl {
+    /// ACPI reset register and the value to write to it
+    reset: Option<(GenericAddress, u8)>,
+    /// PM1a and optional PM1b control ports
+    pm1_cnt: Option<(u16, Option<u16>)>,
+    /// SMI command port and value to switch into ACPI mode
+    acpi_enable: Option<(u16, u8)>,
+    /// SLP_TYPa and SLP_TYPb values for the S5 (soft off) state, from the
+    /// `\_S5` object in the DSDT. Without them the machine cannot be
+    /// turned off
+    s5_slp_typ: Option<(u16, u16)>,
+}
+
+impl PowerControl {
+    /// Construct power control that knows of no ACPI methods and will only
+    /// be able to use the fallbacks
+    pub fn new() -> PowerControl {
+        PowerControl {
+            reset: None,
+            pm1_cnt: None,
+            acpi_enable: None,
+            s5_slp_typ: None,
+        }
+    }
+    /// Record the power management registers from the FADT
+    pub fn from_fadt(fadt: &FADT) -> PowerControl {
+        PowerControl {
+            reset: fadt.reset_register(),
+            pm1_cnt: fadt.pm1_control_ports(),
+            acpi_enable: fadt.acpi_enable(),
+            s5_slp_typ: None,
+        }
+    }
+    /// Set the SLP_TYPa and SLP_TYPb values found in the `\_S5` object.
+    /// Returns false, and leaves power off unusable, if either does not fit
+    /// in the SLP_TYP field
+    pub fn set_s5_sleep_type(&mut self, typ: (u16, u16)) -> bool {
+        if typ.0 > PM1_SLP_TYP_MAX || typ.1 > PM1_SLP_TYP_MAX {
+            return false;
+        }
+        self.s5_slp_typ = Some(typ);
+        true
+    }
+    /// Attempt a reset through the ACPI reset register. Returns if the
+    /// register is not present, or is in an address space we cannot use
+    unsafe fn acpi_reset(&self) {
+        if let Some((reg, value)) = self.reset {
+            match reg.space_id {
+                GAS_SPACE_IO => outb(reg.address as u16, value),
+                GAS_SPACE_PCI_CONFIG => {
+                    /* Encoded as device << 32 | function << 16 | offset
+                     * and is always on bus 0 */
+                    let device = ((reg.address >> 32) & 0x1f) as u32;
+                    let function = ((reg.address >> 16) & 0x7) as u32;
+                    let offset = (reg.address & 0xff) as u32;
+                    outl(PCI_CONFIG_ADDRESS,
+                        0x8000_0000 | (device << 11) | (function << 8) | (offset & 0xfc));
+                    outb(PCI_CONFIG_DATA + (offset & 0x3) as u16, value);
+                },
+                /* Memory mapped reset registers would need a window */
+                _ => (),
+            }
+        }
+    }
+    /// Pulse the reset line through the keyboard controller
+    unsafe fn kbc_reset(&self) {
+        for _ in 0..POLL_LIMIT {
+            if inb(KBC_COMMAND) & KBC_INPUT_FULL == 0 {
+                break;
+            }
+        }
+        outb(KBC_COMMAND, KBC_PULSE_RESET);
+    }
+    /// Give a reset method a short amount of time to take effect
+    fn settle(&self) {
+        for _ in 0..POLL_LIMIT {
+            unsafe { inb(0x80); }
+        }
+    }
+    /// Reset the machine, trying each method in turn
+    pub fn reboot(&self) -> ! {
+        unsafe {
+            self.acpi_reset();
+            self.settle();
+            self.kbc_reset();
+            self.settle();
+        }
+        triple_fault()
+    }
+    /// Switch the chipset into ACPI mode if it is not already
+    unsafe fn enter_acpi_mode(&self, pm1a: u16) {
+        if inw(pm1a) & PM1_SCI_EN != 0 {
+            return;
+        }
+        if let Some((port, value)) = self.acpi_enable {
+            outb(port, value);
+            for _ in 0..POLL_LIMIT {
+                if inw(pm1a) & PM1_SCI_EN != 0 {
+                    break;
+                }
+            }
+        }
+    }
+    /// Turn off the machine by entering the S5 sleep state. If there is no
+    /// ACPI power management, the sleep type of S5 is not known, or it does
+    /// not work, the system is halted
+    pub fn power_off(&self) -> ! {
+        match (self.pm1_cnt, self.s5_slp_typ) {
+            (None, _) => error!("Cannot power off without ACPI PM1 control, halting"),
+            (Some(_), None) => error!("Cannot power off without the sleep type of \\_S5, halting"),
+            (Some((pm1a, pm1b)), Some((typa, typb))) => unsafe {
+                self.enter_acpi_mode(pm1a);
+                outw(pm1a, (inw(pm1a) & !(0x7 << PM1_SLP_TYP_SHIFT))
+                    | (typa << PM1_SLP_TYP_SHIFT) | PM1_SLP_EN);
+                if let Some(pm1b) = pm1b {
+                    outw(pm1b, (inw(pm1b) & !(0x7 << PM1_SLP_TYP_SHIFT))
+                        | (typb << PM1_SLP_TYP_SHIFT) | PM1_SLP_EN);
+                }
+                self.settle();
+                error!("Still running after entering S5, halting");
+            },
+        }
+        halt()
+    }
+}
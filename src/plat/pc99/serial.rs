@@ -0,0 +1,516 @@
+//! 16550 compatible UART driver
+//!
+//! The debug console starts out on the legacy COM1 port, but the firmware
+//! can designate a different port in the SPCR or DBG2 ACPI tables. These
+//! may be memory mapped, as on PCI serial cards, in which case registers
+//! can be 32 bits wide and spaced further apart than the usual one byte.
+//! Memory mapped registers are accessed through the `DeviceWindow`, so
+//! remain valid once the boot windows are gone. That only covers the first
+//! 4GB, so registers above it, as a 64-bit BAR may place them, are
+//! rejected when the address is decoded.
+//!
+//! Nothing guarantees there is actually a UART where we are told, so one
+//! is probed for before use, and waiting to transmit gives up after a
+//! while. Once transmission has timed out characters are only sent if
+//! the UART is immediately ready, so a stuck port costs a single poll per
+//! character rather than stalling the kernel.
+use arch::x86_64::x86::io::*;
+use arch::x86_64::DeviceWindow;
+use vspace::VSpaceWindow;
+use util::Volatile;
+use types::PAddr;
+use super::acpi::{SPCR, DBG2Device, GenericAddress, GAS_SPACE_IO, GAS_SPACE_MEMORY,
+    SERIAL_16550, SERIAL_16450, SERIAL_16550_GAS, DBG2_PORT_SERIAL};
+use super::pci::{Bar, Bdf, PciDevices};
+
+/// Transmit and receive buffer, or low byte of the divisor with DLAB set
+const REG_DATA: usize = 0;
+/// Interrupt enable, or high byte of the divisor with DLAB set
+const REG_IER: usize = 1;
+/// Line control
+const REG_LCR: usize = 3;
+/// Modem control
+const REG_MCR: usize = 4;
+/// Line status
+const REG_LSR: usize = 5;
+/// Modem status
+const REG_MSR: usize = 6;
+/// Scratch register, which has no effect on the UART
+const REG_SCR: usize = 7;
+/// Number of registers in the block
+const NUM_REGS: usize = 8;
+
+/// Line control: divisor latch access
+const LCR_DLAB: u8 = 0x80;
+/// Line control: two stop bits, or 1.5 with 5 data bits
+const LCR_TWO_STOP: u8 = 0x04;
+/// Line control: odd parity
+const LCR_PARITY_ODD: u8 = 0x08;
+/// Line control: even parity
+const LCR_PARITY_EVEN: u8 = 0x18;
+/// Modem control: assert DTR and RTS, and OUT2 which gates interrupts
+const MCR_DTR_RTS_OUT2: u8 = 0x0b;
+/// Modem control: loop the transmitter back to the receiver, with OUT1,
+/// OUT2 and RTS set so the modem status lines can be checked too
+const MCR_LOOPBACK: u8 = 0x1e;
+/// Line status: received data is ready
+const LSR_DATA_READY: u8 = 0x01;
+/// Line status: transmit holding register or transmitter empty
+const LSR_TX_READY: u8 = 0x60;
+/// Value sent during the loopback test
+const LOOPBACK_TEST_BYTE: u8 = 0xae;
+
+/// Number of times to poll the line status before giving up. At the
+/// slowest baud rate we support a character takes about a millisecond to
+/// send, and a poll of an I/O port takes around a microsecond
+const POLL_LIMIT: usize = 100_000;
+
+/// Memory mapped registers must end below this to be reachable through the
+/// `DeviceWindow`
+const MMIO_LIMIT: u64 = 1 << 32;
+
+/// Legacy port bases of ttyS0 through ttyS3
+const LEGACY_PORTS: [u16; 4] = [0x3f8, 0x2f8, 0x3e8, 0x2e8];
+
+/// Standard UART input clock. The baud rate divisor is this divided by
+/// 16 and the baud rate
+pub const DEFAULT_CLOCK: u32 = 1_843_200;
+/// Baud rate we configure when nothing else says otherwise
+pub const DEFAULT_BAUD: u32 = 115200;
+/// Slowest baud rate accepted, below which `POLL_LIMIT` is too short
+const MIN_BAUD: u32 = 9600;
+/// Fastest baud rate accepted. Reaching it needs a faster input clock than
+/// the standard one
+const MAX_BAUD: u32 = 4_000_000;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// Where the registers of a UART are
+pub enum UartAddress {
+    /// I/O ports, one register per port
+    Port(u16),
+    /// Memory mapped, with each register accessed as `width` bytes and
+    /// registers `stride` bytes apart
+    Mmio { base: PAddr, width: u8, stride: u8 },
+}
+
+impl UartAddress {
+    /// Memory mapped registers at `base`. Returns `None` if any of them
+    /// would be at or above `MMIO_LIMIT`
+    fn mmio(base: u64, width: u8, stride: u8) -> Option<UartAddress> {
+        match base.checked_add((NUM_REGS * stride as usize) as u64) {
+            Some(end) if end <= MMIO_LIMIT => Some(UartAddress::Mmio {
+                base: PAddr(base as usize),
+                width: width,
+                stride: stride,
+            }),
+            _ => None,
+        }
+    }
+    /// Decode a generic address structure from the SPCR or DBG2. The bit
+    /// width gives the spacing of the registers, and the access size how
+    /// they must be accessed
+    pub fn from_gas(gas: &GenericAddress) -> Option<UartAddress> {
+        let stride = match gas.bit_width {
+            0 | 8 => 1,
+            16 => 2,
+            32 => 4,
+            _ => return None,
+        };
+        let width = match gas.access_size {
+            0 => stride,
+            1 => 1,
+            3 => 4,
+            _ => return None,
+        };
+        match gas.space_id {
+            _ if gas.address == 0 => None,
+            GAS_SPACE_IO if gas.address <= 0xffff => Some(UartAddress::Port(gas.address as u16)),
+            GAS_SPACE_MEMORY if width <= stride => UartAddress::mmio(gas.address, width, stride),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// Parity bit sent with each character
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// Description of a UART and how it should be configured
+pub struct UartConfig {
+    pub address: UartAddress,
+    /// Baud rate, or `None` to keep what the firmware configured
+    pub baud: Option<u32>,
+    /// Input clock frequency in Hz
+    pub clock: u32,
+    /// Data bits per character, from 5 to 8
+    pub data_bits: u8,
+    pub parity: Parity,
+    /// Stop bits, 1 or 2
+    pub stop_bits: u8,
+}
+
+impl UartConfig {
+    /// The legacy port at `port` with the default settings
+    pub fn port(port: u16) -> UartConfig {
+        UartConfig::new(UartAddress::Port(port), Some(DEFAULT_BAUD))
+    }
+    /// A UART running 8N1 from the standard clock
+    fn new(address: UartAddress, baud: Option<u32>) -> UartConfig {
+        UartConfig {
+            address: address,
+            baud: baud,
+            clock: DEFAULT_CLOCK,
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: 1,
+        }
+    }
+    /// Parse a Linux style console option such as `ttyS0,57600n8`. After
+    /// the port come the baud rate, parity (`n`, `o` or `e`), data bits
+    /// and stop bits (1 or 2), as in `ttyS1,9600e72`. Each setting is
+    /// optional, but only if the ones after it are left out too, and
+    /// default to 115200 baud 8N1. Anything else makes the option invalid
+    pub fn from_console_option(option: &str) -> Option<UartConfig> {
+        let mut parts = option.splitn(2, ',');
+        let tty = parts.next().unwrap_or("");
+        if !tty.starts_with("ttyS") || !tty[4..].chars().all(|c| c.is_digit(10)) {
+            return None;
+        }
+        let index = match tty[4..].parse::<usize>() {
+            Ok(i) if i < LEGACY_PORTS.len() => i,
+            _ => return None,
+        };
+        let mut config = UartConfig::port(LEGACY_PORTS[index]);
+        let settings = match parts.next() {
+            Some(s) => s,
+            None => return Some(config),
+        };
+        let digits = settings.find(|c: char| !c.is_digit(10)).unwrap_or(settings.len());
+        config.baud = match settings[..digits].parse::<u32>() {
+            Ok(baud) if valid_baud(baud) => Some(baud),
+            _ => return None,
+        };
+        let mut rest = settings[digits..].chars();
+        match rest.next() {
+            None => return Some(config),
+            Some('n') => config.parity = Parity::None,
+            Some('o') => config.parity = Parity::Odd,
+            Some('e') => config.parity = Parity::Even,
+            Some(_) => return None,
+        }
+        match rest.next().map(|c| c.to_digit(10)) {
+            None => return Some(config),
+            Some(Some(bits @ 5 ... 8)) => config.data_bits = bits as u8,
+            Some(_) => return None,
+        }
+        match rest.next().map(|c| c.to_digit(10)) {
+            None => return Some(config),
+            Some(Some(stop @ 1 ... 2)) => config.stop_bits = stop as u8,
+            Some(_) => return None,
+        }
+        match rest.next() {
+            None => Some(config),
+            Some(_) => None,
+        }
+    }
+    /// Baud rate divisor for these settings, if a baud rate was given.
+    /// Fails if the rate cannot be made from the input clock with a
+    /// divisor that fits in the divisor latch
+    fn divisor(&self) -> Result<Option<u16>, UartError> {
+        let baud = match self.baud {
+            Some(baud) => baud,
+            None => return Ok(None),
+        };
+        match baud.checked_mul(16).and_then(|d| self.clock.checked_div(d)) {
+            Some(divisor) if divisor != 0 && divisor <= 0xffff => Ok(Some(divisor as u16)),
+            _ => Err(UartError::BadBaud),
+        }
+    }
+    /// Value of the line control register for these settings
+    fn line_control(&self) -> u8 {
+        let mut lcr = self.data_bits.saturating_sub(5) & 0x3;
+        if self.stop_bits > 1 {
+            lcr |= LCR_TWO_STOP;
+        }
+        match self.parity {
+            Parity::None => lcr,
+            Parity::Odd => lcr | LCR_PARITY_ODD,
+            Parity::Even => lcr | LCR_PARITY_EVEN,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// Reasons a UART could not be used
+pub enum UartError {
+    /// Nothing that behaves like a 16550 responded
+    NotPresent,
+    /// Timed out waiting for the UART
+    Timeout,
+    /// The baud rate cannot be made from the input clock
+    BadBaud,
+}
+
+/// True if `baud` is within the rates we accept
+fn valid_baud(baud: u32) -> bool {
+    baud >= MIN_BAUD && baud <= MAX_BAUD
+}
+
+/// Find the interface types we can drive
+fn is_16550(interface_type: u16) -> bool {
+    match interface_type {
+        SERIAL_16550 | SERIAL_16450 | SERIAL_16550_GAS => true,
+        _ => false,
+    }
+}
+
+/// Find the registers of a PCI serial card from its first BAR that can be
+/// reached. Memory BARs above 4GB are skipped
+fn pci_uart_address(devices: &PciDevices, bdf: Bdf) -> Option<UartAddress> {
+    devices.find(bdf).and_then(|dev| {
+        dev.bars.iter().filter_map(|bar| match *bar {
+            Bar::Io { base, .. } => Some(UartAddress::Port(base)),
+            Bar::Memory { base, .. } => UartAddress::mmio(base.0 as u64, 1, 1),
+            Bar::None => None,
+        }).next()
+    })
+}
+
+/// Console UART described by an SPCR. If the UART is a PCI device with no
+/// address given, its registers are found from the BARs in `devices`. A
+/// baud rate out of range is ignored, keeping what the firmware configured
+pub fn from_spcr(spcr: &SPCR, devices: &PciDevices) -> Option<UartConfig> {
+    if !is_16550(spcr.interface_type()) {
+        return None;
+    }
+    UartAddress::from_gas(&spcr.base_address())
+        .or_else(|| spcr.pci_device().and_then(|(bus, device, function)|
+            pci_uart_address(devices, Bdf::new(bus, device, function))))
+        .map(|address| UartConfig {
+            clock: spcr.uart_clock().unwrap_or(DEFAULT_CLOCK),
+            ..UartConfig::new(address,
+                spcr.baud_rate().and_then(|baud| if valid_baud(baud) { Some(baud) } else { None }))
+        })
+}
+
+/// Serial UART described by a DBG2 debug port. DBG2 does not give a baud
+/// rate, so the firmware configuration is kept
+pub fn from_dbg2(device: &DBG2Device) -> Option<UartConfig> {
+    if device.port_type() != DBG2_PORT_SERIAL || !is_16550(device.port_subtype()) {
+        return None;
+    }
+    device.registers()
+        .and_then(|regs| regs.first())
+        .and_then(UartAddress::from_gas)
+        .map(|address| UartConfig::new(address, None))
+}
+
+#[derive(Copy, Clone)]
+/// Mapped registers of a UART
+enum Registers {
+    Port(u16),
+    /// Byte wide registers, `stride` bytes apart
+    Mmio8(&'static [Volatile<u8>], usize),
+    /// 32-bit wide registers, `stride` 32-bit words apart
+    Mmio32(&'static [Volatile<u32>], usize),
+}
+
+#[derive(Copy, Clone)]
+/// A 16550 compatible UART
+pub struct Uart {
+    config: UartConfig,
+    regs: Registers,
+    /// Set once waiting to transmit has timed out, and cleared when the
+    /// UART is next found to be ready
+    stalled: bool,
+}
+
+impl Uart {
+    /// Construct a UART, mapping its registers if they are memory mapped.
+    /// Returns `None` if the registers are not within the `DeviceWindow`
+    ///
+    /// # Safety
+    ///
+    /// There must be a 16550 compatible UART at the given address, and
+    /// nothing else may be using it
+    pub unsafe fn new(config: UartConfig) -> Option<Uart> {
+        let regs = match config.address {
+            UartAddress::Port(port) => Registers::Port(port),
+            UartAddress::Mmio { base, width, stride } => {
+                let window: DeviceWindow<'static> = DeviceWindow::new(());
+                let stride = stride as usize;
+                let addr = match window.try_from_paddr(base) {
+                    Some(addr) => addr,
+                    None => return None,
+                };
+                let regs = match width {
+                    1 => window.make_slice(addr, NUM_REGS * stride)
+                        .map(|r| Registers::Mmio8(r, stride)),
+                    4 => window.make_slice(addr, NUM_REGS * stride / 4)
+                        .map(|r| Registers::Mmio32(r, stride / 4)),
+                    _ => None,
+                };
+                match regs {
+                    Some(regs) => regs,
+                    None => return None,
+                }
+            },
+        };
+        Some(Uart { config: config, regs: regs, stalled: false })
+    }
+    /// How this UART was described
+    pub fn config(&self) -> UartConfig {
+        self.config
+    }
+    fn read(&self, reg: usize) -> u8 {
+        match self.regs {
+            Registers::Port(port) => unsafe{inb(port + reg as u16)},
+            Registers::Mmio8(regs, stride) => regs[reg * stride].read(),
+            Registers::Mmio32(regs, stride) => regs[reg * stride].read() as u8,
+        }
+    }
+    fn write(&self, reg: usize, val: u8) {
+        match self.regs {
+            Registers::Port(port) => unsafe{outb(port + reg as u16, val)},
+            Registers::Mmio8(regs, stride) => regs[reg * stride].write(val),
+            Registers::Mmio32(regs, stride) => regs[reg * stride].write(val as u32),
+        }
+    }
+    /// Poll the line status until any of `bits` are set. Returns false if
+    /// the poll limit was reached first
+    fn poll_status(&self, bits: u8, limit: usize) -> bool {
+        (0..limit).any(|_| self.read(REG_LSR) & bits != 0)
+    }
+    /// Check that there is something that behaves like a 16550 present,
+    /// first through the scratch register and then by sending a byte
+    /// through the loopback path
+    fn probe(&self) -> Result<(), UartError> {
+        for pattern in [0x55, 0xaa].iter() {
+            self.write(REG_SCR, *pattern);
+            if self.read(REG_SCR) != *pattern {
+                return Err(UartError::NotPresent);
+            }
+        }
+        let mcr = self.read(REG_MCR);
+        self.write(REG_MCR, MCR_LOOPBACK);
+        /* discard anything already received */
+        while self.read(REG_LSR) & LSR_DATA_READY != 0 {
+            self.read(REG_DATA);
+        }
+        let result = if !self.poll_status(LSR_TX_READY, POLL_LIMIT) {
+            Err(UartError::Timeout)
+        } else {
+            self.write(REG_DATA, LOOPBACK_TEST_BYTE);
+            if !self.poll_status(LSR_DATA_READY, POLL_LIMIT) {
+                Err(UartError::Timeout)
+            } else if self.read(REG_DATA) != LOOPBACK_TEST_BYTE {
+                Err(UartError::NotPresent)
+            } else {
+                Ok(())
+            }
+        };
+        self.write(REG_MCR, mcr);
+        result
+    }
+    /// Check the UART is present, then program the line settings and
+    /// clear any pending status. If no baud rate was given the divisor is
+    /// left as the firmware set it
+    pub fn init(&mut self) -> Result<(), UartError> {
+        let divisor = try!(self.config.divisor());
+        // disable interrupts
+        self.write(REG_IER, 0);
+        try!(self.probe());
+        if let Some(divisor) = divisor {
+            self.write(REG_LCR, LCR_DLAB);
+            self.write(REG_DATA, divisor as u8);
+            self.write(REG_IER, (divisor >> 8) as u8);
+        }
+        self.write(REG_LCR, self.config.line_control());
+        self.write(REG_MCR, MCR_DTR_RTS_OUT2);
+        // clear receive
+        self.read(REG_DATA);
+        // clear line status
+        self.read(REG_LSR);
+        // clear modem status
+        self.read(REG_MSR);
+        self.stalled = false;
+        Ok(())
+    }
+    /// Send a single character, waiting for space if needed. If the UART
+    /// is not ready in time the character is dropped
+    pub fn putchar(&mut self, c: u8) -> Result<(), UartError> {
+        let limit = if self.stalled { 1 } else { POLL_LIMIT };
+        self.stalled = !self.poll_status(LSR_TX_READY, limit);
+        if self.stalled {
+            return Err(UartError::Timeout);
+        }
+        self.write(REG_DATA, c);
+        Ok(())
+    }
+    /// Receive a single character if one has arrived
+    pub fn getchar(&self) -> Option<u8> {
+        if self.read(REG_LSR) & LSR_DATA_READY != 0 {
+            Some(self.read(REG_DATA))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{UartConfig, UartAddress, UartError, Parity, DEFAULT_BAUD};
+
+    #[test]
+    fn console_option_settings() {
+        let config = UartConfig::from_console_option("ttyS1").unwrap();
+        assert_eq!(config.address, UartAddress::Port(0x2f8));
+        assert_eq!((config.baud, config.data_bits, config.parity, config.stop_bits),
+            (Some(DEFAULT_BAUD), 8, Parity::None, 1));
+        let config = UartConfig::from_console_option("ttyS0,57600").unwrap();
+        assert_eq!((config.baud, config.data_bits, config.parity, config.stop_bits),
+            (Some(57600), 8, Parity::None, 1));
+        let config = UartConfig::from_console_option("ttyS0,9600e7").unwrap();
+        assert_eq!((config.baud, config.data_bits, config.parity, config.stop_bits),
+            (Some(9600), 7, Parity::Even, 1));
+        let config = UartConfig::from_console_option("ttyS3,19200o82").unwrap();
+        assert_eq!(config.address, UartAddress::Port(0x2e8));
+        assert_eq!((config.baud, config.data_bits, config.parity, config.stop_bits),
+            (Some(19200), 8, Parity::Odd, 2));
+    }
+
+    #[test]
+    fn malformed_console_options() {
+        for option in ["ttyS", "ttyS4", "ttyS+1", "ttyUSB0", "ttyS0,", "ttyS0,0", "ttyS0,n8",
+                "ttyS0,115200x8", "ttyS0,115200n9", "ttyS0,115200n83", "ttyS0,115200n81r",
+                "ttyS0,115200n8,1", "ttyS0,268435456", "ttyS0,4294967295", "ttyS0,4294967296",
+                "ttyS0,300"].iter() {
+            assert_eq!(UartConfig::from_console_option(option), None, "{}", option);
+        }
+    }
+
+    #[test]
+    fn baud_divisors() {
+        let mut config = UartConfig::port(0x3f8);
+        assert_eq!(config.divisor(), Ok(Some(1)));
+        config.baud = None;
+        assert_eq!(config.divisor(), Ok(None));
+        config.baud = Some(9600);
+        assert_eq!(config.divisor(), Ok(Some(12)));
+        /* faster than the clock allows, or so fast 16 times it wraps */
+        config.baud = Some(230400);
+        assert_eq!(config.divisor(), Err(UartError::BadBaud));
+        config.baud = Some(268435456);
+        assert_eq!(config.divisor(), Err(UartError::BadBaud));
+        /* too slow for the divisor latch */
+        config.baud = Some(1);
+        assert_eq!(config.divisor(), Err(UartError::BadBaud));
+        config.baud = Some(0);
+        assert_eq!(config.divisor(), Err(UartError::BadBaud));
+    }
+}
@@ -0,0 +1,84 @@
+//! VGA text mode output
+//!
+//! Writes characters straight into the 80x25 text buffer that the BIOS
+//! leaves the display in. There is no input, and no attempt is made to
+//! program the display hardware, so this only works if the boot loader
+//! did not switch to a graphics mode.
+use arch::x86_64::DeviceWindow;
+use vspace::VSpaceWindow;
+use util::Volatile;
+use types::PAddr;
+
+/// Physical address of the colour text buffer
+const TEXT_BUFFER: PAddr = PAddr(0xb8000);
+/// Characters per row
+const COLUMNS: usize = 80;
+/// Number of rows
+const ROWS: usize = 25;
+/// Light grey on black
+const ATTRIBUTE: u16 = 0x07 << 8;
+
+/// Output to the VGA text buffer
+pub struct VgaText {
+    buffer: &'static [Volatile<u16>],
+    row: usize,
+    column: usize,
+}
+
+impl VgaText {
+    /// Access the text buffer and clear the screen
+    ///
+    /// # Safety
+    ///
+    /// The display must be in a VGA text mode, and nothing else may be
+    /// writing to it
+    pub unsafe fn new() -> Option<VgaText> {
+        let window: DeviceWindow<'static> = DeviceWindow::new(());
+        window.try_from_paddr(TEXT_BUFFER)
+            .and_then(|addr| window.make_slice(addr, COLUMNS * ROWS))
+            .map(|buffer| {
+                let vga = VgaText { buffer: buffer, row: 0, column: 0 };
+                vga.clear_rows(0, ROWS);
+                vga
+            })
+    }
+    fn clear_rows(&self, from: usize, to: usize) {
+        for cell in self.buffer[from * COLUMNS..to * COLUMNS].iter() {
+            cell.write(ATTRIBUTE | b' ' as u16);
+        }
+    }
+    /// Move everything up a row, clearing the bottom one
+    fn scroll(&mut self) {
+        for i in 0..(ROWS - 1) * COLUMNS {
+            self.buffer[i].write(self.buffer[i + COLUMNS].read());
+        }
+        self.clear_rows(ROWS - 1, ROWS);
+    }
+    fn newline(&mut self) {
+        self.column = 0;
+        if self.row == ROWS - 1 {
+            self.scroll();
+        } else {
+            self.row += 1;
+        }
+    }
+    /// Display a single character, wrapping at the end of the row
+    pub fn putchar(&mut self, c: u8) {
+        match c {
+            b'\n' => self.newline(),
+            b'\r' => self.column = 0,
+            b'\t' => {
+                for _ in 0..(8 - self.column % 8) {
+                    self.putchar(b' ');
+                }
+            },
+            c => {
+                if self.column == COLUMNS {
+                    self.newline();
+                }
+                self.buffer[self.row * COLUMNS + self.column].write(ATTRIBUTE | c as u16);
+                self.column += 1;
+            },
+        }
+    }
+}
@@ -0,0 +1,721 @@
+//! Intel VT-d DMA remapping
+//!
+//! User level drivers are only isolated if the devices they control cannot
+//! DMA to arbitrary memory. Every remapping unit described by the DMAR is
+//! given a root table, and translation is turned on, at which point a
+//! device can only DMA into frames mapped into the IOMMU domain it has
+//! been bound to. A device that has not been bound to any domain has no
+//! context entry and all of its DMA is blocked.
+//!
+//! Memory that the firmware reports in RMRR structures is identity mapped
+//! into a dedicated domain for the devices that need it, as those devices
+//! may break (or break the machine) if that DMA starts faulting.
+//!
+//! User level makes domains of its own after boot, when there is no
+//! allocator, so frames for their tables are set aside in a `FramePool`
+//! while the boot allocator is still around. Tables are never freed.
+//!
+//! All tables are accessed through a window by their physical address,
+//! so the window must cover any frames handed out by the allocator.
+use vspace::VSpaceWindow;
+use steal_mem::{FrameAllocator, FRAME_SIZE};
+use util::Volatile;
+use types::PAddr;
+use arch::x86_64::cpu::clflush;
+use super::acpi;
+use super::acpi::{DMAR, DMAREntry, DeviceScope, DMARDeviceScope};
+use super::pci::{Bdf, ConfigAccess};
+
+/// Maximum number of remapping units we support
+pub const MAX_UNITS: usize = 4;
+/// Maximum number of device scopes recorded per unit or RMRR
+const MAX_SCOPES: usize = 16;
+/// Maximum number of RMRRs we will honour
+pub const MAX_RMRRS: usize = 8;
+/// Frames set aside for the tables of domains made after boot
+pub const POOL_FRAMES: usize = 256;
+/// Domain ID reserved for the RMRR identity mappings. Domain 0 is skipped
+/// as it is reserved when the hardware is in caching mode
+const RMRR_DOMAIN: u16 = 1;
+/// First domain ID handed out to user domains
+const FIRST_USER_DOMAIN: u16 = 2;
+/// Number of times to poll a status register before declaring the
+/// hardware broken
+const POLL_LIMIT: usize = 1000000;
+
+/// Capability: number of domains supported field
+const CAP_ND_MASK: u64 = 0x7;
+/// Capability: shift of the supported adjusted guest address widths
+const CAP_SAGAW_SHIFT: u64 = 8;
+/// Capability: write buffer flushing required
+const CAP_RWBF: u64 = 1 << 4;
+/// Capability: caching mode, not present entries may be cached
+const CAP_CM: u64 = 1 << 7;
+/// Extended capability: page walks are cache coherent
+const ECAP_C: u64 = 1 << 0;
+/// Extended capability: shift of the IOTLB register offset
+const ECAP_IRO_SHIFT: u64 = 8;
+/// Extended capability: mask of the IOTLB register offset
+const ECAP_IRO_MASK: u64 = 0x3ff;
+
+/// Global command/status: translation enable
+const GCMD_TE: u32 = 1 << 31;
+/// Global command/status: set root table pointer
+const GCMD_SRTP: u32 = 1 << 30;
+/// Global command/status: write buffer flush
+const GCMD_WBF: u32 = 1 << 27;
+/// Bits of the global status that reflect persistent command state, which
+/// must be written back when issuing a new command
+const GSTS_PERSISTENT: u32 = 0x96ff_ffff;
+
+/// Context command: invalidate
+const CCMD_ICC: u64 = 1 << 63;
+/// Context command: global invalidation
+const CCMD_GLOBAL: u64 = 1 << 61;
+/// IOTLB invalidate: invalidate
+const IOTLB_IVT: u64 = 1 << 63;
+/// IOTLB invalidate: global invalidation
+const IOTLB_GLOBAL: u64 = 1 << 60;
+/// IOTLB invalidate: domain selective invalidation
+const IOTLB_DOMAIN: u64 = 2 << 60;
+/// IOTLB invalidate: drain reads and writes
+const IOTLB_DRAIN: u64 = (1 << 49) | (1 << 48);
+/// IOTLB invalidate: shift of the domain ID
+const IOTLB_DID_SHIFT: u64 = 32;
+
+/// Root and context entries: present
+const ENTRY_PRESENT: u64 = 1 << 0;
+/// Context entry high word: shift of the domain ID
+const CONTEXT_DID_SHIFT: u64 = 8;
+/// Second level page table entry: readable
+const SL_READ: u64 = 1 << 0;
+/// Second level page table entry: writable
+const SL_WRITE: u64 = 1 << 1;
+/// Mask of the address in a table entry
+const ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+/// Entries per table
+const TABLE_ENTRIES: usize = 512;
+
+/// Register block of a remapping unit
+#[repr(C)]
+struct VtdRegs {
+    version: Volatile<u32>,
+    reserved0: u32,
+    capability: Volatile<u64>,
+    ext_capability: Volatile<u64>,
+    global_command: Volatile<u32>,
+    global_status: Volatile<u32>,
+    root_table: Volatile<u64>,
+    context_command: Volatile<u64>,
+    reserved1: u32,
+    fault_status: Volatile<u32>,
+}
+
+/// IOTLB invalidation registers, at an offset given by the extended
+/// capabilities
+#[repr(C)]
+struct IotlbRegs {
+    invalidate_address: Volatile<u64>,
+    invalidate: Volatile<u64>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// Errors from manipulating domains and bindings
+pub enum DmaError {
+    /// Could not allocate a frame for a table
+    NoMemory,
+    /// Address is not page aligned or beyond the domain address width
+    BadAddress,
+    /// Something is already mapped at this address
+    AlreadyMapped,
+    /// Nothing is mapped at this address
+    NotMapped,
+    /// No remapping unit covers the device
+    NoUnit,
+    /// Tables could not be accessed through the window
+    Unmappable,
+    /// No such domain, or no more domains can be made
+    NoDomain,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// Access rights of a DMA mapping
+pub struct DmaRights {
+    pub read: bool,
+    pub write: bool,
+}
+
+/// Create a table of entries at a physical address
+fn table<'a, W: VSpaceWindow<'a>>(window: &'a W, paddr: PAddr) -> Result<&'a [Volatile<u64>], DmaError> {
+    unsafe {
+        window.try_from_paddr(paddr)
+            .and_then(|addr| window.make_slice(addr, TABLE_ENTRIES))
+    }.ok_or(DmaError::Unmappable)
+}
+
+/// Write a table entry and make sure the write is visible to the remapping
+/// hardware even if it does not snoop the cache
+fn write_entry(entry: &Volatile<u64>, val: u64) {
+    entry.write(val);
+    unsafe { clflush(entry as *const Volatile<u64> as usize) };
+}
+
+/// A driven remapping unit
+pub struct VtdUnit<'a> {
+    regs: &'a VtdRegs,
+    iotlb: &'a IotlbRegs,
+    cap: u64,
+    ecap: u64,
+}
+
+impl<'a> VtdUnit<'a> {
+    /// Construct from the physical address of the register block
+    ///
+    /// # Safety
+    ///
+    /// There must be a VT-d unit at the provided address
+    pub unsafe fn new<W: VSpaceWindow<'a>>(window: &'a W, base: PAddr) -> Option<VtdUnit<'a>> {
+        let regs: &'a VtdRegs = match window.try_from_paddr(base)
+                .and_then(|addr| window.make(addr)) {
+            Some(r) => r,
+            None => return None,
+        };
+        let cap = regs.capability.read();
+        let ecap = regs.ext_capability.read();
+        let iro = (((ecap >> ECAP_IRO_SHIFT) & ECAP_IRO_MASK) * 16) as usize;
+        let iotlb = match window.try_from_paddr(PAddr(base.0 + iro))
+                .and_then(|addr| window.make(addr)) {
+            Some(r) => r,
+            None => return None,
+        };
+        Some(VtdUnit { regs: regs, iotlb: iotlb, cap: cap, ecap: ecap })
+    }
+    /// True if the unit can walk second level tables with this many levels
+    pub fn supports_levels(&self, levels: u8) -> bool {
+        levels >= 2 && self.cap & (1 << (CAP_SAGAW_SHIFT + levels as u64 - 2)) != 0
+    }
+    /// Number of domain IDs the unit supports
+    pub fn num_domains(&self) -> usize {
+        1 << (4 + 2 * (self.cap & CAP_ND_MASK))
+    }
+    /// True if table walks snoop the processor caches
+    pub fn coherent(&self) -> bool {
+        self.ecap & ECAP_C != 0
+    }
+    /// True if the hardware caches not present entries
+    pub fn caching_mode(&self) -> bool {
+        self.cap & CAP_CM != 0
+    }
+    /// Current fault status
+    pub fn fault_status(&self) -> u32 {
+        self.regs.fault_status.read()
+    }
+    /// Issue a one shot global command and wait for the status to reflect
+    /// that it has completed. `set` is true if the status bit becomes set on
+    /// completion, false if it clears
+    fn global_command(&self, bit: u32, set: bool) -> Result<(), ()> {
+        let status = self.regs.global_status.read() & GSTS_PERSISTENT;
+        self.regs.global_command.write(status | bit);
+        for _ in 0..POLL_LIMIT {
+            if (self.regs.global_status.read() & bit != 0) == set {
+                return Ok(());
+            }
+        }
+        Err(())
+    }
+    /// Flush internal write buffers, if the hardware requires it
+    pub fn flush_write_buffer(&self) -> Result<(), ()> {
+        if self.cap & CAP_RWBF != 0 {
+            self.global_command(GCMD_WBF, false)
+        } else {
+            Ok(())
+        }
+    }
+    /// Point the unit at a root table
+    pub fn set_root_table(&self, root: PAddr) -> Result<(), ()> {
+        self.regs.root_table.write(root.0 as u64);
+        self.global_command(GCMD_SRTP, true)
+    }
+    /// Invalidate every cached context entry
+    pub fn invalidate_context(&self) -> Result<(), ()> {
+        self.regs.context_command.write(CCMD_ICC | CCMD_GLOBAL);
+        for _ in 0..POLL_LIMIT {
+            if self.regs.context_command.read() & CCMD_ICC == 0 {
+                return Ok(());
+            }
+        }
+        Err(())
+    }
+    /// Perform an IOTLB invalidation and wait for it to complete
+    fn invalidate_iotlb_raw(&self, cmd: u64) -> Result<(), ()> {
+        self.iotlb.invalidate.write(IOTLB_IVT | IOTLB_DRAIN | cmd);
+        for _ in 0..POLL_LIMIT {
+            if self.iotlb.invalidate.read() & IOTLB_IVT == 0 {
+                return Ok(());
+            }
+        }
+        Err(())
+    }
+    /// Invalidate all cached translations
+    pub fn invalidate_iotlb(&self) -> Result<(), ()> {
+        self.invalidate_iotlb_raw(IOTLB_GLOBAL)
+    }
+    /// Invalidate cached translations of a single domain
+    pub fn invalidate_domain(&self, domain: u16) -> Result<(), ()> {
+        self.invalidate_iotlb_raw(IOTLB_DOMAIN | ((domain as u64) << IOTLB_DID_SHIFT))
+    }
+    /// Turn on DMA remapping
+    pub fn enable_translation(&self) -> Result<(), ()> {
+        self.global_command(GCMD_TE, true)
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+/// An IOMMU domain. This is the object that a device is bound to, and into
+/// which frames are mapped to allow the device to DMA to them
+pub struct IommuDomain {
+    id: u16,
+    /// Top level second level page table
+    root: PAddr,
+    /// Number of levels of page table
+    levels: u8,
+}
+
+impl IommuDomain {
+    /// Domain ID used by the hardware
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+    /// Number of bits of I/O virtual address the domain covers
+    pub fn address_width(&self) -> usize {
+        12 + 9 * self.levels as usize
+    }
+    /// Find the leaf entry for an I/O virtual address, optionally
+    /// allocating any missing intermediate tables
+    fn leaf<'a, W, A>(&self, window: &'a W, mut alloc: Option<&mut A>, iova: u64)
+            -> Result<&'a Volatile<u64>, DmaError>
+            where W: VSpaceWindow<'a>, A: FrameAllocator {
+        if iova & (FRAME_SIZE as u64 - 1) != 0 || iova >> self.address_width() != 0 {
+            return Err(DmaError::BadAddress);
+        }
+        let mut paddr = self.root;
+        let mut level = self.levels;
+        loop {
+            let shift = 12 + 9 * (level as u64 - 1);
+            let entry = &try!(table(window, paddr))[((iova >> shift) & 0x1ff) as usize];
+            if level == 1 {
+                return Ok(entry);
+            }
+            let val = entry.read();
+            if val & (SL_READ | SL_WRITE) == 0 {
+                let frame = match alloc.as_mut() {
+                    Some(a) => try!(a.alloc_frame().ok_or(DmaError::NoMemory)),
+                    None => return Err(DmaError::NotMapped),
+                };
+                write_entry(entry, frame.0 as u64 | SL_READ | SL_WRITE);
+                paddr = frame;
+            } else {
+                paddr = PAddr((val & ADDR_MASK) as usize);
+            }
+            level -= 1;
+        }
+    }
+    /// Map a frame into the domain at the given I/O virtual address
+    pub fn map<'a, W, A>(&self, window: &'a W, alloc: &mut A, iova: u64, frame: PAddr,
+            rights: DmaRights) -> Result<(), DmaError>
+            where W: VSpaceWindow<'a>, A: FrameAllocator {
+        if frame.0 & (FRAME_SIZE - 1) != 0 {
+            return Err(DmaError::BadAddress);
+        }
+        let entry = try!(self.leaf(window, Some(alloc), iova));
+        if entry.read() & (SL_READ | SL_WRITE) != 0 {
+            return Err(DmaError::AlreadyMapped);
+        }
+        let mut val = frame.0 as u64;
+        if rights.read { val |= SL_READ; }
+        if rights.write { val |= SL_WRITE; }
+        write_entry(entry, val);
+        Ok(())
+    }
+    /// Remove a mapping, returning the frame that was mapped. The caller
+    /// must invalidate the IOTLB of any unit this domain is bound to before
+    /// reusing the frame
+    pub fn unmap<'a, W>(&self, window: &'a W, iova: u64) -> Result<PAddr, DmaError>
+            where W: VSpaceWindow<'a> {
+        let entry = try!(self.leaf::<W, NoAlloc>(window, None, iova));
+        let val = entry.read();
+        if val & (SL_READ | SL_WRITE) == 0 {
+            return Err(DmaError::NotMapped);
+        }
+        write_entry(entry, 0);
+        Ok(PAddr((val & ADDR_MASK) as usize))
+    }
+}
+
+/// Frames taken from the boot allocator for use after boot
+pub struct FramePool {
+    frames: [PAddr; POOL_FRAMES],
+    count: usize,
+}
+
+/// A pool with no frames. Like `pci::EMPTY_DEVICES` this is a constant so
+/// that the pool can be placed in a static
+pub const EMPTY_POOL: FramePool = FramePool { frames: [PAddr(0); POOL_FRAMES], count: 0 };
+
+impl FramePool {
+    /// Take frames from `alloc` until the pool is full or `alloc` runs
+    /// out. Frames are zero, as every frame from a `FrameAllocator` is
+    pub fn fill<A: FrameAllocator>(&mut self, alloc: &mut A) {
+        while self.count < POOL_FRAMES {
+            match alloc.alloc_frame() {
+                Some(frame) => {
+                    self.frames[self.count] = frame;
+                    self.count += 1;
+                },
+                None => break,
+            }
+        }
+    }
+    /// Number of frames left
+    pub fn len(&self) -> usize {
+        self.count
+    }
+}
+
+impl FrameAllocator for FramePool {
+    fn alloc_frame(&mut self) -> Option<PAddr> {
+        if self.count == 0 {
+            return None;
+        }
+        self.count -= 1;
+        Some(self.frames[self.count])
+    }
+}
+
+/// Allocator that never allocates, for walking tables without extending them
+enum NoAlloc {}
+
+impl FrameAllocator for NoAlloc {
+    fn alloc_frame(&mut self) -> Option<PAddr> {
+        None
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// A resolved device scope
+enum Scope {
+    /// A single function
+    Device(Bdf),
+    /// Everything on an inclusive range of buses
+    Buses(u8, u8),
+}
+
+impl Scope {
+    fn contains(&self, bdf: Bdf) -> bool {
+        match *self {
+            Scope::Device(d) => d == bdf,
+            Scope::Buses(first, last) => bdf.bus >= first && bdf.bus <= last,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+/// Fixed size list of resolved scopes
+struct Scopes {
+    scopes: [Scope; MAX_SCOPES],
+    count: usize,
+}
+
+impl Scopes {
+    fn new() -> Scopes {
+        Scopes { scopes: [Scope::Buses(1, 0); MAX_SCOPES], count: 0 }
+    }
+    fn push(&mut self, scope: Scope) {
+        if self.count < MAX_SCOPES {
+            self.scopes[self.count] = scope;
+            self.count += 1;
+        }
+    }
+    fn as_slice(&self) -> &[Scope] {
+        &self.scopes[..self.count]
+    }
+    fn contains(&self, bdf: Bdf) -> bool {
+        self.as_slice().iter().any(|s| s.contains(bdf))
+    }
+    /// Resolve and record the PCI scopes from a DMAR device scope list
+    fn collect<'a, C, I>(&mut self, config: &C, iter: I)
+            where C: ConfigAccess, I: Iterator<Item=DeviceScope<'a>> {
+        for scope in iter {
+            if let DeviceScope::Scope(s) = scope {
+                if let Some(r) = resolve_scope(config, s) {
+                    self.push(r);
+                }
+            }
+        }
+    }
+}
+
+/// Offset of the secondary bus number in a bridge's configuration space
+const BRIDGE_SECONDARY_BUS: u16 = 0x19;
+/// Offset of the subordinate bus number in a bridge's configuration space
+const BRIDGE_SUBORDINATE_BUS: u16 = 0x1a;
+
+/// Follow the path of a device scope through any bridges to find the
+/// device it refers to. I/O APIC and HPET scopes are not PCI functions we
+/// would ever bind, and are ignored
+fn resolve_scope<C: ConfigAccess>(config: &C, scope: &DMARDeviceScope) -> Option<Scope> {
+    let path = scope.path();
+    if path.is_empty() {
+        return None;
+    }
+    let mut bus = scope.start_bus();
+    for hop in &path[..path.len() - 1] {
+        bus = config.read8(Bdf::new(bus, hop[0], hop[1]), BRIDGE_SECONDARY_BUS);
+    }
+    let last = path[path.len() - 1];
+    let bdf = Bdf::new(bus, last[0], last[1]);
+    match scope.scope_type() {
+        acpi::SCOPE_PCI_ENDPOINT => Some(Scope::Device(bdf)),
+        acpi::SCOPE_PCI_SUB_HIERARCHY => Some(Scope::Buses(
+            config.read8(bdf, BRIDGE_SECONDARY_BUS),
+            config.read8(bdf, BRIDGE_SUBORDINATE_BUS))),
+        _ => None,
+    }
+}
+
+#[derive(Copy, Clone)]
+/// What we remember about a remapping unit
+struct UnitInfo {
+    base: PAddr,
+    include_all: bool,
+    scopes: Scopes,
+    /// Root table, once allocated
+    root: PAddr,
+}
+
+#[derive(Copy, Clone)]
+/// A reserved memory region and the devices that use it
+struct Rmrr {
+    start: PAddr,
+    end: PAddr,
+    scopes: Scopes,
+}
+
+/// Platform IOMMU state
+pub struct Iommu {
+    units: [Option<UnitInfo>; MAX_UNITS],
+    rmrrs: [Option<Rmrr>; MAX_RMRRS],
+    /// Number of page table levels used by every domain
+    levels: u8,
+    /// Next free domain ID
+    next_domain: u16,
+    /// Domain IDs supported by every unit
+    max_domains: usize,
+    /// Domain holding the RMRR identity mappings
+    rmrr_domain: Option<IommuDomain>,
+    enabled: bool,
+}
+
+impl Iommu {
+    /// Construct an IOMMU state with no units
+    pub fn new() -> Iommu {
+        Iommu {
+            units: [None; MAX_UNITS],
+            rmrrs: [None; MAX_RMRRS],
+            levels: 0,
+            next_domain: FIRST_USER_DOMAIN,
+            max_domains: 0,
+            rmrr_domain: None,
+            enabled: false,
+        }
+    }
+    /// True if remapping has been turned on
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+    /// Record the units and reserved regions of segment 0 described by a
+    /// DMAR. Configuration space is needed to resolve device scopes that
+    /// are behind bridges
+    pub fn discover<'a, W, C>(&mut self, window: &'a W, dmar: &DMAR, config: &C) -> usize
+            where W: VSpaceWindow<'a>, C: ConfigAccess {
+        let mut invalid = 0;
+        let (mut units, mut rmrrs) = (0, 0);
+        for entry in dmar.iter(window) {
+            match entry {
+                DMAREntry::DRHD(drhd) if drhd.segment() == 0 && units < MAX_UNITS => {
+                    let mut scopes = Scopes::new();
+                    scopes.collect(config, drhd.scopes(window));
+                    self.units[units] = Some(UnitInfo {
+                        base: drhd.register_base(),
+                        include_all: drhd.include_pci_all(),
+                        scopes: scopes,
+                        root: PAddr(0),
+                    });
+                    units += 1;
+                },
+                DMAREntry::RMRR(rmrr) if rmrr.segment() == 0 && rmrrs < MAX_RMRRS => {
+                    let mut scopes = Scopes::new();
+                    scopes.collect(config, rmrr.scopes(window));
+                    let (start, end) = rmrr.range();
+                    self.rmrrs[rmrrs] = Some(Rmrr { start: start, end: end, scopes: scopes });
+                    rmrrs += 1;
+                },
+                DMAREntry::Invalid(_, _) => invalid += 1,
+                _ => (),
+            }
+        }
+        invalid
+    }
+    /// Index of the unit responsible for a device. A unit that lists the
+    /// device explicitly takes priority over the catch all unit
+    fn unit_for(&self, bdf: Bdf) -> Option<usize> {
+        let units = || self.units.iter().enumerate()
+            .filter_map(|(i, u)| u.as_ref().map(|u| (i, u)));
+        units().find(|&(_, u)| u.scopes.contains(bdf))
+            .or_else(|| units().find(|&(_, u)| u.include_all))
+            .map(|(i, _)| i)
+    }
+    /// Allocate a new, empty, domain
+    pub fn create_domain<A: FrameAllocator>(&mut self, alloc: &mut A) -> Result<IommuDomain, DmaError> {
+        if self.levels == 0 {
+            return Err(DmaError::NoUnit);
+        }
+        if self.next_domain as usize >= self.max_domains {
+            return Err(DmaError::NoDomain);
+        }
+        let root = try!(alloc.alloc_frame().ok_or(DmaError::NoMemory));
+        self.next_domain += 1;
+        Ok(IommuDomain { id: self.next_domain - 1, root: root, levels: self.levels })
+    }
+    /// Bind a device to a domain, replacing any existing binding. From this
+    /// point on the device can only DMA to frames mapped in the domain
+    pub fn bind<'a, W, A>(&self, window: &'a W, alloc: &mut A, bdf: Bdf, domain: &IommuDomain)
+            -> Result<(), DmaError>
+            where W: VSpaceWindow<'a>, A: FrameAllocator {
+        let info = match self.unit_for(bdf).and_then(|i| self.units[i]) {
+            Some(u) => u,
+            None => return Err(DmaError::NoUnit),
+        };
+        let root = try!(table(window, info.root));
+        let root_entry = &root[bdf.bus as usize * 2];
+        let context_paddr = if root_entry.read() & ENTRY_PRESENT == 0 {
+            let frame = try!(alloc.alloc_frame().ok_or(DmaError::NoMemory));
+            write_entry(root_entry, frame.0 as u64 | ENTRY_PRESENT);
+            frame
+        } else {
+            PAddr((root_entry.read() & ADDR_MASK) as usize)
+        };
+        let context = try!(table(window, context_paddr));
+        let index = ((bdf.device as usize & 0x1f) << 3 | (bdf.function as usize & 0x7)) * 2;
+        /* Clear the present bit before changing the rest of the entry, so
+         * the hardware never sees a half written entry */
+        write_entry(&context[index], 0);
+        write_entry(&context[index + 1],
+            (domain.levels as u64 - 2) | ((domain.id as u64) << CONTEXT_DID_SHIFT));
+        write_entry(&context[index], domain.root.0 as u64 | ENTRY_PRESENT);
+        if self.enabled {
+            let unit = try!(unsafe{VtdUnit::new(window, info.base)}.ok_or(DmaError::Unmappable));
+            let _ = unit.flush_write_buffer();
+            let _ = unit.invalidate_context();
+            let _ = unit.invalidate_domain(domain.id);
+        }
+        Ok(())
+    }
+    /// Remove any binding of a device, after which all its DMA is blocked
+    pub fn unbind<'a, W: VSpaceWindow<'a>>(&self, window: &'a W, bdf: Bdf) -> Result<(), DmaError> {
+        let info = match self.unit_for(bdf).and_then(|i| self.units[i]) {
+            Some(u) => u,
+            None => return Err(DmaError::NoUnit),
+        };
+        let root_entry = try!(table(window, info.root))[bdf.bus as usize * 2].read();
+        if root_entry & ENTRY_PRESENT == 0 {
+            return Ok(());
+        }
+        let context = try!(table(window, PAddr((root_entry & ADDR_MASK) as usize)));
+        let index = ((bdf.device as usize & 0x1f) << 3 | (bdf.function as usize & 0x7)) * 2;
+        write_entry(&context[index], 0);
+        if self.enabled {
+            let unit = try!(unsafe{VtdUnit::new(window, info.base)}.ok_or(DmaError::Unmappable));
+            let _ = unit.flush_write_buffer();
+            let _ = unit.invalidate_context();
+            let _ = unit.invalidate_iotlb();
+        }
+        Ok(())
+    }
+    /// Invalidate cached translations of a domain in every unit, which
+    /// must be done after `IommuDomain::unmap`
+    pub fn invalidate_domain<'a, W: VSpaceWindow<'a>>(&self, window: &'a W, domain: &IommuDomain) {
+        for info in self.units.iter().filter_map(|u| u.as_ref()) {
+            if let Some(unit) = unsafe{VtdUnit::new(window, info.base)} {
+                let _ = unit.flush_write_buffer();
+                let _ = unit.invalidate_domain(domain.id);
+            }
+        }
+    }
+    /// Identity map every RMRR into the RMRR domain and bind the devices
+    /// that use them
+    fn map_rmrrs<'a, W, A>(&mut self, window: &'a W, alloc: &mut A) -> Result<(), DmaError>
+            where W: VSpaceWindow<'a>, A: FrameAllocator {
+        let root = try!(alloc.alloc_frame().ok_or(DmaError::NoMemory));
+        let domain = IommuDomain { id: RMRR_DOMAIN, root: root, levels: self.levels };
+        let rights = DmaRights { read: true, write: true };
+        for rmrr in self.rmrrs.iter().filter_map(|r| r.as_ref()) {
+            let mut page = rmrr.start.0 & !(FRAME_SIZE - 1);
+            while page < rmrr.end.0 {
+                match domain.map(window, alloc, page as u64, PAddr(page), rights) {
+                    Ok(()) | Err(DmaError::AlreadyMapped) => (),
+                    Err(e) => return Err(e),
+                }
+                page += FRAME_SIZE;
+            }
+            for scope in rmrr.scopes.as_slice() {
+                if let Scope::Device(bdf) = *scope {
+                    try!(self.bind(window, alloc, bdf, &domain));
+                }
+            }
+        }
+        self.rmrr_domain = Some(domain);
+        Ok(())
+    }
+    /// Give every unit a root table, set up RMRR mappings, and turn on
+    /// translation
+    pub fn init<'a, W, A>(&mut self, window: &'a W, alloc: &mut A) -> Result<(), DmaError>
+            where W: VSpaceWindow<'a>, A: FrameAllocator {
+        /* Pick the deepest page table format every unit supports */
+        let mut levels = 4;
+        let mut max_domains = !0;
+        let mut any = false;
+        for slot in self.units.iter_mut() {
+            if let Some(ref mut info) = *slot {
+                let unit = try!(unsafe{VtdUnit::new(window, info.base)}.ok_or(DmaError::Unmappable));
+                while levels > 2 && !unit.supports_levels(levels) {
+                    levels -= 1;
+                }
+                if unit.num_domains() < max_domains {
+                    max_domains = unit.num_domains();
+                }
+                info.root = try!(alloc.alloc_frame().ok_or(DmaError::NoMemory));
+                any = true;
+            }
+        }
+        if !any || levels < 3 {
+            return Err(DmaError::NoUnit);
+        }
+        self.levels = levels;
+        self.max_domains = max_domains;
+        try!(self.map_rmrrs(window, alloc));
+        for info in self.units.iter().filter_map(|u| u.as_ref()) {
+            let unit = try!(unsafe{VtdUnit::new(window, info.base)}.ok_or(DmaError::Unmappable));
+            let ok = unit.flush_write_buffer()
+                .and_then(|_| unit.set_root_table(info.root))
+                .and_then(|_| unit.invalidate_context())
+                .and_then(|_| unit.invalidate_iotlb())
+                .and_then(|_| unit.enable_translation());
+            if ok.is_err() {
+                return Err(DmaError::NoUnit);
+            }
+        }
+        self.enabled = true;
+        Ok(())
+    }
+}
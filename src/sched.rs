@@ -0,0 +1,374 @@
+//! Scheduling
+//!
+//! Each processor has a scheduler of its own, which runs the highest
+//! priority thread that can run, round robin amongst threads of the same
+//! priority, each of which runs for at most a timeslice before the next
+//! has a turn. A thread can run if it is runnable and has a scheduling
+//! context with budget left. Every entry to the kernel charges the time
+//! since the last one to the scheduling context of the current thread, and
+//! leaving the kernel sets the local timer for when that budget runs out,
+//! so a thread is stopped at the end of its budget even if it never makes
+//! a system call. A scheduling context that runs out waits on a queue,
+//! ordered by when its next period starts, and its thread can run again
+//! once it has been refilled.
+//!
+//! This gives temporal isolation: how much of the processor a thread takes
+//! is bounded by its scheduling context, or, for a passive server, by that
+//! of the client it is working for (see `ipc`), rather than by how well
+//! behaved every thread of a higher priority is.
+//!
+//! Only the boot processor is running, so there is a single scheduler. As
+//! with the rest of the state of a cluster, nothing here is locked.
+use arch::{self, Arch};
+use log;
+use object::{ObjectError, SchedContext, Tcb, ThreadState, MAX_PRIORITY};
+use ::core::{cmp, ptr};
+
+/// Processor specific support for the scheduler
+pub trait ArchTimer {
+    /// Arrange for a timer interrupt at `deadline`, in cycles of
+    /// `arch::rdtsc`, or for none at all. Interrupting early is allowed
+    fn set_deadline(deadline: Option<u64>);
+    /// Wait with interrupts enabled, returning once one has been taken
+    unsafe fn wait_for_interrupt();
+}
+
+/// Index of the scheduler of the boot processor, the only one there is
+pub const BOOT_CPU: usize = 0;
+
+const NUM_PRIORITIES: usize = MAX_PRIORITY as usize + 1;
+const BITMAP_WORDS: usize = NUM_PRIORITIES / 64;
+
+/// Rate of the cycle counter assumed when the platform could not measure it
+const DEFAULT_CLOCK_HZ: u64 = 1_000_000_000;
+
+/// Longest a thread runs, in microseconds, whilst others of its priority
+/// are waiting
+const TIMESLICE_US: u64 = 5_000;
+/// Longest an idle processor sleeps, in microseconds, before checking the
+/// console for the debugger or monitor
+const IDLE_POLL_US: u64 = 10_000;
+
+#[derive(Copy, Clone)]
+/// Threads of one priority, linked through `Tcb::sched_next`
+struct ReadyQueue {
+    head: *mut Tcb,
+    tail: *mut Tcb,
+}
+
+const EMPTY_QUEUE: ReadyQueue = ReadyQueue { head: 0 as *mut Tcb, tail: 0 as *mut Tcb };
+
+/// Scheduler of one processor
+struct Scheduler {
+    /// Threads that can run, other than the current one, by priority
+    queues: [ReadyQueue; NUM_PRIORITIES],
+    /// Bit for each priority whose queue is not empty
+    bitmap: [u64; BITMAP_WORDS],
+    /// Scheduling contexts with no budget left, by when they are refilled,
+    /// linked through `SchedContext::next_release`. A scheduling context is
+    /// on this queue exactly when it has no budget left
+    releases: *mut SchedContext,
+    /// Thread running, or null whilst idle
+    current: *mut Tcb,
+    /// When time was last charged
+    charged: u64,
+    /// When the timeslice of the current thread ends
+    slice_end: u64,
+}
+
+static mut SCHEDULER: Scheduler = Scheduler {
+    queues: [EMPTY_QUEUE; NUM_PRIORITIES],
+    bitmap: [0; BITMAP_WORDS],
+    releases: 0 as *mut SchedContext,
+    current: 0 as *mut Tcb,
+    charged: 0,
+    slice_end: 0,
+};
+
+/// Cycles of `arch::rdtsc` in a second
+static mut CLOCK_HZ: u64 = DEFAULT_CLOCK_HZ;
+
+/// True if `tcb` can be given a turn
+unsafe fn can_run(tcb: *mut Tcb) -> bool {
+    let sc = (*tcb).sched_context();
+    (*tcb).state() == ThreadState::Runnable && !sc.is_null() && (*sc).remaining() != 0
+}
+
+impl Scheduler {
+    /// Queue `tcb` at the back of its priority, or the front if it was
+    /// preempted and so keeps its place
+    unsafe fn enqueue(&mut self, tcb: *mut Tcb, front: bool) {
+        let prio = (*tcb).priority() as usize;
+        let queue = &mut self.queues[prio];
+        if queue.head.is_null() {
+            (*tcb).sched_next = ptr::null_mut();
+            queue.head = tcb;
+            queue.tail = tcb;
+        } else if front {
+            (*tcb).sched_next = queue.head;
+            queue.head = tcb;
+        } else {
+            (*tcb).sched_next = ptr::null_mut();
+            (*queue.tail).sched_next = tcb;
+            queue.tail = tcb;
+        }
+        (*tcb).queued = true;
+        self.bitmap[prio / 64] |= 1 << (prio % 64);
+    }
+    /// Take `tcb` out of its queue
+    unsafe fn remove(&mut self, tcb: *mut Tcb) {
+        let prio = (*tcb).priority() as usize;
+        let queue = &mut self.queues[prio];
+        let mut prev: *mut Tcb = ptr::null_mut();
+        let mut next = queue.head;
+        while !next.is_null() && next != tcb {
+            prev = next;
+            next = (*next).sched_next;
+        }
+        if next.is_null() {
+            return;
+        }
+        if prev.is_null() {
+            queue.head = (*tcb).sched_next;
+        } else {
+            (*prev).sched_next = (*tcb).sched_next;
+        }
+        if queue.tail == tcb {
+            queue.tail = prev;
+        }
+        (*tcb).sched_next = ptr::null_mut();
+        (*tcb).queued = false;
+        if queue.head.is_null() {
+            self.bitmap[prio / 64] &= !(1 << (prio % 64));
+        }
+    }
+    /// Highest priority with a thread queued
+    fn highest(&self) -> Option<usize> {
+        self.bitmap.iter().enumerate().rev()
+            .find(|&(_, word)| *word != 0)
+            .map(|(index, word)| index * 64 + 63 - word.leading_zeros() as usize)
+    }
+    /// Take the thread at the front of the highest priority queue, or null
+    unsafe fn dequeue_highest(&mut self) -> *mut Tcb {
+        match self.highest() {
+            Some(prio) => {
+                let tcb = self.queues[prio].head;
+                self.remove(tcb);
+                tcb
+            },
+            None => ptr::null_mut(),
+        }
+    }
+    /// Queue `tcb` if it can now run and is neither queued nor running
+    unsafe fn wake(&mut self, tcb: *mut Tcb) {
+        if !tcb.is_null() && tcb != self.current && !(*tcb).queued && can_run(tcb) {
+            self.enqueue(tcb, false);
+        }
+    }
+    /// Put a scheduling context that has run out on the release queue
+    unsafe fn deplete(&mut self, sc: *mut SchedContext) {
+        let mut link: *mut *mut SchedContext = &mut self.releases;
+        while !(*link).is_null() && (**link).release() <= (*sc).release() {
+            link = &mut (**link).next_release;
+        }
+        (*sc).next_release = *link;
+        *link = sc;
+    }
+    /// Take `sc` off the release queue, if it is on it
+    unsafe fn remove_release(&mut self, sc: *mut SchedContext) {
+        let mut link: *mut *mut SchedContext = &mut self.releases;
+        while !(*link).is_null() {
+            if *link == sc {
+                *link = (*sc).next_release;
+                (*sc).next_release = ptr::null_mut();
+                return;
+            }
+            link = &mut (**link).next_release;
+        }
+    }
+    /// Refill every scheduling context whose next period has started by
+    /// `now`, queueing any thread that can run again
+    unsafe fn release(&mut self, now: u64) {
+        while !self.releases.is_null() && (*self.releases).release() <= now {
+            let sc = self.releases;
+            self.releases = (*sc).next_release;
+            (*sc).next_release = ptr::null_mut();
+            (*sc).refill(now);
+            self.wake((*sc).tcb());
+        }
+    }
+    /// Charge the time since the last charge to the current thread
+    unsafe fn charge(&mut self, now: u64) {
+        let used = now.wrapping_sub(self.charged);
+        self.charged = now;
+        if self.current.is_null() {
+            return;
+        }
+        let sc = (*self.current).sched_context();
+        if sc.is_null() || (*sc).remaining() == 0 {
+            return;
+        }
+        (*sc).refill(now);
+        if (*sc).charge(used) && !(*sc).refill(now) {
+            self.deplete(sc);
+        }
+    }
+}
+
+/// Set the rate of the cycle counter, as measured by the platform, which
+/// budgets and periods are converted with
+pub fn init(clock_hz: Option<u64>) {
+    match clock_hz {
+        Some(hz) if hz != 0 => unsafe{CLOCK_HZ = hz},
+        _ => warn!("Cycle counter rate unknown, assuming {}Hz for budgets", DEFAULT_CLOCK_HZ),
+    }
+}
+
+/// Cycles of `arch::rdtsc` in `us` microseconds
+pub fn us_to_cycles(us: u64) -> u64 {
+    us.saturating_mul(unsafe{CLOCK_HZ}) / 1_000_000
+}
+
+/// Thread running on this processor
+pub fn current() -> *mut Tcb {
+    unsafe{SCHEDULER.current}
+}
+
+/// Charge the current thread for its time so far. This must be done on
+/// entry to the kernel, before the current thread's scheduling context can
+/// be changed
+pub fn charge() {
+    unsafe{SCHEDULER.charge(arch::rdtsc())}
+}
+
+/// Make `tcb` runnable, queueing it if it can then run
+///
+/// # Safety
+///
+/// `tcb` must be valid, as must its scheduling context
+pub unsafe fn make_runnable(tcb: *mut Tcb) {
+    (*tcb).set_state(ThreadState::Runnable);
+    SCHEDULER.wake(tcb);
+}
+
+/// Stop `tcb` from running, leaving it in `state`
+///
+/// # Safety
+///
+/// `tcb` must be valid
+pub unsafe fn block(tcb: *mut Tcb, state: ThreadState) {
+    (*tcb).set_state(state);
+    if (*tcb).queued {
+        SCHEDULER.remove(tcb);
+    }
+}
+
+/// Send the current thread to the back of the queue for its priority
+pub fn yield_current() {
+    unsafe {
+        let current = SCHEDULER.current;
+        if !current.is_null() && can_run(current) {
+            SCHEDULER.enqueue(current, false);
+        }
+        SCHEDULER.current = ptr::null_mut();
+    }
+}
+
+/// Have `tcb`, which must not have a scheduling context, run on `sc`
+///
+/// # Safety
+///
+/// Both must be valid, and `sc` not used by any thread
+pub unsafe fn bind(tcb: *mut Tcb, sc: *mut SchedContext) {
+    (*tcb).set_sched_context(sc);
+    (*sc).set_tcb(tcb);
+    SCHEDULER.wake(tcb);
+}
+
+/// Move the scheduling context of `from` to `to`, which must not have one
+///
+/// # Safety
+///
+/// Both must be valid
+pub unsafe fn donate(from: *mut Tcb, to: *mut Tcb) {
+    let sc = (*from).sched_context();
+    (*from).set_sched_context(ptr::null_mut());
+    if (*from).queued {
+        SCHEDULER.remove(from);
+    }
+    (*to).set_sched_context(sc);
+    if !sc.is_null() {
+        (*sc).set_tcb(to);
+    }
+    SCHEDULER.wake(to);
+}
+
+/// Give `sc` a budget and period, in microseconds, on the processor `cpu`,
+/// starting a new period with a full budget
+///
+/// # Safety
+///
+/// `sc`, and any thread using it, must be valid
+pub unsafe fn configure(cpu: usize, sc: *mut SchedContext, budget_us: u64, period_us: u64)
+        -> Result<(), ObjectError> {
+    if cpu != BOOT_CPU {
+        return Err(ObjectError::InvalidArgument);
+    }
+    let now = arch::rdtsc();
+    /* time used so far comes out of the old budget */
+    SCHEDULER.charge(now);
+    try!((*sc).configure(us_to_cycles(budget_us), us_to_cycles(period_us), now));
+    SCHEDULER.remove_release(sc);
+    SCHEDULER.wake((*sc).tcb());
+    Ok(())
+}
+
+/// Pick the thread to run next, and set the timer for when it must stop.
+/// If nothing can run this waits, with interrupts enabled, until something
+/// can
+pub fn schedule() -> *mut Tcb {
+    unsafe {
+        let sched = &mut SCHEDULER;
+        loop {
+            let now = arch::rdtsc();
+            sched.charge(now);
+            sched.release(now);
+            let current = sched.current;
+            let runnable = !current.is_null() && can_run(current);
+            let expired = now >= sched.slice_end;
+            let keep = runnable && sched.highest().map_or(true, |prio| {
+                let current_prio = (*current).priority() as usize;
+                prio < current_prio || (prio == current_prio && !expired)
+            });
+            if !keep {
+                /* a thread preempted by a higher priority keeps its place,
+                 * one whose timeslice is up goes behind its equals */
+                if runnable {
+                    sched.enqueue(current, !expired);
+                }
+                sched.current = sched.dequeue_highest();
+                sched.slice_end = now.saturating_add(us_to_cycles(TIMESLICE_US));
+            } else if expired {
+                /* nothing else of its priority is waiting */
+                sched.slice_end = now.saturating_add(us_to_cycles(TIMESLICE_US));
+            }
+            let release = if sched.releases.is_null() {
+                None
+            } else {
+                Some((*sched.releases).release())
+            };
+            if !sched.current.is_null() {
+                /* stop at the end of the budget or the timeslice, unless a
+                 * refill might preempt first */
+                let out = now.saturating_add((*(*sched.current).sched_context()).remaining());
+                let out = cmp::min(out, sched.slice_end);
+                Arch::set_deadline(Some(release.map_or(out, |release| cmp::min(release, out))));
+                return sched.current;
+            }
+            let poll = now.saturating_add(us_to_cycles(IDLE_POLL_US));
+            Arch::set_deadline(Some(release.map_or(poll, |release| cmp::min(release, poll))));
+            Arch::wait_for_interrupt();
+            log::poll_console();
+        }
+    }
+}
@@ -12,9 +12,40 @@ use ::vspace::VSpaceWindow;
 use ::core::marker::PhantomData;
 use ::core::ops;
 use ::core::mem::{size_of, forget, transmute};
+use ::core::ptr;
+use ::core::sync::atomic::{AtomicUsize, Ordering};
 use ::util;
 use types::*;
 
+/// Number of allocations made by every `StealMem`
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+/// Bytes handed out by every `StealMem`
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+/// Bytes lost to alignment, or left at the end of a range that the next
+/// allocation did not fit in
+static WASTED: AtomicUsize = AtomicUsize::new(0);
+/// Number of ranges taken from the memory iterators
+static RANGES: AtomicUsize = AtomicUsize::new(0);
+
+/// Totals over every `StealMem`, as shown by the debugger monitor
+#[derive(Debug, Copy, Clone)]
+pub struct StealStats {
+    pub allocations: usize,
+    pub allocated: usize,
+    pub wasted: usize,
+    pub ranges: usize,
+}
+
+/// Current allocation totals
+pub fn stats() -> StealStats {
+    StealStats {
+        allocations: ALLOCATIONS.load(Ordering::Relaxed),
+        allocated: ALLOCATED.load(Ordering::Relaxed),
+        wasted: WASTED.load(Ordering::Relaxed),
+        ranges: RANGES.load(Ordering::Relaxed),
+    }
+}
+
 /// Custom box for our returned alloccations
 /// This does not implement drop as we do not support freeing these.
 /// Has a reference to phantom data to ensure this allocation does not
@@ -65,6 +96,18 @@ impl<'a, T> ops::Place<T> for StealBoxPlace<'a, T> {
     }
 }
 
+/// Size of a frame as handed out by a `FrameAllocator`
+pub const FRAME_SIZE: usize = 4 * util::KB;
+
+/// Allocator of physical frames. Used for structures that hardware finds
+/// by physical address, such as page tables, where the caller needs to
+/// know the physical address and does not want an object constructed
+pub trait FrameAllocator {
+    /// Allocate a single zeroed frame of `FRAME_SIZE` bytes, aligned to
+    /// its size
+    fn alloc_frame(&mut self) -> Option<PAddr>;
+}
+
 /// Abstract implementation of the memory stealing allocator.
 ///
 /// Allocations can be performed by
@@ -132,6 +175,9 @@ impl<'a, 'w, I, W> StealMem<'a, 'w, I, W>
         let next_base = PAddr(util::round_up((self.range.0).0, align));
         /* see if this fits */
         if next_base.0 + size <= (self.range.1).0 {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+            ALLOCATED.fetch_add(size, Ordering::Relaxed);
+            WASTED.fetch_add(next_base.0 - (self.range.0).0, Ordering::Relaxed);
             (self.range.0).0 = next_base.0 + size;
             return Some(next_base);
         }
@@ -139,6 +185,8 @@ impl<'a, 'w, I, W> StealMem<'a, 'w, I, W>
         match self.iter.next() {
             Some(range) =>
                 {
+                    WASTED.fetch_add((self.range.1).0 - (self.range.0).0, Ordering::Relaxed);
+                    RANGES.fetch_add(1, Ordering::Relaxed);
                     self.range = range;
                     self.alloc_raw(size, align)
                 },
@@ -146,3 +194,20 @@ impl<'a, 'w, I, W> StealMem<'a, 'w, I, W>
         }
     }
 }
+
+impl<'a, 'w, I, W> FrameAllocator for StealMem<'a, 'w, I, W>
+        where I: Iterator<Item=(PAddr, PAddr)>, W:VSpaceWindow<'a> {
+    /// Frames are zeroed through the window of the allocator, so the same
+    /// restrictions as `alloc` apply about the window covering the memory
+    fn alloc_frame(&mut self) -> Option<PAddr> {
+        let paddr = match self.alloc_raw(FRAME_SIZE, FRAME_SIZE) {
+            Some(p) => p,
+            None => return None,
+        };
+        unsafe {
+            let base = self.window.from_paddr(paddr);
+            ptr::write_bytes(*base as *mut u8, 0, FRAME_SIZE);
+        }
+        Some(paddr)
+    }
+}
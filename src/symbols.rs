@@ -0,0 +1,146 @@
+//! Kernel symbol table
+//!
+//! The build links the kernel twice. The symbols of the first link are
+//! turned into a table by `gen_ksyms.sh` and placed in the `.ksyms` section
+//! of the second, which the linker script puts after everything else so
+//! that no code moves between the two links. The table is sorted by
+//! address and holds the mangled names, which are demangled when printed.
+use ::core::{fmt, slice, str};
+use ::core::fmt::Write;
+
+extern {
+    /// Number of symbols
+    static ksyms_count: u64;
+    /// Address of each symbol, in increasing order
+    static ksyms_addresses: u64;
+    /// Offset of the name of each symbol into `ksyms_names`, with an extra
+    /// entry for the end of the last name
+    static ksyms_offsets: u32;
+    /// Names of all the symbols, back to back
+    static ksyms_names: u8;
+}
+
+/// The embedded table
+struct Table {
+    addresses: &'static [u64],
+    offsets: &'static [u32],
+    names: &'static [u8],
+}
+
+fn table() -> Table {
+    unsafe {
+        let count = ksyms_count as usize;
+        let offsets = slice::from_raw_parts(&ksyms_offsets as *const u32, count + 1);
+        Table {
+            addresses: slice::from_raw_parts(&ksyms_addresses as *const u64, count),
+            offsets: offsets,
+            names: slice::from_raw_parts(&ksyms_names as *const u8, offsets[count] as usize),
+        }
+    }
+}
+
+/// Find the symbol containing `addr`, returning its name and the offset
+/// of `addr` into it. Symbol sizes are not recorded, so any address up to
+/// the next symbol is attributed to the one before it
+pub fn lookup(addr: usize) -> Option<(&'static str, usize)> {
+    let table = table();
+    let index = match table.addresses.binary_search(&(addr as u64)) {
+        Ok(i) => i,
+        Err(0) => return None,
+        Err(i) => i - 1,
+    };
+    let name = &table.names[table.offsets[index] as usize..table.offsets[index + 1] as usize];
+    str::from_utf8(name).ok()
+        .map(|name| (name, addr - table.addresses[index] as usize))
+}
+
+/// Display of a symbol name, demangling it if it is a mangled Rust name
+pub struct Demangle<'a>(pub &'a str);
+
+/// Escape sequences used in mangled names
+const ESCAPES: [(&'static str, &'static str); 12] = [
+    ("$LT$", "<"), ("$GT$", ">"), ("$RF$", "&"), ("$BP$", "*"), ("$C$", ","), ("$SP$", "@"),
+    ("$u20$", " "), ("$u27$", "'"), ("$u5b$", "["), ("$u5d$", "]"), ("$u7b$", "{"), ("$u7d$", "}"),
+];
+
+/// Write a single path component, undoing the escaping of the mangling
+fn write_component(f: &mut fmt::Formatter, mut s: &str) -> fmt::Result {
+    while !s.is_empty() {
+        if s.starts_with("..") {
+            try!(f.write_str("::"));
+            s = &s[2..];
+            continue;
+        }
+        match ESCAPES.iter().find(|&&(from, _)| s.starts_with(from)) {
+            Some(&(from, to)) => {
+                try!(f.write_str(to));
+                s = &s[from.len()..];
+            },
+            None => {
+                let len = s.chars().next().map(|c| c.len_utf8()).unwrap_or(1);
+                try!(f.write_str(&s[..len]));
+                s = &s[len..];
+            },
+        }
+    }
+    Ok(())
+}
+
+/// True for the hash that ends every mangled name
+fn is_hash(component: &str) -> bool {
+    component.len() == 17 && component.starts_with('h')
+        && component[1..].bytes().all(|b| (b as char).is_digit(16))
+}
+
+impl<'a> fmt::Display for Demangle<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        /* mangled names are _ZN followed by length prefixed components and
+         * a trailing E. Anything else is printed as is */
+        let mut rest = match (self.0.starts_with("_ZN"), self.0.ends_with('E')) {
+            (true, true) if self.0.len() > 4 => &self.0[3..self.0.len() - 1],
+            _ => return f.write_str(self.0),
+        };
+        let mut first = true;
+        while !rest.is_empty() {
+            let digits = rest.bytes().take_while(|b| (*b as char).is_digit(10)).count();
+            let len = match rest[..digits].parse::<usize>() {
+                Ok(len) if digits + len <= rest.len() => len,
+                _ => return f.write_str(self.0),
+            };
+            let component = &rest[digits..digits + len];
+            rest = &rest[digits + len..];
+            if rest.is_empty() && is_hash(component) {
+                break;
+            }
+            if !first {
+                try!(f.write_str("::"));
+            }
+            first = false;
+            try!(write_component(f, component));
+        }
+        Ok(())
+    }
+}
+
+/// Write `addr` as a symbol and offset, if it is in the table
+pub fn write_symbol(out: &mut Write, addr: usize) -> fmt::Result {
+    match lookup(addr) {
+        Some((name, offset)) => write!(out, "{}+{:#x}", Demangle(name), offset),
+        None => write!(out, "?"),
+    }
+}
+
+/// Write a backtrace, one return address per line. Return addresses point
+/// just after the call, which may be past the end of the calling function
+/// if the call never returns, so the symbol is looked up one byte before
+pub fn write_backtrace<I: Iterator<Item=usize>>(out: &mut Write, frames: I) -> fmt::Result {
+    try!(write!(out, "Backtrace:\n"));
+    for (i, addr) in frames.enumerate() {
+        try!(write!(out, "  {:2} {:016x} ", i, addr));
+        match lookup(addr - 1) {
+            Some((name, offset)) => try!(write!(out, "{}+{:#x}\n", Demangle(name), offset + 1)),
+            None => try!(write!(out, "?\n")),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,688 @@
+//! Running threads
+//!
+//! The processor runs one thread at a time, the current one (see `sched`).
+//! Entering the kernel from user level saves the registers of the current
+//! thread into its TCB, and leaving loads the registers, address space and
+//! thread local storage base of whichever thread is current by then, which
+//! is all a switch between threads is. Everything specific to the
+//! processor is behind `ArchThread`.
+//!
+//! System calls take capabilities by address in the capability space of
+//! the caller, at the full depth of a capability pointer. The capability
+//! a system call invokes, its first argument, can instead be given as a
+//! proxy (see `cap::proxy`), either in the caller's address space or in
+//! that of another protection domain, as chosen by the bits of the system
+//! call word above its number. System calls return zero on success, or
+//! the negation of a `SyscallError`. The only ones so far are for queued
+//! IPC (see `ipc`), yielding, configuring scheduling contexts, managing
+//! capabilities in CNodes, reading the kernel log (see `klog`), and
+//! isolating the DMA of devices with IOMMU domains. A revoke can take any
+//! amount of time, so it stops after `REVOKE_BATCH` deletions and has the
+//! thread make the same system call again once it is next run, which
+//! carries on from where it stopped. A thread that faults is stopped, as
+//! faults cannot yet be sent to its fault handler.
+//!
+//! The first thread is made during early boot, whilst the boot allocator
+//! is still around, and started once boot is done. It gets a CNode with
+//! capabilities to its own CNode, protection domain, TCB and scheduling
+//! context, to the scheduling control of the boot processor, to the kernel
+//! log and to IOMMU control, and runs code supplied by the architecture.
+//! Its boot information, mapped read only at `INITIAL_BOOT_INFO`, describes
+//! the devices found during device discovery (see `map_boot_info`).
+use arch::{self, Arch};
+use cap::{self, Cap, CapError, CapKind, CapRights, CNode, CNodeCap, CPtr, InvokeAddr, Preempt,
+    Slot, REVOKE_BATCH, lookup_invocation, lookup_slot};
+use cap::cnode::CPTR_BITS;
+use ipc::{self, IpcError};
+use object::{Endpoint, Pd, SchedContext, Shared, Tcb, TcbShared, ThreadState, MAX_PRIORITY};
+use sched::{self, BOOT_CPU};
+use steal_mem::{FrameAllocator, FRAME_SIZE, StealMem};
+use types::PAddr;
+use vspace::VSpaceWindow;
+use arch::DeviceWindow;
+use klog;
+use log;
+use plat::{self, DmaError, BOOT_DEVICE_SIZE};
+use util::Volatile;
+use ::core::cmp;
+use ::core::mem::align_of;
+use ::core::ptr;
+
+/// Processor specific support for running user level threads
+pub trait ArchThread {
+    /// Saved user level registers
+    type Context: Copy;
+    /// Registers of a new thread that starts at `entry` with its stack
+    /// pointer at `stack`
+    fn new_context(entry: usize, stack: usize) -> Self::Context;
+    /// Set the value a system call returns
+    fn set_result(context: &mut Self::Context, result: usize);
+    /// Set the message and badge that a call or reply delivers
+    fn set_message(context: &mut Self::Context, badge: u64, message: usize);
+    /// Have the thread make system call `number` again, with the same
+    /// arguments, when it next returns to user level
+    fn restart_syscall(context: &mut Self::Context, number: usize);
+    /// Set up the calling processor to run user level
+    unsafe fn init_cpu();
+    /// Construct an address space with the kernel mapped and nothing else
+    unsafe fn new_vspace<A: FrameAllocator>(alloc: &mut A) -> Option<PAddr>;
+    /// Map `frame` at the user address `vaddr` of `vspace`, allocating
+    /// tables from `alloc`. Returns false if it could not be mapped
+    unsafe fn map_frame<A: FrameAllocator>(vspace: PAddr, vaddr: usize, frame: PAddr, write: bool,
+        alloc: &mut A) -> bool;
+    /// Code of the first thread, which must be position independent
+    fn initial_code() -> &'static [u8];
+    /// Switch to `vspace` and return to user level with `context`, using
+    /// `tls_base` as the base of thread local storage
+    unsafe fn switch_to(context: &Self::Context, vspace: PAddr, tls_base: usize) -> !;
+}
+
+/// Registers of a thread
+pub type Context = <Arch as ArchThread>::Context;
+
+#[derive(Debug, Copy, Clone)]
+/// Why user level entered the kernel
+pub enum Trap {
+    /// System call, with the word holding its number and how the invoked
+    /// capability is addressed, and its arguments
+    Syscall(usize, [usize; 6]),
+    /// Processor exception, by architecture specific vector, along with its
+    /// error code and the faulting address, if it has one
+    Fault { vector: u64, code: u64, address: usize },
+    /// The timer set by the scheduler went off
+    Timer,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// Errors from system calls, which are returned negated
+pub enum SyscallError {
+    /// There is no system call with that number
+    NoSyscall = 1,
+    /// A capability was not found, was of the wrong kind, or lacked a right
+    InvalidCap = 2,
+    /// An argument did not make sense
+    InvalidArgument = 3,
+    /// A reply was sent with no caller waiting for one
+    NoCaller = 4,
+    /// A receive was done with a caller still waiting for a reply
+    ReplyPending = 5,
+    /// A slot that should have held a capability was empty
+    SlotEmpty = 6,
+    /// A slot that should have been empty held a capability
+    SlotOccupied = 7,
+    /// The capability cannot be copied, or given the badge or guard asked
+    /// for
+    CannotDerive = 8,
+    /// Memory set aside for the operation has run out
+    NoMemory = 9,
+    /// Hardware the operation needs is missing or cannot be reached
+    NoHardware = 10,
+}
+
+impl From<CapError> for SyscallError {
+    fn from(err: CapError) -> SyscallError {
+        match err {
+            CapError::SlotEmpty => SyscallError::SlotEmpty,
+            CapError::SlotOccupied => SyscallError::SlotOccupied,
+            CapError::CannotDerive => SyscallError::CannotDerive,
+            /* only revoke is preempted, and it is restarted instead */
+            CapError::InvalidArgument | CapError::Preempted => SyscallError::InvalidArgument,
+            CapError::NotCNode | CapError::GuardMismatch | CapError::BadDepth
+                | CapError::NotProxyPage | CapError::InvalidProxy | CapError::TtlNotDecreasing
+                | CapError::InsufficientRights | CapError::NotPd => SyscallError::InvalidCap,
+        }
+    }
+}
+
+impl From<DmaError> for SyscallError {
+    fn from(err: DmaError) -> SyscallError {
+        match err {
+            DmaError::NoMemory => SyscallError::NoMemory,
+            DmaError::NoUnit | DmaError::Unmappable => SyscallError::NoHardware,
+            DmaError::BadAddress | DmaError::AlreadyMapped | DmaError::NotMapped
+                | DmaError::NoDomain => SyscallError::InvalidArgument,
+        }
+    }
+}
+
+impl From<IpcError> for SyscallError {
+    fn from(err: IpcError) -> SyscallError {
+        match err {
+            IpcError::NoCaller => SyscallError::NoCaller,
+            IpcError::ReplyPending => SyscallError::ReplyPending,
+        }
+    }
+}
+
+/// Where the first thread has its code, stack and shared TCB state
+const INITIAL_CODE: usize = 0x400000;
+const INITIAL_STACK: usize = 0x800000;
+const INITIAL_SHARED: usize = 0xa00000;
+/// Where the boot information of the first thread is mapped
+pub const INITIAL_BOOT_INFO: usize = 0xc00000;
+/// Device descriptions that fit in each frame of the boot information
+const BOOT_RECORDS_PER_FRAME: usize = FRAME_SIZE / BOOT_DEVICE_SIZE;
+/// Radix of the CNode of the first thread, small enough to fit in a frame
+const INITIAL_CNODE_RADIX: u8 = 5;
+/// Slots of the first CNode holding capabilities to the first thread's
+/// CNode, protection domain, TCB and scheduling context, to the scheduling
+/// control of the boot processor, to the kernel log and to IOMMU control
+pub const INITIAL_SLOT_CNODE: usize = 1;
+pub const INITIAL_SLOT_PD: usize = 2;
+pub const INITIAL_SLOT_TCB: usize = 3;
+pub const INITIAL_SLOT_SC: usize = 4;
+pub const INITIAL_SLOT_SCHED_CONTROL: usize = 5;
+pub const INITIAL_SLOT_LOG: usize = 6;
+pub const INITIAL_SLOT_IOMMU: usize = 7;
+/// Budget and period of the first thread, in microseconds. It has the whole
+/// of the processor until it hands some out
+const INITIAL_BUDGET_US: u64 = 10_000;
+const INITIAL_PERIOD_US: u64 = 10_000;
+
+/// Give up the rest of the turn to other threads of the same priority
+pub const SYS_YIELD: usize = 0;
+/// Call the endpoint at the first argument with the second as the message,
+/// and wait for the reply
+pub const SYS_CALL: usize = 1;
+/// Receive a call on the endpoint at the first argument
+pub const SYS_RECV: usize = 2;
+/// Reply to the caller with the first argument as the message
+pub const SYS_REPLY: usize = 3;
+/// Reply with the second argument as the message, then receive on the
+/// endpoint at the first argument
+pub const SYS_REPLY_RECV: usize = 4;
+/// Using the scheduling control at the first argument, set the scheduling
+/// context at the second to the budget in the low 32 bits of the third,
+/// and the period in its high 32 bits, both in microseconds
+pub const SYS_SC_CONFIGURE: usize = 5;
+/// Copy a capability between two slots of the CNode whose capability is at
+/// the first argument, which needs the write right. Slots are addressed
+/// within that CNode, at the depth in the second argument. The destination,
+/// which must be empty, is the third argument and the source the fourth
+pub const SYS_CNODE_COPY: usize = 6;
+/// As for `SYS_CNODE_COPY`, keeping only the rights in the fifth argument,
+/// encoded as for `CapRights::from_word`, and applying the sixth as a badge
+/// or guard as for `Cap::mint`
+pub const SYS_CNODE_MINT: usize = 7;
+/// As for `SYS_CNODE_COPY`, but moving the capability instead
+pub const SYS_CNODE_MOVE: usize = 8;
+/// Empty the slot at the third argument, addressed as for `SYS_CNODE_COPY`
+pub const SYS_CNODE_DELETE: usize = 9;
+/// Delete everything derived from the capability in the slot at the third
+/// argument, addressed as for `SYS_CNODE_COPY`
+pub const SYS_CNODE_REVOKE: usize = 10;
+/// Turn the proxy at the fourth argument, in the caller's address space,
+/// into a real capability in the empty slot at the third argument,
+/// addressed as for `SYS_CNODE_COPY`
+pub const SYS_CNODE_LINK: usize = 11;
+/// Mark the user page at the second argument, in the address space of the
+/// protection domain at the first argument, as holding proxies if the
+/// third argument is non zero, or unmark it otherwise. The capability to
+/// the protection domain needs the write right
+pub const SYS_PD_MARK_PROXY_PAGE: usize = 12;
+/// Copy kernel log output into the caller's memory at the second argument,
+/// at most the third argument bytes of it, starting from the position in
+/// the log at the fourth argument. The first argument is the log
+/// capability. The position of the first byte copied and the number of
+/// bytes copied are returned in the badge and message registers, as for a
+/// reply (see `klog::read`)
+pub const SYS_LOG_READ: usize = 13;
+/// Make an IOMMU domain with nothing mapped, and put a capability to it in
+/// the empty slot at the fourth argument of the CNode at the second,
+/// translating the third argument bits of it. The first argument is the
+/// IOMMU control capability
+pub const SYS_IOMMU_CREATE_DOMAIN: usize = 14;
+/// Bind the PCI function with the requester ID in the third argument to
+/// the IOMMU domain at the first argument, which needs the write right.
+/// The second argument is the IOMMU control capability
+pub const SYS_IOMMU_BIND: usize = 15;
+/// Block all DMA of the PCI function with the requester ID in the second
+/// argument. The first argument is the IOMMU control capability
+pub const SYS_IOMMU_UNBIND: usize = 16;
+/// Map the frame at the caller's page at the second argument into the
+/// IOMMU domain at the first argument, which needs the write right, at the
+/// I/O address in the third argument. Devices may write to it if the
+/// fourth argument is non zero and the caller's page is writable
+pub const SYS_IOMMU_MAP: usize = 17;
+/// Unmap the I/O address in the second argument from the IOMMU domain at
+/// the first argument, which needs the write right. Devices may use the
+/// mapping until `SYS_IOMMU_INVALIDATE`
+pub const SYS_IOMMU_UNMAP: usize = 18;
+/// Flush translations of the IOMMU domain at the first argument cached by
+/// the hardware
+pub const SYS_IOMMU_INVALIDATE: usize = 19;
+
+/// Bits of the system call word holding the system call number. The bits
+/// above select how the invoked capability is addressed, and for
+/// `SYSCALL_ADDR_REMOTE` the bits above `SYSCALL_REMOTE_SHIFT` are the
+/// capability pointer of the protection domain the proxy is in
+pub const SYSCALL_NUMBER_BITS: usize = 8;
+pub const SYSCALL_REMOTE_SHIFT: usize = 16;
+/// The invoked capability is at a capability pointer
+pub const SYSCALL_ADDR_CAP: usize = 0;
+/// The invoked capability is the proxy at a user address
+pub const SYSCALL_ADDR_PROXY: usize = 1;
+/// The invoked capability is the proxy at a user address of another
+/// protection domain
+pub const SYSCALL_ADDR_REMOTE: usize = 2;
+
+/// Most bytes of the kernel log `SYS_LOG_READ` copies at once, as they go
+/// through the kernel stack
+const LOG_READ_CHUNK: usize = 256;
+
+/// Some thread has made a system call. Only the first is logged, as the
+/// sign that user level is up and running
+static mut SYSCALL_SEEN: bool = false;
+
+/// Kernel pointer to a frame from the boot allocator
+unsafe fn frame_ptr<T>(frame: PAddr) -> Result<*mut T, ()> {
+    let window: DeviceWindow<'static> = DeviceWindow::new(());
+    window.try_from_paddr(frame)
+        .and_then(|addr| window.make::<[u8; FRAME_SIZE]>(addr))
+        .map(|frame| frame as *const [u8; FRAME_SIZE] as *mut T)
+        .ok_or(())
+}
+
+/// Put `cap` in slot `index` of `cnode` as an original capability
+unsafe fn insert_initial(cnode: CNode, index: usize, cap: Cap) -> Result<(), ()> {
+    let slot = try!(cnode.slot(index).ok_or(()));
+    cap::insert_original(slot, cap).map_err(|_| ())
+}
+
+/// Map the boot information of the first thread at `INITIAL_BOOT_INFO` in
+/// `vspace`, read only. It is a run of `BOOT_DEVICE_SIZE` byte records, the
+/// first holding the number of devices described and then the number that
+/// could not be recorded, as 8 byte little endian values, and each after
+/// that describing a device as for `plat::write_boot_device`
+unsafe fn map_boot_info<A: FrameAllocator>(vspace: PAddr, alloc: &mut A) -> Result<(), ()> {
+    let (count, dropped) = plat::boot_device_count();
+    let frames = (count + BOOT_RECORDS_PER_FRAME) / BOOT_RECORDS_PER_FRAME;
+    let mut index = 0;
+    for page in 0..frames {
+        let frame = try!(alloc.alloc_frame().ok_or(()));
+        if !Arch::map_frame(vspace, INITIAL_BOOT_INFO + page * FRAME_SIZE, frame, false, alloc) {
+            return Err(());
+        }
+        let records = &mut *try!(
+            frame_ptr::<[[u8; BOOT_DEVICE_SIZE]; BOOT_RECORDS_PER_FRAME]>(frame));
+        for record in records.iter_mut() {
+            if index == 0 {
+                for i in 0..8 {
+                    record[i] = (count >> (i * 8)) as u8;
+                    record[8 + i] = (dropped >> (i * 8)) as u8;
+                }
+            } else if !plat::write_boot_device(index - 1, record) {
+                break;
+            }
+            index += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Make the first thread and everything it needs, ready to be started by
+/// `start` once boot is done
+///
+/// # Safety
+///
+/// The allocator must return memory in the device window, which must
+/// outlive the thread
+pub unsafe fn create_initial<'a, 'w, I, W>(alloc: &mut StealMem<'a, 'w, I, W>) -> Result<*mut Tcb, ()>
+        where I: Iterator<Item=(PAddr, PAddr)>, W: VSpaceWindow<'a> {
+    let code = Arch::initial_code();
+    if code.len() > FRAME_SIZE || CNode::size(INITIAL_CNODE_RADIX) > FRAME_SIZE {
+        return Err(());
+    }
+    let vspace = try!(Arch::new_vspace(alloc).ok_or(()));
+    /* code, stack and shared state each get a frame of their own */
+    let mut frames = [PAddr(0); 3];
+    for (frame, &(vaddr, write)) in frames.iter_mut()
+            .zip([(INITIAL_CODE, false), (INITIAL_STACK, true), (INITIAL_SHARED, true)].iter()) {
+        *frame = try!(alloc.alloc_frame().ok_or(()));
+        if !Arch::map_frame(vspace, vaddr, *frame, write, alloc) {
+            return Err(());
+        }
+    }
+    ptr::copy_nonoverlapping(code.as_ptr(), try!(frame_ptr::<u8>(frames[0])), code.len());
+    try!(map_boot_info(vspace, alloc));
+    let shared = try!(Shared::<TcbShared>::new(frames[2]).map_err(|_| ()));
+    /* a guard over the rest of the address puts every slot at a depth of a
+     * whole capability pointer */
+    let cnode_frame = try!(alloc.alloc_frame().ok_or(()));
+    let cnode = try!(CNode::new(try!(frame_ptr(cnode_frame)), INITIAL_CNODE_RADIX).ok_or(()));
+    let cnode_cap = try!(CNodeCap::new(cnode)
+        .with_guard((CPTR_BITS - INITIAL_CNODE_RADIX as u32) as u64).map_err(|_| ()));
+    let cnode_cap = Cap::new(CapKind::CNode(cnode_cap));
+    let pd_place = try!(alloc.alloc::<Pd>(align_of::<Pd>()).ok_or(()));
+    let mut pd = pd_place <- try!(Pd::new(vspace, cnode_cap).map_err(|_| ()));
+    let pd = &mut *pd as *mut Pd;
+    let context = Arch::new_context(INITIAL_CODE, INITIAL_STACK + FRAME_SIZE);
+    let tcb_place = try!(alloc.alloc::<Tcb>(align_of::<Tcb>()).ok_or(()));
+    let mut tcb = tcb_place <- Tcb::new(shared, pd, context, MAX_PRIORITY);
+    let tcb = &mut *tcb as *mut Tcb;
+    let sc_place = try!(alloc.alloc::<SchedContext>(align_of::<SchedContext>()).ok_or(()));
+    let mut sc = sc_place <- try!(SchedContext::new(sched::us_to_cycles(INITIAL_BUDGET_US),
+        sched::us_to_cycles(INITIAL_PERIOD_US), arch::rdtsc()).map_err(|_| ()));
+    let sc = &mut *sc as *mut SchedContext;
+    sched::bind(tcb, sc);
+    try!(insert_initial(cnode, INITIAL_SLOT_CNODE, cnode_cap));
+    try!(insert_initial(cnode, INITIAL_SLOT_PD, Cap::new(CapKind::Pd(pd))));
+    try!(insert_initial(cnode, INITIAL_SLOT_TCB, Cap::new(CapKind::Tcb(tcb))));
+    try!(insert_initial(cnode, INITIAL_SLOT_SC, Cap::new(CapKind::SchedContext(sc))));
+    try!(insert_initial(cnode, INITIAL_SLOT_SCHED_CONTROL,
+        Cap::new(CapKind::SchedControl(BOOT_CPU))));
+    try!(insert_initial(cnode, INITIAL_SLOT_LOG, Cap::new(CapKind::Log)));
+    try!(insert_initial(cnode, INITIAL_SLOT_IOMMU, Cap::new(CapKind::IommuControl)));
+    Ok(tcb)
+}
+
+/// Make `tcb` runnable and start running threads
+///
+/// # Safety
+///
+/// Must be called once, at the end of boot, with the thread from
+/// `create_initial`
+pub unsafe fn start(tcb: *mut Tcb) -> ! {
+    info!("Starting first thread {:p}", tcb);
+    sched::make_runnable(tcb);
+    resume()
+}
+
+/// Return to whichever thread the scheduler picks, waiting for one if none
+/// can run
+fn resume() -> ! {
+    let tcb = unsafe{&*sched::schedule()};
+    let tls_base = match tcb.tls_base() {
+        Ok(tls_base) => tls_base,
+        Err(err) => {
+            warn!("Thread {:p} has bad shared state {:?}, ignoring its TLS base", tcb, err);
+            0
+        },
+    };
+    unsafe{Arch::switch_to(tcb.context(), (*tcb.pd()).vspace(), tls_base)}
+}
+
+/// Called by the architecture for every entry to the kernel from the
+/// current thread, with the registers it entered with
+pub fn user_trap(context: &Context, trap: Trap) -> ! {
+    sched::charge();
+    let tcb = unsafe{&mut *sched::current()};
+    *tcb.context_mut() = *context;
+    match trap {
+        Trap::Syscall(number, args) => {
+            if unsafe{!SYSCALL_SEEN} {
+                unsafe{SYSCALL_SEEN = true};
+                info!("User level is running, thread {:p} made system call {}", tcb, number);
+            }
+            /* success is set first, as a call that blocks has nothing
+             * more done to its result when the reply wakes it */
+            Arch::set_result(tcb.context_mut(), 0);
+            if let Err(err) = unsafe{syscall(tcb, number, args)} {
+                debug!("Thread {:p} system call {} failed: {:?}", tcb, number, err);
+                Arch::set_result(tcb.context_mut(), (err as usize).wrapping_neg());
+            }
+        },
+        Trap::Timer => log::poll_console(),
+        Trap::Fault { vector, code, address } => {
+            warn!("Thread {:p} fault {} code {:x} address {:x}, stopping it", tcb, vector, code,
+                address);
+            if let Some(handler) = tcb.fault_handler() {
+                warn!("Fault handler {:x} cannot be told, there is no fault IPC", handler.0);
+            }
+            unsafe{sched::block(tcb, ThreadState::Inactive)};
+        },
+    }
+    resume()
+}
+
+/// Address of the capability at `cptr` in the capability space of the
+/// caller
+fn cptr(cptr: usize) -> InvokeAddr {
+    InvokeAddr::Cap(CPtr(cptr as u64), CPTR_BITS)
+}
+
+/// How the capability invoked by a system call, at `arg`, is addressed,
+/// from the bits of the system call word above the number
+fn invoked_addr(word: usize, arg: usize) -> Result<InvokeAddr, SyscallError> {
+    let mode = (word & ((1 << SYSCALL_REMOTE_SHIFT) - 1)) >> SYSCALL_NUMBER_BITS;
+    match mode {
+        SYSCALL_ADDR_CAP => Ok(cptr(arg)),
+        SYSCALL_ADDR_PROXY => Ok(InvokeAddr::Proxy(arg)),
+        SYSCALL_ADDR_REMOTE => Ok(InvokeAddr::Remote {
+            pd: CPtr((word >> SYSCALL_REMOTE_SHIFT) as u64),
+            depth: CPTR_BITS,
+            proxy: arg,
+        }),
+        _ => Err(SyscallError::InvalidArgument),
+    }
+}
+
+/// Find the capability at `addr`, which must have at least `rights`. The
+/// capability has the rights it was found with, which for a proxy are
+/// those of the whole chain
+fn lookup_cap(tcb: &Tcb, addr: InvokeAddr, rights: CapRights) -> Result<Cap, SyscallError> {
+    let pd = unsafe{&*tcb.pd()};
+    let resolved = try!(lookup_invocation(pd, addr));
+    if !resolved.rights.contains(rights) {
+        return Err(SyscallError::InvalidCap);
+    }
+    let mut cap = unsafe{*(*resolved.slot).cap()};
+    cap.rights = resolved.rights;
+    Ok(cap)
+}
+
+/// Find the endpoint at `addr`, and the badge of the capability to it
+fn lookup_endpoint(tcb: &Tcb, addr: InvokeAddr, rights: CapRights)
+        -> Result<(*mut Endpoint, u64), SyscallError> {
+    let cap = try!(lookup_cap(tcb, addr, rights));
+    match cap.kind {
+        CapKind::Endpoint(ep) => Ok((ep, cap.badge)),
+        _ => Err(SyscallError::InvalidCap),
+    }
+}
+
+/// Find the slot at `index`, translating `depth` bits of it, in the CNode
+/// whose capability is at `addr`. The capability must have the write right
+fn lookup_cnode_slot(tcb: &Tcb, addr: InvokeAddr, depth: usize, index: usize)
+        -> Result<*mut Slot, SyscallError> {
+    let write = CapRights { read: false, write: true, grant: false };
+    let cap = try!(lookup_cap(tcb, addr, write));
+    match cap.kind {
+        CapKind::CNode(_) => (),
+        _ => return Err(SyscallError::InvalidCap),
+    }
+    if depth > CPTR_BITS as usize {
+        return Err(SyscallError::InvalidArgument);
+    }
+    Ok(try!(lookup_slot(&cap, CPtr(index as u64), depth as u32)))
+}
+
+/// Copy at most `len` bytes of the kernel log from position `pos` to the
+/// user address `vaddr` of `vspace`, which must be mapped writable. The
+/// log is read a piece at a time, and copying stops early if more was
+/// logged meanwhile than the ring holds, so what is copied is always one
+/// unbroken run of the log
+unsafe fn read_log(vspace: PAddr, vaddr: usize, len: usize, pos: u64)
+        -> Result<(u64, usize), SyscallError> {
+    let window: DeviceWindow<'static> = DeviceWindow::new(());
+    let mut buf = [0u8; LOG_READ_CHUNK];
+    let mut start = None;
+    let mut done = 0;
+    while done < len {
+        let addr = try!(vaddr.checked_add(done).ok_or(SyscallError::InvalidArgument));
+        let paddr = match arch::lookup_user_page(vspace, addr) {
+            Some(ref page) if page.writable => page.paddr,
+            _ => return Err(SyscallError::InvalidArgument),
+        };
+        /* pieces never cross a page, so each is one physical run */
+        let want = cmp::min(cmp::min(len - done, LOG_READ_CHUNK), FRAME_SIZE - addr % FRAME_SIZE);
+        let next = start.map_or(pos, |start| start + done as u64);
+        let (from, count) = klog::read(next, &mut buf[..want]);
+        if start.map_or(false, |_| from != next) {
+            break;
+        }
+        if start.is_none() {
+            start = Some(from);
+        }
+        let dest = try!(window.try_from_paddr(paddr)
+            .and_then(|addr| window.make_slice::<Volatile<u8>>(addr, count))
+            .ok_or(SyscallError::InvalidArgument));
+        for (dest, &byte) in dest.iter().zip(buf[..count].iter()) {
+            dest.write(byte);
+        }
+        done += count;
+        if count < want {
+            break;
+        }
+    }
+    Ok((start.unwrap_or(pos), done))
+}
+
+/// Check that the capability at `addr` is to IOMMU control
+fn lookup_iommu_control(tcb: &Tcb, addr: InvokeAddr) -> Result<(), SyscallError> {
+    match try!(lookup_cap(tcb, addr, CapRights::none())).kind {
+        CapKind::IommuControl => Ok(()),
+        _ => Err(SyscallError::InvalidCap),
+    }
+}
+
+/// Find the index of the IOMMU domain at `addr`, whose capability must
+/// have the write right
+fn lookup_iommu_domain(tcb: &Tcb, addr: InvokeAddr) -> Result<usize, SyscallError> {
+    let write = CapRights { read: false, write: true, grant: false };
+    match try!(lookup_cap(tcb, addr, write)).kind {
+        CapKind::IommuDomain(domain) => Ok(domain),
+        _ => Err(SyscallError::InvalidCap),
+    }
+}
+
+/// Carry out the system call in `word` for the current thread
+unsafe fn syscall(tcb: *mut Tcb, word: usize, args: [usize; 6]) -> Result<(), SyscallError> {
+    let send = CapRights { read: false, write: true, grant: false };
+    let receive = CapRights { read: true, write: false, grant: false };
+    let number = word & ((1 << SYSCALL_NUMBER_BITS) - 1);
+    let invoked = try!(invoked_addr(word, args[0]));
+    match number {
+        SYS_YIELD => {
+            sched::yield_current();
+            Ok(())
+        },
+        SYS_CALL => {
+            let (ep, badge) = try!(lookup_endpoint(&*tcb, invoked, send));
+            ipc::call(tcb, ep, badge, args[1]);
+            Ok(())
+        },
+        SYS_RECV | SYS_REPLY_RECV => {
+            let (ep, _) = try!(lookup_endpoint(&*tcb, invoked, receive));
+            if number == SYS_REPLY_RECV {
+                try!(ipc::reply(tcb, args[1]));
+            }
+            Ok(try!(ipc::receive(tcb, ep)))
+        },
+        SYS_REPLY => Ok(try!(ipc::reply(tcb, args[0]))),
+        SYS_SC_CONFIGURE => {
+            let cpu = match try!(lookup_cap(&*tcb, invoked, CapRights::none())).kind {
+                CapKind::SchedControl(cpu) => cpu,
+                _ => return Err(SyscallError::InvalidCap),
+            };
+            let sc = match try!(lookup_cap(&*tcb, cptr(args[1]), send)).kind {
+                CapKind::SchedContext(sc) => sc,
+                _ => return Err(SyscallError::InvalidCap),
+            };
+            let budget = args[2] as u32 as u64;
+            let period = (args[2] as u64) >> 32;
+            sched::configure(cpu, sc, budget, period).map_err(|_| SyscallError::InvalidArgument)
+        },
+        SYS_CNODE_COPY | SYS_CNODE_MINT | SYS_CNODE_MOVE => {
+            let dest = try!(lookup_cnode_slot(&*tcb, invoked, args[1], args[2]));
+            let src = try!(lookup_cnode_slot(&*tcb, invoked, args[1], args[3]));
+            Ok(try!(match number {
+                SYS_CNODE_COPY => cap::copy(src, dest),
+                SYS_CNODE_MINT => cap::mint(src, dest, CapRights::from_word(args[4] as u64),
+                    args[5] as u64),
+                _ => cap::move_cap(src, dest),
+            }))
+        },
+        SYS_CNODE_DELETE => {
+            cap::delete(try!(lookup_cnode_slot(&*tcb, invoked, args[1], args[2])));
+            Ok(())
+        },
+        SYS_CNODE_REVOKE => {
+            let slot = try!(lookup_cnode_slot(&*tcb, invoked, args[1], args[2]));
+            match cap::revoke(slot, &mut Preempt::new(REVOKE_BATCH)) {
+                Err(CapError::Preempted) => {
+                    /* what was deleted stays deleted, so the next attempt
+                     * carries on from here */
+                    Arch::restart_syscall((*tcb).context_mut(), word);
+                    Ok(())
+                },
+                result => Ok(try!(result)),
+            }
+        },
+        SYS_CNODE_LINK => {
+            let dest = try!(lookup_cnode_slot(&*tcb, invoked, args[1], args[2]));
+            let pd = &*(*tcb).pd();
+            Ok(try!(cap::link(pd.vspace(), pd.cspace(), args[3], dest)))
+        },
+        SYS_PD_MARK_PROXY_PAGE => {
+            let write = CapRights { read: false, write: true, grant: false };
+            let pd = match try!(lookup_cap(&*tcb, invoked, write)).kind {
+                CapKind::Pd(pd) => pd,
+                _ => return Err(SyscallError::InvalidCap),
+            };
+            Ok(try!(cap::mark_page((*pd).vspace(), args[1], args[2] != 0)))
+        },
+        SYS_LOG_READ => {
+            match try!(lookup_cap(&*tcb, invoked, receive)).kind {
+                CapKind::Log => (),
+                _ => return Err(SyscallError::InvalidCap),
+            }
+            let (start, count) = try!(read_log((*(*tcb).pd()).vspace(), args[1], args[2],
+                args[3] as u64));
+            Arch::set_message((*tcb).context_mut(), start, count);
+            Ok(())
+        },
+        SYS_IOMMU_CREATE_DOMAIN => {
+            try!(lookup_iommu_control(&*tcb, invoked));
+            let slot = try!(lookup_cnode_slot(&*tcb, cptr(args[1]), args[2], args[3]));
+            /* domains are never freed, so do not make one with nowhere to
+             * put it */
+            if !(*slot).is_empty() {
+                return Err(SyscallError::SlotOccupied);
+            }
+            let domain = try!(plat::iommu_create_domain());
+            Ok(try!(cap::insert_original(slot, Cap::new(CapKind::IommuDomain(domain)))))
+        },
+        SYS_IOMMU_BIND => {
+            let domain = try!(lookup_iommu_domain(&*tcb, invoked));
+            try!(lookup_iommu_control(&*tcb, cptr(args[1])));
+            Ok(try!(plat::iommu_bind(domain, args[2] as u16)))
+        },
+        SYS_IOMMU_UNBIND => {
+            try!(lookup_iommu_control(&*tcb, invoked));
+            Ok(try!(plat::iommu_unbind(args[1] as u16)))
+        },
+        SYS_IOMMU_MAP => {
+            let domain = try!(lookup_iommu_domain(&*tcb, invoked));
+            if args[1] % FRAME_SIZE != 0 {
+                return Err(SyscallError::InvalidArgument);
+            }
+            let page = try!(arch::lookup_user_page((*(*tcb).pd()).vspace(), args[1])
+                .ok_or(SyscallError::InvalidArgument));
+            let write = args[3] != 0;
+            if write && !page.writable {
+                return Err(SyscallError::InvalidArgument);
+            }
+            Ok(try!(plat::iommu_map(domain, args[2] as u64, page.paddr, write)))
+        },
+        SYS_IOMMU_UNMAP => {
+            let domain = try!(lookup_iommu_domain(&*tcb, invoked));
+            Ok(try!(plat::iommu_unmap(domain, args[1] as u64)))
+        },
+        SYS_IOMMU_INVALIDATE => {
+            let domain = try!(lookup_iommu_domain(&*tcb, invoked));
+            Ok(try!(plat::iommu_invalidate(domain)))
+        },
+        _ => Err(SyscallError::NoSyscall),
+    }
+}
@@ -2,7 +2,11 @@
 pub mod constants;
 pub mod string;
 pub mod math;
+pub mod volatile;
+pub mod spinlock;
 
 pub use self::constants::*;
 pub use self::string::*;
 pub use self::math::*;
+pub use self::volatile::*;
+pub use self::spinlock::*;
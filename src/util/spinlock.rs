@@ -0,0 +1,76 @@
+//! Simple spin lock
+//!
+//! For data that is shared between CPUs, or between normal execution and
+//! paths like panic, where there is nothing to block on. The lock is not
+//! fair and does not disable interrupts, so must not be taken by interrupt
+//! handlers that may have interrupted its holder.
+
+use ::core::cell::UnsafeCell;
+use ::core::sync::atomic::{AtomicBool, Ordering};
+use ::core::ops::{Deref, DerefMut};
+
+/// A value protected by a spin lock
+pub struct SpinLock<T> {
+    locked: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+unsafe impl<T: Send> Send for SpinLock<T> {}
+
+/// Access to the value of a held `SpinLock`, which is released when this
+/// is dropped
+pub struct SpinLockGuard<'a, T: 'a> {
+    lock: &'a SpinLock<T>,
+}
+
+impl<T> SpinLock<T> {
+    /// Construct an unlocked lock. This is a `const fn` so that locks can
+    /// be placed in statics
+    pub const fn new(data: T) -> SpinLock<T> {
+        SpinLock { locked: AtomicBool::new(false), data: UnsafeCell::new(data) }
+    }
+    /// Acquire the lock, spinning until it is available
+    pub fn lock(&self) -> SpinLockGuard<T> {
+        while self.locked.compare_and_swap(false, true, Ordering::Acquire) {
+            while self.locked.load(Ordering::Relaxed) {}
+        }
+        SpinLockGuard { lock: self }
+    }
+    /// Acquire the lock if it is available right now
+    pub fn try_lock(&self) -> Option<SpinLockGuard<T>> {
+        if self.locked.compare_and_swap(false, true, Ordering::Acquire) {
+            None
+        } else {
+            Some(SpinLockGuard { lock: self })
+        }
+    }
+    /// Access the value without taking the lock, for when its holder may
+    /// never release it, such as a processor stopped by a panic
+    ///
+    /// # Safety
+    ///
+    /// Nothing else may be accessing the value
+    pub unsafe fn force_get(&self) -> &mut T {
+        &mut *self.data.get()
+    }
+}
+
+impl<'a, T> Deref for SpinLockGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for SpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for SpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+    }
+}
@@ -27,10 +27,16 @@ pub trait FromStrExt: Sized {
     }
 }
 
-/// TODO: automate this with a macro for all the usual types
-impl FromStrExt for u16 {
-    fn from_str_radix(input: &str, radix: u32) -> Result<u16, ParseIntError> {
-        u16::from_str_radix(input, radix)
-    }
+/// Implement `FromStrExt` for integer types that have an inherent
+/// `from_str_radix`
+macro_rules! from_str_ext_impl {
+    ($($t:ty)*) => ($(
+        impl FromStrExt for $t {
+            fn from_str_radix(input: &str, radix: u32) -> Result<$t, ParseIntError> {
+                <$t>::from_str_radix(input, radix)
+            }
+        }
+    )*)
 }
 
+from_str_ext_impl! { u8 u16 u32 u64 usize }
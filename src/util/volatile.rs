@@ -0,0 +1,22 @@
+//! Volatile memory cells
+//!
+//! Used for describing memory that may change underneath us, such as
+//! device registers, in a struct layout that can be created from a window
+
+use ::core::cell::UnsafeCell;
+use ::core::ptr;
+
+/// A value that is always accessed with volatile reads and writes
+#[repr(C)]
+pub struct Volatile<T: Copy>(UnsafeCell<T>);
+
+impl<T: Copy> Volatile<T> {
+    /// Perform a volatile read of the value
+    pub fn read(&self) -> T {
+        unsafe { ptr::read_volatile(self.0.get()) }
+    }
+    /// Perform a volatile write of the value
+    pub fn write(&self, val: T) {
+        unsafe { ptr::write_volatile(self.0.get(), val) }
+    }
+}